@@ -0,0 +1,59 @@
+use std::time::{Duration, Instant};
+
+const ITERATIONS: u32 = 5;
+
+fn bench(name: &str, source: &str) {
+    let mut best = Duration::from_secs(u64::MAX);
+    for _ in 0..ITERATIONS {
+        let start = Instant::now();
+        zx::run(source);
+        best = best.min(start.elapsed());
+    }
+    println!("{:<24} {:?}", name, best);
+}
+
+// A single monomorphic function called many times, so every call site's
+// argument and result end up connected to each other.
+fn shared_function(n: usize) -> String {
+    let mut s = String::from("let f = fun x -> x");
+    for i in 0..n {
+        s += &format!(
+            ";\nlet x{} = f {}",
+            i,
+            if i % 2 == 0 { "true" } else { "false" }
+        );
+    }
+    s
+}
+
+// A long chain of variables, each merging the previous two.
+fn merge_chain(n: usize) -> String {
+    let mut s = String::from("let x0 = true;\nlet x1 = false");
+    for i in 2..n {
+        s += &format!(
+            ";\nlet x{} = if x{} then x{} else x{}",
+            i,
+            i - 1,
+            i - 1,
+            i - 2
+        );
+    }
+    s
+}
+
+// Records nested inside each other, accessed through a chain of fields.
+fn nested_records(n: usize) -> String {
+    let mut s = String::from("let r0 = { a = true }");
+    for i in 1..n {
+        s += &format!(";\nlet r{} = {{ a = r{}.a; b = r{} }}", i, i - 1, i - 1);
+    }
+    s
+}
+
+fn main() {
+    for &n in &[100, 1000] {
+        bench(&format!("shared_function/{}", n), &shared_function(n));
+        bench(&format!("merge_chain/{}", n), &merge_chain(n));
+        bench(&format!("nested_records/{}", n), &nested_records(n));
+    }
+}
@@ -0,0 +1,38 @@
+#ifndef ZX_H
+#define ZX_H
+
+#include <stdbool.h>
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Outcome of zx_check. On failure, message describes the first error and
+ * start/end are byte offsets into the source, line/col one based. A panic
+ * in the checker is reported as a failure with all positions 0. */
+typedef struct zx_result {
+    bool ok;
+    char *message;
+    size_t start;
+    size_t end;
+    size_t line;
+    size_t col;
+} zx_result;
+
+/* Parses and type checks a script. Returns the same value as out->ok.
+ * Release out with zx_result_free. */
+bool zx_check(const char *source, zx_result *out);
+void zx_result_free(zx_result *result);
+
+/* Displayed type of the global `name` defined by `source`, or NULL if the
+ * script doesn't check, doesn't define it or the checker panics. Release
+ * with zx_string_free. */
+char *zx_type_of(const char *source, const char *name);
+void zx_string_free(char *s);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
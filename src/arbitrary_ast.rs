@@ -0,0 +1,125 @@
+use crate::ast::{Expr, Literal, Spanned, Syntax, TopLevel, VarDefinition};
+use crate::span::Span;
+use arbitrary::{Arbitrary, Result, Unstructured};
+
+// Random ASTs for property tests. Names are drawn from small pools so that
+// generated scripts often refer to variables, fields and tags that exist,
+// which gets much further into the checker than random names would. All
+// spans are empty, and every AST has the shape the parser could produce,
+// so formatting it gives a script that parses back to the same tree.
+
+const VARS: &[&str] = &["a", "b", "f", "x", "y"];
+const FIELDS: &[&str] = &["p", "q", "r"];
+const TAGS: &[&str] = &["`A", "`B", "`C"];
+
+// Limits on the size of generated ASTs
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AstSize {
+    // Maximum nesting of expressions
+    pub depth: usize,
+    // Maximum number of record fields, match arms, recursive definitions
+    // or top level items
+    pub width: usize,
+}
+
+impl Default for AstSize {
+    fn default() -> Self {
+        Self { depth: 4, width: 3 }
+    }
+}
+
+fn spanned<T>(t: T) -> Spanned<T> {
+    (t, Span::default())
+}
+
+fn name(u: &mut Unstructured, pool: &[&str]) -> Result<Spanned<String>> {
+    Ok(spanned((*u.choose(pool)?).to_owned()))
+}
+
+fn list<T>(
+    u: &mut Unstructured,
+    min: usize,
+    size: AstSize,
+    mut f: impl FnMut(&mut Unstructured) -> Result<T>,
+) -> Result<Vec<T>> {
+    let n = u.int_in_range(min..=size.width.max(min))?;
+    (0..n).map(|_| f(u)).collect()
+}
+
+fn func(u: &mut Unstructured, size: AstSize) -> Result<Spanned<Expr>> {
+    let arg = name(u, VARS)?;
+    let body = arbitrary_expr(u, size)?;
+    Ok(spanned(Expr::FuncDef(arg, Box::new(body))))
+}
+
+fn rec_defs(u: &mut Unstructured, size: AstSize) -> Result<Vec<VarDefinition>> {
+    list(u, 1, size, |u| {
+        Ok((name(u, VARS)?, Box::new(func(u, size)?)))
+    })
+}
+
+// Generates an expression nested at most `size.depth` deep. Smaller inputs
+// give smaller expressions, since running out of data picks leaves.
+pub fn arbitrary_expr(u: &mut Unstructured, size: AstSize) -> Result<Spanned<Expr>> {
+    if size.depth == 0 || u.is_empty() {
+        let expr = match u.int_in_range(0..=2)? {
+            0 => Expr::Variable(name(u, VARS)?.0),
+            1 => Expr::Literal(Literal::Bool(u.arbitrary()?)),
+            _ => Expr::Literal(Literal::Str(u.arbitrary()?)),
+        };
+        return Ok(spanned(expr));
+    }
+
+    let sub = AstSize {
+        depth: size.depth - 1,
+        ..size
+    };
+    let expr = |u: &mut Unstructured| arbitrary_expr(u, sub).map(Box::new);
+    let expr = match u.int_in_range(0..=10)? {
+        0 => Expr::Call(expr(u)?, expr(u)?),
+        1 => Expr::Case(name(u, TAGS)?, expr(u)?),
+        2 => Expr::FieldAccess(expr(u)?, name(u, FIELDS)?),
+        3 => return func(u, sub),
+        4 => Expr::If(expr(u)?, expr(u)?, expr(u)?, Syntax::Plain),
+        5 => Expr::Let((name(u, VARS)?, expr(u)?), expr(u)?, Syntax::Plain),
+        6 => Expr::LetRec(rec_defs(u, sub)?, expr(u)?),
+        7 => Expr::Match(
+            expr(u)?,
+            list(u, 1, sub, |u| {
+                let pattern = (name(u, TAGS)?, name(u, VARS)?);
+                Ok((pattern, Box::new(arbitrary_expr(u, sub)?)))
+            })?,
+        ),
+        8 => Expr::Record(list(u, 0, sub, |u| {
+            Ok((name(u, FIELDS)?, Box::new(arbitrary_expr(u, sub)?)))
+        })?),
+        9 => Expr::Variable(name(u, VARS)?.0),
+        _ => Expr::Literal(Literal::Bool(u.arbitrary()?)),
+    };
+    Ok(spanned(expr))
+}
+
+pub fn arbitrary_top_level(u: &mut Unstructured, size: AstSize) -> Result<TopLevel> {
+    Ok(match u.int_in_range(0..=2)? {
+        0 => TopLevel::Expr(arbitrary_expr(u, size)?),
+        1 => TopLevel::LetDef((name(u, VARS)?, Box::new(arbitrary_expr(u, size)?))),
+        _ => TopLevel::LetRecDef(rec_defs(u, size)?),
+    })
+}
+
+// A non-empty script of at most `size.width` items
+pub fn arbitrary_script(u: &mut Unstructured, size: AstSize) -> Result<Vec<TopLevel>> {
+    list(u, 1, size, |u| arbitrary_top_level(u, size))
+}
+
+impl<'a> Arbitrary<'a> for Expr {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(arbitrary_expr(u, AstSize::default())?.0)
+    }
+}
+
+impl<'a> Arbitrary<'a> for TopLevel {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        arbitrary_top_level(u, AstSize::default())
+    }
+}
@@ -1,29 +1,348 @@
-#[derive(Debug)]
+pub use crate::span::{FileId, Span};
+use std::collections::{BTreeSet, HashMap};
+
+pub type Spanned<T> = (T, Span);
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Literal {
     Bool(bool),
+    Str(String),
 }
 
-type VarDefinition = (String, Box<Expr>);
-type CaseMatchPattern = (String, String);
+pub(crate) type VarDefinition = (Spanned<String>, Box<Spanned<Expr>>);
+pub type CaseMatchPattern = (Spanned<String>, Spanned<String>);
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Expr {
-    Call(Box<Expr>, Box<Expr>),
-    Case(String, Box<Expr>),
-    FieldAccess(Box<Expr>, String),
-    FuncDef(String, Box<Expr>),
-    If(Box<Expr>, Box<Expr>, Box<Expr>),
-    Let(VarDefinition, Box<Expr>),
-    LetRec(Vec<VarDefinition>, Box<Expr>),
+    // `a op b`, which calls the function named `op` with `a` and then `b`
+    BinOp(Box<Spanned<Expr>>, Spanned<String>, Box<Spanned<Expr>>),
+    Call(Box<Spanned<Expr>>, Box<Spanned<Expr>>),
+    // `a < b <= c`, which is `a < b` and `b <= c` with `b` evaluated once
+    Compare(
+        Box<Spanned<Expr>>,
+        Vec<(Spanned<String>, Box<Spanned<Expr>>)>,
+    ),
+    Case(Spanned<String>, Box<Spanned<Expr>>),
+    // `#{"key": expr; ...}`, where a later entry replaces an earlier one
+    // with the same key
+    Dict(Vec<(Spanned<String>, Box<Spanned<Expr>>)>),
+    FieldAccess(Box<Spanned<Expr>>, Spanned<String>),
+    FuncDef(Spanned<String>, Box<Spanned<Expr>>),
+    If(
+        Box<Spanned<Expr>>,
+        Box<Spanned<Expr>>,
+        Box<Spanned<Expr>>,
+        Syntax,
+    ),
+    Let(VarDefinition, Box<Spanned<Expr>>, Syntax),
+    LetRec(Vec<VarDefinition>, Box<Spanned<Expr>>),
     Literal(Literal),
-    Match(Box<Expr>, Vec<(CaseMatchPattern, Box<Expr>)>),
-    Record(Vec<(String, Box<Expr>)>),
+    // `name! arg...`, replaced by expand_macros
+    MacroCall(Spanned<String>, Vec<Box<Spanned<Expr>>>),
+    Match(
+        Box<Spanned<Expr>>,
+        Vec<(CaseMatchPattern, Box<Spanned<Expr>>)>,
+    ),
+    Record(Vec<(Spanned<String>, Box<Spanned<Expr>>)>),
+    // `#[expr; ...]`, where repeated elements are kept once
+    Set(Vec<Box<Spanned<Expr>>>),
     Variable(String),
 }
 
-#[derive(Debug)]
+// Which of the ways of writing an if or a let the source used, so that
+// formatting keeps it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syntax {
+    Plain,
+    // `cond ? a : b`
+    Ternary,
+    // `body where a = x, b = y`, which is the Let of `a`
+    Where,
+    // The Lets of the bindings after the first of a where, such as `b`
+    WhereNext,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Assoc {
+    Left,
+    Right,
+    None,
+}
+
+// The declared associativity and precedence of each operator in a file
+pub type Fixities = HashMap<String, (Assoc, u8)>;
+
+// A parameter of a macro rule, which either binds the argument or only
+// matches an argument that is the same literal
+#[derive(Debug, Clone)]
+pub enum MacroPattern {
+    Var(String),
+    Literal(Literal),
+}
+
+// `@name` or `@name("arg")` before a definition. A `///` doc comment is
+// the attribute `doc` with the text of the comment as its argument. The
+// checker understands `@deprecated` and `@type("str -> str")`, other
+// attributes are left for tools.
+#[derive(Debug, Clone)]
+pub struct Attribute {
+    pub name: Spanned<String>,
+    pub arg: Option<Spanned<String>>,
+}
+
+#[derive(Debug, Clone)]
 pub enum TopLevel {
-    Expr(Expr),
+    Expr(Spanned<Expr>),
     LetDef(VarDefinition),
     LetRecDef(Vec<VarDefinition>),
+    // `const name = expr`, replaced by its value by eval_consts
+    Const(VarDefinition),
+    // The path of a file whose items are spliced in, see include.rs
+    Include(Spanned<String>),
+    // `macro name pattern... = body`, one rule of the macro
+    Macro(
+        Spanned<String>,
+        Vec<Spanned<MacroPattern>>,
+        Box<Spanned<Expr>>,
+    ),
+    // `test "name" { expr }`, run by `zx test` and skipped otherwise
+    Test(Spanned<String>, Box<Spanned<Expr>>),
+    // `infixl 6 op...`, read by the parser before the rest of the file
+    Fixity(Assoc, Spanned<u8>, Vec<Spanned<String>>),
+    // A definition along with its attributes
+    Attributed(Vec<Spanned<Attribute>>, Box<TopLevel>),
+}
+
+impl TopLevel {
+    pub fn attributes(&self) -> &[Spanned<Attribute>] {
+        match self {
+            TopLevel::Attributed(attrs, _) => attrs,
+            _ => &[],
+        }
+    }
+
+    // The span of the first attribute, name or expression of the item, which
+    // tells where it starts, as far as comments before it go
+    pub(crate) fn first_span(&self) -> Span {
+        match self {
+            TopLevel::Expr((_, span))
+            | TopLevel::LetDef(((_, span), _))
+            | TopLevel::Const(((_, span), _))
+            | TopLevel::Include((_, span))
+            | TopLevel::Test((_, span), _)
+            | TopLevel::Macro((_, span), ..)
+            | TopLevel::Fixity(_, (_, span), _) => *span,
+            TopLevel::LetRecDef(defs) => (defs[0].0).1,
+            TopLevel::Attributed(attrs, _) => attrs[0].1,
+        }
+    }
+
+    // The item itself, without any attributes
+    pub fn unattributed(&self) -> &TopLevel {
+        let mut item = self;
+        while let TopLevel::Attributed(_, inner) = item {
+            item = inner;
+        }
+        item
+    }
+
+    // Top level items that are closed don't depend on anything else in the
+    // script and can be checked in isolation. Recursive definitions may
+    // still refer to each other.
+    pub fn is_closed(&self) -> bool {
+        !matches!(self.unattributed(), TopLevel::Include(_)) && self.free_vars().is_empty()
+    }
+
+    // The uses of variables the item doesn't define, in source order.
+    // Included items aren't known, so an include has none.
+    pub fn free_vars(&self) -> Vec<Spanned<&str>> {
+        let mut bound = vec![];
+        let mut free = vec![];
+        match self.unattributed() {
+            TopLevel::Expr(expr) => collect_free_vars(expr, &mut bound, &mut free),
+            TopLevel::Test(_, expr) => collect_free_vars(expr, &mut bound, &mut free),
+            TopLevel::LetDef((_, expr)) | TopLevel::Const((_, expr)) => {
+                collect_free_vars(expr, &mut bound, &mut free)
+            }
+            TopLevel::LetRecDef(defs) => {
+                bound.extend(defs.iter().map(|((name, _), _)| name.as_str()));
+                for (_, expr) in defs {
+                    collect_free_vars(expr, &mut bound, &mut free);
+                }
+            }
+            TopLevel::Include(_) | TopLevel::Macro(..) | TopLevel::Fixity(..) => {}
+            TopLevel::Attributed(..) => unreachable!(),
+        }
+        free
+    }
+}
+
+// The parameter of a function defined by clauses, see clauses
+pub(crate) const CLAUSES_ARG: &str = "#arg";
+
+// Turns `| `A a -> x | `B b -> y` into
+// `fun arg -> match arg with `A a -> x | `B b -> y`
+pub(crate) fn clauses(span: Span, arms: Vec<(CaseMatchPattern, Box<Spanned<Expr>>)>) -> Expr {
+    let arg = Box::new((Expr::Variable(CLAUSES_ARG.to_owned()), span));
+    let body = Box::new((Expr::Match(arg, arms), span));
+    Expr::FuncDef((CLAUSES_ARG.to_owned(), span), body)
+}
+
+// Turns `body where a = x, b = y` into `let a = x in let b = y in body`
+pub(crate) fn where_bindings(body: Box<Spanned<Expr>>, mut defs: Vec<VarDefinition>) -> Expr {
+    let span = body.1;
+    let first = defs.remove(0);
+    let rest = defs.into_iter().rev().fold(body, |rest, def| {
+        Box::new((Expr::Let(def, rest, Syntax::WhereNext), span))
+    });
+    Expr::Let(first, rest, Syntax::Where)
+}
+
+// The uses of variables an expression doesn't bind, in source order.
+// Operators count as uses of the variables named after them.
+pub fn free_vars(expr: &Spanned<Expr>) -> Vec<Spanned<&str>> {
+    let mut free = vec![];
+    collect_free_vars(expr, &mut vec![], &mut free);
+    free
+}
+
+// The names of the variables an expression doesn't bind, each once
+pub fn free_var_names(expr: &Spanned<Expr>) -> BTreeSet<&str> {
+    free_vars(expr).into_iter().map(|(name, _)| name).collect()
+}
+
+// Turns `f _ x`, a call with `_` for some of its arguments, into
+// `fun a -> f a x`, with one parameter per hole from left to right. `#`
+// can't appear in identifiers, so the parameters can't clash with names in
+// the source.
+pub(crate) fn fill_holes(mut call: Box<Spanned<Expr>>) -> Box<Spanned<Expr>> {
+    // Collected right to left, so the last hole is the outermost parameter
+    let mut holes = vec![];
+    let mut spine = &mut *call;
+    while let (Expr::Call(func, arg), _) = spine {
+        if let (Expr::Variable(name), span) = &mut **arg {
+            if name == "_" {
+                *name = format!("_#{}", holes.len());
+                holes.push((name.clone(), *span));
+            }
+        }
+        spine = func;
+    }
+    let span = call.1;
+    holes
+        .into_iter()
+        .fold(call, |body, arg| Box::new((Expr::FuncDef(arg, body), span)))
+}
+
+fn collect_free_vars<'a>(
+    (expr, span): &'a Spanned<Expr>,
+    bound: &mut Vec<&'a str>,
+    out: &mut Vec<Spanned<&'a str>>,
+) {
+    use Expr::*;
+    match expr {
+        BinOp(lhs, (op, op_span), rhs) => {
+            if !bound.contains(&op.as_str()) {
+                out.push((op, *op_span));
+            }
+            collect_free_vars(lhs, bound, out);
+            collect_free_vars(rhs, bound, out);
+        }
+        Compare(first, rest) => {
+            collect_free_vars(first, bound, out);
+            for ((op, op_span), expr) in rest {
+                if !bound.contains(&op.as_str()) {
+                    out.push((op, *op_span));
+                }
+                collect_free_vars(expr, bound, out);
+            }
+        }
+        Call(func, arg) => {
+            collect_free_vars(func, bound, out);
+            collect_free_vars(arg, bound, out);
+        }
+        Case(_, expr) | FieldAccess(expr, _) => collect_free_vars(expr, bound, out),
+        FuncDef((arg, _), body) => {
+            bound.push(arg);
+            collect_free_vars(body, bound, out);
+            bound.pop();
+        }
+        If(cond, then_expr, else_expr, _) => {
+            collect_free_vars(cond, bound, out);
+            collect_free_vars(then_expr, bound, out);
+            collect_free_vars(else_expr, bound, out);
+        }
+        Let(((name, _), var_expr), rest, _) => {
+            collect_free_vars(var_expr, bound, out);
+            bound.push(name);
+            collect_free_vars(rest, bound, out);
+            bound.pop();
+        }
+        LetRec(defs, rest) => {
+            let n = bound.len();
+            bound.extend(defs.iter().map(|((name, _), _)| name.as_str()));
+            for (_, expr) in defs {
+                collect_free_vars(expr, bound, out);
+            }
+            collect_free_vars(rest, bound, out);
+            bound.truncate(n);
+        }
+        Literal(_) => {}
+        MacroCall((name, name_span), args) => {
+            // The expansion may refer to anything, so count the macro itself
+            // as free to keep the item from being considered closed
+            out.push((name, *name_span));
+            for arg in args {
+                collect_free_vars(arg, bound, out);
+            }
+        }
+        Match(expr, cases) => {
+            collect_free_vars(expr, bound, out);
+            for ((_, (name, _)), rhs) in cases {
+                bound.push(name);
+                collect_free_vars(rhs, bound, out);
+                bound.pop();
+            }
+        }
+        Dict(fields) | Record(fields) => {
+            for (_, expr) in fields {
+                collect_free_vars(expr, bound, out);
+            }
+        }
+        Set(items) => {
+            for expr in items {
+                collect_free_vars(expr, bound, out);
+            }
+        }
+        Variable(name) => {
+            if !bound.contains(&name.as_str()) {
+                out.push((name, *span));
+            }
+        }
+    }
+}
+
+// The expressions directly inside `expr`, in source order
+pub(crate) fn children(expr: &Expr) -> Vec<&Spanned<Expr>> {
+    use Expr::*;
+    match expr {
+        BinOp(lhs, _, rhs) | Call(lhs, rhs) => vec![lhs, rhs],
+        Compare(first, rest) => std::iter::once(&**first)
+            .chain(rest.iter().map(|(_, expr)| &**expr))
+            .collect(),
+        Case(_, expr) | FieldAccess(expr, _) | FuncDef(_, expr) => vec![expr],
+        If(cond, then_expr, else_expr, _) => vec![cond, then_expr, else_expr],
+        Let((_, var_expr), rest, _) => vec![var_expr, rest],
+        LetRec(defs, rest) => defs
+            .iter()
+            .map(|(_, expr)| &**expr)
+            .chain(std::iter::once(&**rest))
+            .collect(),
+        Literal(_) | Variable(_) => vec![],
+        MacroCall(_, args) | Set(args) => args.iter().map(|arg| &**arg).collect(),
+        Match(expr, cases) => std::iter::once(&**expr)
+            .chain(cases.iter().map(|(_, rhs)| &**rhs))
+            .collect(),
+        Dict(fields) | Record(fields) => fields.iter().map(|(_, expr)| &**expr).collect(),
+    }
 }
@@ -0,0 +1,232 @@
+use crate::ty::{TypeCheckerCore, TypeNode, TypeckState, UTypeHead, VTypeHead, Value, ID};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+// A type as a deterministic automaton, for tools that want the structure
+// display and simplify produce without parsing their output. Each state
+// stands for a set of flow graph nodes in one polarity: in positive
+// states everything flowing into them, in negative ones everything they
+// flow into. Heads of the same kind are merged as in the displayed type,
+// but no type variables are dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypeAutomaton {
+    // The start state is always the first
+    pub states: Vec<State>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Polarity {
+    // What values give
+    Positive,
+    // What uses accept
+    Negative,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct State {
+    pub polarity: Polarity,
+    pub heads: Vec<Head>,
+    // The type variable nodes in the state, by ID in the flow graph
+    pub vars: Vec<ID>,
+    // At most one per label, sorted by label
+    pub transitions: Vec<(Label, usize)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Head {
+    Bool,
+    Str,
+    Func,
+    // The fields and tags are those of the state's transitions. Positive
+    // records only have the fields every record in the state has, and
+    // negative cases the tags every match in the state handles.
+    Record,
+    Case,
+    Dict,
+    Set,
+    Custom(String),
+}
+
+// What a transition leads to the type of. Arg flips the polarity, the
+// others keep it.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Label {
+    Arg,
+    Ret,
+    Field(String),
+    // Tags include the leading backtick
+    Tag(String),
+    DictValue,
+    SetElem,
+}
+
+type Key = (Polarity, BTreeSet<ID>);
+
+struct Builder<'a> {
+    core: &'a TypeCheckerCore,
+    states: Vec<State>,
+    ids: HashMap<Key, usize>,
+    pending: Vec<(usize, Key)>,
+}
+
+impl Builder<'_> {
+    fn state(&mut self, key: Key) -> usize {
+        if let Some(&i) = self.ids.get(&key) {
+            return i;
+        }
+        let i = self.states.len();
+        self.states.push(State {
+            polarity: key.0,
+            heads: vec![],
+            vars: vec![],
+            transitions: vec![],
+        });
+        self.ids.insert(key.clone(), i);
+        self.pending.push((i, key));
+        i
+    }
+
+    fn fill(&mut self, i: usize, (polarity, roots): Key) {
+        let core = self.core;
+        let mut closure = roots.clone();
+        for &id in &roots {
+            match polarity {
+                Polarity::Positive => closure.extend(core.r.upsets(id)),
+                Polarity::Negative => closure.extend(core.r.downsets(id)),
+            }
+        }
+
+        let mut heads = BTreeSet::new();
+        let mut targets: BTreeMap<Label, BTreeSet<ID>> = BTreeMap::new();
+        let mut add = |label, id: ID| targets.entry(label).or_default().insert(id);
+        // Positive records and negative matches are merged below, since
+        // only what all of them have counts
+        let mut records = vec![];
+        let mut matches = vec![];
+        let mut vars = vec![];
+        for &id in &closure {
+            let head = match (&core.types[id], polarity) {
+                (TypeNode::Var, _) => {
+                    vars.push(id);
+                    continue;
+                }
+                (TypeNode::Value(head), Polarity::Positive) => match head {
+                    VTypeHead::VBool => Head::Bool,
+                    VTypeHead::VStr => Head::Str,
+                    VTypeHead::VFunc { arg, ret } => {
+                        add(Label::Arg, arg.0);
+                        add(Label::Ret, ret.0);
+                        Head::Func
+                    }
+                    VTypeHead::VObj { fields } => {
+                        records.push(fields);
+                        Head::Record
+                    }
+                    VTypeHead::VCase { case: (tag, v) } => {
+                        add(Label::Tag(tag.clone()), v.0);
+                        Head::Case
+                    }
+                    VTypeHead::VDict { value } => {
+                        add(Label::DictValue, value.0);
+                        Head::Dict
+                    }
+                    VTypeHead::VSet { elem } => {
+                        add(Label::SetElem, elem.0);
+                        Head::Set
+                    }
+                    VTypeHead::VCustom(head) => Head::Custom(head.0.name().to_owned()),
+                },
+                (TypeNode::Use(head), Polarity::Negative) => match head {
+                    UTypeHead::UBool => Head::Bool,
+                    UTypeHead::UStr => Head::Str,
+                    UTypeHead::UFunc { arg, ret } => {
+                        add(Label::Arg, arg.0);
+                        add(Label::Ret, ret.0);
+                        Head::Func
+                    }
+                    UTypeHead::UObj { field: (name, u) } => {
+                        add(Label::Field(name.clone()), u.0);
+                        Head::Record
+                    }
+                    UTypeHead::UCase { cases } => {
+                        matches.push(cases);
+                        Head::Case
+                    }
+                    UTypeHead::UDict { value } => {
+                        add(Label::DictValue, value.0);
+                        Head::Dict
+                    }
+                    UTypeHead::USet { elem } => {
+                        add(Label::SetElem, elem.0);
+                        Head::Set
+                    }
+                    UTypeHead::UCustom(head) => Head::Custom(head.0.name().to_owned()),
+                },
+                _ => continue,
+            };
+            heads.insert(head);
+        }
+        if let Some((first, rest)) = records.split_first() {
+            for name in first
+                .keys()
+                .filter(|name| rest.iter().all(|r| r.contains_key(*name)))
+            {
+                for r in &records {
+                    add(Label::Field(name.clone()), r[name].0);
+                }
+            }
+        }
+        if let Some((first, rest)) = matches.split_first() {
+            for tag in first
+                .keys()
+                .filter(|tag| rest.iter().all(|m| m.contains_key(*tag)))
+            {
+                for m in &matches {
+                    add(Label::Tag(tag.clone()), m[tag].0);
+                }
+            }
+        }
+
+        let transitions = targets
+            .into_iter()
+            .map(|(label, ids)| {
+                let target_polarity = match (&label, polarity) {
+                    (Label::Arg, Polarity::Positive) => Polarity::Negative,
+                    (Label::Arg, Polarity::Negative) => Polarity::Positive,
+                    _ => polarity,
+                };
+                let target = self.state((target_polarity, ids));
+                (label, target)
+            })
+            .collect();
+        let state = &mut self.states[i];
+        state.heads = heads.into_iter().collect();
+        state.vars = vars;
+        state.transitions = transitions;
+    }
+}
+
+impl TypeCheckerCore {
+    // The automaton of the type of `v`, with one state per distinct set of
+    // nodes reachable from it, so recursive types give cycles.
+    pub fn automaton(&self, v: Value) -> TypeAutomaton {
+        let mut builder = Builder {
+            core: self,
+            states: vec![],
+            ids: HashMap::new(),
+            pending: vec![],
+        };
+        builder.state((Polarity::Positive, std::iter::once(v.0).collect()));
+        while let Some((i, key)) = builder.pending.pop() {
+            builder.fill(i, key);
+        }
+        TypeAutomaton {
+            states: builder.states,
+        }
+    }
+}
+
+impl TypeckState {
+    pub fn automaton_of(&self, name: &str) -> Option<TypeAutomaton> {
+        self.type_of(name).map(|v| self.core.automaton(v))
+    }
+}
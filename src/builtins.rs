@@ -0,0 +1,531 @@
+use crate::{
+    error::{Error, ErrorKind, FormatError, Result, RuntimeError},
+    eval,
+    ty::{self, TypeCheckerCore, TypeckState},
+};
+use std::{
+    cell::Cell,
+    collections::{hash_map::RandomState, BTreeMap, BTreeSet, HashMap},
+    hash::{BuildHasher, Hasher},
+    rc::Rc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// Builtins implemented natively, for what the prelude can't express in zx.
+// Each has a type registered with the checker and a value for the
+// interpreter. Lists are cases, `Cons {head; tail} or `Nil {}, like the
+// lists scripts build themselves.
+
+// The type of a list of strings
+fn str_list(core: &mut TypeCheckerCore) -> ty::Value {
+    let (list, list_use) = core.var();
+    let head = core.str();
+    let cons = core.obj(vec![("head".to_owned(), head), ("tail".to_owned(), list)]);
+    let cons = core.case(("`Cons".to_owned(), cons));
+    let empty = core.obj(vec![]);
+    let nil = core.case(("`Nil".to_owned(), empty));
+    core.flow_fitting(cons, list_use);
+    core.flow_fitting(nil, list_use);
+    list
+}
+
+// `Some v | `None {}, for a `v` that may be missing
+fn option(core: &mut TypeCheckerCore, v: ty::Value) -> ty::Value {
+    let (ret, ret_use) = core.var();
+    let some = core.case(("`Some".to_owned(), v));
+    let empty = core.obj(vec![]);
+    let none = core.case(("`None".to_owned(), empty));
+    core.flow_fitting(some, ret_use);
+    core.flow_fitting(none, ret_use);
+    ret
+}
+
+// A function taking two strings
+fn str_str_to(core: &mut TypeCheckerCore, ret: ty::Value) -> ty::Value {
+    let arg = core.str_use();
+    let inner = core.func(arg, ret);
+    let arg = core.str_use();
+    core.func(arg, inner)
+}
+
+pub(crate) enum FormatPiece {
+    Text(String),
+    Field(String),
+}
+
+// Splits a format template into text and `{field}` placeholders, with `{{`
+// and `}}` standing for literal braces.
+pub(crate) fn parse_format(template: &str) -> std::result::Result<Vec<FormatPiece>, FormatError> {
+    let mut pieces = vec![];
+    let mut text = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                text.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                text.push('}');
+            }
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) if c.is_ascii_alphanumeric() || c == '_' => name.push(c),
+                        _ => return Err(FormatError::UnclosedField),
+                    }
+                }
+                if !name.starts_with(|c: char| c.is_ascii_lowercase() || c == '_') {
+                    return Err(FormatError::InvalidField(name.to_owned()));
+                }
+                if !text.is_empty() {
+                    pieces.push(FormatPiece::Text(std::mem::take(&mut text)));
+                }
+                pieces.push(FormatPiece::Field(name));
+            }
+            '}' => return Err(FormatError::UnmatchedBrace),
+            c => text.push(c),
+        }
+    }
+    if !text.is_empty() {
+        pieces.push(FormatPiece::Text(text));
+    }
+    Ok(pieces)
+}
+
+pub(crate) fn register(state: &mut TypeckState) {
+    state.register_builtin("contains", |core| {
+        let ret = core.bool();
+        str_str_to(core, ret)
+    });
+    state.register_builtin("split", |core| {
+        let ret = str_list(core);
+        str_str_to(core, ret)
+    });
+    state.register_builtin("bool_to_str", |core| {
+        let arg = core.bool_use();
+        let ret = core.str();
+        core.func(arg, ret)
+    });
+    state.register_builtin("str_to_bool", |core| {
+        let arg = core.str_use();
+        let ret = core.bool();
+        let ret = option(core, ret);
+        core.func(arg, ret)
+    });
+    // Dicts are immutable, set returns a copy with the entry replaced
+    state.register_builtin("get", |core| {
+        let (value, value_use) = core.var();
+        let ret = option(core, value);
+        let key = core.str_use();
+        let inner = core.func(key, ret);
+        let dict = core.dict_use(value_use);
+        core.func(dict, inner)
+    });
+    state.register_builtin("set", |core| {
+        let (value, value_use) = core.var();
+        let ret = core.dict(value);
+        let inner = core.func(value_use, ret);
+        let key = core.str_use();
+        let inner = core.func(key, inner);
+        let dict = core.dict_use(value_use);
+        core.func(dict, inner)
+    });
+    state.register_builtin("has", |core| {
+        let (_, value_use) = core.var();
+        let ret = core.bool();
+        let key = core.str_use();
+        let inner = core.func(key, ret);
+        let dict = core.dict_use(value_use);
+        core.func(dict, inner)
+    });
+    state.register_builtin("insert", |core| {
+        let (elem, elem_use) = core.var();
+        let ret = core.set(elem);
+        let inner = core.func(elem_use, ret);
+        let set = core.set_use(elem_use);
+        core.func(set, inner)
+    });
+    state.register_builtin("member", |core| {
+        let (_, elem_use) = core.var();
+        let ret = core.bool();
+        let inner = core.func(elem_use, ret);
+        let set = core.set_use(elem_use);
+        core.func(set, inner)
+    });
+    state.register_builtin("union", |core| {
+        let (elem, elem_use) = core.var();
+        let ret = core.set(elem);
+        let rhs = core.set_use(elem_use);
+        let inner = core.func(rhs, ret);
+        let lhs = core.set_use(elem_use);
+        core.func(lhs, inner)
+    });
+}
+
+fn str_arg(v: eval::Value<'_>) -> Result<Rc<str>> {
+    match &v {
+        eval::Value::Str(s) => Ok(s.clone()),
+        _ => Err(Error::unspanned(ErrorKind::Runtime(
+            RuntimeError::ExpectedStr,
+        ))),
+    }
+}
+
+fn some<'a>(v: eval::Value<'a>) -> eval::Value<'a> {
+    eval::Value::Case("`Some".to_owned(), Rc::new(v))
+}
+
+fn none<'a>() -> eval::Value<'a> {
+    let empty = eval::Value::Record(Rc::new(BTreeMap::new()));
+    eval::Value::Case("`None".to_owned(), Rc::new(empty))
+}
+
+fn dict_arg(v: eval::Value<'_>) -> Result<Rc<BTreeMap<String, eval::Value<'_>>>> {
+    match &v {
+        eval::Value::Dict(entries) => Ok(entries.clone()),
+        _ => Err(Error::unspanned(ErrorKind::Runtime(
+            RuntimeError::ExpectedDict,
+        ))),
+    }
+}
+
+fn set_arg(v: eval::Value<'_>) -> Result<Rc<BTreeMap<String, eval::Value<'_>>>> {
+    match &v {
+        eval::Value::Set(elems) => Ok(elems.clone()),
+        _ => Err(Error::unspanned(ErrorKind::Runtime(
+            RuntimeError::ExpectedSet,
+        ))),
+    }
+}
+
+fn list<'a>(items: Vec<eval::Value<'a>>) -> eval::Value<'a> {
+    let empty = eval::Value::Record(Rc::new(BTreeMap::new()));
+    let mut list = eval::Value::Case("`Nil".to_owned(), Rc::new(empty));
+    for head in items.into_iter().rev() {
+        let mut fields = BTreeMap::new();
+        fields.insert("head".to_owned(), head);
+        fields.insert("tail".to_owned(), list);
+        list = eval::Value::Case(
+            "`Cons".to_owned(),
+            Rc::new(eval::Value::Record(Rc::new(fields))),
+        );
+    }
+    list
+}
+
+// The string builtins take the string being searched first:
+//
+//  - `contains s part` is whether `part` occurs in `s`
+//  - `split s sep` is the parts of `s` between occurrences of `sep`, or its
+//    chars if `sep` is empty
+//
+// The conversions are `bool_to_str b`, giving "true" or "false", and
+// `str_to_bool s`, its inverse, which is `None {} for any other string.
+//
+// `format "template" args` replaces each `{name}` in the template with the
+// str in field `name` of the record `args`.
+//
+// The dict builtins take the dict first:
+//
+//  - `get d key` is `Some v for the value at `key`, or `None {}
+//  - `set d key v` is a copy of `d` with `key` mapped to `v`
+//  - `has d key` is whether `d` has an entry for `key`
+//
+// as do the set builtins:
+//
+//  - `insert s x` is a copy of `s` with `x` added
+//  - `member s x` is whether `x` is in `s`
+//  - `union s t` has the elements of both `s` and `t`
+pub(crate) fn values<'a>() -> Vec<(&'static str, eval::Value<'a>)> {
+    vec![
+        (
+            "contains",
+            eval::Value::native(|s| {
+                let s = str_arg(s)?;
+                Ok(eval::Value::native(move |part| {
+                    Ok(eval::Value::Bool(s.contains(&*str_arg(part)?)))
+                }))
+            }),
+        ),
+        (
+            "split",
+            eval::Value::native(|s| {
+                let s = str_arg(s)?;
+                Ok(eval::Value::native(move |sep| {
+                    let sep = str_arg(sep)?;
+                    let parts: Vec<_> = if sep.is_empty() {
+                        s.chars()
+                            .map(|c| eval::Value::Str(c.to_string().into()))
+                            .collect()
+                    } else {
+                        s.split(&*sep)
+                            .map(|part| eval::Value::Str(part.into()))
+                            .collect()
+                    };
+                    Ok(list(parts))
+                }))
+            }),
+        ),
+        (
+            "bool_to_str",
+            eval::Value::native(|b| match b {
+                eval::Value::Bool(b) => Ok(eval::Value::Str(b.to_string().into())),
+                _ => Err(Error::unspanned(ErrorKind::Runtime(
+                    RuntimeError::ExpectedBool,
+                ))),
+            }),
+        ),
+        (
+            "str_to_bool",
+            eval::Value::native(|s| {
+                Ok(match &*str_arg(s)? {
+                    "true" => some(eval::Value::Bool(true)),
+                    "false" => some(eval::Value::Bool(false)),
+                    _ => none(),
+                })
+            }),
+        ),
+        (
+            "format",
+            eval::Value::native(|template| {
+                let pieces = parse_format(&str_arg(template)?)
+                    .map_err(|e| Error::unspanned(ErrorKind::Format(e)))?;
+                Ok(eval::Value::native(move |args| {
+                    let fields = match &args {
+                        eval::Value::Record(fields) => fields.clone(),
+                        _ => {
+                            return Err(Error::unspanned(ErrorKind::Runtime(
+                                RuntimeError::ExpectedRecord,
+                            )))
+                        }
+                    };
+                    let mut out = String::new();
+                    for piece in &pieces {
+                        match piece {
+                            FormatPiece::Text(text) => out.push_str(text),
+                            FormatPiece::Field(name) => match fields.get(name) {
+                                Some(v) => out.push_str(&str_arg(v.clone())?),
+                                None => {
+                                    let kind = ErrorKind::MissingField(name.clone());
+                                    return Err(Error::unspanned(kind));
+                                }
+                            },
+                        }
+                    }
+                    Ok(eval::Value::Str(out.into()))
+                }))
+            }),
+        ),
+        (
+            "get",
+            eval::Value::native(|d| {
+                let d = dict_arg(d)?;
+                Ok(eval::Value::native(move |key| {
+                    Ok(match d.get(&*str_arg(key)?) {
+                        Some(v) => some(v.clone()),
+                        None => none(),
+                    })
+                }))
+            }),
+        ),
+        (
+            "set",
+            eval::Value::native(|d| {
+                let d = dict_arg(d)?;
+                Ok(eval::Value::native(move |key| {
+                    let d = d.clone();
+                    let key = str_arg(key)?;
+                    Ok(eval::Value::native(move |v| {
+                        let mut d = (*d).clone();
+                        d.insert(key.to_string(), v);
+                        Ok(eval::Value::Dict(Rc::new(d)))
+                    }))
+                }))
+            }),
+        ),
+        (
+            "has",
+            eval::Value::native(|d| {
+                let d = dict_arg(d)?;
+                Ok(eval::Value::native(move |key| {
+                    Ok(eval::Value::Bool(d.contains_key(&*str_arg(key)?)))
+                }))
+            }),
+        ),
+        (
+            "insert",
+            eval::Value::native(|s| {
+                let s = set_arg(s)?;
+                Ok(eval::Value::native(move |x| {
+                    let mut s = (*s).clone();
+                    s.insert(x.key(), x);
+                    Ok(eval::Value::Set(Rc::new(s)))
+                }))
+            }),
+        ),
+        (
+            "member",
+            eval::Value::native(|s| {
+                let s = set_arg(s)?;
+                Ok(eval::Value::native(move |x| {
+                    Ok(eval::Value::Bool(s.contains_key(&x.key())))
+                }))
+            }),
+        ),
+        (
+            "union",
+            eval::Value::native(|s| {
+                let s = set_arg(s)?;
+                Ok(eval::Value::native(move |t| {
+                    let mut s = (*s).clone();
+                    s.extend(set_arg(t)?.iter().map(|(k, v)| (k.clone(), v.clone())));
+                    Ok(eval::Value::Set(Rc::new(s)))
+                }))
+            }),
+        ),
+    ]
+}
+
+// Builtins that make scripts nondeterministic, so they are left out unless
+// the host enables them, with TypeckState::enable for the checker and by
+// passing `value` to eval_script_with_builtins for the interpreter, or
+// those of a CapabilityPolicy. Both take an argument that is ignored, as
+// in `now {}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Capability {
+    // `now`, the seconds since the Unix epoch as a str such as
+    // "1700000000.250"
+    Clock,
+    // `random`, a random bool
+    Random,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 2] = [Capability::Clock, Capability::Random];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Capability::Clock => "now",
+            Capability::Random => "random",
+        }
+    }
+
+    pub(crate) fn register(self, state: &mut TypeckState) {
+        match self {
+            Capability::Clock => state.register_builtin("now", |core| {
+                let (_, arg) = core.var();
+                let ret = core.str();
+                core.func(arg, ret)
+            }),
+            Capability::Random => state.register_builtin("random", |core| {
+                let (_, arg) = core.var();
+                let ret = core.bool();
+                core.func(arg, ret)
+            }),
+        }
+    }
+
+    pub fn value<'a>(self) -> eval::Value<'a> {
+        match self {
+            Capability::Clock => eval::Value::native(|_| {
+                let t = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                Ok(time_value(t))
+            }),
+            // Each RandomState is seeded differently, which is random enough
+            // without pulling in a dependency
+            Capability::Random => eval::Value::native(|_| {
+                let bits = RandomState::new().build_hasher().finish();
+                Ok(eval::Value::Bool(bits & 1 == 1))
+            }),
+        }
+    }
+}
+
+fn time_value<'a>(t: Duration) -> eval::Value<'a> {
+    let s = format!("{}.{:03}", t.as_secs(), t.subsec_millis());
+    eval::Value::Str(s.into())
+}
+
+// Stands in for the clock and randomness of the capabilities, so that a
+// script gives the same results every time it runs, for tests, replays and
+// caching. Dicts and sets are already ordered by key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Determinism {
+    // `random` gives the same bools in the same order for the same seed
+    pub seed: u64,
+    // What `now` always gives, as the time since the Unix epoch
+    pub time: Duration,
+}
+
+impl Determinism {
+    fn value<'a>(self, capability: Capability) -> eval::Value<'a> {
+        match capability {
+            Capability::Clock => eval::Value::native(move |_| Ok(time_value(self.time))),
+            // SplitMix64, which is enough for scripts and has no state
+            // beyond the counter
+            Capability::Random => {
+                let state = Cell::new(self.seed);
+                eval::Value::native(move |_| {
+                    state.set(state.get().wrapping_add(0x9e37_79b9_7f4a_7c15));
+                    let mut z = state.get();
+                    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+                    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+                    z ^= z >> 31;
+                    Ok(eval::Value::Bool(z & 1 == 1))
+                })
+            }
+        }
+    }
+}
+
+// Which capabilities scripts may use when they run. Every capability is
+// in scope, so that a script checked with it enabled still runs, but
+// calling one the policy doesn't allow fails with a Denied error. Nothing
+// is allowed by default.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CapabilityPolicy {
+    allowed: BTreeSet<Capability>,
+    determinism: Option<Determinism>,
+}
+
+impl CapabilityPolicy {
+    pub fn allow(&mut self, capability: Capability) -> &mut Self {
+        self.allowed.insert(capability);
+        self
+    }
+
+    pub fn allows(&self, capability: Capability) -> bool {
+        self.allowed.contains(&capability)
+    }
+
+    pub fn deterministic(&mut self, determinism: Determinism) -> &mut Self {
+        self.determinism = Some(determinism);
+        self
+    }
+
+    // The values of the capabilities, for eval_script_with_builtins. Each
+    // call gives a deterministic `random` that starts again from the seed.
+    pub fn builtins<'a>(&self) -> HashMap<String, eval::Value<'a>> {
+        Capability::ALL
+            .iter()
+            .map(|&capability| {
+                let v = match self.determinism {
+                    _ if !self.allows(capability) => Self::denied(capability),
+                    Some(determinism) => determinism.value(capability),
+                    None => capability.value(),
+                };
+                (capability.name().to_owned(), v)
+            })
+            .collect()
+    }
+
+    fn denied<'a>(capability: Capability) -> eval::Value<'a> {
+        eval::Value::native(move |_| Err(Error::unspanned(ErrorKind::Denied(capability))))
+    }
+}
@@ -0,0 +1,132 @@
+use crate::{Error, Source, TypeckState};
+use std::{
+    ffi::{CStr, CString},
+    os::raw::c_char,
+    panic, ptr,
+};
+
+// Mirrors `zx_result` in include/zx.h. `message` is NULL on success and
+// must be released with zx_result_free otherwise.
+#[repr(C)]
+pub struct ZxResult {
+    pub ok: bool,
+    pub message: *mut c_char,
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub col: usize,
+}
+
+unsafe fn source_arg(source: *const c_char) -> Result<Source, Error> {
+    let bytes = CStr::from_ptr(source).to_bytes().to_vec();
+    Source::from_bytes("<ffi>", bytes).map_err(|d| d.error)
+}
+
+fn c_string(s: String) -> *mut c_char {
+    // Messages never contain NUL, but don't abort across the FFI boundary if
+    // one does.
+    CString::new(s.replace('\0', " ")).unwrap().into_raw()
+}
+
+// What a panic caught at the FFI boundary reports, since unwinding into C
+// is undefined behavior
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    let reason = match (
+        payload.downcast_ref::<&str>(),
+        payload.downcast_ref::<String>(),
+    ) {
+        (Some(s), _) => s.to_string(),
+        (_, Some(s)) => s.clone(),
+        _ => "unknown panic".to_owned(),
+    };
+    format!("Internal error: {}", reason)
+}
+
+fn check(source: *const c_char) -> Result<(Source, TypeckState), (Option<Source>, Error)> {
+    let source = unsafe { source_arg(source) }.map_err(|e| (None, e))?;
+    let parsed = match crate::parse(&source.text) {
+        Ok(parsed) => parsed,
+        Err(e) => return Err((Some(source), e)),
+    };
+    let mut state = TypeckState::new();
+    match state.check_script(&parsed) {
+        Ok(()) => Ok((source, state)),
+        Err(e) => Err((Some(source), e)),
+    }
+}
+
+/// # Safety
+/// `source` must be a NUL terminated string and `out` must point to writable
+/// memory for a `zx_result`.
+#[no_mangle]
+pub unsafe extern "C" fn zx_check(source: *const c_char, out: *mut ZxResult) -> bool {
+    let mut result = ZxResult {
+        ok: true,
+        message: ptr::null_mut(),
+        start: 0,
+        end: 0,
+        line: 0,
+        col: 0,
+    };
+    match panic::catch_unwind(|| check(source)) {
+        Ok(Ok(_)) => {}
+        Ok(Err((source, e))) => {
+            let span = e.span.unwrap_or_default();
+            let (line, col) = source.map_or((0, 0), |s| s.line_col(span.start));
+            result = ZxResult {
+                ok: false,
+                message: c_string(e.to_string()),
+                start: span.start,
+                end: span.end,
+                line,
+                col,
+            };
+        }
+        Err(payload) => {
+            result.ok = false;
+            result.message = c_string(panic_message(payload));
+        }
+    }
+    let ok = result.ok;
+    out.write(result);
+    ok
+}
+
+/// # Safety
+/// `result` must have been filled in by zx_check and not freed already.
+#[no_mangle]
+pub unsafe extern "C" fn zx_result_free(result: *mut ZxResult) {
+    let result = &mut *result;
+    if !result.message.is_null() {
+        drop(CString::from_raw(result.message));
+        result.message = ptr::null_mut();
+    }
+}
+
+/// Checks `source` and returns the displayed type of its global `name`, or
+/// NULL if the script doesn't check, doesn't define it or the checker
+/// panics. Free the result with zx_string_free.
+///
+/// # Safety
+/// Both arguments must be NUL terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn zx_type_of(source: *const c_char, name: *const c_char) -> *mut c_char {
+    let name = match CStr::from_ptr(name).to_str() {
+        Ok(name) => name,
+        Err(_) => return ptr::null_mut(),
+    };
+    let ty = panic::catch_unwind(|| match check(source) {
+        Ok((_, state)) => state.display_type_of(name),
+        Err(_) => None,
+    });
+    ty.ok().flatten().map_or(ptr::null_mut(), c_string)
+}
+
+/// # Safety
+/// `s` must be NULL or a string returned by this library.
+#[no_mangle]
+pub unsafe extern "C" fn zx_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
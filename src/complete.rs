@@ -0,0 +1,297 @@
+use crate::{
+    ast::{Expr, Spanned, TopLevel, VarDefinition},
+    lexer,
+    semantic::SemanticKind,
+    simplify::TypeExpr,
+    span::{FileId, Span},
+    ty::{TypeBuilder, TypeCheckerCore, TypeckState, Value},
+};
+use std::collections::{BTreeMap, HashSet};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Completion {
+    pub label: String,
+    // What the name is, never a tag
+    pub kind: SemanticKind,
+    // The displayed type
+    pub ty: String,
+}
+
+impl TypeckState {
+    // What could be written at `offset` in `source`, the first file of the
+    // script: the fields of the expression before a `.`, or else the
+    // variables in scope. Only names starting with the part of an
+    // identifier before `offset` are included, sorted by name. Scopes and types come from `parsed`, which
+    // must be the script last checked successfully, so `source` may be a
+    // later version being typed as long as it is the same up to that
+    // identifier.
+    pub fn completions_at(
+        &self,
+        parsed: &[TopLevel],
+        source: &str,
+        offset: usize,
+    ) -> Vec<Completion> {
+        let before = &source[..offset];
+        let prefix = &before[before.trim_end_matches(lexer::is_ident_char).len()..];
+        let start = offset - prefix.len();
+        let mut found = match before[..start].strip_suffix('.') {
+            Some(receiver) => match self.receiver_type(parsed, receiver) {
+                Some(TypeExpr::Record(fields)) => fields
+                    .into_iter()
+                    .map(|(label, ty)| Completion {
+                        label,
+                        kind: SemanticKind::Field,
+                        ty: ty.to_string(),
+                    })
+                    .collect(),
+                _ => vec![],
+            },
+            None => self
+                .in_scope(parsed, start)
+                .into_iter()
+                .filter(|&(name, _)| !lexer::is_operator(name))
+                .map(|(name, (kind, ty))| Completion {
+                    label: name.to_owned(),
+                    kind,
+                    ty: match ty {
+                        Type::Value(v) => self.core.display(v),
+                        Type::Builtin(build) => {
+                            let mut core = TypeCheckerCore::new();
+                            let v = build(&mut core);
+                            core.display(v)
+                        }
+                    },
+                })
+                .collect(),
+        };
+        found.retain(|c| c.label.starts_with(prefix));
+        found.sort_by(|a, b| a.label.cmp(&b.label));
+        found
+    }
+
+    // The type of the expression `before` ends with, which is the innermost
+    // expression ending there as field access binds tighter than anything
+    // else. If the script had none, as when it's being typed, a variable
+    // followed by fields such as `a.b` is looked up instead.
+    fn receiver_type(&self, parsed: &[TopLevel], before: &str) -> Option<TypeExpr> {
+        if let Some(v) = self.type_ending_at(FileId::default(), before.len()) {
+            return Some(self.core.simplify(v));
+        }
+        let path = &before[before
+            .trim_end_matches(|c| lexer::is_ident_char(c) || c == '.')
+            .len()..];
+        let mut names = path.split('.');
+        let start = before.len() - path.len();
+        let mut ty = match self.in_scope(parsed, start).remove(names.next()?)? {
+            (_, Type::Value(v)) => self.core.simplify(v),
+            (_, Type::Builtin(_)) => return None,
+        };
+        for name in names {
+            ty = match ty {
+                TypeExpr::Record(fields) => fields.into_iter().find(|(f, _)| f == name)?.1,
+                _ => return None,
+            };
+        }
+        Some(ty)
+    }
+
+    // Every variable in scope at `offset`, with what it is and its type
+    fn in_scope<'a>(
+        &'a self,
+        parsed: &'a [TopLevel],
+        offset: usize,
+    ) -> BTreeMap<&'a str, (SemanticKind, Type<'a>)> {
+        let mut scope = Scope {
+            file: FileId::default(),
+            offset,
+            vars: vec![],
+            globals: HashSet::new(),
+        };
+        scope.script(parsed);
+
+        // Inner scopes shadow outer ones, and the script earlier globals and
+        // builtins
+        let mut found = BTreeMap::new();
+        for (name, span, kind) in scope.vars.into_iter().rev() {
+            if let Some(v) = self.type_of_span(span) {
+                found.entry(name).or_insert((kind, Type::Value(v)));
+            }
+        }
+        for (name, &(v, span)) in &self.bindings.m {
+            if !scope.globals.contains(&span) {
+                found
+                    .entry(name)
+                    .or_insert((SemanticKind::Global, Type::Value(v)));
+            }
+        }
+        for (name, build) in self.bindings.builtins() {
+            found
+                .entry(name)
+                .or_insert((SemanticKind::Builtin, Type::Builtin(build)));
+        }
+        found
+    }
+}
+
+// The binders of `parsed` in scope at `offset`, outermost first
+pub(crate) fn binders_in_scope(
+    parsed: &[TopLevel],
+    file: FileId,
+    offset: usize,
+) -> Vec<(&str, Span, SemanticKind)> {
+    let mut scope = Scope {
+        file,
+        offset,
+        vars: vec![],
+        globals: HashSet::new(),
+    };
+    scope.script(parsed);
+    scope.vars
+}
+
+// Builtins get a type of their own at every use
+enum Type<'a> {
+    Value(Value),
+    Builtin(&'a TypeBuilder),
+}
+
+// Finds the binders in scope at an offset, by descending into the
+// expressions containing it
+struct Scope<'a> {
+    // Items of other files were included before the offset
+    file: FileId,
+    offset: usize,
+    // Outermost first
+    vars: Vec<(&'a str, Span, SemanticKind)>,
+    // The binders of every global the script defines, in scope or not
+    globals: HashSet<Span>,
+}
+
+// Parameters made up by desugaring have a `#` no identifier can contain
+fn is_synthetic(name: &str) -> bool {
+    name.contains('#')
+}
+
+impl<'a> Scope<'a> {
+    fn bind(&mut self, (name, span): &'a Spanned<String>, kind: SemanticKind) {
+        if !is_synthetic(name) && name != "_" {
+            self.vars.push((name, *span, kind));
+        }
+    }
+
+    fn around(&self, span: Span) -> bool {
+        span.file == self.file && span.contains(self.offset)
+    }
+
+    // Whether `pos` in the file of `span` comes before the offset, as all
+    // of an included file does
+    fn before(&self, span: Span, pos: usize) -> bool {
+        span.file != self.file || pos < self.offset
+    }
+
+    fn script(&mut self, parsed: &'a [TopLevel]) {
+        for item in parsed {
+            match item.unattributed() {
+                TopLevel::Expr(expr) => self.expr(expr),
+                TopLevel::Test(_, expr) => self.expr(expr),
+                TopLevel::LetDef(def) | TopLevel::Const(def) => {
+                    let ((_, span), expr) = def;
+                    self.globals.insert(*span);
+                    self.expr(expr);
+                    if self.before(expr.1, expr.1.end) {
+                        self.bind(&def.0, SemanticKind::Global);
+                    }
+                }
+                TopLevel::LetRecDef(defs) => {
+                    self.globals.extend(defs.iter().map(|((_, span), _)| *span));
+                    if defs
+                        .iter()
+                        .any(|((_, span), _)| self.before(*span, span.start))
+                    {
+                        self.defs(defs, SemanticKind::Global);
+                    }
+                }
+                TopLevel::Include(_) | TopLevel::Macro(..) | TopLevel::Fixity(..) => {}
+                TopLevel::Attributed(..) => unreachable!(),
+            }
+        }
+    }
+
+    // The binders of a let rec, which are in scope in all its definitions
+    fn defs(&mut self, defs: &'a [VarDefinition], kind: SemanticKind) {
+        for (name, _) in defs {
+            self.bind(name, kind);
+        }
+        for (_, expr) in defs {
+            self.expr(expr);
+        }
+    }
+
+    fn expr(&mut self, expr: &'a Spanned<Expr>) {
+        if !self.around(expr.1) {
+            return;
+        }
+        match &expr.0 {
+            Expr::BinOp(lhs, _, rhs) => {
+                self.expr(lhs);
+                self.expr(rhs);
+            }
+            Expr::Compare(first, rest) => {
+                self.expr(first);
+                for (_, expr) in rest {
+                    self.expr(expr);
+                }
+            }
+            Expr::Call(func, arg) => {
+                self.expr(func);
+                self.expr(arg);
+            }
+            Expr::Case(_, expr) | Expr::FieldAccess(expr, _) => self.expr(expr),
+            Expr::FuncDef(arg, body) => {
+                self.bind(arg, SemanticKind::Parameter);
+                self.expr(body);
+            }
+            Expr::If(cond, then_expr, else_expr, _) => {
+                self.expr(cond);
+                self.expr(then_expr);
+                self.expr(else_expr);
+            }
+            Expr::Let((name, var_expr), rest, _) => {
+                self.expr(var_expr);
+                if self.around(rest.1) && !self.around(var_expr.1) {
+                    self.bind(name, SemanticKind::Local);
+                    self.expr(rest);
+                }
+            }
+            Expr::LetRec(defs, rest) => {
+                self.defs(defs, SemanticKind::Local);
+                self.expr(rest);
+            }
+            Expr::Literal(_) | Expr::Variable(_) => {}
+            Expr::MacroCall(_, args) => {
+                for arg in args {
+                    self.expr(arg);
+                }
+            }
+            Expr::Match(expr, cases) => {
+                self.expr(expr);
+                for ((_, name), rhs) in cases {
+                    if self.around(rhs.1) {
+                        self.bind(name, SemanticKind::Local);
+                        self.expr(rhs);
+                    }
+                }
+            }
+            Expr::Record(fields) | Expr::Dict(fields) => {
+                for (_, expr) in fields {
+                    self.expr(expr);
+                }
+            }
+            Expr::Set(items) => {
+                for expr in items {
+                    self.expr(expr);
+                }
+            }
+        }
+    }
+}
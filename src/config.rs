@@ -0,0 +1,107 @@
+use crate::{builtins::Capability, error::WarningKind};
+use std::collections::{BTreeMap, BTreeSet};
+
+// Everything a host can change about how scripts are checked, for
+// TypeckState::with_config. The default is what TypeckState::new does.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CheckConfig {
+    pub limits: Limits,
+    pub lint_levels: LintLevels,
+    pub strictness: Strictness,
+    // The builtins with effects that scripts may use
+    pub feature_gates: BTreeSet<Capability>,
+}
+
+// Bounds on the solver's work, traded for precision
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    // See TypeCheckerCore::set_widening
+    pub widening: Option<usize>,
+}
+
+// What kind of warning a lint level applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Lint {
+    Deprecated,
+    // Allowed unless set otherwise, as most scripts don't annotate
+    MissingAnnotation,
+}
+
+impl Lint {
+    pub fn of(kind: &WarningKind) -> Self {
+        match kind {
+            WarningKind::Deprecated(..) => Lint::Deprecated,
+            WarningKind::MissingAnnotation(_) => Lint::MissingAnnotation,
+        }
+    }
+
+    fn default_level(self) -> Level {
+        match self {
+            Lint::Deprecated => Level::Warn,
+            Lint::MissingAnnotation => Level::Allow,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    // Not reported
+    Allow,
+    Warn,
+    // Reported as an error, which fails the check
+    Deny,
+}
+
+// The level of every lint, its default unless set otherwise
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LintLevels(BTreeMap<Lint, Level>);
+
+impl LintLevels {
+    pub fn get(&self, lint: Lint) -> Level {
+        self.0
+            .get(&lint)
+            .copied()
+            .unwrap_or_else(|| lint.default_level())
+    }
+
+    pub fn set(&mut self, lint: Lint, level: Level) {
+        self.0.insert(lint, level);
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Strictness {
+    #[default]
+    Lenient,
+    // Every lint is denied unless it is explicitly allowed, as for CI or
+    // libraries whose definitions must all have reviewed type annotations
+    Strict,
+}
+
+impl CheckConfig {
+    // The config the zx tools check with. With ZX_WIDEN=N, types flowing
+    // into any one place beyond the first N are merged, and with ZX_STRICT
+    // set, strictness is Strict.
+    pub fn from_env() -> Result<Self, String> {
+        let mut config = CheckConfig::default();
+        if let Ok(limit) = std::env::var("ZX_WIDEN") {
+            match limit.parse() {
+                Ok(limit) => config.limits.widening = Some(limit),
+                Err(_) => return Err(format!("ZX_WIDEN must be a number, not {:?}", limit)),
+            }
+        }
+        if std::env::var_os("ZX_STRICT").is_some() {
+            config.strictness = Strictness::Strict;
+        }
+        Ok(config)
+    }
+
+    // The level a lint is reported at, after strictness
+    pub fn level(&self, lint: Lint) -> Level {
+        match (self.strictness, self.lint_levels.0.get(&lint)) {
+            (Strictness::Strict, Some(Level::Allow)) => Level::Allow,
+            (Strictness::Strict, _) => Level::Deny,
+            (Strictness::Lenient, _) => self.lint_levels.get(lint),
+        }
+    }
+}
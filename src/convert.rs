@@ -0,0 +1,58 @@
+use crate::ty::{TypeCheckerCore, TypeckState, Use, Value};
+
+// Rust types with a matching zx type, for giving builtins precise types.
+// Values describe what the host passes to scripts, uses what it accepts
+// from them. Structs map to records and enums to cases, see the derive
+// macro behind the `derive` feature.
+pub trait ZxType {
+    fn zx_value(core: &mut TypeCheckerCore) -> Value;
+    fn zx_use(core: &mut TypeCheckerCore) -> Use;
+}
+
+impl ZxType for bool {
+    fn zx_value(core: &mut TypeCheckerCore) -> Value {
+        core.bool()
+    }
+    fn zx_use(core: &mut TypeCheckerCore) -> Use {
+        core.bool_use()
+    }
+}
+
+// The empty record, used for enum variants without a payload
+impl ZxType for () {
+    fn zx_value(core: &mut TypeCheckerCore) -> Value {
+        core.obj(vec![])
+    }
+    fn zx_use(core: &mut TypeCheckerCore) -> Use {
+        core.obj_use_all(vec![])
+    }
+}
+
+impl<A: ZxType, R: ZxType> ZxType for fn(A) -> R {
+    fn zx_value(core: &mut TypeCheckerCore) -> Value {
+        let arg = A::zx_use(core);
+        let ret = R::zx_value(core);
+        core.func(arg, ret)
+    }
+    fn zx_use(core: &mut TypeCheckerCore) -> Use {
+        let arg = A::zx_value(core);
+        let ret = R::zx_use(core);
+        core.func_use(arg, ret)
+    }
+}
+
+impl<T: ZxType + ?Sized> ZxType for Box<T> {
+    fn zx_value(core: &mut TypeCheckerCore) -> Value {
+        T::zx_value(core)
+    }
+    fn zx_use(core: &mut TypeCheckerCore) -> Use {
+        T::zx_use(core)
+    }
+}
+
+impl TypeckState {
+    // Registers a builtin whose type is that of `T`.
+    pub fn register_typed<T: ZxType + 'static>(&mut self, name: impl Into<String>) {
+        self.register_builtin(name, T::zx_value);
+    }
+}
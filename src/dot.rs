@@ -0,0 +1,124 @@
+use crate::ty::{TypeCheckerCore, TypeNode, UTypeHead, VTypeHead, ID};
+use std::fmt::Write;
+
+impl TypeCheckerCore {
+    // Renders the flow graph in Graphviz DOT syntax. Values are green, uses
+    // red and variables grey. Solid edges are reachability, dashed ones
+    // point from a head to the nodes it contains.
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph types {\n    node [style=filled];\n");
+        let mut children = vec![];
+        for (i, node) in self.types.iter().enumerate() {
+            let (label, color) = match node {
+                TypeNode::Var => ("var".to_owned(), "lightgrey"),
+                TypeNode::Value(head) => (value_label(head, &mut children, i), "palegreen"),
+                TypeNode::Use(head) => (use_label(head, &mut children, i), "lightpink"),
+            };
+            writeln!(
+                out,
+                "    n{} [label=\"{} {}\", fillcolor={}];",
+                i,
+                i,
+                escape(&label),
+                color
+            )
+            .unwrap();
+        }
+        for i in 0..self.types.len() {
+            let mut targets: Vec<_> = self.r.downsets(i).collect();
+            targets.sort_unstable();
+            for j in targets {
+                writeln!(out, "    n{} -> n{};", i, j).unwrap();
+            }
+        }
+        for (i, j, label) in children {
+            writeln!(
+                out,
+                "    n{} -> n{} [style=dashed, label=\"{}\"];",
+                i,
+                j,
+                escape(&label)
+            )
+            .unwrap();
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+// The label of a node, without the nodes it contains
+pub(crate) fn label(node: &TypeNode) -> String {
+    match node {
+        TypeNode::Var => "var".to_owned(),
+        TypeNode::Value(head) => value_label(head, &mut vec![], 0),
+        TypeNode::Use(head) => use_label(head, &mut vec![], 0),
+    }
+}
+
+fn value_label(head: &VTypeHead, children: &mut Vec<(ID, ID, String)>, i: ID) -> String {
+    match head {
+        VTypeHead::VBool => "bool".to_owned(),
+        VTypeHead::VStr => "str".to_owned(),
+        VTypeHead::VFunc { arg, ret } => {
+            children.push((i, arg.0, "arg".to_owned()));
+            children.push((i, ret.0, "ret".to_owned()));
+            "fun".to_owned()
+        }
+        VTypeHead::VObj { fields } => {
+            for (name, v) in fields {
+                children.push((i, v.0, name.clone()));
+            }
+            let names: Vec<_> = fields.keys().map(String::as_str).collect();
+            format!("{{{}}}", names.join(", "))
+        }
+        VTypeHead::VCase { case: (tag, v) } => {
+            children.push((i, v.0, "val".to_owned()));
+            tag.clone()
+        }
+        VTypeHead::VDict { value } => {
+            children.push((i, value.0, "val".to_owned()));
+            "dict".to_owned()
+        }
+        VTypeHead::VSet { elem } => {
+            children.push((i, elem.0, "elem".to_owned()));
+            "set".to_owned()
+        }
+        VTypeHead::VCustom(head) => head.0.name().to_owned(),
+    }
+}
+
+fn use_label(head: &UTypeHead, children: &mut Vec<(ID, ID, String)>, i: ID) -> String {
+    match head {
+        UTypeHead::UBool => "bool".to_owned(),
+        UTypeHead::UStr => "str".to_owned(),
+        UTypeHead::UFunc { arg, ret } => {
+            children.push((i, arg.0, "arg".to_owned()));
+            children.push((i, ret.0, "ret".to_owned()));
+            "call".to_owned()
+        }
+        UTypeHead::UObj { field: (name, u) } => {
+            children.push((i, u.0, name.clone()));
+            format!(".{}", name)
+        }
+        UTypeHead::UCase { cases } => {
+            for (tag, u) in cases {
+                children.push((i, u.0, tag.clone()));
+            }
+            let tags: Vec<_> = cases.keys().map(String::as_str).collect();
+            format!("match {}", tags.join(" "))
+        }
+        UTypeHead::UDict { value } => {
+            children.push((i, value.0, "val".to_owned()));
+            "dict".to_owned()
+        }
+        UTypeHead::USet { elem } => {
+            children.push((i, elem.0, "elem".to_owned()));
+            "set".to_owned()
+        }
+        UTypeHead::UCustom(head) => head.0.name().to_owned(),
+    }
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -0,0 +1,279 @@
+use crate::{builtins::Capability, span::Span};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    Syntax(SyntaxError),
+    UndefinedVariable(String),
+    RepeatedField(String),
+    RepeatedCase(String),
+    MissingField(String),
+    UnhandledCase(String),
+    UnexpectedTypes,
+    Runtime(RuntimeError),
+    InvalidUtf8,
+    Io(IoError),
+    Cancelled,
+    // A missing or cyclic include, or one that wasn't expanded
+    Include(IncludeError),
+    // An unknown macro, or a call that matches none of its rules
+    Macro(MacroError),
+    // A const that refers to something other than earlier consts, or whose
+    // value isn't data
+    Const(ConstError),
+    // An invalid format string, or a use of format other than calling it
+    // on a string literal
+    Format(FormatError),
+    // A written out type, as for check_signature, that can't be turned
+    // into nodes
+    Signature(SignatureError),
+    // A warning whose lint is denied by the CheckConfig
+    Lint(WarningKind),
+    // An evaluation that used up its eval::Budget
+    OutOfFuel(Resource),
+    // A call of a builtin the host's CapabilityPolicy doesn't allow
+    Denied(Capability),
+    // A rename that isn't of a variable defined in the script, or that
+    // would change what some variable refers to
+    Rename(RenameError),
+}
+
+// Text that doesn't parse, either a script or a written out type
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyntaxError {
+    UnexpectedToken(String),
+    InvalidToken,
+    UnexpectedEnd,
+    UnterminatedString,
+    InvalidEscape,
+    // An identifier that can't be used as a name, such as `__proto__`
+    ReservedName(String),
+    // A backtick not followed by an uppercase letter
+    LowercaseTag,
+    NoFixity(String),
+    // Operators of the same precedence that associate differently
+    Unchainable(String, String),
+    Precedence,
+    // The rest only come from written out types
+    ExpectedEnd,
+    ExpectedToken(String),
+    ExpectedTag,
+    ExpectedField,
+    ExpectedType,
+    ExpectedTypeVar,
+}
+
+// A value of the wrong kind met during evaluation, which can only happen
+// in scripts that weren't checked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeError {
+    ExpectedFunction,
+    ExpectedBool,
+    ExpectedRecord,
+    ExpectedCase,
+    ExpectedStr,
+    ExpectedDict,
+    ExpectedSet,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IoError {
+    NotFound,
+    PermissionDenied,
+    // Any other error, with the description given by the OS
+    Other(String),
+}
+
+impl From<&std::io::Error> for IoError {
+    fn from(e: &std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::NotFound => IoError::NotFound,
+            std::io::ErrorKind::PermissionDenied => IoError::PermissionDenied,
+            _ => IoError::Other(e.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IncludeError {
+    // The paths of the files in the cycle, starting and ending with the
+    // same one
+    Cycle(Vec<String>),
+    // The path as written, and why it couldn't be read
+    Unreadable(String, String),
+    NotExpanded(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MacroError {
+    Undefined(String),
+    // The macro and the number of arguments of the call
+    NoRule(String, usize),
+    NotExpanded(String),
+}
+
+// The first string of each is the name of the const
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConstError {
+    // A use of a variable bound by a let
+    UsesLet(String, String),
+    // A use of a builtin the host registered
+    UsesHost(String, String),
+    HoldsFunction(String),
+    // Evaluating the const used up its budget
+    OutOfFuel(String, Resource),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FormatError {
+    // A use of format other than calling it on a string literal
+    NotLiteral,
+    UnclosedField,
+    InvalidField(String),
+    UnmatchedBrace,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureError {
+    // The number of @type attributes and of definitions, which differ
+    AnnotationCount(usize, usize),
+    MissingType,
+    // The parts of types that nodes can't hold where they are
+    AnyInResult,
+    IntersectionInResult,
+    NeverInArgument,
+    UnionInArgument,
+}
+
+// Where a string is the new name, it is the second one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RenameError {
+    NoVariable,
+    DefinedInOtherFile,
+    NotInScript,
+    InvalidName(String, String),
+    AlreadyDefined(String),
+    // A use of the old name that a binder of the new one would shadow
+    Shadowed(String, String),
+    // A use of the new name that the renamed binder would capture
+    Captured(String, String),
+    // An operator the parser treats specially
+    Special(String),
+    HasFixity(String),
+    SharedFixity(String),
+    FixityInOtherFile(String),
+}
+
+// What an eval::Budget limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    Steps,
+    Allocations,
+    Depth,
+}
+
+impl Resource {
+    pub fn name(self) -> &'static str {
+        match self {
+            Resource::Steps => "steps",
+            Resource::Allocations => "allocations",
+            Resource::Depth => "depth",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub span: Option<Span>,
+    // The calls a runtime error happened in, innermost first
+    pub trace: Vec<Frame>,
+}
+
+// A call of a function defined in a script or the prelude
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    // The variable the function was bound to, if any
+    pub function: Option<String>,
+    // Where it was called
+    pub span: Span,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn new(kind: ErrorKind, span: Span) -> Self {
+        Self {
+            kind,
+            span: Some(span),
+            trace: vec![],
+        }
+    }
+
+    pub fn unspanned(kind: ErrorKind) -> Self {
+        Self {
+            kind,
+            span: None,
+            trace: vec![],
+        }
+    }
+
+    // Attaches a span to errors that don't have one yet. Errors found while
+    // propagating flow have no location of their own, so they are reported
+    // at the expression that caused the flow.
+    pub fn or_span(mut self, span: Span) -> Self {
+        self.span.get_or_insert(span);
+        self
+    }
+
+    // Adds a call the error passed through on its way out
+    pub(crate) fn in_call(mut self, function: Option<&str>, span: Span) -> Self {
+        self.trace.push(Frame {
+            function: function.map(str::to_owned),
+            span,
+        });
+        self
+    }
+}
+
+// Messages come from the English catalog, see messages.rs
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&crate::messages::english(self))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
+impl std::error::Error for Error {}
+
+// Problems that don't stop a script from checking
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WarningKind {
+    // A use of a deprecated binding, with the reason given by the attribute
+    Deprecated(String, Option<String>),
+    // A top level definition without a `@type` attribute
+    MissingAnnotation(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub kind: WarningKind,
+    pub span: Span,
+}
+
+impl fmt::Display for WarningKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&crate::messages::english_warning(self))
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
@@ -0,0 +1,1053 @@
+use crate::{
+    ast, builtins,
+    error::{
+        ConstError, Error, ErrorKind, IncludeError, MacroError, Resource, Result, RuntimeError,
+    },
+    lexer, operators, prelude,
+    span::Span,
+};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fmt,
+    rc::Rc,
+};
+
+type NativeFn<'a> = dyn Fn(Value<'a>) -> Result<Value<'a>> + 'a;
+
+#[derive(Clone)]
+pub enum Value<'a> {
+    Bool(bool),
+    Str(Rc<str>),
+    Func(Rc<Closure<'a>>),
+    Record(Rc<BTreeMap<String, Value<'a>>>),
+    Case(String, Rc<Value<'a>>),
+    Dict(Rc<BTreeMap<String, Value<'a>>>),
+    // Keyed by Value::key, which also orders the elements
+    Set(Rc<BTreeMap<String, Value<'a>>>),
+    // Numbered like closures
+    Native(usize, Rc<NativeFn<'a>>),
+}
+
+impl<'a> Value<'a> {
+    // A function implemented by the host, for use as a builtin.
+    pub fn native(f: impl Fn(Value<'a>) -> Result<Value<'a>> + 'a) -> Self {
+        Value::Native(function_id(), Rc::new(f))
+    }
+
+    // Identifies a value for sets, with structurally equal data having the
+    // same key. Functions are only equal to themselves.
+    pub(crate) fn key(&self) -> String {
+        match self {
+            Value::Func(f) => format!("<fun {}>", f.id),
+            Value::Native(id, _) => format!("<native {}>", id),
+            Value::Record(fields) => {
+                let fields: Vec<_> = fields
+                    .iter()
+                    .map(|(name, v)| format!("{} = {}", name, v.key()))
+                    .collect();
+                format!("{{{}}}", fields.join("; "))
+            }
+            Value::Case(tag, v) => format!("{} ({})", tag, v.key()),
+            Value::Dict(entries) => {
+                let entries: Vec<_> = entries
+                    .iter()
+                    .map(|(k, v)| format!("{}: {}", lexer::quote(k), v.key()))
+                    .collect();
+                format!("#{{{}}}", entries.join("; "))
+            }
+            Value::Set(elems) => {
+                let elems: Vec<_> = elems.keys().cloned().collect();
+                format!("#[{}]", elems.join("; "))
+            }
+            Value::Bool(_) | Value::Str(_) => self.to_string(),
+        }
+    }
+}
+
+// Long lists are chains of cases and records as deep as the list is long,
+// which dropping one link at a time would recurse through. Instead the
+// parts only this value holds are moved onto a worklist and dropped empty.
+impl Drop for Value<'_> {
+    fn drop(&mut self) {
+        let mut parts = vec![];
+        take_parts(self, &mut parts);
+        while let Some(mut v) = parts.pop() {
+            take_parts(&mut v, &mut parts);
+        }
+    }
+}
+
+fn take_parts<'a>(v: &mut Value<'a>, out: &mut Vec<Value<'a>>) {
+    match v {
+        Value::Case(_, v) => {
+            if let Some(v) = Rc::get_mut(v) {
+                if v.has_parts() {
+                    out.push(std::mem::replace(v, Value::Bool(false)));
+                }
+            }
+        }
+        Value::Record(m) | Value::Dict(m) | Value::Set(m) => {
+            if let Some(m) = Rc::get_mut(m) {
+                out.extend(std::mem::take(m).into_values());
+            }
+        }
+        _ => {}
+    }
+}
+
+impl Value<'_> {
+    fn has_parts(&self) -> bool {
+        matches!(
+            self,
+            Value::Case(..) | Value::Record(_) | Value::Dict(_) | Value::Set(_)
+        )
+    }
+}
+
+pub struct Closure<'a> {
+    // See function_id
+    id: usize,
+    // For stack traces
+    name: Option<&'a str>,
+    arg: &'a str,
+    body: &'a ast::Spanned<ast::Expr>,
+    env: Rc<Env<'a>>,
+}
+
+// Recursive definitions are kept as a group rather than as closures so
+// that functions don't need to hold a reference to themselves. The closure
+// a lookup builds is numbered from the first ID of the group, so that every
+// lookup gives the same function.
+enum Env<'a> {
+    Builtins(HashMap<String, Value<'a>>),
+    Bind(&'a str, Value<'a>, Rc<Env<'a>>),
+    Rec(&'a [ast::VarDefinition], usize, Rc<Env<'a>>),
+}
+
+impl<'a> Env<'a> {
+    fn rec(defs: &'a [ast::VarDefinition], parent: Rc<Env<'a>>) -> Self {
+        Env::Rec(defs, function_ids(defs.len()), parent)
+    }
+
+    fn get(self: &Rc<Self>, name: &str) -> Option<Value<'a>> {
+        let mut env = self;
+        loop {
+            match &**env {
+                Env::Builtins(m) => return m.get(name).cloned(),
+                Env::Bind(k, v, parent) => {
+                    if *k == name {
+                        return Some(v.clone());
+                    }
+                    env = parent;
+                }
+                Env::Rec(defs, first, parent) => {
+                    let def = defs.iter().enumerate().find(|(_, ((k, _), _))| k == name);
+                    if let Some((i, ((k, _), expr))) = def {
+                        if let ast::Expr::FuncDef((arg, _), body) = &expr.0 {
+                            return Some(Value::Func(Rc::new(Closure {
+                                id: first + i,
+                                name: Some(k),
+                                arg,
+                                body,
+                                env: env.clone(),
+                            })));
+                        }
+                    }
+                    env = parent;
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Value<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Str(s) => write!(f, "{}", lexer::quote(s)),
+            Value::Func(_) | Value::Native(..) => write!(f, "<fun>"),
+            Value::Record(fields) => {
+                write!(f, "{{")?;
+                for (i, (name, v)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "; ")?;
+                    }
+                    write!(f, "{} = {}", name, v)?;
+                }
+                write!(f, "}}")
+            }
+            Value::Case(tag, v) => match &**v {
+                Value::Case(..) => write!(f, "{} ({})", tag, v),
+                _ => write!(f, "{} {}", tag, v),
+            },
+            Value::Dict(entries) => {
+                write!(f, "#{{")?;
+                for (i, (key, v)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "; ")?;
+                    }
+                    write!(f, "{}: {}", lexer::quote(key), v)?;
+                }
+                write!(f, "}}")
+            }
+            Value::Set(elems) => {
+                write!(f, "#[")?;
+                for (i, v) in elems.values().enumerate() {
+                    if i > 0 {
+                        write!(f, "; ")?;
+                    }
+                    write!(f, "{}", v)?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+// Limits on the work of an evaluation, for scripts that aren't trusted.
+// Running out stops the evaluation with an OutOfFuel error. None is no
+// limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Budget {
+    // Expressions evaluated, including those of the prelude's functions
+    pub steps: Option<u64>,
+    // Values built by expressions, counting each field, entry and element,
+    // and bindings made by calls, lets and matches
+    pub allocations: Option<u64>,
+    // Expressions being evaluated at once, which keeps recursion from
+    // overflowing the host's stack
+    pub depth: Option<u64>,
+}
+
+// What is left of the budget of the evaluation running on this thread.
+// Natives call back into evaluation without a way to pass it along, so it
+// isn't an argument.
+#[derive(Clone, Copy)]
+struct Fuel {
+    steps: u64,
+    allocations: u64,
+    depth: u64,
+}
+
+impl Fuel {
+    const UNLIMITED: Fuel = Fuel {
+        steps: u64::MAX,
+        allocations: u64::MAX,
+        depth: u64::MAX,
+    };
+
+    fn of(budget: Budget) -> Self {
+        Fuel {
+            steps: budget.steps.unwrap_or(u64::MAX),
+            allocations: budget.allocations.unwrap_or(u64::MAX),
+            depth: budget.depth.unwrap_or(u64::MAX),
+        }
+    }
+}
+
+thread_local! {
+    static FUEL: Cell<Fuel> = const { Cell::new(Fuel::UNLIMITED) };
+    // The number of functions created so far by the evaluation in
+    // progress, which tells them apart in sets
+    static FUNCTIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+fn function_id() -> usize {
+    function_ids(1)
+}
+
+// The first of `n` consecutive IDs
+fn function_ids(n: usize) -> usize {
+    FUNCTIONS.with(|next| next.replace(next.get() + n))
+}
+
+// Numbers the functions of an evaluation from 0, so that sets of them are
+// ordered the same in every run, putting back the count of any evaluation
+// it is nested in when dropped
+struct Numbered(usize);
+
+impl Numbered {
+    fn new() -> Self {
+        Numbered(FUNCTIONS.with(|n| n.replace(0)))
+    }
+}
+
+impl Drop for Numbered {
+    fn drop(&mut self) {
+        FUNCTIONS.with(|n| n.set(self.0));
+    }
+}
+
+// Takes `n` from one kind of fuel
+fn burn(resource: Resource, n: u64, span: Span) -> Result<()> {
+    FUEL.with(|fuel| {
+        let mut left = fuel.get();
+        let field = match resource {
+            Resource::Steps => &mut left.steps,
+            Resource::Allocations => &mut left.allocations,
+            Resource::Depth => &mut left.depth,
+        };
+        *field = field
+            .checked_sub(n)
+            .ok_or_else(|| Error::new(ErrorKind::OutOfFuel(resource), span))?;
+        fuel.set(left);
+        Ok(())
+    })
+}
+
+// Gives back the depth taken by an expression once it is evaluated
+struct Nested;
+
+impl Drop for Nested {
+    fn drop(&mut self) {
+        FUEL.with(|fuel| {
+            let mut left = fuel.get();
+            left.depth += 1;
+            fuel.set(left);
+        });
+    }
+}
+
+// Sets the budget of an evaluation, putting back that of any evaluation it
+// is nested in when dropped
+struct Budgeted(Fuel);
+
+impl Budgeted {
+    fn new(budget: Budget) -> Self {
+        Budgeted(FUEL.with(|fuel| fuel.replace(Fuel::of(budget))))
+    }
+}
+
+impl Drop for Budgeted {
+    fn drop(&mut self) {
+        FUEL.with(|fuel| fuel.set(self.0));
+    }
+}
+
+// Hooks for an interactive debugger, called around the evaluation of the
+// script's expressions. The prelude's aren't reported.
+pub trait Debugger {
+    // Where evaluation pauses. It pauses before the outermost expressions
+    // that start inside a breakpoint, so a breakpoint covering a line
+    // pauses once each time the line is reached.
+    fn breakpoints(&self) -> &[Span] {
+        &[]
+    }
+
+    // Called before evaluating the expression at `span` when it hits a
+    // breakpoint or ends a step, with the variables in scope there
+    fn paused(&mut self, span: Span, scope: &Scope<'_, '_>) -> Resume;
+
+    // Called after evaluating every expression, for showing results
+    fn evaluated(&mut self, _span: Span, _result: &Result<Value<'_>>) {}
+}
+
+// How evaluation continues after a pause
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resume {
+    // Until the next breakpoint
+    Continue,
+    // Pausing before the next expression evaluated
+    Step,
+    // Pausing before the next expression that isn't part of this one
+    StepOver,
+    // Pausing before the next expression that isn't part of the one this
+    // is part of
+    StepOut,
+}
+
+// The variables in scope where evaluation paused
+pub struct Scope<'e, 'a> {
+    env: &'e Rc<Env<'a>>,
+}
+
+impl<'a> Scope<'_, 'a> {
+    pub fn get(&self, name: &str) -> Option<Value<'a>> {
+        self.env.get(name)
+    }
+
+    // The variables the script defined, innermost first, without those
+    // they shadow. Builtins and the prelude aren't included.
+    pub fn variables(&self) -> Vec<(&'a str, Value<'a>)> {
+        let mut names = vec![];
+        let mut env = self.env;
+        loop {
+            match &**env {
+                Env::Builtins(_) => break,
+                Env::Bind(name, _, parent) => {
+                    names.push(*name);
+                    env = parent;
+                }
+                Env::Rec(defs, _, parent) => {
+                    names.extend(defs.iter().map(|((name, _), _)| name.as_str()));
+                    env = parent;
+                }
+            }
+        }
+        let mut seen = HashSet::new();
+        names
+            .into_iter()
+            .filter(|name| seen.insert(*name))
+            .map(|name| (name, self.env.get(name).unwrap()))
+            .collect()
+    }
+}
+
+struct Debugging {
+    debugger: Rc<RefCell<dyn Debugger>>,
+    // The spans of the expressions being evaluated, outermost first
+    stack: Vec<Span>,
+    // Pauses before the next expression at most this deep
+    pause_within: Option<usize>,
+}
+
+thread_local! {
+    static DEBUGGING: RefCell<Option<Debugging>> = const { RefCell::new(None) };
+}
+
+// Reports an expression about to be evaluated to the debugger. It is taken
+// out while its hooks run, so that evaluation they start isn't debugged.
+fn debug_enter(env: &Rc<Env<'_>>, span: Span) {
+    let mut d = match DEBUGGING.with(|d| d.borrow_mut().take()) {
+        Some(d) => d,
+        None => return,
+    };
+    let depth = d.stack.len();
+    let hit = |bp: &Span, span: Span| bp.file == span.file && bp.contains(span.start);
+    let pause = {
+        let debugger = d.debugger.borrow();
+        let bps = debugger.breakpoints();
+        let parent_hit = d
+            .stack
+            .last()
+            .is_some_and(|&p| bps.iter().any(|bp| hit(bp, p)));
+        (!parent_hit && bps.iter().any(|bp| hit(bp, span)))
+            || d.pause_within.is_some_and(|max| depth <= max)
+    };
+    d.stack.push(span);
+    if pause {
+        let resume = d.debugger.borrow_mut().paused(span, &Scope { env });
+        d.pause_within = match resume {
+            Resume::Continue => None,
+            Resume::Step => Some(usize::MAX),
+            Resume::StepOver => Some(depth),
+            Resume::StepOut => depth.checked_sub(1),
+        };
+    }
+    DEBUGGING.with(|cell| *cell.borrow_mut() = Some(d));
+}
+
+fn debug_exit(span: Span, result: &Result<Value<'_>>) {
+    let mut d = match DEBUGGING.with(|d| d.borrow_mut().take()) {
+        Some(d) => d,
+        None => return,
+    };
+    d.stack.pop();
+    d.debugger.borrow_mut().evaluated(span, result);
+    DEBUGGING.with(|cell| *cell.borrow_mut() = Some(d));
+}
+
+// Evaluates a script with the prelude in scope, returning the value of
+// every top level expression. Scripts that passed type checking can't fail
+// at runtime, other than by recursing forever.
+pub fn eval_script(parsed: &[ast::TopLevel]) -> Result<Vec<Value<'_>>> {
+    eval_script_with_builtins(parsed, HashMap::new())
+}
+
+// Same as eval_script, with the host values in `builtins` in scope, to match
+// the builtins registered with the type checker.
+pub fn eval_script_with_builtins<'a>(
+    parsed: &'a [ast::TopLevel],
+    builtins: HashMap<String, Value<'a>>,
+) -> Result<Vec<Value<'a>>> {
+    eval_script_with_budget(parsed, builtins, Budget::default())
+}
+
+// Same as eval_script_with_builtins, but stopping once `budget` is used up
+pub fn eval_script_with_budget<'a>(
+    parsed: &'a [ast::TopLevel],
+    builtins: HashMap<String, Value<'a>>,
+    budget: Budget,
+) -> Result<Vec<Value<'a>>> {
+    let _budgeted = Budgeted::new(budget);
+    eval_items(parsed, builtins, |_, _| {})
+}
+
+// Same as eval_script_with_builtins, reporting to `debugger`
+pub fn eval_script_debugged<'a>(
+    parsed: &'a [ast::TopLevel],
+    builtins: HashMap<String, Value<'a>>,
+    debugger: Rc<RefCell<dyn Debugger>>,
+) -> Result<Vec<Value<'a>>> {
+    let debugging = Debugging {
+        debugger,
+        stack: vec![],
+        pause_within: None,
+    };
+    let outer = DEBUGGING.with(|d| d.replace(Some(debugging)));
+    let res = eval_items(parsed, builtins, |_, _| {});
+    DEBUGGING.with(|d| *d.borrow_mut() = outer);
+    res
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TestOutcome {
+    Passed,
+    // The test evaluated to false
+    Failed,
+    // The test stopped with a runtime error
+    Error(Error),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestResult {
+    pub name: String,
+    pub span: Span,
+    pub outcome: TestOutcome,
+}
+
+// Evaluates a checked script and runs each of its tests where it appears,
+// so that a test sees the definitions before it. Errors outside of tests
+// stop the whole run.
+pub fn run_tests(parsed: &[ast::TopLevel]) -> Result<Vec<TestResult>> {
+    let mut results = vec![];
+    eval_items(parsed, HashMap::new(), |(name, span), res| {
+        let outcome = match res {
+            Ok(Value::Bool(true)) => TestOutcome::Passed,
+            Ok(_) => TestOutcome::Failed,
+            Err(e) => TestOutcome::Error(e),
+        };
+        results.push(TestResult {
+            name: name.clone(),
+            span: *span,
+            outcome,
+        });
+    })?;
+    Ok(results)
+}
+
+// Evaluates the items of a script in order, returning the values of the
+// expressions and passing each test's name and result to `on_test`.
+fn eval_items<'a>(
+    parsed: &'a [ast::TopLevel],
+    mut builtins: HashMap<String, Value<'a>>,
+    mut on_test: impl FnMut(&'a ast::Spanned<String>, Result<Value<'a>>),
+) -> Result<Vec<Value<'a>>> {
+    let _numbered = Numbered::new();
+    // Host values take precedence over the prelude and native builtins, as
+    // in the type checker
+    let prelude_env = Rc::new(Env::Builtins(HashMap::new()));
+    for ((name, _), expr) in prelude::definitions() {
+        if !builtins.contains_key(name) {
+            let v = eval_bound(&prelude_env, name, expr)?;
+            builtins.insert(name.clone(), v);
+        }
+    }
+    for (name, v) in builtins::values() {
+        builtins.entry(name.to_owned()).or_insert(v);
+    }
+    let mut env = Rc::new(Env::Builtins(builtins));
+    let mut out = vec![];
+    for item in parsed {
+        match item.unattributed() {
+            ast::TopLevel::Expr(expr) => out.push(eval_expr(&env, expr)?),
+            ast::TopLevel::LetDef(((name, _), expr)) | ast::TopLevel::Const(((name, _), expr)) => {
+                let v = eval_bound(&env, name, expr)?;
+                env = Rc::new(Env::Bind(name, v, env));
+            }
+            ast::TopLevel::LetRecDef(defs) => env = Rc::new(Env::rec(defs, env)),
+            ast::TopLevel::Test(name, expr) => on_test(name, eval_expr(&env, expr)),
+            ast::TopLevel::Include((path, span)) => {
+                let e = IncludeError::NotExpanded(path.clone());
+                return Err(Error::new(ErrorKind::Include(e), *span));
+            }
+            ast::TopLevel::Macro(..) | ast::TopLevel::Fixity(..) => {}
+            ast::TopLevel::Attributed(..) => unreachable!(),
+        }
+    }
+    Ok(out)
+}
+
+// What evaluating a const may take. The depth is low enough for
+// unoptimized builds not to overflow the main thread's stack.
+const CONST_BUDGET: Budget = Budget {
+    steps: Some(1_000_000),
+    allocations: None,
+    depth: Some(256),
+};
+
+// Evaluates the consts of a checked script, replacing each with a let of
+// its value. A const must be pure, so it may only use the prelude, the
+// native builtins and earlier consts, and its value must be data, which is
+// turned back into an expression. Each const is evaluated within
+// CONST_BUDGET, so one that recurses forever is an error.
+pub fn eval_consts(items: Vec<ast::TopLevel>) -> Result<Vec<ast::TopLevel>> {
+    // Whether each name in scope is a const, so that consts shadowed by a
+    // let can't be used
+    let mut is_const: HashMap<String, bool> = prelude::definitions()
+        .map(|((name, _), _)| (name.clone(), true))
+        .chain(
+            builtins::values()
+                .into_iter()
+                .map(|(name, _)| (name.to_owned(), true)),
+        )
+        .collect();
+    // The consts evaluated so far
+    let mut consts = vec![];
+    let mut out = Vec::with_capacity(items.len());
+    for item in items {
+        // Attributes stay on the let that replaces the const
+        let (attrs, item) = match item {
+            ast::TopLevel::Attributed(attrs, item) => (attrs, *item),
+            item => (vec![], item),
+        };
+        let wrap = |item| {
+            if attrs.is_empty() {
+                item
+            } else {
+                ast::TopLevel::Attributed(attrs, Box::new(item))
+            }
+        };
+        let ((name, name_span), expr) = match item {
+            ast::TopLevel::Const(def) => def,
+            item => {
+                match &item {
+                    ast::TopLevel::LetDef(((name, _), _)) => {
+                        is_const.insert(name.clone(), false);
+                    }
+                    ast::TopLevel::LetRecDef(defs) => {
+                        for ((name, _), _) in defs {
+                            is_const.insert(name.clone(), false);
+                        }
+                    }
+                    _ => {}
+                }
+                out.push(wrap(item));
+                continue;
+            }
+        };
+
+        let span = expr.1;
+        for (var, var_span) in ast::free_vars(&expr) {
+            let e = match is_const.get(var) {
+                Some(true) => continue,
+                Some(false) => ConstError::UsesLet(name.clone(), var.to_owned()),
+                None => ConstError::UsesHost(name.clone(), var.to_owned()),
+            };
+            return Err(Error::new(ErrorKind::Const(e), var_span));
+        }
+
+        consts.push(ast::TopLevel::Expr(*expr));
+        let value = {
+            let values = eval_script_with_budget(&consts, HashMap::new(), CONST_BUDGET).map_err(
+                |e| match e.kind {
+                    ErrorKind::OutOfFuel(resource) => {
+                        let e = ConstError::OutOfFuel(name.clone(), resource);
+                        Error::new(ErrorKind::Const(e), span)
+                    }
+                    _ => e,
+                },
+            )?;
+            value_expr(values.last().unwrap(), span).ok_or_else(|| {
+                let e = ConstError::HoldsFunction(name.clone());
+                Error::new(ErrorKind::Const(e), span)
+            })?
+        };
+        consts.pop();
+        is_const.insert(name.clone(), true);
+        let def = ((name, name_span), Box::new((value, span)));
+        consts.push(ast::TopLevel::LetDef(def.clone()));
+        out.push(wrap(ast::TopLevel::LetDef(def)));
+    }
+    Ok(out)
+}
+
+// The expression for a value, if it contains no functions
+fn value_expr(v: &Value<'_>, span: Span) -> Option<ast::Expr> {
+    let boxed = |v| Some(Box::new((value_expr(v, span)?, span)));
+    Some(match v {
+        Value::Bool(b) => ast::Expr::Literal(ast::Literal::Bool(*b)),
+        Value::Str(s) => ast::Expr::Literal(ast::Literal::Str(s.to_string())),
+        Value::Record(fields) => ast::Expr::Record(
+            fields
+                .iter()
+                .map(|(name, v)| Some(((name.clone(), span), boxed(v)?)))
+                .collect::<Option<_>>()?,
+        ),
+        Value::Case(tag, v) => ast::Expr::Case((tag.clone(), span), boxed(v)?),
+        Value::Dict(entries) => ast::Expr::Dict(
+            entries
+                .iter()
+                .map(|(key, v)| Some(((key.clone(), span), boxed(v)?)))
+                .collect::<Option<_>>()?,
+        ),
+        Value::Set(elems) => ast::Expr::Set(elems.values().map(boxed).collect::<Option<_>>()?),
+        Value::Func(_) | Value::Native(..) => return None,
+    })
+}
+
+fn runtime_error(e: RuntimeError, span: Span) -> Error {
+    Error::new(ErrorKind::Runtime(e), span)
+}
+
+fn apply<'a>(f: Value<'a>, arg: Value<'a>, span: Span) -> Result<Value<'a>> {
+    match &f {
+        Value::Func(f) => {
+            burn(Resource::Allocations, 1, span)?;
+            let env = Rc::new(Env::Bind(f.arg, arg, f.env.clone()));
+            eval_expr(&env, f.body).map_err(|e| e.in_call(f.name, span))
+        }
+        Value::Native(_, f) => f(arg).map_err(|e| e.or_span(span)),
+        _ => Err(runtime_error(RuntimeError::ExpectedFunction, span)),
+    }
+}
+
+// `fun x -> second (first x)`
+fn compose<'a>(first: Value<'a>, second: Value<'a>, span: Span) -> Value<'a> {
+    Value::native(move |x| {
+        let mid = apply(first.clone(), x, span)?;
+        apply(second.clone(), mid, span)
+    })
+}
+
+// Evaluates the value of a let, naming a function defined there after the
+// variable
+fn eval_bound<'a>(
+    env: &Rc<Env<'a>>,
+    name: &'a str,
+    expr: &'a ast::Spanned<ast::Expr>,
+) -> Result<Value<'a>> {
+    match (&eval_expr(env, expr)?, &expr.0) {
+        (Value::Func(f), ast::Expr::FuncDef(..)) => Ok(Value::Func(Rc::new(Closure {
+            id: f.id,
+            name: Some(name),
+            arg: f.arg,
+            body: f.body,
+            env: f.env.clone(),
+        }))),
+        (v, _) => Ok(v.clone()),
+    }
+}
+
+// What is left of evaluating an expression after eval_step
+enum Next<'a> {
+    Value(Value<'a>),
+    // Evaluating the expression in the environment gives the value
+    Tail(Rc<Env<'a>>, &'a ast::Spanned<ast::Expr>),
+    // Calling the function gives the value
+    Call(Rc<Closure<'a>>, Value<'a>, Span),
+}
+
+// How many of the tail calls of an expression stack traces keep. Tail calls
+// replace their caller rather than nesting, so a loop written as recursion
+// runs in constant stack, and only the latest of its calls are reported.
+const TAIL_FRAMES: usize = 64;
+
+fn tail_apply<'a>(f: Value<'a>, arg: Value<'a>, span: Span) -> Result<Next<'a>> {
+    match &f {
+        Value::Func(f) => Ok(Next::Call(f.clone(), arg, span)),
+        _ => apply(f, arg, span).map(Next::Value),
+    }
+}
+
+fn eval_expr<'a>(env: &Rc<Env<'a>>, expr: &'a ast::Spanned<ast::Expr>) -> Result<Value<'a>> {
+    burn(Resource::Depth, 1, expr.1)?;
+    let _nested = Nested;
+    let mut calls = VecDeque::new();
+    // The expressions to report to the debugger once their value is known
+    let mut debugged = vec![];
+    let res = eval_tail(env.clone(), expr, &mut calls, &mut debugged);
+    for span in debugged.into_iter().rev() {
+        debug_exit(span, &res);
+    }
+    calls.into_iter().rev().fold(res, |res, (name, span)| {
+        res.map_err(|e| e.in_call(name, span))
+    })
+}
+
+// Evaluates steps of an expression until one gives its value, recording the
+// tail calls made on the way
+fn eval_tail<'a>(
+    mut env: Rc<Env<'a>>,
+    mut expr: &'a ast::Spanned<ast::Expr>,
+    calls: &mut VecDeque<(Option<&'a str>, Span)>,
+    debugged: &mut Vec<Span>,
+) -> Result<Value<'a>> {
+    loop {
+        burn(Resource::Steps, 1, expr.1)?;
+        if expr.1.file != prelude::FILE && DEBUGGING.with(|d| d.borrow().is_some()) {
+            debug_enter(&env, expr.1);
+            debugged.push(expr.1);
+        }
+        match eval_step(&env, expr)? {
+            Next::Value(v) => return Ok(v),
+            Next::Tail(next_env, next) => {
+                env = next_env;
+                expr = next;
+            }
+            Next::Call(f, arg, span) => {
+                burn(Resource::Allocations, 1, span)?;
+                if calls.len() == TAIL_FRAMES {
+                    calls.pop_front();
+                }
+                calls.push_back((f.name, span));
+                env = Rc::new(Env::Bind(f.arg, arg, f.env.clone()));
+                expr = f.body;
+            }
+        }
+    }
+}
+
+// Evaluates an expression as far as its value or an expression in tail
+// position, whose value is the expression's
+fn eval_step<'a>(env: &Rc<Env<'a>>, expr: &'a ast::Spanned<ast::Expr>) -> Result<Next<'a>> {
+    use ast::Expr::*;
+    let (expr, span) = expr;
+    let span = *span;
+    let value = match expr {
+        Literal(ast::Literal::Bool(b)) => Value::Bool(*b),
+        Literal(ast::Literal::Str(s)) => Value::Str(s.as_str().into()),
+        MacroCall((name, _), _) => {
+            let e = MacroError::NotExpanded(name.clone());
+            return Err(Error::new(ErrorKind::Macro(e), span));
+        }
+        Variable(name) => env
+            .get(name)
+            .ok_or_else(|| Error::new(ErrorKind::UndefinedVariable(name.clone()), span))?,
+        Record(fields) => {
+            burn(Resource::Allocations, 1 + fields.len() as u64, span)?;
+            let mut m = BTreeMap::new();
+            for ((name, _), expr) in fields {
+                m.insert(name.clone(), eval_expr(env, expr)?);
+            }
+            Value::Record(Rc::new(m))
+        }
+        Dict(entries) => {
+            burn(Resource::Allocations, 1 + entries.len() as u64, span)?;
+            let mut m = BTreeMap::new();
+            for ((key, _), expr) in entries {
+                m.insert(key.clone(), eval_expr(env, expr)?);
+            }
+            Value::Dict(Rc::new(m))
+        }
+        Set(items) => {
+            burn(Resource::Allocations, 1 + items.len() as u64, span)?;
+            let mut m = BTreeMap::new();
+            for expr in items {
+                let v = eval_expr(env, expr)?;
+                m.insert(v.key(), v);
+            }
+            Value::Set(Rc::new(m))
+        }
+        Case((tag, _), val_expr) => {
+            burn(Resource::Allocations, 1, span)?;
+            let v = eval_expr(env, val_expr)?;
+            Value::Case(tag.clone(), Rc::new(v))
+        }
+        If(cond_expr, then_expr, else_expr, _) => match eval_expr(env, cond_expr)? {
+            Value::Bool(true) => return Ok(Next::Tail(env.clone(), then_expr)),
+            Value::Bool(false) => return Ok(Next::Tail(env.clone(), else_expr)),
+            _ => return Err(runtime_error(RuntimeError::ExpectedBool, cond_expr.1)),
+        },
+        FieldAccess(lhs_expr, (name, _)) => match &eval_expr(env, lhs_expr)? {
+            Value::Record(fields) => fields
+                .get(name)
+                .cloned()
+                .ok_or_else(|| Error::new(ErrorKind::MissingField(name.clone()), span))?,
+            _ => return Err(runtime_error(RuntimeError::ExpectedRecord, lhs_expr.1)),
+        },
+        Match(match_expr, cases) => {
+            let scrutinee = eval_expr(env, match_expr)?;
+            let (tag, v) = match &scrutinee {
+                Value::Case(tag, v) => (tag, v),
+                _ => return Err(runtime_error(RuntimeError::ExpectedCase, match_expr.1)),
+            };
+            let (((_, _), (name, _)), rhs_expr) = cases
+                .iter()
+                .find(|(((t, _), _), _)| t == tag)
+                .ok_or_else(|| Error::new(ErrorKind::UnhandledCase(tag.clone()), span))?;
+            burn(Resource::Allocations, 1, span)?;
+            let env = Rc::new(Env::Bind(name, (**v).clone(), env.clone()));
+            return Ok(Next::Tail(env, rhs_expr));
+        }
+        FuncDef((arg, _), body) => {
+            burn(Resource::Allocations, 1, span)?;
+            Value::Func(Rc::new(Closure {
+                id: function_id(),
+                name: None,
+                arg,
+                body,
+                env: env.clone(),
+            }))
+        }
+        BinOp(lhs_expr, (op, op_span), rhs_expr) => {
+            let lhs = eval_expr(env, lhs_expr)?;
+            let f = match (env.get(op), operators::builtin(op)) {
+                (Some(f), _) => apply(f, lhs, *op_span)?,
+                (None, Some(operators::Builtin::Compose)) => {
+                    let rhs = eval_expr(env, rhs_expr)?;
+                    return Ok(Next::Value(compose(lhs, rhs, *op_span)));
+                }
+                (None, Some(operators::Builtin::ComposeBack)) => {
+                    let rhs = eval_expr(env, rhs_expr)?;
+                    return Ok(Next::Value(compose(rhs, lhs, *op_span)));
+                }
+                (None, Some(operators::Builtin::Method(method))) => match &lhs {
+                    Value::Record(fields) => fields.get(method).cloned().ok_or_else(|| {
+                        Error::new(ErrorKind::MissingField(method.to_owned()), span)
+                    })?,
+                    _ => return Err(runtime_error(RuntimeError::ExpectedRecord, lhs_expr.1)),
+                },
+                (None, None) => {
+                    let kind = ErrorKind::UndefinedVariable(op.clone());
+                    return Err(Error::new(kind, *op_span));
+                }
+            };
+            return tail_apply(f, eval_expr(env, rhs_expr)?, *op_span);
+        }
+        Compare(first_expr, rest) => {
+            let mut lhs = eval_expr(env, first_expr)?;
+            for ((op, op_span), rhs_expr) in rest {
+                let rhs = eval_expr(env, rhs_expr)?;
+                let holds = match (env.get(op), &lhs, &rhs) {
+                    (Some(f), ..) => {
+                        let f = apply(f, lhs, *op_span)?;
+                        match apply(f, rhs.clone(), *op_span)? {
+                            Value::Bool(b) => b,
+                            _ => return Err(runtime_error(RuntimeError::ExpectedBool, span)),
+                        }
+                    }
+                    (None, Value::Str(a), Value::Str(b)) => match op.as_str() {
+                        "<" => a < b,
+                        "<=" => a <= b,
+                        ">" => a > b,
+                        _ => a >= b,
+                    },
+                    (None, ..) => return Err(runtime_error(RuntimeError::ExpectedStr, span)),
+                };
+                if !holds {
+                    return Ok(Next::Value(Value::Bool(false)));
+                }
+                lhs = rhs;
+            }
+            Value::Bool(true)
+        }
+        Call(func_expr, arg_expr) => {
+            let f = eval_expr(env, func_expr)?;
+            return tail_apply(f, eval_expr(env, arg_expr)?, func_expr.1);
+        }
+        Let(((name, _), var_expr), rest_expr, _) => {
+            let v = eval_bound(env, name, var_expr)?;
+            burn(Resource::Allocations, 1, span)?;
+            let env = Rc::new(Env::Bind(name, v, env.clone()));
+            return Ok(Next::Tail(env, rest_expr));
+        }
+        LetRec(defs, rest_expr) => {
+            burn(Resource::Allocations, 1, span)?;
+            let env = Rc::new(Env::rec(defs, env.clone()));
+            return Ok(Next::Tail(env, rest_expr));
+        }
+    };
+    Ok(Next::Value(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{eval_script, eval_script_with_budget, run_tests, Budget, TestOutcome, Value};
+    use crate::{parse, ty::TypeckState, ErrorKind, Resource};
+    use std::collections::HashMap;
+
+    // Counts up a binary number of 15 bits, making 2^15 tail calls, far
+    // more than fit on the stack if each took a frame
+    const DEEP: &str = r#"
+let rec zeros = fun n -> match n with
+    `Cons c -> `Cons {head = false; tail = zeros c.tail}
+  | `Nil n -> `Nil n;
+let rec incr = fun bits -> match bits with
+    `Cons c -> (if c.head then map_option (fun rest -> `Cons {head = false; tail = rest}) (incr c.tail) else `Some (`Cons {head = true; tail = c.tail}))
+  | `Nil n -> `None n;
+let rec count = fun bits -> match incr bits with
+    `Some next -> count next
+  | `None _ -> true;
+let rec even = fun bits -> match incr bits with
+    `Some next -> odd next
+  | `None _ -> false
+and odd = fun bits -> match incr bits with
+    `Some next -> even next
+  | `None _ -> true;
+let bits = zeros (split "..............." "");
+test "self recursion" { count bits };
+test "mutual recursion" { even bits }"#;
+
+    #[test]
+    fn deep_recursion_passes() {
+        let parsed = parse(DEEP).unwrap();
+        TypeckState::new().check_script(&parsed).unwrap();
+        let results = run_tests(&parsed).unwrap();
+        assert_eq!(results.len(), 2);
+        for result in results {
+            assert_eq!(result.outcome, TestOutcome::Passed, "{}", result.name);
+        }
+    }
+
+    #[test]
+    fn keys_functions_the_same_in_every_run() {
+        let script = "let f = fun x -> x; let g = fun x -> x; \
+            let rec h = fun x -> h x and k = fun x -> x; #[g; f; g; h; k; h]";
+        let parsed = parse(script).unwrap();
+        let keys = || match eval_script(&parsed).unwrap().pop() {
+            Some(Value::Set(ref elems)) => elems.keys().cloned().collect::<Vec<_>>(),
+            _ => unreachable!(),
+        };
+        let first = keys();
+        assert_eq!(first.len(), 4);
+        assert_eq!(first, keys());
+    }
+
+    // Each cell of the list holds the rest, so dropping it one cell at a
+    // time would recurse 300k deep
+    #[test]
+    fn drops_long_lists() {
+        let source = format!("split \"{}\" \"\"", ".".repeat(300_000));
+        let parsed = parse(&source).unwrap();
+        drop(eval_script(&parsed).unwrap());
+    }
+
+    #[test]
+    fn finds_recursive_functions_in_sets() {
+        let parsed = parse("let rec f = fun x -> x; member (insert #[] f) f").unwrap();
+        TypeckState::new().check_script(&parsed).unwrap();
+        let values = eval_script(&parsed).unwrap();
+        assert!(matches!(values.last(), Some(Value::Bool(true))));
+    }
+
+    #[test]
+    fn loops_run_out_of_fuel() {
+        let cases = [
+            ("let rec spin = fun x -> spin x; spin true", Resource::Steps),
+            (
+                "let rec grow = fun x -> grow {a = x}; grow true",
+                Resource::Allocations,
+            ),
+            (
+                "let rec deep = fun x -> not (deep x); deep true",
+                Resource::Depth,
+            ),
+        ];
+        for (script, resource) in cases {
+            let parsed = parse(script).unwrap();
+            TypeckState::new().check_script(&parsed).unwrap();
+            let mut budget = Budget::default();
+            match resource {
+                Resource::Steps => budget.steps = Some(10_000),
+                Resource::Allocations => budget.allocations = Some(10_000),
+                // Shallow enough for the stack of a test thread
+                Resource::Depth => budget.depth = Some(20),
+            }
+            let res = eval_script_with_budget(&parsed, HashMap::new(), budget);
+            let kind = res.err().map(|e| e.kind);
+            assert_eq!(kind, Some(ErrorKind::OutOfFuel(resource)), "{}", script);
+        }
+    }
+}
@@ -0,0 +1,501 @@
+use crate::{
+    ast::{
+        self, Assoc, CaseMatchPattern, Expr, Literal, MacroPattern, Spanned, Syntax, TopLevel,
+        VarDefinition,
+    },
+    lexer,
+    span::Span,
+};
+
+const INDENT: usize = 4;
+
+// How tightly an expression binds, matching the SimpleExpr, CaseExpr,
+// CallExpr and Expr levels of the grammar.
+fn level(expr: &Expr) -> u8 {
+    match expr {
+        Expr::Dict(_)
+        | Expr::FieldAccess(..)
+        | Expr::Record(_)
+        | Expr::Set(_)
+        | Expr::Variable(_)
+        | Expr::Literal(_) => 3,
+        Expr::Case(..) => 2,
+        Expr::Call(..) => 1,
+        Expr::BinOp(..)
+        | Expr::Compare(..)
+        | Expr::FuncDef(..)
+        | Expr::If(..)
+        | Expr::Let(..)
+        | Expr::LetRec(..)
+        | Expr::MacroCall(..)
+        | Expr::Match(..) => 0,
+    }
+}
+
+// Prints a script in canonical form. Formatting the output again gives the
+// same text.
+pub fn format_script(parsed: &[TopLevel]) -> String {
+    format_with_comments(parsed, vec![])
+}
+
+// Same as format_script, but keeping the `//` comments of `source`, the
+// text `parsed` was parsed from. A comment goes before the first item or
+// expression after it, except that one following code on its line stays at
+// the end of a line.
+pub fn format_source(parsed: &[TopLevel], source: &str) -> String {
+    let comments = lexer::Lexer::new(source)
+        .filter_map(|token| match token {
+            Ok((start, token @ lexer::Token::Comment(_), end)) => {
+                let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+                Some(Comment {
+                    span: Span::new(start, end),
+                    line_start,
+                    trailing: !source[line_start..start].trim().is_empty(),
+                    text: token.to_string(),
+                })
+            }
+            _ => None,
+        })
+        .collect();
+    format_with_comments(parsed, comments)
+}
+
+fn format_with_comments(parsed: &[TopLevel], mut comments: Vec<Comment>) -> String {
+    comments.reverse();
+    let mut f = Formatter {
+        out: String::new(),
+        inline: false,
+        comments,
+        printed: 0,
+    };
+    for (i, item) in parsed.iter().enumerate() {
+        let start = item.first_span().start;
+        if i > 0 {
+            f.out.push(';');
+            f.end_line(start);
+        } else {
+            f.comments_before(start, 0);
+        }
+        f.top_level(item);
+    }
+    f.end_line(usize::MAX);
+    f.out
+}
+
+struct Comment {
+    span: Span,
+    // Where the line of the comment starts in the source
+    line_start: usize,
+    // Whether there is code before it on its line
+    trailing: bool,
+    // Including the `//`
+    text: String,
+}
+
+struct Formatter {
+    out: String,
+    // Set inside parentheses and records, where everything stays on one line
+    inline: bool,
+    // The comments not printed yet, last first
+    comments: Vec<Comment>,
+    // Where the expressions printed so far end in the source
+    printed: usize,
+}
+
+impl Formatter {
+    // Prints the comments before `start` in the source, each ending a line
+    fn comments_before(&mut self, start: usize, indent: usize) {
+        while let Some(comment) = self.comments.pop() {
+            if comment.span.end > start {
+                self.comments.push(comment);
+                break;
+            }
+            if !self.out.is_empty() && !self.out.ends_with(char::is_whitespace) {
+                self.out.push(' ');
+            }
+            self.out.push_str(&comment.text);
+            self.out.push('\n');
+            self.out.push_str(&" ".repeat(indent));
+        }
+    }
+
+    // Puts the comments that ended a line of the source before `next` at
+    // the end of this one
+    fn trailing_comments(&mut self, next: impl Fn(&Comment) -> bool) {
+        while let Some(comment) = self.comments.pop() {
+            if !comment.trailing || !next(&comment) {
+                self.comments.push(comment);
+                break;
+            }
+            self.out.push(' ');
+            self.out.push_str(&comment.text);
+        }
+    }
+
+    // Ends the line between items, with the comments before `next` after it
+    fn end_line(&mut self, next: usize) {
+        self.trailing_comments(|comment| comment.span.end <= next);
+        self.out.push('\n');
+        self.comments_before(next, 0);
+    }
+
+    fn top_level(&mut self, item: &TopLevel) {
+        match item {
+            TopLevel::Expr(expr) => self.expr(expr, 0, 0),
+            TopLevel::LetDef(def) => self.let_def(def, 0),
+            TopLevel::Const(((name, _), expr)) => {
+                self.out.push_str("const ");
+                self.name(name);
+                self.out.push_str(" = ");
+                self.expr(expr, 0, 0);
+            }
+            TopLevel::LetRecDef(defs) => self.let_rec_defs(defs, 0),
+            TopLevel::Include((path, _)) => {
+                self.out.push_str("include ");
+                self.out.push_str(&lexer::quote(path));
+            }
+            TopLevel::Test((name, _), expr) => {
+                self.out.push_str("test ");
+                self.out.push_str(&lexer::quote(name));
+                self.out.push_str(" {");
+                self.newline(INDENT);
+                self.expr(expr, 0, INDENT);
+                self.newline(0);
+                self.out.push('}');
+            }
+            TopLevel::Macro((name, _), patterns, body) => {
+                self.out.push_str("macro ");
+                self.out.push_str(name);
+                for (pattern, _) in patterns {
+                    self.out.push(' ');
+                    match pattern {
+                        MacroPattern::Var(name) => self.out.push_str(name),
+                        MacroPattern::Literal(lit) => self.literal(lit),
+                    }
+                }
+                self.out.push_str(" = ");
+                self.expr(body, 0, 0);
+            }
+            TopLevel::Fixity(assoc, (prec, _), ops) => {
+                self.out.push_str(match assoc {
+                    Assoc::Left => "infixl",
+                    Assoc::Right => "infixr",
+                    Assoc::None => "infix",
+                });
+                self.out.push_str(&format!(" {}", prec));
+                for (op, _) in ops {
+                    self.out.push(' ');
+                    self.out.push_str(op);
+                }
+            }
+            TopLevel::Attributed(attrs, item) => {
+                for (attr, _) in attrs {
+                    if let ("doc", Some((text, _))) = (attr.name.0.as_str(), &attr.arg) {
+                        for line in text.split('\n') {
+                            self.out
+                                .push_str(&lexer::Token::DocComment(line.to_owned()).to_string());
+                            self.out.push('\n');
+                        }
+                        continue;
+                    }
+                    self.out.push('@');
+                    self.out.push_str(&attr.name.0);
+                    if let Some((arg, _)) = &attr.arg {
+                        self.out.push('(');
+                        self.out.push_str(&lexer::quote(arg));
+                        self.out.push(')');
+                    }
+                    self.out.push('\n');
+                }
+                self.top_level(item);
+            }
+        }
+    }
+
+    fn newline(&mut self, indent: usize) {
+        if self.inline {
+            self.out.push(' ');
+        } else {
+            // Those on a line of the last expression printed
+            let printed = self.printed;
+            self.trailing_comments(|comment| comment.line_start <= printed);
+            self.out.push('\n');
+            self.out.push_str(&" ".repeat(indent));
+        }
+    }
+
+    fn one_line(&mut self, cb: impl FnOnce(&mut Self)) {
+        let old = std::mem::replace(&mut self.inline, true);
+        cb(self);
+        self.inline = old;
+    }
+
+    // Operators are referred to by name in parentheses
+    fn name(&mut self, name: &str) {
+        if name.starts_with("_#") {
+            self.out.push('_');
+        } else if lexer::is_operator(name) {
+            self.out.push('(');
+            self.out.push_str(name);
+            self.out.push(')');
+        } else {
+            self.out.push_str(name);
+        }
+    }
+
+    fn let_def(&mut self, ((name, _), expr): &VarDefinition, indent: usize) {
+        self.out.push_str("let ");
+        self.name(name);
+        self.out.push_str(" = ");
+        self.expr(expr, 0, indent);
+    }
+
+    fn let_rec_defs(&mut self, defs: &[VarDefinition], indent: usize) {
+        self.out.push_str("let rec ");
+        for (i, ((name, _), expr)) in defs.iter().enumerate() {
+            if i > 0 {
+                self.newline(indent);
+                self.out.push_str("and ");
+            }
+            self.name(name);
+            self.out.push_str(" = ");
+            self.expr(expr, 0, indent);
+        }
+    }
+
+    // Clauses have a `|` before every arm, a match only between them. On
+    // their own lines, the first clause starts on the line after the `=`.
+    fn match_arms(
+        &mut self,
+        cases: &[(CaseMatchPattern, Box<Spanned<Expr>>)],
+        indent: usize,
+        clauses: bool,
+    ) {
+        for (i, (((tag, _), (name, _)), rhs)) in cases.iter().enumerate() {
+            if i == 0 && !clauses {
+                self.newline(indent + INDENT);
+            } else if i == 0 {
+                if !self.inline {
+                    self.out.truncate(self.out.trim_end().len());
+                    self.newline(indent + INDENT - 2);
+                }
+                self.out.push_str("| ");
+            } else {
+                self.newline(indent + INDENT - 2);
+                self.out.push_str("| ");
+            }
+            self.out.push_str(tag);
+            self.out.push(' ');
+            self.out.push_str(name);
+            self.out.push_str(" -> ");
+            self.expr(rhs, 1, indent + INDENT);
+        }
+    }
+
+    fn literal(&mut self, lit: &Literal) {
+        match lit {
+            Literal::Bool(b) => self.out.push_str(if *b { "true" } else { "false" }),
+            Literal::Str(s) => self.out.push_str(&lexer::quote(s)),
+        }
+    }
+
+    fn expr(&mut self, (expr, span): &Spanned<Expr>, min: u8, indent: usize) {
+        self.comments_before(span.start, indent);
+        if level(expr) < min {
+            self.out.push('(');
+            self.one_line(|f| f.expr_unparenthesized(expr, indent));
+            self.out.push(')');
+        } else {
+            self.expr_unparenthesized(expr, indent);
+        }
+        self.printed = self.printed.max(span.end);
+    }
+
+    // Where the grammar takes an operator expression, as the condition of
+    // `?` and the parts of a where do
+    fn op_expr(&mut self, expr: &Spanned<Expr>, indent: usize) {
+        let min = match expr.0 {
+            Expr::BinOp(..) | Expr::Compare(..) => 0,
+            _ => 1,
+        };
+        self.expr(expr, min, indent);
+    }
+
+    fn expr_unparenthesized(&mut self, expr: &Expr, indent: usize) {
+        match expr {
+            Expr::BinOp(lhs, (op, _), rhs) => {
+                self.expr(lhs, 1, indent);
+                self.out.push(' ');
+                self.out.push_str(op);
+                self.out.push(' ');
+                self.expr(rhs, 1, indent);
+            }
+            Expr::Call(func, arg) => {
+                self.expr(func, 1, indent);
+                self.out.push(' ');
+                self.expr(arg, 2, indent);
+            }
+            Expr::Compare(first, rest) => {
+                self.expr(first, 1, indent);
+                for ((op, _), expr) in rest {
+                    self.out.push(' ');
+                    self.out.push_str(op);
+                    self.out.push(' ');
+                    self.expr(expr, 1, indent);
+                }
+            }
+            Expr::Case((tag, _), val) => {
+                self.out.push_str(tag);
+                self.out.push(' ');
+                self.expr(val, 2, indent);
+            }
+            Expr::Dict(entries) => {
+                self.out.push_str("#{");
+                for (i, ((key, _), val)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        self.out.push_str("; ");
+                    }
+                    self.out.push_str(&lexer::quote(key));
+                    self.out.push_str(": ");
+                    self.one_line(|f| f.expr(val, 0, indent));
+                }
+                self.out.push('}');
+            }
+            Expr::FieldAccess(lhs, (name, _)) => {
+                self.expr(lhs, 3, indent);
+                self.out.push('.');
+                self.out.push_str(name);
+            }
+            // The parameters for holes, see ast::fill_holes. The parentheses
+            // the function gets where a call wouldn't need them are what
+            // delimits the holes.
+            Expr::FuncDef((arg, _), body) if arg.starts_with("_#") => self.expr(body, 0, indent),
+            Expr::FuncDef((arg, _), body) if arg == ast::CLAUSES_ARG => match &body.0 {
+                Expr::Match(_, cases) => self.match_arms(cases, indent, true),
+                _ => unreachable!("clauses without a match"),
+            },
+            Expr::FuncDef((arg, _), body) => {
+                self.out.push_str("fun ");
+                self.out.push_str(arg);
+                self.out.push_str(" -> ");
+                self.expr(body, 0, indent);
+            }
+            Expr::If(cond, then_expr, else_expr, Syntax::Ternary) => {
+                self.op_expr(cond, indent);
+                self.out.push_str(" ? ");
+                self.expr(then_expr, 0, indent);
+                self.out.push_str(" : ");
+                self.expr(else_expr, 0, indent);
+            }
+            Expr::If(cond, then_expr, else_expr, _) => {
+                self.out.push_str("if ");
+                self.expr(cond, 0, indent);
+                self.out.push_str(" then ");
+                self.expr(then_expr, 0, indent);
+                self.out.push_str(" else ");
+                self.expr(else_expr, 0, indent);
+            }
+            Expr::Let(def, rest, Syntax::Where) => {
+                let mut defs = vec![def];
+                let mut body = rest;
+                while let (Expr::Let(def, rest, Syntax::WhereNext), _) = &**body {
+                    defs.push(def);
+                    body = rest;
+                }
+                self.op_expr(body, indent);
+                self.out.push_str(" where ");
+                for (i, ((name, _), val)) in defs.into_iter().enumerate() {
+                    if i > 0 {
+                        self.out.push_str(", ");
+                    }
+                    self.name(name);
+                    self.out.push_str(" = ");
+                    self.op_expr(val, indent);
+                }
+            }
+            Expr::Let(def, rest, _) => {
+                self.let_def(def, indent);
+                self.out.push_str(" in");
+                self.newline(indent);
+                self.expr(rest, 0, indent);
+            }
+            Expr::LetRec(defs, rest) => {
+                self.let_rec_defs(defs, indent);
+                self.out.push_str(" in");
+                self.newline(indent);
+                self.expr(rest, 0, indent);
+            }
+            Expr::Literal(lit) => self.literal(lit),
+            Expr::MacroCall((name, _), args) => {
+                self.out.push_str(name);
+                self.out.push('!');
+                for arg in args {
+                    self.out.push(' ');
+                    self.expr(arg, 2, indent);
+                }
+            }
+            Expr::Match(val, cases) => {
+                self.out.push_str("match ");
+                self.expr(val, 0, indent);
+                self.out.push_str(" with");
+                self.match_arms(cases, indent, false);
+            }
+            Expr::Record(fields) => {
+                self.out.push('{');
+                for (i, ((name, _), val)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        self.out.push_str("; ");
+                    }
+                    self.out.push_str(name);
+                    self.out.push_str(" = ");
+                    self.one_line(|f| f.expr(val, 0, indent));
+                }
+                self.out.push('}');
+            }
+            Expr::Set(items) => {
+                self.out.push_str("#[");
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        self.out.push_str("; ");
+                    }
+                    self.one_line(|f| f.expr(item, 0, indent));
+                }
+                self.out.push(']');
+            }
+            Expr::Variable(name) => self.name(name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_source;
+    use crate::parse;
+
+    fn format(source: &str) -> String {
+        format_source(&parse(source).unwrap(), source)
+    }
+
+    #[test]
+    fn keeps_comments() {
+        let source = "// head
+let a = true; // after a
+let f = fun x -> match x with
+    `A v -> v // first arm
+  | `B w -> w;
+let g = // before body
+fun y -> y
+// at the end
+";
+        assert_eq!(format(source), source);
+    }
+
+    #[test]
+    fn keeps_sugar() {
+        let source = "let a = b ? \"b\" : \"c\";
+let d = {e = e; f = f} where e = true, f = not e;
+(x where x = \"inner\") where x = \"outer\"
+";
+        assert_eq!(format(source), source);
+    }
+}
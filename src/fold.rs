@@ -0,0 +1,183 @@
+use crate::ast::{self, Expr, Spanned, Syntax, TopLevel};
+
+// Simplifies expressions whose outcome is known from literals alone, for
+// running an already checked script. Folding only ever drops code that
+// would not run or whose evaluation can't fail or diverge, so results are
+// unchanged and every folded expression has a type at least as general as
+// the original:
+//
+//  - `if true then a else b` becomes `a`, and likewise for false
+//  - `{a = x; b = y}.a` becomes `x` when `y` is a value
+//  - `match `A x with `A v -> e | ...` becomes `let v = x in e`
+pub fn fold_constants(items: Vec<TopLevel>) -> Vec<TopLevel> {
+    items.into_iter().map(fold_item).collect()
+}
+
+fn fold_item(item: TopLevel) -> TopLevel {
+    match item {
+        TopLevel::Expr(expr) => TopLevel::Expr(fold(expr)),
+        TopLevel::LetDef((name, expr)) => TopLevel::LetDef((name, fold_box(*expr))),
+        TopLevel::Const((name, expr)) => TopLevel::Const((name, fold_box(*expr))),
+        TopLevel::Test(name, expr) => TopLevel::Test(name, fold_box(*expr)),
+        TopLevel::LetRecDef(defs) => TopLevel::LetRecDef(
+            defs.into_iter()
+                .map(|(name, expr)| (name, fold_box(*expr)))
+                .collect(),
+        ),
+        TopLevel::Attributed(attrs, item) => {
+            TopLevel::Attributed(attrs, Box::new(fold_item(*item)))
+        }
+        item => item,
+    }
+}
+
+// Whether evaluating an expression can't have any effect beyond producing
+// its value
+fn is_value(expr: &Expr) -> bool {
+    match expr {
+        Expr::Literal(_) | Expr::Variable(_) | Expr::FuncDef(..) => true,
+        Expr::Case(_, val) => is_value(&val.0),
+        Expr::Dict(fields) | Expr::Record(fields) => fields.iter().all(|(_, e)| is_value(&e.0)),
+        Expr::Set(items) => items.iter().all(|e| is_value(&e.0)),
+        _ => false,
+    }
+}
+
+fn fold_box(expr: Spanned<Expr>) -> Box<Spanned<Expr>> {
+    Box::new(fold(expr))
+}
+
+fn fold((expr, span): Spanned<Expr>) -> Spanned<Expr> {
+    use Expr::*;
+    let expr = match expr {
+        BinOp(lhs, op, rhs) => BinOp(fold_box(*lhs), op, fold_box(*rhs)),
+        Call(func, arg) => Call(fold_box(*func), fold_box(*arg)),
+        Compare(first, rest) => Compare(
+            fold_box(*first),
+            rest.into_iter().map(|(op, e)| (op, fold_box(*e))).collect(),
+        ),
+        Case(tag, val) => Case(tag, fold_box(*val)),
+        FieldAccess(lhs, (name, name_span)) => match fold(*lhs) {
+            (Record(fields), _)
+                if fields
+                    .iter()
+                    .all(|((n, _), e)| *n == name || is_value(&e.0)) =>
+            {
+                // Records can't repeat fields once checked
+                match fields.into_iter().find(|((n, _), _)| *n == name) {
+                    Some((_, expr)) => return *expr,
+                    None => unreachable!("field access on a record without the field"),
+                }
+            }
+            lhs => FieldAccess(Box::new(lhs), (name, name_span)),
+        },
+        FuncDef(arg, body) => FuncDef(arg, fold_box(*body)),
+        If(cond, then_expr, else_expr, syntax) => match fold(*cond) {
+            (Literal(ast::Literal::Bool(true)), _) => return fold(*then_expr),
+            (Literal(ast::Literal::Bool(false)), _) => return fold(*else_expr),
+            cond => If(
+                Box::new(cond),
+                fold_box(*then_expr),
+                fold_box(*else_expr),
+                syntax,
+            ),
+        },
+        Let((name, val), rest, syntax) => Let((name, fold_box(*val)), fold_box(*rest), syntax),
+        LetRec(defs, rest) => LetRec(
+            defs.into_iter()
+                .map(|(name, expr)| (name, fold_box(*expr)))
+                .collect(),
+            fold_box(*rest),
+        ),
+        Match(val, cases) => match fold(*val) {
+            (Case((tag, _), payload), _) if cases.iter().any(|((t, _), _)| t.0 == tag) => {
+                let ((_, name), rhs) = cases.into_iter().find(|((t, _), _)| t.0 == tag).unwrap();
+                Let((name, payload), fold_box(*rhs), Syntax::Plain)
+            }
+            val => Match(
+                Box::new(val),
+                cases
+                    .into_iter()
+                    .map(|(pattern, rhs)| (pattern, fold_box(*rhs)))
+                    .collect(),
+            ),
+        },
+        Dict(entries) => Dict(
+            entries
+                .into_iter()
+                .map(|(key, expr)| (key, fold_box(*expr)))
+                .collect(),
+        ),
+        Record(fields) => Record(
+            fields
+                .into_iter()
+                .map(|(name, expr)| (name, fold_box(*expr)))
+                .collect(),
+        ),
+        Set(items) => Set(items.into_iter().map(|e| fold_box(*e)).collect()),
+        MacroCall(name, args) => MacroCall(name, args.into_iter().map(|e| fold_box(*e)).collect()),
+        expr @ (Literal(_) | Variable(_)) => expr,
+    };
+    (expr, span)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fold_constants;
+    use crate::{fmt::format_script, parse, ty::TypeckState};
+
+    fn types(items: &[crate::ast::TopLevel]) -> Vec<String> {
+        let mut state = TypeckState::new();
+        let types = state.check_script_with_types(items).unwrap();
+        types
+            .iter()
+            .map(|(_, v)| state.core().display(*v))
+            .collect()
+    }
+
+    // Folds `script` to `folded`, keeping the types of its items
+    fn check(script: &str, folded: &str) {
+        let parsed = parse(script).unwrap();
+        let before = types(&parsed);
+        let parsed = fold_constants(parsed);
+        assert_eq!(format_script(&parsed).trim_end(), folded);
+        assert_eq!(types(&parsed), before);
+    }
+
+    #[test]
+    fn folds_if() {
+        check(
+            r#"let a = if true then "yes" else "no""#,
+            r#"let a = "yes""#,
+        );
+        check(
+            "let b = fun x -> if false then x else x",
+            "let b = fun x -> x",
+        );
+    }
+
+    #[test]
+    fn folds_field_projection() {
+        check(r#"let a = {x = true; y = "y"}.x"#, "let a = true");
+        check(
+            "let b = fun f -> {x = f; y = fun z -> z}.y",
+            "let b = fun f -> fun z -> z",
+        );
+    }
+
+    #[test]
+    fn keeps_field_projection_with_effects() {
+        check(
+            "let a = fun f -> {x = true; y = f true}.x",
+            "let a = fun f -> {x = true; y = f true}.x",
+        );
+    }
+
+    #[test]
+    fn folds_match() {
+        check(
+            r#"let a = match `A "a" with `A v -> v | `B _ -> "b""#,
+            "let a = let v = \"a\" in\nv",
+        );
+    }
+}
@@ -0,0 +1,104 @@
+use crate::{
+    ast::{Expr, TopLevel},
+    parse, Error, TypeckState,
+};
+use std::panic::{self, AssertUnwindSafe};
+
+// Inputs over these limits are rejected before checking. Both keep the
+// recursion in the checker and in dropping the AST well within the stack.
+const MAX_INPUT_LEN: usize = 16 * 1024;
+const MAX_DEPTH: usize = 256;
+
+// What happened to a fuzzer input. Only Panicked indicates a bug, errors
+// are the expected result for most inputs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FuzzOutcome {
+    // Parsed and type checked, and every type could be displayed
+    Checked,
+    // A syntax or type error
+    Error(Error),
+    // Not UTF-8 or over one of the resource limits
+    Rejected(&'static str),
+    // The panic message
+    Panicked(String),
+}
+
+impl FuzzOutcome {
+    pub fn is_bug(&self) -> bool {
+        matches!(self, FuzzOutcome::Panicked(_))
+    }
+}
+
+fn too_deep(expr: &Expr, depth: usize) -> bool {
+    use Expr::*;
+    let d = match depth.checked_sub(1) {
+        Some(d) => d,
+        None => return true,
+    };
+    match expr {
+        BinOp(a, _, b) | Call(a, b) => too_deep(&a.0, d) || too_deep(&b.0, d),
+        Compare(e, rest) => too_deep(&e.0, d) || rest.iter().any(|(_, e)| too_deep(&e.0, d)),
+        Case(_, e) | FieldAccess(e, _) | FuncDef(_, e) => too_deep(&e.0, d),
+        If(a, b, c, _) => too_deep(&a.0, d) || too_deep(&b.0, d) || too_deep(&c.0, d),
+        Let((_, e), rest, _) => too_deep(&e.0, d) || too_deep(&rest.0, d),
+        LetRec(defs, rest) => defs.iter().any(|(_, e)| too_deep(&e.0, d)) || too_deep(&rest.0, d),
+        Literal(_) | Variable(_) => false,
+        MacroCall(_, args) => args.iter().any(|e| too_deep(&e.0, d)),
+        Match(e, cases) => too_deep(&e.0, d) || cases.iter().any(|(_, e)| too_deep(&e.0, d)),
+        Dict(fields) | Record(fields) => fields.iter().any(|(_, e)| too_deep(&e.0, d)),
+        Set(items) => items.iter().any(|e| too_deep(&e.0, d)),
+    }
+}
+
+fn check(source: &str) -> FuzzOutcome {
+    let parsed = match parse(source) {
+        Ok(parsed) => parsed,
+        Err(e) => return FuzzOutcome::Error(e),
+    };
+    let deep = parsed.iter().any(|item| match item.unattributed() {
+        TopLevel::Expr((e, _)) => too_deep(e, MAX_DEPTH),
+        TopLevel::LetDef((_, e)) | TopLevel::Const((_, e)) => too_deep(&e.0, MAX_DEPTH),
+        TopLevel::LetRecDef(defs) => defs.iter().any(|(_, e)| too_deep(&e.0, MAX_DEPTH)),
+        TopLevel::Test(_, e) => too_deep(&e.0, MAX_DEPTH),
+        TopLevel::Include(_) | TopLevel::Fixity(..) => false,
+        TopLevel::Macro(_, _, e) => too_deep(&e.0, MAX_DEPTH),
+        TopLevel::Attributed(..) => unreachable!(),
+    });
+    if deep {
+        return FuzzOutcome::Rejected("expression nested too deeply");
+    }
+
+    let mut state = TypeckState::new();
+    match state.check_script_with_types(&parsed) {
+        Ok(types) => {
+            for (_, v) in types {
+                state.core().display(v);
+            }
+            FuzzOutcome::Checked
+        }
+        Err(e) => FuzzOutcome::Error(e),
+    }
+}
+
+// Parses and checks arbitrary bytes as a script, for fuzzing harnesses:
+//
+//     fuzz_target!(|data: &[u8]| assert!(!zx::fuzz_check(data).is_bug()));
+pub fn fuzz_check(data: &[u8]) -> FuzzOutcome {
+    if data.len() > MAX_INPUT_LEN {
+        return FuzzOutcome::Rejected("input too long");
+    }
+    let source = match std::str::from_utf8(data) {
+        Ok(source) => source,
+        Err(_) => return FuzzOutcome::Rejected("input is not UTF-8"),
+    };
+    panic::catch_unwind(AssertUnwindSafe(|| check(source))).unwrap_or_else(|payload| {
+        let msg = match payload.downcast::<String>() {
+            Ok(msg) => *msg,
+            Err(payload) => match payload.downcast::<&str>() {
+                Ok(msg) => (*msg).to_owned(),
+                Err(_) => "unknown panic".to_owned(),
+            },
+        };
+        FuzzOutcome::Panicked(msg)
+    })
+}
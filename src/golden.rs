@@ -0,0 +1,102 @@
+use crate::{eval::eval_consts, expand_macros, parse, source::Source, ty::TypeckState};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+// Golden file tests: each `.zx` file in a directory is checked and the
+// result compared against the `.expected` file next to it, which holds
+// either the type of every top level item or the error.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    Failed { expected: String, actual: String },
+    // The expected file was missing or different and has been rewritten
+    Blessed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileResult {
+    pub path: PathBuf,
+    pub outcome: Outcome,
+}
+
+// The text an expected file should contain for a script: one `name: type`
+// line per top level item, using `-` for expressions, followed by a
+// `warning line:col: msg` line per warning, or a single line with the error
+// and its position.
+pub fn render(source: &Source) -> String {
+    let mut state = TypeckState::new();
+    let res = parse(&source.text)
+        .and_then(expand_macros)
+        .and_then(|parsed| {
+            let types = state.check_script_with_types(&parsed)?;
+            eval_consts(parsed)?;
+            Ok(types)
+        });
+    match res {
+        Ok(types) => {
+            let mut out: String = types
+                .into_iter()
+                .map(|(name, v)| {
+                    let name = name.as_deref().unwrap_or("-");
+                    format!("{}: {}\n", name, state.core().display(v))
+                })
+                .collect();
+            for warning in state.warnings() {
+                let (line, col) = source.line_col(warning.span.start);
+                out.push_str(&format!("warning {}:{}: {}\n", line, col, warning));
+            }
+            out
+        }
+        Err(e) => match e.span {
+            Some(span) => {
+                let (line, col) = source.line_col(span.start);
+                format!("error {}:{}: {}\n", line, col, e)
+            }
+            None => format!("error: {}\n", e),
+        },
+    }
+}
+
+// Runs every `.zx` file in `dir` in name order. With `bless`, expected
+// files that are missing or out of date are rewritten instead of failing.
+pub fn run_dir(dir: impl AsRef<Path>, bless: bool) -> io::Result<Vec<FileResult>> {
+    let mut paths = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "zx") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut results = vec![];
+    for path in paths {
+        let actual = match Source::read_file(&path) {
+            Ok(source) => render(&source),
+            Err(d) => format!("error: {}\n", d.error),
+        };
+        let expected_path = path.with_extension("expected");
+        let expected = match fs::read_to_string(&expected_path) {
+            Ok(text) => Some(text),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+
+        let outcome = if expected.as_deref() == Some(actual.as_str()) {
+            Outcome::Passed
+        } else if bless {
+            fs::write(&expected_path, &actual)?;
+            Outcome::Blessed
+        } else {
+            Outcome::Failed {
+                expected: expected.unwrap_or_default(),
+                actual,
+            }
+        };
+        results.push(FileResult { path, outcome });
+    }
+    Ok(results)
+}
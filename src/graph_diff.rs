@@ -0,0 +1,87 @@
+use crate::{
+    dot,
+    ty::{TypeCheckerCore, TypeNode, ID},
+};
+use std::{collections::BTreeSet, fmt};
+
+// The flow graph at one point, to compare with a later one. Unlike
+// TypeckState::snapshot this can't be restored, only diffed. Node IDs are
+// only comparable between snapshots if the core wasn't compacted between
+// them.
+#[derive(Debug, Clone)]
+pub struct GraphSnapshot {
+    nodes: Vec<TypeNode>,
+    edges: BTreeSet<(ID, ID)>,
+}
+
+// What changed between two snapshots. A node whose ID now holds a
+// different head, as after a rollback and new nodes, counts as both
+// removed and added. Nodes are labelled as in to_dot.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GraphDiff {
+    pub added_nodes: Vec<(ID, String)>,
+    pub removed_nodes: Vec<(ID, String)>,
+    pub added_edges: Vec<(ID, ID)>,
+    pub removed_edges: Vec<(ID, ID)>,
+}
+
+impl GraphDiff {
+    // Whether the graphs are the same, e.g. after rolling back everything
+    // since the first snapshot
+    pub fn is_empty(&self) -> bool {
+        self.added_nodes.is_empty()
+            && self.removed_nodes.is_empty()
+            && self.added_edges.is_empty()
+            && self.removed_edges.is_empty()
+    }
+}
+
+// One line per change, as `+n3 str` or `-n1 -> n3`
+impl fmt::Display for GraphDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, label) in &self.removed_nodes {
+            writeln!(f, "-n{} {}", i, label)?;
+        }
+        for (i, label) in &self.added_nodes {
+            writeln!(f, "+n{} {}", i, label)?;
+        }
+        for (i, j) in &self.removed_edges {
+            writeln!(f, "-n{} -> n{}", i, j)?;
+        }
+        for (i, j) in &self.added_edges {
+            writeln!(f, "+n{} -> n{}", i, j)?;
+        }
+        Ok(())
+    }
+}
+
+impl TypeCheckerCore {
+    pub fn snapshot(&self) -> GraphSnapshot {
+        let edges = (0..self.types.len())
+            .flat_map(|i| self.r.downsets(i).map(move |j| (i, j)))
+            .collect();
+        GraphSnapshot {
+            nodes: self.types.clone(),
+            edges,
+        }
+    }
+
+    // The nodes and edges in `after` but not `before`, and the other way
+    // around
+    pub fn diff(before: &GraphSnapshot, after: &GraphSnapshot) -> GraphDiff {
+        let changed = |from: &GraphSnapshot, to: &GraphSnapshot| -> Vec<(ID, String)> {
+            to.nodes
+                .iter()
+                .enumerate()
+                .filter(|&(i, node)| from.nodes.get(i) != Some(node))
+                .map(|(i, node)| (i, dot::label(node)))
+                .collect()
+        };
+        GraphDiff {
+            added_nodes: changed(before, after),
+            removed_nodes: changed(after, before),
+            added_edges: after.edges.difference(&before.edges).copied().collect(),
+            removed_edges: before.edges.difference(&after.edges).copied().collect(),
+        }
+    }
+}
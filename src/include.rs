@@ -0,0 +1,142 @@
+use crate::{
+    ast::{Fixities, TopLevel},
+    error::{Error, ErrorKind, IncludeError, Result},
+    lexer::Token,
+    operators, parse_tokens,
+    source::Source,
+    source_map::SourceMap,
+    span::{FileId, Span},
+    tokens,
+};
+use std::path::{Component, Path, PathBuf};
+
+// `include "path"` splices the top level items of another file in place of
+// the include, before checking. Paths are relative to the including file,
+// and each included file is added to the source map so that spans and
+// errors point into it. The operators declared in an included file can be
+// used in the including one, so includes are expanded before the file
+// including them is parsed.
+
+// Parses `file` and the files it includes, reading them from disk.
+pub fn parse_with_includes(map: &mut SourceMap, file: FileId) -> Result<Vec<TopLevel>> {
+    parse_with_includes_using(map, file, |path| {
+        Source::read_file(path).map_err(|d| d.error.to_string())
+    })
+}
+
+// Same as parse_with_includes, but includes are read with `load`, which is
+// given the included path joined to the directory of the including file.
+pub fn parse_with_includes_using(
+    map: &mut SourceMap,
+    file: FileId,
+    mut load: impl FnMut(&Path) -> std::result::Result<Source, String>,
+) -> Result<Vec<TopLevel>> {
+    let (items, included) = expand_file(map, file, &mut load)?;
+    Ok(splice(items, included))
+}
+
+// Same as parse_with_includes, but the includes of `file` itself are kept
+// in place, for printing it as written. The included files are still read,
+// for the operators they declare.
+pub fn parse_keeping_includes(map: &mut SourceMap, file: FileId) -> Result<Vec<TopLevel>> {
+    let (items, _) = expand_file(map, file, &mut |path| {
+        Source::read_file(path).map_err(|d| d.error.to_string())
+    })?;
+    Ok(items)
+}
+
+fn expand_file(
+    map: &mut SourceMap,
+    file: FileId,
+    load: &mut impl FnMut(&Path) -> std::result::Result<Source, String>,
+) -> Result<(Vec<TopLevel>, Vec<Vec<TopLevel>>)> {
+    let path = normalize(Path::new(&map.get(file).unwrap().source().name));
+    expand(map, file, path, load, &mut vec![], &mut Fixities::new())
+}
+
+// Replaces each include in `items` by the items of the included file
+fn splice(items: Vec<TopLevel>, included: Vec<Vec<TopLevel>>) -> Vec<TopLevel> {
+    let mut out = vec![];
+    let mut included = included.into_iter();
+    for item in items {
+        match item {
+            TopLevel::Include(_) => out.extend(included.next().unwrap()),
+            item => out.push(item),
+        }
+    }
+    out
+}
+
+// Removes `.` components and resolves `..` where possible, so that the same
+// file reached by different paths is recognized in cycles.
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir
+                if matches!(out.components().next_back(), Some(Component::Normal(_))) =>
+            {
+                out.pop();
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// Parses `file`, returning its items along with the items of each of its
+// includes, in order
+fn expand(
+    map: &mut SourceMap,
+    file: FileId,
+    path: PathBuf,
+    load: &mut impl FnMut(&Path) -> std::result::Result<Source, String>,
+    // The files currently being expanded, outermost first
+    stack: &mut Vec<PathBuf>,
+    // Gets the operators declared in the file and the files it includes
+    fixities: &mut Fixities,
+) -> Result<(Vec<TopLevel>, Vec<Vec<TopLevel>>)> {
+    let tokens = tokens(map.get(file).unwrap().text());
+    let dir = path.parent().unwrap_or_else(|| Path::new("")).to_owned();
+    stack.push(path);
+
+    // The items of each include, in order
+    let mut included = vec![];
+    let mut inherited = Fixities::new();
+    for pair in tokens.windows(2) {
+        let (name, span) = match pair {
+            [Ok((_, Token::Include, _)), Ok((l, Token::Str(name), r))] => {
+                (name.clone(), Span::in_file(file, *l, *r))
+            }
+            _ => continue,
+        };
+        let target = normalize(&dir.join(&name));
+        if let Some(i) = stack.iter().position(|p| *p == target) {
+            let cycle: Vec<_> = stack[i..]
+                .iter()
+                .chain(std::iter::once(&target))
+                .map(|p| p.display().to_string())
+                .collect();
+            return Err(Error::new(
+                ErrorKind::Include(IncludeError::Cycle(cycle)),
+                span,
+            ));
+        }
+
+        let source = load(&target).map_err(|e| {
+            let e = IncludeError::Unreadable(name.clone(), e.to_string());
+            Error::new(ErrorKind::Include(e), span)
+        })?;
+        let id = map.add(source);
+        let (items, inner) = expand(map, id, target, load, stack, &mut inherited)?;
+        included.push(splice(items, inner));
+    }
+    stack.pop();
+
+    let declared = operators::declared(&tokens);
+    let items = parse_tokens(tokens, file, &inherited)?;
+    fixities.extend(inherited);
+    fixities.extend(declared);
+    Ok((items, included))
+}
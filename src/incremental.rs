@@ -0,0 +1,171 @@
+use crate::{
+    ast,
+    config::CheckConfig,
+    error::{Result, Warning},
+    fmt::format_script,
+    span::Span,
+    ty::Snapshot,
+    ty::TypeckState,
+};
+
+// Re-checks successive versions of a script, skipping the unchanged top level
+// items at the start. A single snapshot is kept at the first item that
+// changed last time, so repeatedly editing the same definition only
+// re-checks from there on.
+pub struct IncrementalCheck {
+    state: TypeckState,
+    // Canonical text of the items whose definitions are in `state`
+    checked: Vec<String>,
+    // The warnings of each checked item, with the first span of the item
+    // they are relative to, see warnings
+    warnings: Vec<(Span, Vec<Warning>)>,
+    snapshot: Snapshot,
+    snapshot_len: usize,
+    config: CheckConfig,
+}
+
+impl IncrementalCheck {
+    pub fn new() -> Self {
+        Self::with_config(CheckConfig::default())
+    }
+
+    // Same as new, but checking as `config` says
+    pub fn with_config(config: CheckConfig) -> Self {
+        let state = TypeckState::with_config(config.clone());
+        Self {
+            snapshot: state.snapshot(),
+            state,
+            checked: vec![],
+            warnings: vec![],
+            snapshot_len: 0,
+            config,
+        }
+    }
+
+    pub fn state(&self) -> &TypeckState {
+        &self.state
+    }
+
+    // The warnings of every item of the last version checked, including the
+    // unchanged ones that weren't checked again, in the order of the items
+    pub fn warnings(&self) -> Vec<Warning> {
+        self.warnings
+            .iter()
+            .flat_map(|(_, warnings)| warnings.iter().cloned())
+            .collect()
+    }
+
+    // Gives each checked item the warnings of `state` within it
+    fn record(&mut self, items: &[ast::TopLevel]) {
+        let mut out: Vec<_> = items
+            .iter()
+            .map(|item| (item.first_span(), vec![]))
+            .collect();
+        for warning in self.state.warnings() {
+            let span = warning.span;
+            let owner = out
+                .iter()
+                .rposition(|(start, _)| start.file == span.file && start.start <= span.start);
+            if let Some(i) = owner {
+                out[i].1.push(warning.clone());
+            }
+        }
+        self.warnings.extend(out);
+    }
+
+    // Moves the warnings of unchanged items along with them, as text before
+    // them may have changed
+    fn rebase(&mut self, parsed: &[ast::TopLevel]) {
+        for ((old, warnings), item) in self.warnings.iter_mut().zip(parsed) {
+            let new = item.first_span();
+            for warning in warnings {
+                let span = &mut warning.span;
+                if span.file == old.file {
+                    span.file = new.file;
+                    span.start = span.start - old.start + new.start;
+                    span.end = span.end - old.start + new.start;
+                }
+            }
+            *old = new;
+        }
+    }
+
+    // Checks a new version of the script, returning how many items had to be
+    // checked again.
+    pub fn check(&mut self, parsed: &[ast::TopLevel]) -> Result<usize> {
+        let keys: Vec<_> = parsed
+            .iter()
+            .map(|item| format_script(std::slice::from_ref(item)))
+            .collect();
+        let same = keys
+            .iter()
+            .zip(&self.checked)
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        if same == keys.len() && same == self.checked.len() {
+            self.rebase(parsed);
+            return Ok(0);
+        }
+
+        if same < self.checked.len() {
+            if same < self.snapshot_len {
+                self.state = TypeckState::with_config(self.config.clone());
+                self.snapshot = self.state.snapshot();
+                self.snapshot_len = 0;
+            }
+            self.state.restore(&self.snapshot);
+            self.checked.truncate(self.snapshot_len);
+            self.warnings.truncate(self.snapshot_len);
+        }
+        self.rebase(parsed);
+
+        let start = self.checked.len();
+        if start < same {
+            self.state.check_script(&parsed[start..same])?;
+            self.checked.extend_from_slice(&keys[start..same]);
+            self.record(&parsed[start..same]);
+        }
+        if self.snapshot_len != same {
+            self.snapshot = self.state.snapshot();
+            self.snapshot_len = same;
+        }
+        self.state.check_script(&parsed[same..])?;
+        self.checked.extend_from_slice(&keys[same..]);
+        self.record(&parsed[same..]);
+        Ok(parsed.len() - start)
+    }
+}
+
+impl Default for IncrementalCheck {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IncrementalCheck;
+    use crate::parse;
+
+    #[test]
+    fn keeps_warnings_of_unchanged_items() {
+        let mut checker = IncrementalCheck::new();
+        let first = "@deprecated let old = fun x -> x; let a = old true; let b = true";
+        checker.check(&parse(first).unwrap()).unwrap();
+        assert_eq!(checker.warnings().len(), 1);
+        // The snapshot is taken before the edited item the first time
+        let second = "@deprecated let old = fun x -> x; let a = old true; let b = false";
+        checker.check(&parse(second).unwrap()).unwrap();
+
+        // Only the last item is checked again, but the warning moves with
+        // the space added before it
+        let third = "@deprecated let old = fun x -> x;\n\nlet a = old true; let b = true";
+        assert_eq!(checker.check(&parse(third).unwrap()).unwrap(), 1);
+        let warnings = checker.warnings();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].to_string(), "old is deprecated");
+        let span = warnings[0].span;
+        assert_eq!(&third[span.start..span.end], "old");
+    }
+}
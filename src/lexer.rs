@@ -0,0 +1,412 @@
+use crate::{error::SyntaxError, span::Span};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    Ident(String),
+    // Includes the leading backtick
+    Tag(String),
+    // The contents of a string literal, with escapes resolved
+    Str(String),
+    // Digits, only used for precedences in fixity declarations
+    Number(String),
+    // A run of symbol chars other than the ones with a meaning of their own,
+    // such as `<+>`
+    Operator(String),
+    // The text of a line comment after the `//`
+    Comment(String),
+    // The text of a `///` doc comment, without a space after the slashes
+    DocComment(String),
+    Let,
+    Rec,
+    And,
+    In,
+    Include,
+    Macro,
+    Const,
+    Test,
+    Infixl,
+    Infixr,
+    Infix,
+    Fun,
+    If,
+    Then,
+    Else,
+    Match,
+    With,
+    Where,
+    True,
+    False,
+    LParen,
+    RParen,
+    LBrace,
+    // `#{`, which opens a dict literal
+    HashBrace,
+    RBrace,
+    // `#[`, which opens a set literal
+    HashBracket,
+    RBracket,
+    Eq,
+    Semi,
+    Comma,
+    Colon,
+    Question,
+    Dot,
+    Arrow,
+    Pipe,
+    Bang,
+    At,
+}
+
+// A token with its start and end offsets, as lalrpop expects them
+pub type Triple = (usize, Token, usize);
+
+// Token classes for syntax highlighting
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum TokenKind {
+    Keyword,
+    Ident,
+    Tag,
+    Str,
+    Number,
+    Bool,
+    Comment,
+    Operator,
+    Punctuation,
+    // Text that doesn't form a valid token
+    Error,
+}
+
+impl TokenKind {
+    // The TextMate scope editors usually color this kind of token with
+    pub fn scope(self) -> &'static str {
+        use TokenKind::*;
+        match self {
+            Keyword => "keyword.control.zx",
+            Ident => "variable.other.zx",
+            Tag => "entity.name.tag.zx",
+            Str => "string.quoted.double.zx",
+            Number => "constant.numeric.zx",
+            Bool => "constant.language.boolean.zx",
+            Comment => "comment.line.double-slash.zx",
+            Operator => "keyword.operator.zx",
+            Punctuation => "punctuation.separator.zx",
+            Error => "invalid.illegal.zx",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SpannedToken {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LexError {
+    pub kind: SyntaxError,
+    pub span: Span,
+}
+
+impl Token {
+    pub fn kind(&self) -> TokenKind {
+        use Token::*;
+        match self {
+            Ident(_) => TokenKind::Ident,
+            Tag(_) => TokenKind::Tag,
+            Str(_) => TokenKind::Str,
+            Number(_) => TokenKind::Number,
+            Comment(_) | DocComment(_) => TokenKind::Comment,
+            True | False => TokenKind::Bool,
+            Let | Rec | And | In | Include | Macro | Const | Test | Infixl | Infixr | Infix
+            | Fun | If | Then | Else | Match | With | Where => TokenKind::Keyword,
+            Eq | Arrow | Pipe | Dot | Bang | Question | Operator(_) => TokenKind::Operator,
+            LParen | RParen | LBrace | HashBrace | RBrace | HashBracket | RBracket | Semi
+            | Comma | Colon | At => TokenKind::Punctuation,
+        }
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Token::*;
+        let s = match self {
+            Ident(s) | Tag(s) | Number(s) | Operator(s) => s,
+            Str(s) => return write!(f, "{}", quote(s)),
+            Comment(s) => return write!(f, "//{}", s),
+            DocComment(s) if s.is_empty() => "///",
+            DocComment(s) => return write!(f, "/// {}", s),
+            Let => "let",
+            Rec => "rec",
+            And => "and",
+            In => "in",
+            Include => "include",
+            Macro => "macro",
+            Const => "const",
+            Test => "test",
+            Infixl => "infixl",
+            Infixr => "infixr",
+            Infix => "infix",
+            Fun => "fun",
+            If => "if",
+            Then => "then",
+            Else => "else",
+            Match => "match",
+            With => "with",
+            Where => "where",
+            True => "true",
+            False => "false",
+            LParen => "(",
+            RParen => ")",
+            LBrace => "{",
+            HashBrace => "#{",
+            RBrace => "}",
+            HashBracket => "#[",
+            RBracket => "]",
+            Eq => "=",
+            Semi => ";",
+            Comma => ",",
+            Colon => ":",
+            Question => "?",
+            Dot => ".",
+            Arrow => "->",
+            Pipe => "|",
+            Bang => "!",
+            At => "@",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+// Writes a string as a literal that lexes back to the same string.
+pub fn quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn keyword(ident: &str) -> Option<Token> {
+    use Token::*;
+    Some(match ident {
+        "let" => Let,
+        "rec" => Rec,
+        "and" => And,
+        "in" => In,
+        "include" => Include,
+        "macro" => Macro,
+        "const" => Const,
+        "test" => Test,
+        "infixl" => Infixl,
+        "infixr" => Infixr,
+        "infix" => Infix,
+        "fun" => Fun,
+        "if" => If,
+        "then" => Then,
+        "else" => Else,
+        "match" => Match,
+        "with" => With,
+        "where" => Where,
+        "true" => True,
+        "false" => False,
+        _ => return None,
+    })
+}
+
+pub(crate) fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn is_operator_char(c: char) -> bool {
+    "!$%&*+-./:<=>?^|~".contains(c)
+}
+
+// Whether a name is that of a user defined operator rather than a variable
+pub(crate) fn is_operator(name: &str) -> bool {
+    name.starts_with(is_operator_char)
+}
+
+// Splits a script into tokens, including comments. Stops after the first
+// error.
+pub struct Lexer<'a> {
+    src: &'a str,
+    pos: usize,
+    failed: bool,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(src: &'a str) -> Self {
+        Self {
+            src,
+            pos: 0,
+            failed: false,
+        }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.src[self.pos..]
+    }
+
+    // Advances past the longest prefix whose chars match `pred`.
+    fn eat_while(&mut self, pred: impl Fn(char) -> bool) -> &'a str {
+        let rest = self.rest();
+        let len = rest.find(|c| !pred(c)).unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+
+    fn error(&mut self, kind: SyntaxError, span: Span) -> Option<Result<Triple, LexError>> {
+        self.failed = true;
+        Some(Err(LexError { kind, span }))
+    }
+
+    // Invalid escapes are reported once the whole literal has been read, so
+    // that lexing can resume after it.
+    fn string(&mut self, start: usize) -> Option<Result<Triple, LexError>> {
+        let mut s = String::new();
+        let mut bad_escape = None;
+        let mut chars = self.rest().chars();
+        loop {
+            let c = match chars.next() {
+                Some(c) => c,
+                None => {
+                    self.pos = self.src.len();
+                    return self.error(SyntaxError::UnterminatedString, Span::new(start, self.pos));
+                }
+            };
+            self.pos += c.len_utf8();
+            match c {
+                '"' => break,
+                '\\' => {
+                    let escape_start = self.pos - 1;
+                    let c = chars.next();
+                    self.pos += c.map_or(0, char::len_utf8);
+                    match c {
+                        Some('"') => s.push('"'),
+                        Some('\\') => s.push('\\'),
+                        Some('n') => s.push('\n'),
+                        Some('t') => s.push('\t'),
+                        _ => {
+                            bad_escape.get_or_insert(Span::new(escape_start, self.pos));
+                        }
+                    }
+                }
+                c => s.push(c),
+            }
+        }
+        match bad_escape {
+            Some(span) => self.error(SyntaxError::InvalidEscape, span),
+            None => Some(Ok((start, Token::Str(s), self.pos))),
+        }
+    }
+}
+
+impl Iterator for Lexer<'_> {
+    type Item = Result<Triple, LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        self.eat_while(char::is_whitespace);
+        let start = self.pos;
+        let c = self.rest().chars().next()?;
+
+        if c == '_' || c.is_ascii_lowercase() {
+            let ident = self.eat_while(is_ident_char);
+            // make sure __proto__ is not considered a valid identifier
+            if ident == "__proto__" {
+                let kind = SyntaxError::ReservedName(ident.to_owned());
+                return self.error(kind, Span::new(start, self.pos));
+            }
+            let token = keyword(ident).unwrap_or_else(|| Token::Ident(ident.to_owned()));
+            return Some(Ok((start, token, self.pos)));
+        }
+
+        self.pos += c.len_utf8();
+        let token = match c {
+            '`' => {
+                if !self.rest().starts_with(|c: char| c.is_ascii_uppercase()) {
+                    return self.error(SyntaxError::LowercaseTag, Span::new(start, self.pos));
+                }
+                self.eat_while(is_ident_char);
+                Token::Tag(self.src[start..self.pos].to_owned())
+            }
+            '"' => return self.string(start),
+            '/' if self.rest().starts_with('/') => {
+                self.pos += 1;
+                let text = self.eat_while(|c| c != '\n');
+                // Only three slashes start a doc comment, not four or more
+                match text.strip_prefix('/') {
+                    Some(doc) if !doc.starts_with('/') => {
+                        let doc = doc.trim_end_matches('\r');
+                        Token::DocComment(doc.strip_prefix(' ').unwrap_or(doc).to_owned())
+                    }
+                    _ => Token::Comment(text.to_owned()),
+                }
+            }
+            c if c.is_ascii_digit() => {
+                self.eat_while(|c| c.is_ascii_digit());
+                Token::Number(self.src[start..self.pos].to_owned())
+            }
+            c if is_operator_char(c) => {
+                self.eat_while(is_operator_char);
+                match &self.src[start..self.pos] {
+                    "->" => Token::Arrow,
+                    "=" => Token::Eq,
+                    "." => Token::Dot,
+                    "|" => Token::Pipe,
+                    "!" => Token::Bang,
+                    ":" => Token::Colon,
+                    "?" => Token::Question,
+                    op => Token::Operator(op.to_owned()),
+                }
+            }
+            '#' if self.rest().starts_with('{') => {
+                self.pos += 1;
+                Token::HashBrace
+            }
+            '#' if self.rest().starts_with('[') => {
+                self.pos += 1;
+                Token::HashBracket
+            }
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '{' => Token::LBrace,
+            '}' => Token::RBrace,
+            ']' => Token::RBracket,
+            ';' => Token::Semi,
+            ',' => Token::Comma,
+            '@' => Token::At,
+            _ => return self.error(SyntaxError::InvalidToken, Span::new(start, self.pos)),
+        };
+        Some(Ok((start, token, self.pos)))
+    }
+}
+
+// Splits a script into tokens for highlighting. Unlike parsing, this goes
+// on after lexical errors, returning the text they cover as Error tokens.
+pub fn tokenize(source: &str) -> Vec<SpannedToken> {
+    let mut lexer = Lexer::new(source);
+    let mut tokens = vec![];
+    while let Some(res) = lexer.next() {
+        let (kind, span) = match res {
+            Ok((l, token, r)) => (token.kind(), Span::new(l, r)),
+            Err(e) => {
+                lexer.failed = false;
+                (TokenKind::Error, e.span)
+            }
+        };
+        tokens.push(SpannedToken { kind, span });
+    }
+    tokens
+}
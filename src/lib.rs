@@ -1,19 +1,174 @@
-#[macro_use]
-extern crate lalrpop_util;
+// Errors and diagnostics are returned by value, stack traces and all, as
+// they are only made on the way out
+#![allow(clippy::result_large_err)]
 
 #[macro_use]
-extern crate anyhow;
+extern crate lalrpop_util;
 
-lalrpop_mod!(grammar);
+lalrpop_mod!(
+    #[allow(clippy::all)]
+    grammar
+);
 
-mod ast;
-mod reachability;
+#[cfg(feature = "arbitrary")]
+mod arbitrary_ast;
+pub mod ast;
+mod automaton;
+mod builtins;
+#[cfg(feature = "capi")]
+pub mod capi;
+mod complete;
+mod config;
+mod convert;
+mod dot;
+mod error;
+pub mod eval;
+mod fmt;
+mod fold;
+mod fuzz;
+pub mod golden;
+mod graph_diff;
+mod include;
+mod incremental;
+pub mod lexer;
+#[cfg(feature = "lsp")]
+pub mod lsp;
+mod macros;
+mod messages;
+mod operators;
+mod prelude;
+mod pretty;
+#[cfg(feature = "python")]
+mod python;
+mod quick_fix;
+pub mod reachability;
+mod references;
+mod rename;
+mod render;
+mod save;
+mod semantic;
+mod session;
+mod signature;
+mod signature_help;
+mod simplify;
+mod source;
+mod source_map;
+mod span;
+mod symbols;
 mod ty;
+mod type_parse;
+#[cfg(feature = "wasm")]
+pub mod wasm;
 
-pub fn run(source: &str) {
+#[cfg(feature = "arbitrary")]
+pub use arbitrary_ast::{arbitrary_expr, arbitrary_script, arbitrary_top_level, AstSize};
+pub use automaton::{Head, Label, Polarity, State, TypeAutomaton};
+pub use builtins::{Capability, CapabilityPolicy, Determinism};
+pub use complete::Completion;
+pub use config::{CheckConfig, Level, Limits, Lint, LintLevels, Strictness};
+pub use convert::ZxType;
+pub use error::{
+    ConstError, Error, ErrorKind, FormatError, Frame, IncludeError, IoError, MacroError,
+    RenameError, Resource, Result, RuntimeError, SignatureError, SyntaxError, Warning, WarningKind,
+};
+pub use fmt::{format_script, format_source};
+pub use fold::fold_constants;
+pub use fuzz::{fuzz_check, FuzzOutcome};
+pub use graph_diff::{GraphDiff, GraphSnapshot};
+pub use include::{parse_keeping_includes, parse_with_includes, parse_with_includes_using};
+pub use incremental::IncrementalCheck;
+pub use lexer::{tokenize, SpannedToken, TokenKind};
+pub use macros::expand_macros;
+pub use messages::Catalog;
+pub use pretty::Layout;
+pub use quick_fix::{quick_fixes, QuickFix};
+pub use references::{References, Referent};
+pub use rename::{apply_edits, TextEdit};
+pub use render::stderr_supports_color;
+pub use semantic::{SemanticKind, SemanticToken};
+pub use session::{Sandbox, Session};
+pub use signature_help::SignatureHelp;
+pub use simplify::TypeExpr;
+pub use source::{Diagnostic, Source};
+pub use source_map::{SourceFile, SourceMap};
+pub use span::{FileId, Span};
+pub use symbols::{outline, symbols, Symbol, SymbolKind};
+pub use ty::{
+    CancelToken, CheckCounters, CustomHead, DisplayedType, Progress, Snapshot, Stats, TypeBuilder,
+    TypeCheckerCore, TypeckState, Use, Value,
+};
+#[cfg(feature = "derive")]
+pub use zx_derive::ZxType;
+
+use lalrpop_util::ParseError;
+
+pub fn parse(source: &str) -> Result<Vec<ast::TopLevel>> {
+    parse_in_file(source, FileId::default())
+}
+
+// Same as parse, but every span in the AST and in errors refers to `file`.
+pub fn parse_in_file(source: &str, file: FileId) -> Result<Vec<ast::TopLevel>> {
+    parse_tokens(tokens(source), file, &ast::Fixities::new())
+}
+
+// The tokens of a source that the parser reads, which are all but comments
+pub(crate) fn tokens(source: &str) -> Vec<std::result::Result<lexer::Triple, lexer::LexError>> {
+    lexer::Lexer::new(source)
+        .filter(|t| !matches!(t, Ok((_, lexer::Token::Comment(_), _))))
+        .collect()
+}
+
+// Parses the tokens of `file`, whose operators may also be declared in
+// `included`
+pub(crate) fn parse_tokens(
+    tokens: Vec<std::result::Result<lexer::Triple, lexer::LexError>>,
+    file: FileId,
+    included: &ast::Fixities,
+) -> Result<Vec<ast::TopLevel>> {
     let parser = grammar::ScriptParser::new();
-    let script = parser.parse(source).unwrap();
+    let ops = operators::fixities(&tokens, included);
+    parser.parse(file, &ops, tokens).map_err(|e| {
+        let (kind, span) = match e {
+            ParseError::InvalidToken { location } => {
+                (SyntaxError::InvalidToken, Span::new(location, location))
+            }
+            ParseError::UnrecognizedEOF { location, .. } => {
+                (SyntaxError::UnexpectedEnd, Span::new(location, location))
+            }
+            ParseError::UnrecognizedToken {
+                token: (l, t, r), ..
+            }
+            | ParseError::ExtraToken { token: (l, t, r) } => {
+                (SyntaxError::UnexpectedToken(t.to_string()), Span::new(l, r))
+            }
+            ParseError::User { error } => (error.kind, error.span),
+        };
+        Error::new(ErrorKind::Syntax(kind), Span { file, ..span })
+    })
+}
+
+pub fn run(source: &str) {
+    let script = parse(source).unwrap();
 
     let mut typeck = ty::TypeckState::new();
     typeck.check_script(&script).unwrap();
 }
+
+// Parses the script in `file` with the files it includes and expands its
+// macros, which is where checking and running a script start.
+pub fn parse_script(map: &mut SourceMap, file: FileId) -> Result<Vec<ast::TopLevel>> {
+    expand_macros(parse_with_includes(map, file)?)
+}
+
+// Same as run, but reads the script from a file, with its includes, and
+// reports errors along with the name and position in the file they are in.
+pub fn run_file(path: impl AsRef<std::path::Path>) -> std::result::Result<(), Diagnostic> {
+    let mut map = SourceMap::new();
+    let file = map.add(Source::read_file(path)?);
+    let check = |map: &mut SourceMap| {
+        let script = parse_script(map, file)?;
+        ty::TypeckState::new().check_script(&script)?;
+        eval::eval_consts(script).map(drop)
+    };
+    check(&mut map).map_err(|e| map.diagnostic(e))
+}
@@ -0,0 +1,746 @@
+use crate::{
+    ast::TopLevel, CheckConfig, Error, FileId, Referent, Result, SemanticKind,
+    SemanticToken as Token, Source, SourceFile, SourceMap, Span, Symbol, SymbolKind as Kind,
+    TypeckState,
+};
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, RequestId, Response};
+use lsp_types::{
+    notification::{
+        DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument,
+        Notification as NotificationTrait, PublishDiagnostics,
+    },
+    request::{
+        CodeActionRequest, Completion, DocumentSymbolRequest, GotoDefinition, HoverRequest,
+        References, Rename, Request as RequestTrait, SemanticTokensFullRequest,
+        SignatureHelpRequest,
+    },
+    CodeAction, CodeActionKind, CodeActionOrCommand, CodeActionParams,
+    CodeActionProviderCapability, CompletionItem, CompletionItemKind, CompletionOptions,
+    CompletionParams, CompletionResponse, Diagnostic, DiagnosticRelatedInformation,
+    DiagnosticSeverity, DidChangeTextDocumentParams, DidCloseTextDocumentParams,
+    DidOpenTextDocumentParams, DocumentSymbol, DocumentSymbolParams, DocumentSymbolResponse,
+    GotoDefinitionParams, GotoDefinitionResponse, Hover, HoverContents, HoverParams,
+    HoverProviderCapability, Location, MarkupContent, MarkupKind, OneOf, ParameterInformation,
+    ParameterLabel, Position, PublishDiagnosticsParams, Range, ReferenceParams, RenameParams,
+    SemanticToken, SemanticTokenModifier, SemanticTokenType, SemanticTokens,
+    SemanticTokensFullOptions, SemanticTokensLegend, SemanticTokensOptions, SemanticTokensParams,
+    SemanticTokensResult, SemanticTokensServerCapabilities, ServerCapabilities, SignatureHelp,
+    SignatureHelpOptions, SignatureHelpParams, SignatureInformation, SymbolKind,
+    TextDocumentSyncCapability, TextDocumentSyncKind, TextEdit, Url, WorkspaceEdit,
+};
+use std::collections::HashMap;
+
+struct Document {
+    // The document is the first file, followed by those it includes
+    files: SourceMap,
+    // Of the document itself
+    symbols: Vec<Symbol>,
+    state: TypeckState,
+    // Empty unless the document checked
+    tokens: Vec<Token>,
+    // With includes expanded but not macros, empty unless the document
+    // parsed
+    items: Vec<TopLevel>,
+    checked: bool,
+    // The first error found, if it didn't check
+    error: Option<Error>,
+    // The last version that checked, if this one doesn't, for completing
+    // code while it's being typed
+    previous: Option<Box<Document>>,
+}
+
+impl Document {
+    fn map(&self) -> &SourceFile {
+        self.files.get(FileId::default()).unwrap()
+    }
+
+    // Where a span of the document or one of its includes is
+    fn location(&self, uri: &Url, span: Span) -> Option<Location> {
+        let file = self.files.get(span.file)?;
+        let uri = match span.file == FileId::default() {
+            true => uri.clone(),
+            false => Url::from_file_path(&file.source().name).ok()?,
+        };
+        Some(Location::new(uri, range(file, span)))
+    }
+
+    // The last version that checked, with its items
+    fn checked(&self) -> Option<(&Document, &[TopLevel])> {
+        match self.checked {
+            true => Some((self, &self.items)),
+            false => self.previous.as_deref()?.checked(),
+        }
+    }
+}
+
+// The token types of the legend, indexed by encode_tokens
+const TOKEN_TYPES: [SemanticTokenType; 4] = [
+    SemanticTokenType::PARAMETER,
+    SemanticTokenType::VARIABLE,
+    SemanticTokenType::PROPERTY,
+    SemanticTokenType::ENUM_MEMBER,
+];
+
+// Globals are static variables and builtins default library ones
+const TOKEN_MODIFIERS: [SemanticTokenModifier; 2] = [
+    SemanticTokenModifier::STATIC,
+    SemanticTokenModifier::DEFAULT_LIBRARY,
+];
+
+// Runs a language server over stdin and stdout until the client shuts it
+// down. Documents are fully re-checked whenever they change, with their
+// includes and macros expanded and the config of CheckConfig::from_env.
+pub fn run() -> anyhow::Result<()> {
+    let (connection, io_threads) = Connection::stdio();
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        document_symbol_provider: Some(OneOf::Left(true)),
+        definition_provider: Some(OneOf::Left(true)),
+        references_provider: Some(OneOf::Left(true)),
+        rename_provider: Some(OneOf::Left(true)),
+        code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
+        completion_provider: Some(CompletionOptions {
+            trigger_characters: Some(vec![".".to_owned()]),
+            ..Default::default()
+        }),
+        // Arguments are separated by spaces
+        signature_help_provider: Some(SignatureHelpOptions {
+            trigger_characters: Some(vec![" ".to_owned()]),
+            ..Default::default()
+        }),
+        semantic_tokens_provider: Some(SemanticTokensServerCapabilities::SemanticTokensOptions(
+            SemanticTokensOptions {
+                legend: SemanticTokensLegend {
+                    token_types: TOKEN_TYPES.to_vec(),
+                    token_modifiers: TOKEN_MODIFIERS.to_vec(),
+                },
+                full: Some(SemanticTokensFullOptions::Bool(true)),
+                ..Default::default()
+            },
+        )),
+        ..Default::default()
+    };
+    connection.initialize(serde_json::to_value(capabilities)?)?;
+
+    let mut server = Server {
+        connection: &connection,
+        documents: HashMap::new(),
+        config: CheckConfig::from_env().map_err(anyhow::Error::msg)?,
+    };
+    server.main_loop()?;
+
+    // The writer thread only stops once the connection is gone.
+    drop(connection);
+    io_threads.join()?;
+    Ok(())
+}
+
+struct Server<'a> {
+    connection: &'a Connection,
+    documents: HashMap<Url, Document>,
+    config: CheckConfig,
+}
+
+impl<'a> Server<'a> {
+    fn main_loop(&mut self) -> anyhow::Result<()> {
+        for msg in &self.connection.receiver {
+            match msg {
+                Message::Request(req) => {
+                    if self.connection.handle_shutdown(&req)? {
+                        return Ok(());
+                    }
+                    self.handle_request(req)?;
+                }
+                Message::Notification(not) => self.handle_notification(not)?,
+                Message::Response(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_request(&mut self, req: Request) -> anyhow::Result<()> {
+        let id = req.id.clone();
+        match self.dispatch(req) {
+            Ok(result) => self.respond(id, result),
+            Err((code, msg)) => self.send(Response::new_err(id, code as i32, msg).into()),
+        }
+    }
+
+    // The result of a request, or the code and message of the error to
+    // answer it with
+    fn dispatch(
+        &mut self,
+        req: Request,
+    ) -> std::result::Result<serde_json::Value, (ErrorCode, String)> {
+        let result = match req.method.as_str() {
+            HoverRequest::METHOD => {
+                let params: HoverParams =
+                    serde_json::from_value(req.params).map_err(invalid_params)?;
+                let pos = params.text_document_position_params;
+                serde_json::to_value(self.hover(&pos.text_document.uri, pos.position))
+            }
+            GotoDefinition::METHOD => {
+                let params: GotoDefinitionParams =
+                    serde_json::from_value(req.params).map_err(invalid_params)?;
+                let pos = params.text_document_position_params;
+                serde_json::to_value(self.definition(pos.text_document.uri, pos.position))
+            }
+            References::METHOD => {
+                let params: ReferenceParams =
+                    serde_json::from_value(req.params).map_err(invalid_params)?;
+                let pos = params.text_document_position;
+                let include_declaration = params.context.include_declaration;
+                let refs =
+                    self.references(pos.text_document.uri, pos.position, include_declaration);
+                serde_json::to_value(refs)
+            }
+            DocumentSymbolRequest::METHOD => {
+                let params: DocumentSymbolParams =
+                    serde_json::from_value(req.params).map_err(invalid_params)?;
+                serde_json::to_value(self.document_symbols(&params.text_document.uri))
+            }
+            Completion::METHOD => {
+                let params: CompletionParams =
+                    serde_json::from_value(req.params).map_err(invalid_params)?;
+                let pos = params.text_document_position;
+                serde_json::to_value(self.completions(&pos.text_document.uri, pos.position))
+            }
+            Rename::METHOD => {
+                let params: RenameParams =
+                    serde_json::from_value(req.params).map_err(invalid_params)?;
+                let pos = params.text_document_position;
+                match self.rename(pos.text_document.uri, pos.position, &params.new_name) {
+                    Some(Ok(edit)) => serde_json::to_value(edit),
+                    Some(Err(e)) => return Err((ErrorCode::RequestFailed, e.to_string())),
+                    None => Ok(serde_json::Value::Null),
+                }
+            }
+            CodeActionRequest::METHOD => {
+                let params: CodeActionParams =
+                    serde_json::from_value(req.params).map_err(invalid_params)?;
+                serde_json::to_value(self.code_actions(params))
+            }
+            SignatureHelpRequest::METHOD => {
+                let params: SignatureHelpParams =
+                    serde_json::from_value(req.params).map_err(invalid_params)?;
+                let pos = params.text_document_position_params;
+                serde_json::to_value(self.signature_help(&pos.text_document.uri, pos.position))
+            }
+            SemanticTokensFullRequest::METHOD => {
+                let params: SemanticTokensParams =
+                    serde_json::from_value(req.params).map_err(invalid_params)?;
+                serde_json::to_value(self.semantic_tokens(&params.text_document.uri))
+            }
+            _ => {
+                let msg = format!("Unsupported request {}", req.method);
+                return Err((ErrorCode::MethodNotFound, msg));
+            }
+        };
+        result.map_err(|e| (ErrorCode::InternalError, e.to_string()))
+    }
+
+    // Notifications can't be answered, so those with invalid params are
+    // dropped
+    fn handle_notification(&mut self, not: Notification) -> anyhow::Result<()> {
+        match not.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                if let Ok(params) = serde_json::from_value::<DidOpenTextDocumentParams>(not.params)
+                {
+                    let doc = params.text_document;
+                    self.update(doc.uri, doc.text)?;
+                }
+            }
+            DidChangeTextDocument::METHOD => {
+                if let Ok(params) =
+                    serde_json::from_value::<DidChangeTextDocumentParams>(not.params)
+                {
+                    // Only full document sync is advertised, so the last
+                    // change holds the entire text.
+                    if let Some(change) = params.content_changes.into_iter().last() {
+                        self.update(params.text_document.uri, change.text)?;
+                    }
+                }
+            }
+            DidCloseTextDocument::METHOD => {
+                if let Ok(params) = serde_json::from_value::<DidCloseTextDocumentParams>(not.params)
+                {
+                    let uri = params.text_document.uri;
+                    self.documents.remove(&uri);
+                    self.publish_diagnostics(uri, vec![])?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn update(&mut self, uri: Url, text: String) -> anyhow::Result<()> {
+        // Built directly rather than with Source::new, which would drop a
+        // byte order mark and shift every position on the first line.
+        // Includes are read relative to the document's path.
+        let name = match uri.to_file_path() {
+            Ok(path) => path.display().to_string(),
+            Err(()) => uri.to_string(),
+        };
+        let mut doc = analyze(Source { name, text }, &self.config);
+        if !doc.checked {
+            doc.previous = self
+                .documents
+                .remove(&uri)
+                .and_then(|old| match old.checked {
+                    true => Some(Box::new(old)),
+                    false => old.previous,
+                });
+        }
+        let map = doc.map();
+        let mut diagnostics: Vec<_> = doc
+            .error
+            .iter()
+            .map(|e| diagnostic(&doc, &uri, e))
+            .collect();
+        let warnings = doc.state.warnings().iter();
+        let warnings = warnings.filter(|w| w.span.file == FileId::default());
+        diagnostics.extend(warnings.map(|w| Diagnostic {
+            range: range(map, w.span),
+            severity: Some(DiagnosticSeverity::WARNING),
+            source: Some("zx".to_owned()),
+            message: w.to_string(),
+            ..Default::default()
+        }));
+
+        self.documents.insert(uri.clone(), doc);
+        self.publish_diagnostics(uri, diagnostics)
+    }
+
+    fn hover(&self, uri: &Url, pos: Position) -> Option<Hover> {
+        let doc = self.documents.get(uri)?;
+        let offset = offset(doc.map(), pos);
+        let found = doc.state.type_at(FileId::default(), offset)?;
+        let value = match doc.symbols.iter().find(|s| s.name_span == found.span) {
+            Some(sym) => {
+                let mut value = format!("```\n{}: {}\n```", sym.name, found.ty);
+                if let Some(docs) = doc.state.docs(&sym.name) {
+                    value.push_str("\n\n");
+                    value.push_str(&docs);
+                }
+                value
+            }
+            None => format!("```\n{}\n```", found.ty),
+        };
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value,
+            }),
+            range: Some(range(doc.map(), found.span)),
+        })
+    }
+
+    fn definition(&self, uri: Url, pos: Position) -> Option<GotoDefinitionResponse> {
+        let doc = self.documents.get(&uri)?;
+        let def = doc
+            .state
+            .definition_at(FileId::default(), offset(doc.map(), pos))?;
+        Some(GotoDefinitionResponse::Scalar(doc.location(&uri, def)?))
+    }
+
+    fn references(
+        &self,
+        uri: Url,
+        pos: Position,
+        include_declaration: bool,
+    ) -> Option<Vec<Location>> {
+        let doc = self.documents.get(&uri)?;
+        let offset = offset(doc.map(), pos);
+        let refs = doc
+            .state
+            .references(&doc.items, FileId::default(), offset)?;
+        let mut spans = refs.uses;
+        if let (Referent::Variable(def), true) = (refs.referent, include_declaration) {
+            spans.push(def);
+            spans.sort_by_key(|span| (span.file, span.start));
+        }
+        let locations = spans
+            .into_iter()
+            .filter_map(|span| doc.location(&uri, span))
+            .collect();
+        Some(locations)
+    }
+
+    fn document_symbols(&self, uri: &Url) -> Option<DocumentSymbolResponse> {
+        let doc = self.documents.get(uri)?;
+        let mut outline = match doc.checked {
+            true => doc.state.outline(&doc.items),
+            false => crate::outline(&doc.items),
+        };
+        outline.retain(|s| s.span.file == FileId::default());
+        Some(DocumentSymbolResponse::Nested(document_symbols(
+            doc.map(),
+            outline,
+        )))
+    }
+
+    fn semantic_tokens(&self, uri: &Url) -> Option<SemanticTokensResult> {
+        let doc = self.documents.get(uri)?;
+        Some(SemanticTokensResult::Tokens(SemanticTokens {
+            result_id: None,
+            data: encode_tokens(doc.map(), &doc.tokens),
+        }))
+    }
+
+    fn completions(&self, uri: &Url, pos: Position) -> Option<CompletionResponse> {
+        let doc = self.documents.get(uri)?;
+        let (checked, items) = doc.checked()?;
+        let completions =
+            checked
+                .state
+                .completions_at(items, doc.map().text(), offset(doc.map(), pos));
+        let items = completions
+            .into_iter()
+            .map(|c| CompletionItem {
+                kind: Some(match c.kind {
+                    SemanticKind::Field => CompletionItemKind::FIELD,
+                    _ => CompletionItemKind::VARIABLE,
+                }),
+                label: c.label,
+                detail: Some(c.ty),
+                ..Default::default()
+            })
+            .collect();
+        Some(CompletionResponse::Array(items))
+    }
+
+    // Only a document that checked can be renamed in, as the rename has to
+    // know every use
+    fn rename(&self, uri: Url, pos: Position, new_name: &str) -> Option<Result<WorkspaceEdit>> {
+        let doc = self.documents.get(&uri)?;
+        if !doc.checked {
+            return None;
+        }
+        let edits = match doc.state.rename(
+            &doc.items,
+            FileId::default(),
+            offset(doc.map(), pos),
+            new_name,
+        ) {
+            Ok(edits) => edits,
+            Err(e) => return Some(Err(e)),
+        };
+        let edits = edits
+            .into_iter()
+            .map(|e| TextEdit::new(range(doc.map(), e.span), e.new_text))
+            .collect();
+        Some(Ok(WorkspaceEdit::new(HashMap::from([(uri, edits)]))))
+    }
+
+    // The quick fixes for the document's error, if it's in the range
+    fn code_actions(&self, params: CodeActionParams) -> Option<Vec<CodeActionOrCommand>> {
+        let uri = params.text_document.uri;
+        let doc = self.documents.get(&uri)?;
+        let error = doc.error.as_ref()?;
+        if error.span?.file != FileId::default() {
+            return None;
+        }
+        let diagnostic = diagnostic(doc, &uri, error);
+        let at = diagnostic.range;
+        if at.end < params.range.start || params.range.end < at.start {
+            return Some(vec![]);
+        }
+        let actions = crate::quick_fixes(&doc.items, doc.map().text(), error)
+            .into_iter()
+            .map(|fix| {
+                let edits = fix
+                    .edits
+                    .into_iter()
+                    .map(|e| TextEdit::new(range(doc.map(), e.span), e.new_text))
+                    .collect();
+                CodeActionOrCommand::CodeAction(CodeAction {
+                    title: fix.title,
+                    kind: Some(CodeActionKind::QUICKFIX),
+                    diagnostics: Some(vec![diagnostic.clone()]),
+                    edit: Some(WorkspaceEdit::new(HashMap::from([(uri.clone(), edits)]))),
+                    is_preferred: Some(true),
+                    ..Default::default()
+                })
+            })
+            .collect();
+        Some(actions)
+    }
+
+    fn signature_help(&self, uri: &Url, pos: Position) -> Option<SignatureHelp> {
+        let doc = self.documents.get(uri)?;
+        let (checked, items) = doc.checked()?;
+        let help = checked
+            .state
+            .signature_at(items, doc.map().text(), offset(doc.map(), pos))?;
+        let name = &checked.map().text()[help.span.start..help.span.end];
+        let mut label = name.to_owned();
+        let mut parameters = vec![];
+        for param in &help.params {
+            label.push(' ');
+            let start = label.encode_utf16().count() as u32;
+            label.push_str(param);
+            let end = label.encode_utf16().count() as u32;
+            parameters.push(ParameterInformation {
+                label: ParameterLabel::LabelOffsets([start, end]),
+                documentation: None,
+            });
+        }
+        label.push_str(" : ");
+        label.push_str(&help.ret);
+        Some(SignatureHelp {
+            signatures: vec![SignatureInformation {
+                label,
+                documentation: None,
+                parameters: Some(parameters),
+                active_parameter: None,
+            }],
+            active_signature: Some(0),
+            active_parameter: Some(help.active as u32),
+        })
+    }
+
+    fn publish_diagnostics(&self, uri: Url, diagnostics: Vec<Diagnostic>) -> anyhow::Result<()> {
+        let params = PublishDiagnosticsParams {
+            uri,
+            diagnostics,
+            version: None,
+        };
+        let not = Notification::new(PublishDiagnostics::METHOD.to_owned(), params);
+        self.send(not.into())
+    }
+
+    fn respond(&self, id: RequestId, result: serde_json::Value) -> anyhow::Result<()> {
+        self.send(Response::new_ok(id, result).into())
+    }
+
+    fn send(&self, msg: Message) -> anyhow::Result<()> {
+        self.connection.sender.send(msg)?;
+        Ok(())
+    }
+}
+
+fn invalid_params(e: serde_json::Error) -> (ErrorCode, String) {
+    (ErrorCode::InvalidParams, e.to_string())
+}
+
+// Parses and checks a document from scratch, along with the files it
+// includes. It has no previous version yet.
+fn analyze(source: Source, config: &CheckConfig) -> Document {
+    let mut files = SourceMap::new();
+    files.add(source);
+    let mut doc = Document {
+        files,
+        symbols: vec![],
+        state: TypeckState::with_config(config.clone()),
+        tokens: vec![],
+        items: vec![],
+        checked: false,
+        error: None,
+        previous: None,
+    };
+    doc.items = match crate::parse_with_includes(&mut doc.files, FileId::default()) {
+        Ok(items) => items,
+        Err(e) => {
+            doc.error = Some(e);
+            return doc;
+        }
+    };
+
+    let checked = crate::expand_macros(doc.items.clone())
+        .and_then(|expanded| doc.state.check_script(&expanded));
+    let in_document = |span: &Span| span.file == FileId::default();
+    match checked {
+        Ok(()) => {
+            doc.symbols = doc.state.symbols(&doc.items);
+            doc.tokens = doc.state.semantic_tokens(&doc.items);
+            doc.tokens.retain(|t| in_document(&t.span));
+            doc.checked = true;
+        }
+        Err(e) => {
+            doc.symbols = crate::symbols(&doc.items);
+            doc.error = Some(e);
+        }
+    }
+    doc.symbols.retain(|s| in_document(&s.span));
+    doc
+}
+
+// An error in an included file is shown at the document's first include,
+// with the place it's at as related information
+fn diagnostic(doc: &Document, uri: &Url, e: &Error) -> Diagnostic {
+    let span = e.span.unwrap_or_default();
+    let mut diagnostic = Diagnostic {
+        range: range(doc.map(), span),
+        severity: Some(DiagnosticSeverity::ERROR),
+        source: Some("zx".to_owned()),
+        message: e.to_string(),
+        ..Default::default()
+    };
+    if span.file != FileId::default() {
+        let include = crate::parse(doc.map().text())
+            .ok()
+            .and_then(|items| {
+                items.into_iter().find_map(|item| match item {
+                    TopLevel::Include((_, span)) => Some(span),
+                    _ => None,
+                })
+            })
+            .unwrap_or_default();
+        diagnostic.range = range(doc.map(), include);
+        diagnostic.related_information = doc.location(uri, span).map(|location| {
+            vec![DiagnosticRelatedInformation {
+                location,
+                message: e.to_string(),
+            }]
+        });
+    }
+    diagnostic
+}
+
+#[allow(deprecated)]
+fn document_symbols(map: &SourceFile, symbols: Vec<Symbol>) -> Vec<DocumentSymbol> {
+    symbols
+        .into_iter()
+        .map(|s| DocumentSymbol {
+            name: s.name,
+            detail: s.ty,
+            kind: match s.kind {
+                Kind::Function => SymbolKind::FUNCTION,
+                Kind::Value => SymbolKind::VARIABLE,
+                Kind::Group => SymbolKind::NAMESPACE,
+                Kind::Module => SymbolKind::MODULE,
+            },
+            tags: None,
+            deprecated: None,
+            range: range(map, s.span),
+            selection_range: range(map, s.name_span),
+            children: match s.children.is_empty() {
+                true => None,
+                false => Some(document_symbols(map, s.children)),
+            },
+        })
+        .collect()
+}
+
+// Each token's position is relative to the one before, as LSP wants
+fn encode_tokens(map: &SourceFile, tokens: &[Token]) -> Vec<SemanticToken> {
+    let mut last = (0, 0);
+    tokens
+        .iter()
+        .map(|t| {
+            let (token_type, modifiers) = match t.kind {
+                SemanticKind::Parameter => (0, 0),
+                SemanticKind::Local => (1, 0),
+                SemanticKind::Global => (1, 1),
+                SemanticKind::Builtin => (1, 2),
+                SemanticKind::Field => (2, 0),
+                SemanticKind::Tag => (3, 0),
+            };
+            let (line, start) = map.utf16_position(t.span.start);
+            let (_, end) = map.utf16_position(t.span.end);
+            let delta_start = if line == last.0 {
+                start - last.1
+            } else {
+                start
+            };
+            let token = SemanticToken {
+                delta_line: (line - last.0) as u32,
+                delta_start: delta_start as u32,
+                length: (end - start) as u32,
+                token_type,
+                token_modifiers_bitset: modifiers,
+            };
+            last = (line, start);
+            token
+        })
+        .collect()
+}
+
+// LSP positions count UTF-16 code units within a line.
+fn position(map: &SourceFile, offset: usize) -> Position {
+    let (line, character) = map.utf16_position(offset);
+    Position::new(line as u32, character as u32)
+}
+
+fn offset(map: &SourceFile, pos: Position) -> usize {
+    map.utf16_offset(pos.line as usize, pos.character as usize)
+}
+
+fn range(map: &SourceFile, span: Span) -> Range {
+    Range::new(position(map, span.start), position(map, span.end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Server;
+    use crate::CheckConfig;
+    use lsp_server::{Connection, ErrorCode, Message, Request, RequestId};
+    use lsp_types::{
+        notification::{Notification as NotificationTrait, PublishDiagnostics},
+        request::{HoverRequest, Request as RequestTrait},
+        DiagnosticSeverity, PublishDiagnosticsParams, Url,
+    };
+    use std::{collections::HashMap, fs};
+
+    // Runs `f` with a server whose messages are received by the client
+    fn with_server(f: impl FnOnce(&mut Server, &Connection)) {
+        let (connection, client) = Connection::memory();
+        let mut server = Server {
+            connection: &connection,
+            documents: HashMap::new(),
+            config: CheckConfig::default(),
+        };
+        f(&mut server, &client);
+    }
+
+    #[test]
+    fn answers_invalid_params_with_an_error() {
+        with_server(|server, client| {
+            let params = serde_json::json!({"position": "nowhere"});
+            let req = Request::new(RequestId::from(1), HoverRequest::METHOD.to_owned(), params);
+            server.handle_request(req).unwrap();
+            match client.receiver.try_recv().unwrap() {
+                Message::Response(resp) => {
+                    assert_eq!(resp.id, RequestId::from(1));
+                    assert_eq!(resp.error.unwrap().code, ErrorCode::InvalidParams as i32);
+                }
+                msg => panic!("{:?}", msg),
+            }
+        });
+    }
+
+    #[test]
+    fn reports_errors_in_includes_at_the_include() {
+        let dir = std::env::temp_dir().join(format!("zx-lsp-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("lib.zx"), "let ok = true;\nlet bad = true.x").unwrap();
+        let main = dir.join("main.zx");
+        let uri = Url::from_file_path(&main).unwrap();
+        let text = "let a = true;\ninclude \"lib.zx\";\nlet b = a";
+
+        with_server(|server, client| {
+            server.update(uri.clone(), text.to_owned()).unwrap();
+            let params: PublishDiagnosticsParams = match client.receiver.try_recv().unwrap() {
+                Message::Notification(not) => {
+                    assert_eq!(not.method, PublishDiagnostics::METHOD);
+                    serde_json::from_value(not.params).unwrap()
+                }
+                msg => panic!("{:?}", msg),
+            };
+            assert_eq!(params.uri, uri);
+            assert_eq!(params.diagnostics.len(), 1, "{:?}", params.diagnostics);
+            let diagnostic = &params.diagnostics[0];
+            assert_eq!(diagnostic.severity, Some(DiagnosticSeverity::ERROR));
+            assert_eq!(diagnostic.range.start.line, 1);
+            let related = &diagnostic.related_information.as_ref().unwrap()[0];
+            assert_eq!(
+                related.location.uri,
+                Url::from_file_path(dir.join("lib.zx")).unwrap()
+            );
+            assert_eq!(related.location.range.start.line, 1);
+        });
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
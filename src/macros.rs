@@ -0,0 +1,303 @@
+use crate::{
+    ast::{Expr, MacroPattern, Spanned, TopLevel},
+    error::{Error, ErrorKind, MacroError, Result},
+    span::Span,
+};
+use std::collections::HashMap;
+
+// Macros are rewritten away before checking. Each `macro` item adds a rule,
+// and a call `name! arg...` is replaced by the body of the first rule, in
+// definition order, whose patterns match the arguments. Calls may only use
+// macros defined by earlier items, so bodies are expanded as soon as their
+// rule is defined and expansions never need expanding again.
+//
+// Expansion is hygienic for the bindings a body introduces: they are renamed
+// to names that can't be written in source, so they never capture variables
+// in the arguments or clash with the caller's. Free variables in a body refer
+// to whatever is in scope at the call.
+
+type Rule = (Vec<Spanned<MacroPattern>>, Spanned<Expr>);
+
+struct Expander {
+    macros: HashMap<String, Vec<Rule>>,
+    // Counter for fresh names
+    next: usize,
+}
+
+// Replaces every macro call with its expansion and removes the macro items.
+pub fn expand_macros(items: Vec<TopLevel>) -> Result<Vec<TopLevel>> {
+    let mut ex = Expander {
+        macros: HashMap::new(),
+        next: 0,
+    };
+    let mut out = Vec::with_capacity(items.len());
+    for item in items {
+        out.extend(ex.item(item)?);
+    }
+    Ok(out)
+}
+
+fn macro_error(e: MacroError, span: Span) -> Error {
+    Error::new(ErrorKind::Macro(e), span)
+}
+
+impl Expander {
+    // The expanded item, or None for macro items
+    fn item(&mut self, item: TopLevel) -> Result<Option<TopLevel>> {
+        Ok(Some(match item {
+            TopLevel::Macro((name, _), patterns, body) => {
+                let body = self.expand(*body)?;
+                self.macros.entry(name).or_default().push((patterns, body));
+                return Ok(None);
+            }
+            TopLevel::Expr(expr) => TopLevel::Expr(self.expand(expr)?),
+            TopLevel::LetDef((name, expr)) => TopLevel::LetDef((name, self.expand_box(*expr)?)),
+            TopLevel::Const((name, expr)) => TopLevel::Const((name, self.expand_box(*expr)?)),
+            TopLevel::Test(name, expr) => TopLevel::Test(name, self.expand_box(*expr)?),
+            TopLevel::LetRecDef(defs) => TopLevel::LetRecDef(
+                defs.into_iter()
+                    .map(|(name, expr)| Ok((name, self.expand_box(*expr)?)))
+                    .collect::<Result<_>>()?,
+            ),
+            TopLevel::Attributed(attrs, item) => match self.item(*item)? {
+                Some(item) => TopLevel::Attributed(attrs, Box::new(item)),
+                None => return Ok(None),
+            },
+            item @ (TopLevel::Include(_) | TopLevel::Fixity(..)) => item,
+        }))
+    }
+
+    fn expand_box(&mut self, expr: Spanned<Expr>) -> Result<Box<Spanned<Expr>>> {
+        self.expand(expr).map(Box::new)
+    }
+
+    fn expand(&mut self, (expr, span): Spanned<Expr>) -> Result<Spanned<Expr>> {
+        use Expr::*;
+        let expr = match expr {
+            BinOp(lhs, op, rhs) => BinOp(self.expand_box(*lhs)?, op, self.expand_box(*rhs)?),
+            Call(func, arg) => Call(self.expand_box(*func)?, self.expand_box(*arg)?),
+            Compare(first, rest) => Compare(
+                self.expand_box(*first)?,
+                rest.into_iter()
+                    .map(|(op, e)| Ok((op, self.expand_box(*e)?)))
+                    .collect::<Result<_>>()?,
+            ),
+            Case(tag, val) => Case(tag, self.expand_box(*val)?),
+            FieldAccess(lhs, name) => FieldAccess(self.expand_box(*lhs)?, name),
+            FuncDef(arg, body) => FuncDef(arg, self.expand_box(*body)?),
+            If(cond, then_expr, else_expr, syntax) => If(
+                self.expand_box(*cond)?,
+                self.expand_box(*then_expr)?,
+                self.expand_box(*else_expr)?,
+                syntax,
+            ),
+            Let((name, val), rest, syntax) => Let(
+                (name, self.expand_box(*val)?),
+                self.expand_box(*rest)?,
+                syntax,
+            ),
+            LetRec(defs, rest) => {
+                let defs = defs
+                    .into_iter()
+                    .map(|(name, expr)| Ok((name, self.expand_box(*expr)?)))
+                    .collect::<Result<_>>()?;
+                LetRec(defs, self.expand_box(*rest)?)
+            }
+            Match(val, cases) => {
+                let val = self.expand_box(*val)?;
+                let cases = cases
+                    .into_iter()
+                    .map(|(pattern, rhs)| Ok((pattern, self.expand_box(*rhs)?)))
+                    .collect::<Result<_>>()?;
+                Match(val, cases)
+            }
+            Dict(entries) => Dict(
+                entries
+                    .into_iter()
+                    .map(|(key, expr)| Ok((key, self.expand_box(*expr)?)))
+                    .collect::<Result<_>>()?,
+            ),
+            Set(items) => Set(items
+                .into_iter()
+                .map(|expr| self.expand_box(*expr))
+                .collect::<Result<_>>()?),
+            Record(fields) => Record(
+                fields
+                    .into_iter()
+                    .map(|(name, expr)| Ok((name, self.expand_box(*expr)?)))
+                    .collect::<Result<_>>()?,
+            ),
+            expr @ (Literal(_) | Variable(_)) => expr,
+            MacroCall((name, name_span), args) => {
+                let args = args
+                    .into_iter()
+                    .map(|arg| self.expand(*arg))
+                    .collect::<Result<Vec<_>>>()?;
+                return self.instantiate(&name, name_span, args);
+            }
+        };
+        Ok((expr, span))
+    }
+
+    // The body of the first matching rule, with the arguments substituted for
+    // the pattern variables.
+    fn instantiate(
+        &mut self,
+        name: &str,
+        span: Span,
+        args: Vec<Spanned<Expr>>,
+    ) -> Result<Spanned<Expr>> {
+        let rules = match self.macros.get(name) {
+            Some(rules) => rules,
+            None => return Err(macro_error(MacroError::Undefined(name.to_owned()), span)),
+        };
+        let matches = |patterns: &[Spanned<MacroPattern>]| {
+            patterns.len() == args.len()
+                && patterns
+                    .iter()
+                    .zip(&args)
+                    .all(|((pattern, _), (arg, _))| match (pattern, arg) {
+                        (MacroPattern::Var(_), _) => true,
+                        (MacroPattern::Literal(lit), Expr::Literal(arg)) => lit == arg,
+                        (MacroPattern::Literal(_), _) => false,
+                    })
+        };
+        let (patterns, body) = match rules.iter().find(|(patterns, _)| matches(patterns)) {
+            Some(rule) => rule.clone(),
+            None => {
+                let e = MacroError::NoRule(name.to_owned(), args.len());
+                return Err(macro_error(e, span));
+            }
+        };
+
+        let mut vars = HashMap::new();
+        for ((pattern, _), arg) in patterns.into_iter().zip(args) {
+            if let MacroPattern::Var(var) = pattern {
+                vars.insert(var, arg);
+            }
+        }
+        let mut subst = Subst {
+            vars,
+            renames: vec![],
+            next: &mut self.next,
+        };
+        Ok(subst.expr(body))
+    }
+}
+
+struct Subst<'a> {
+    vars: HashMap<String, Spanned<Expr>>,
+    // Binders introduced by the body in scope, with their new names
+    renames: Vec<(String, String)>,
+    next: &'a mut usize,
+}
+
+impl Subst<'_> {
+    // Starts the scope of a binder, returning its new name. `#` can't appear
+    // in identifiers, so the name can't clash with anything in the source.
+    fn bind(&mut self, name: &str) -> String {
+        *self.next += 1;
+        let fresh = format!("{}#{}", name, self.next);
+        self.renames.push((name.to_owned(), fresh.clone()));
+        fresh
+    }
+
+    // The name a variable the substitution doesn't replace is referred to by
+    fn renamed(&self, name: String) -> String {
+        match self.renames.iter().rev().find(|(old, _)| *old == name) {
+            Some((_, fresh)) => fresh.clone(),
+            None => name,
+        }
+    }
+
+    fn boxed(&mut self, expr: Spanned<Expr>) -> Box<Spanned<Expr>> {
+        Box::new(self.expr(expr))
+    }
+
+    fn expr(&mut self, (expr, span): Spanned<Expr>) -> Spanned<Expr> {
+        use Expr::*;
+        let n = self.renames.len();
+        let expr = match expr {
+            Variable(name) => {
+                if let Some((_, fresh)) = self.renames.iter().rev().find(|(old, _)| *old == name) {
+                    Variable(fresh.clone())
+                } else if let Some(arg) = self.vars.get(&name) {
+                    return arg.clone();
+                } else {
+                    Variable(name)
+                }
+            }
+            BinOp(lhs, (op, op_span), rhs) => BinOp(
+                self.boxed(*lhs),
+                (self.renamed(op), op_span),
+                self.boxed(*rhs),
+            ),
+            Call(func, arg) => Call(self.boxed(*func), self.boxed(*arg)),
+            Compare(first, rest) => Compare(
+                self.boxed(*first),
+                rest.into_iter()
+                    .map(|((op, op_span), e)| ((self.renamed(op), op_span), self.boxed(*e)))
+                    .collect(),
+            ),
+            Case(tag, val) => Case(tag, self.boxed(*val)),
+            FieldAccess(lhs, name) => FieldAccess(self.boxed(*lhs), name),
+            FuncDef((arg, arg_span), body) => {
+                let arg = self.bind(&arg);
+                FuncDef((arg, arg_span), self.boxed(*body))
+            }
+            If(cond, then_expr, else_expr, syntax) => If(
+                self.boxed(*cond),
+                self.boxed(*then_expr),
+                self.boxed(*else_expr),
+                syntax,
+            ),
+            Let(((name, name_span), val), rest, syntax) => {
+                let val = self.boxed(*val);
+                let name = self.bind(&name);
+                Let(((name, name_span), val), self.boxed(*rest), syntax)
+            }
+            LetRec(defs, rest) => {
+                let names: Vec<_> = defs
+                    .iter()
+                    .map(|((name, name_span), _)| (self.bind(name), *name_span))
+                    .collect();
+                let defs = names
+                    .into_iter()
+                    .zip(defs)
+                    .map(|(name, (_, expr))| (name, self.boxed(*expr)))
+                    .collect();
+                LetRec(defs, self.boxed(*rest))
+            }
+            Match(val, cases) => {
+                let val = self.boxed(*val);
+                let cases = cases
+                    .into_iter()
+                    .map(|((tag, (name, name_span)), rhs)| {
+                        let name = self.bind(&name);
+                        let rhs = self.boxed(*rhs);
+                        self.renames.pop();
+                        ((tag, (name, name_span)), rhs)
+                    })
+                    .collect();
+                Match(val, cases)
+            }
+            Dict(entries) => Dict(
+                entries
+                    .into_iter()
+                    .map(|(key, expr)| (key, self.boxed(*expr)))
+                    .collect(),
+            ),
+            Set(items) => Set(items.into_iter().map(|expr| self.boxed(*expr)).collect()),
+            Record(fields) => Record(
+                fields
+                    .into_iter()
+                    .map(|(name, expr)| (name, self.boxed(*expr)))
+                    .collect(),
+            ),
+            // Bodies were already expanded, so there are no calls left
+            expr @ (Literal(_) | MacroCall(..)) => expr,
+        };
+        self.renames.truncate(n);
+        (expr, span)
+    }
+}
@@ -1,3 +1,293 @@
+use std::{process, thread, time::Duration};
+
+const USAGE: &str = "usage: zx <check|run|test|fmt|dot> [FILE]
+       zx check --watch FILE
+       zx test-examples [--bless] DIR
+       zx rename FILE LINE:COL NEW_NAME
+
+Reads from stdin if FILE is missing or `-`. With --watch, FILE is checked
+again whenever it changes. test-examples compares the results of the .zx
+files in DIR against their .expected files, and --bless updates them.
+check colors its errors when stderr is a terminal, unless NO_COLOR is set.
+With ZX_WIDEN=N, check trades precision for time on very large scripts by
+merging the types flowing into any one place beyond the first N. With
+ZX_STRICT set, warnings are errors and every top level definition needs a
+@type annotation.
+run stops scripts that recurse too deep with an error. fmt checks FILE as
+check does and prints it formatted, keeping its includes.
+test runs the test blocks of FILE and fails if any of them fail.
+rename prints FILE with the variable at LINE:COL and its uses renamed.";
+
 fn main() {
-    zx::run(r"fun x -> if x then true else { a = true }");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match &args[..] {
+        [command, dir] if command == "test-examples" => test_examples(dir, false),
+        [command, flag, dir] if command == "test-examples" && flag == "--bless" => {
+            test_examples(dir, true)
+        }
+        [command, path, pos, new_name] if command == "rename" => {
+            let pos = pos
+                .split_once(':')
+                .and_then(|(line, col)| Some((line.parse().ok()?, col.parse().ok()?)));
+            match pos {
+                Some(pos) => run_command(path, true, |map, file| rename(map, file, pos, new_name)),
+                None => usage(),
+            }
+            return;
+        }
+        _ => {}
+    }
+    if let [command, flag, path] = &args[..] {
+        if command == "check" && flag == "--watch" && path != "-" {
+            watch(path);
+        }
+        usage();
+    }
+
+    let (command, path) = match &args[..] {
+        [command] => (command.as_str(), "-"),
+        [command, path] => (command.as_str(), path.as_str()),
+        _ => usage(),
+    };
+    let is_check = command == "check" || command == "fmt";
+    let command = match command {
+        "check" => check,
+        "run" => run,
+        "test" => test,
+        "fmt" => fmt,
+        "dot" => dot,
+        _ => usage(),
+    };
+    run_command(path, is_check, command)
+}
+
+// Runs a command on the file at `path`. Errors of commands that only check
+// are shown with the source they point at, and others with their trace.
+fn run_command(
+    path: &str,
+    is_check: bool,
+    command: impl FnOnce(&mut zx::SourceMap, zx::FileId) -> zx::Result<()>,
+) {
+    let source = if path == "-" {
+        zx::Source::read_stdin()
+    } else {
+        zx::Source::read_file(path)
+    };
+    let source = match source {
+        Ok(source) => source,
+        Err(d) => {
+            eprintln!("{}", d);
+            let is_io = matches!(d.error.kind, zx::ErrorKind::Io(_));
+            process::exit(if is_io { 2 } else { 1 });
+        }
+    };
+
+    let mut map = zx::SourceMap::new();
+    let file = map.add(source);
+    if let Err(e) = command(&mut map, file) {
+        let d = map.diagnostic(e);
+        if is_check {
+            eprint!("{}", map.render(&d, zx::stderr_supports_color()));
+        } else {
+            eprintln!("{}", d);
+            eprint!("{}", map.render_trace(&d.error));
+        }
+        process::exit(1);
+    }
+}
+
+// Polls the file for changes and re-checks it as check does, starting from
+// the first top level item that differs from the last version checked.
+fn watch(path: &str) -> ! {
+    let mut checker = zx::IncrementalCheck::with_config(config());
+    let mut last = None;
+    loop {
+        let modified = Some(std::fs::metadata(path).and_then(|m| m.modified()).ok());
+        if modified != last {
+            last = modified;
+            match zx::Source::read_file(path) {
+                Ok(source) => {
+                    let mut map = zx::SourceMap::new();
+                    let file = map.add(source);
+                    let mut n = 0;
+                    let res = check_with(&mut map, file, |parsed| {
+                        n = checker.check(parsed)?;
+                        Ok(checker.warnings())
+                    });
+                    match res {
+                        Ok(()) => println!("{}: ok ({} items checked)", path, n),
+                        Err(e) => println!("{}", map.diagnostic(e)),
+                    }
+                }
+                Err(d) => println!("{}", d),
+            }
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+}
+
+fn test_examples(dir: &str, bless: bool) -> ! {
+    let results = match zx::golden::run_dir(dir, bless) {
+        Ok(results) => results,
+        Err(e) => {
+            eprintln!("{}: error: {}", dir, e);
+            process::exit(2);
+        }
+    };
+    let mut failed = 0;
+    for result in &results {
+        let path = result.path.display();
+        match &result.outcome {
+            zx::golden::Outcome::Passed => {}
+            zx::golden::Outcome::Blessed => println!("blessed {}", path),
+            zx::golden::Outcome::Failed { expected, actual } => {
+                failed += 1;
+                println!(
+                    "FAILED {}\n--- expected\n{}--- actual\n{}",
+                    path, expected, actual
+                );
+            }
+        }
+    }
+    println!("{} passed, {} failed", results.len() - failed, failed);
+    process::exit(if failed > 0 { 1 } else { 0 });
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+// The config of ZX_WIDEN and ZX_STRICT
+fn config() -> zx::CheckConfig {
+    zx::CheckConfig::from_env().unwrap_or_else(|msg| {
+        eprintln!("{}", msg);
+        process::exit(2);
+    })
+}
+
+fn check(map: &mut zx::SourceMap, file: zx::FileId) -> zx::Result<()> {
+    let mut state = zx::TypeckState::with_config(config());
+    check_with(map, file, |parsed| {
+        state.check_script(parsed)?;
+        Ok(state.warnings().to_vec())
+    })
+}
+
+// What check does, with `typeck` checking the expanded script and
+// returning its warnings
+fn check_with(
+    map: &mut zx::SourceMap,
+    file: zx::FileId,
+    typeck: impl FnOnce(&[zx::ast::TopLevel]) -> zx::Result<Vec<zx::Warning>>,
+) -> zx::Result<()> {
+    let parsed = zx::parse_script(map, file)?;
+    let color = zx::stderr_supports_color();
+    for warning in typeck(&parsed)? {
+        eprint!("{}", map.render_warning(&warning, color));
+    }
+    zx::eval::eval_consts(parsed)?;
+    Ok(())
+}
+
+// run evaluates on a thread with this much stack, and stops scripts that
+// recurse deeper than RUN_DEPTH with an error before they overflow it. The
+// frames of unoptimized builds are about ten times as large.
+const RUN_STACK: usize = 1 << 30;
+const RUN_DEPTH: u64 = if cfg!(debug_assertions) {
+    30_000
+} else {
+    300_000
+};
+
+fn run(map: &mut zx::SourceMap, file: zx::FileId) -> zx::Result<()> {
+    let parsed = zx::parse_script(map, file)?;
+    zx::TypeckState::new().check_script(&parsed)?;
+    let parsed = zx::fold_constants(zx::eval::eval_consts(parsed)?);
+    let budget = zx::eval::Budget {
+        depth: Some(RUN_DEPTH),
+        ..zx::eval::Budget::default()
+    };
+    let eval = || {
+        let layout = zx::Layout::default();
+        let values = zx::eval::eval_script_with_budget(&parsed, Default::default(), budget)?;
+        for v in values {
+            println!("{}", v.pretty(&layout));
+        }
+        Ok(())
+    };
+    thread::scope(|s| {
+        let handle = thread::Builder::new()
+            .stack_size(RUN_STACK)
+            .spawn_scoped(s, eval)
+            .expect("can't start the evaluation thread");
+        handle
+            .join()
+            .unwrap_or_else(|e| std::panic::resume_unwind(e))
+    })
+}
+
+fn test(map: &mut zx::SourceMap, file: zx::FileId) -> zx::Result<()> {
+    let parsed = zx::parse_script(map, file)?;
+    zx::TypeckState::new().check_script(&parsed)?;
+    let parsed = zx::eval::eval_consts(parsed)?;
+    let results = zx::eval::run_tests(&parsed)?;
+    let mut failed = 0;
+    for result in &results {
+        match &result.outcome {
+            zx::eval::TestOutcome::Passed => println!("test {} ... ok", result.name),
+            zx::eval::TestOutcome::Failed => {
+                failed += 1;
+                println!("test {} ... FAILED", result.name);
+            }
+            zx::eval::TestOutcome::Error(e) => {
+                failed += 1;
+                println!("test {} ... FAILED", result.name);
+                println!("{}", map.diagnostic(e.clone()));
+                print!("{}", map.render_trace(e));
+            }
+        }
+    }
+    println!("{} passed, {} failed", results.len() - failed, failed);
+    if failed > 0 {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+// Includes are formatted as they are, without expanding them
+// The script is checked as check does, but only the file itself is printed
+fn fmt(map: &mut zx::SourceMap, file: zx::FileId) -> zx::Result<()> {
+    check(map, file)?;
+    let parsed = zx::parse_keeping_includes(map, file)?;
+    print!(
+        "{}",
+        zx::format_source(&parsed, map.get(file).unwrap().text())
+    );
+    Ok(())
+}
+
+// The script is checked with its includes, but only the file itself is
+// rewritten
+fn rename(
+    map: &mut zx::SourceMap,
+    file: zx::FileId,
+    (line, col): (usize, usize),
+    new_name: &str,
+) -> zx::Result<()> {
+    let parsed = zx::parse_with_includes(map, file)?;
+    let mut state = zx::TypeckState::new();
+    state.check_script(&zx::expand_macros(parsed.clone())?)?;
+    let source = map.get(file).unwrap();
+    let edits = state.rename(&parsed, file, source.offset(line, col), new_name)?;
+    print!("{}", zx::apply_edits(source.text(), &edits));
+    Ok(())
+}
+
+fn dot(map: &mut zx::SourceMap, file: zx::FileId) -> zx::Result<()> {
+    let parsed = zx::parse_script(map, file)?;
+    let mut state = zx::TypeckState::new();
+    state.check_script(&parsed)?;
+    print!("{}", state.core().to_dot());
+    Ok(())
 }
@@ -0,0 +1,519 @@
+use crate::error::{
+    ConstError, Error, ErrorKind, FormatError, IncludeError, IoError, MacroError, RenameError,
+    RuntimeError, SignatureError, SyntaxError, WarningKind,
+};
+use crate::source::Diagnostic;
+use std::collections::HashMap;
+
+// Templates for every error and warning message, keyed by code. `{name}` in
+// a template is replaced by the argument of that name. The message of a
+// denied lint is that of its warning, from the same catalog.
+const ENGLISH: &[(&str, &str)] = &[
+    ("E0001", "Unexpected token `{token}`"),
+    ("E0002", "Undefined variable {name}"),
+    ("E0003", "Repeated field name: {name}"),
+    ("E0004", "Repeated match case {tag}"),
+    ("E0005", "Missing field: {name}"),
+    ("E0006", "Unhandled case: {tag}"),
+    ("E0007", "Unexpected types"),
+    ("E0008", "Expected a function"),
+    ("E0009", "Invalid UTF-8"),
+    ("E0010", "Can't read the file: {reason}"),
+    ("E0011", "Check cancelled"),
+    ("E0012", "Include cycle: {cycle}"),
+    ("E0013", "Undefined macro {name}"),
+    (
+        "E0014",
+        "Const {name} uses {var}, but consts can only use earlier consts",
+    ),
+    ("E0015", "format must be called on a string literal"),
+    (
+        "E0016",
+        "Found {annotations} type annotations for {definitions} definitions, which need one each",
+    ),
+    ("E0017", "{warning}"),
+    ("E0018", "Evaluation used up its budget of {resource}"),
+    ("E0019", "This script isn't allowed to use {builtin}"),
+    ("E0020", "There is no variable to rename here"),
+    ("E0021", "Invalid token"),
+    ("E0022", "Unexpected end of input"),
+    ("E0023", "Unterminated string literal"),
+    ("E0024", "Invalid escape sequence"),
+    ("E0025", "Invalid identifier `{name}`"),
+    ("E0026", "Expected an uppercase letter after `"),
+    ("E0027", "Operator {op} has no fixity declaration"),
+    (
+        "E0028",
+        "Can't chain {left} and {right} without parentheses",
+    ),
+    ("E0029", "Precedences go from 0 to 9"),
+    ("E0030", "Expected the end of the type"),
+    ("E0031", "Expected {token}"),
+    ("E0032", "Expected a tag name"),
+    ("E0033", "Expected a field name"),
+    ("E0034", "Expected a type"),
+    ("E0035", "Expected a type variable such as 'a"),
+    ("E0036", "Expected a bool"),
+    ("E0037", "Expected a record"),
+    ("E0038", "Expected a case"),
+    ("E0039", "Expected a str"),
+    ("E0040", "Expected a dict"),
+    ("E0041", "Expected a set"),
+    ("E0042", "File not found"),
+    ("E0043", "Permission denied"),
+    ("E0044", "Can't include {path}: {reason}"),
+    ("E0045", "Include of {path} wasn't expanded"),
+    ("E0046", "No rule of macro {name} matches {count} arguments"),
+    ("E0047", "Call of macro {name} wasn't expanded"),
+    (
+        "E0048",
+        "Const {name} uses {var}, which is provided by the host and may have effects",
+    ),
+    (
+        "E0049",
+        "Const {name} holds a function, but consts must be data",
+    ),
+    (
+        "E0050",
+        "Const {name} ran out of {resource} before it had a value",
+    ),
+    ("E0051", "Expected a field name and } after {"),
+    ("E0052", "Invalid field name \"{name}\" in format string"),
+    ("E0053", "Unmatched } in format string, use }} for a brace"),
+    ("E0054", "@type needs a type, as in @type(\"str -> str\")"),
+    (
+        "E0055",
+        "Written out types can't contain any in the type of a result",
+    ),
+    (
+        "E0056",
+        "Written out types can't contain intersections in the type of a result",
+    ),
+    (
+        "E0057",
+        "Written out types can't contain never in the type of an argument",
+    ),
+    (
+        "E0058",
+        "Written out types can't contain unions in the type of an argument",
+    ),
+    (
+        "E0059",
+        "Only variables defined in this file can be renamed",
+    ),
+    (
+        "E0060",
+        "Only variables defined in the script can be renamed",
+    ),
+    ("E0061", "{old} can't be renamed to \"{new}\""),
+    ("E0062", "{name} is already defined here"),
+    ("E0063", "A use of {old} would refer to this {new} instead"),
+    ("E0064", "This {new} would refer to the renamed {old}"),
+    (
+        "E0065",
+        "{op} has a meaning of its own, so it can't be renamed",
+    ),
+    ("E0066", "{op} already has a fixity declaration"),
+    ("E0067", "The fixity of {op} is shared with this definition"),
+    ("E0068", "The fixity of {op} is declared in another file"),
+    ("W0001", "{name} is deprecated"),
+    ("W0002", "{name} is deprecated: {note}"),
+    ("W0003", "{name} has no type annotation"),
+];
+
+type Args = Vec<(&'static str, String)>;
+
+impl ErrorKind {
+    // Stable identifier of the kind of error, independent of the language
+    // messages are shown in.
+    pub fn code(&self) -> &'static str {
+        use ErrorKind::*;
+        match self {
+            Syntax(e) => e.code(),
+            UndefinedVariable(_) => "E0002",
+            RepeatedField(_) => "E0003",
+            RepeatedCase(_) => "E0004",
+            MissingField(_) => "E0005",
+            UnhandledCase(_) => "E0006",
+            UnexpectedTypes => "E0007",
+            Runtime(e) => e.code(),
+            InvalidUtf8 => "E0009",
+            Io(e) => e.code(),
+            Cancelled => "E0011",
+            Include(e) => e.code(),
+            Macro(e) => e.code(),
+            Const(e) => e.code(),
+            Format(e) => e.code(),
+            Signature(e) => e.code(),
+            Lint(_) => "E0017",
+            OutOfFuel(_) => "E0018",
+            Denied(_) => "E0019",
+            Rename(e) => e.code(),
+        }
+    }
+
+    // The values interpolated into the message template
+    pub fn args(&self) -> Args {
+        use ErrorKind::*;
+        match self {
+            Syntax(e) => e.args(),
+            UndefinedVariable(name) | RepeatedField(name) | MissingField(name) => {
+                vec![("name", name.clone())]
+            }
+            RepeatedCase(tag) | UnhandledCase(tag) => vec![("tag", tag.clone())],
+            Io(IoError::Other(reason)) => vec![("reason", reason.clone())],
+            Include(e) => e.args(),
+            Macro(e) => e.args(),
+            Const(e) => e.args(),
+            Format(FormatError::InvalidField(name)) => vec![("name", name.clone())],
+            Signature(SignatureError::AnnotationCount(annotations, definitions)) => vec![
+                ("annotations", annotations.to_string()),
+                ("definitions", definitions.to_string()),
+            ],
+            Lint(warning) => vec![("warning", english_warning(warning))],
+            OutOfFuel(resource) => vec![("resource", resource.name().to_owned())],
+            Denied(capability) => vec![("builtin", capability.name().to_owned())],
+            Rename(e) => e.args(),
+            UnexpectedTypes | Runtime(_) | InvalidUtf8 | Io(_) | Cancelled | Format(_)
+            | Signature(_) => vec![],
+        }
+    }
+}
+
+impl SyntaxError {
+    fn code(&self) -> &'static str {
+        use SyntaxError::*;
+        match self {
+            UnexpectedToken(_) => "E0001",
+            InvalidToken => "E0021",
+            UnexpectedEnd => "E0022",
+            UnterminatedString => "E0023",
+            InvalidEscape => "E0024",
+            ReservedName(_) => "E0025",
+            LowercaseTag => "E0026",
+            NoFixity(_) => "E0027",
+            Unchainable(..) => "E0028",
+            Precedence => "E0029",
+            ExpectedEnd => "E0030",
+            ExpectedToken(_) => "E0031",
+            ExpectedTag => "E0032",
+            ExpectedField => "E0033",
+            ExpectedType => "E0034",
+            ExpectedTypeVar => "E0035",
+        }
+    }
+
+    fn args(&self) -> Args {
+        use SyntaxError::*;
+        match self {
+            UnexpectedToken(token) | ExpectedToken(token) => vec![("token", token.clone())],
+            ReservedName(name) => vec![("name", name.clone())],
+            NoFixity(op) => vec![("op", op.clone())],
+            Unchainable(left, right) => vec![("left", left.clone()), ("right", right.clone())],
+            _ => vec![],
+        }
+    }
+}
+
+impl RuntimeError {
+    fn code(self) -> &'static str {
+        match self {
+            RuntimeError::ExpectedFunction => "E0008",
+            RuntimeError::ExpectedBool => "E0036",
+            RuntimeError::ExpectedRecord => "E0037",
+            RuntimeError::ExpectedCase => "E0038",
+            RuntimeError::ExpectedStr => "E0039",
+            RuntimeError::ExpectedDict => "E0040",
+            RuntimeError::ExpectedSet => "E0041",
+        }
+    }
+}
+
+impl IoError {
+    fn code(&self) -> &'static str {
+        match self {
+            IoError::Other(_) => "E0010",
+            IoError::NotFound => "E0042",
+            IoError::PermissionDenied => "E0043",
+        }
+    }
+}
+
+impl IncludeError {
+    fn code(&self) -> &'static str {
+        match self {
+            IncludeError::Cycle(_) => "E0012",
+            IncludeError::Unreadable(..) => "E0044",
+            IncludeError::NotExpanded(_) => "E0045",
+        }
+    }
+
+    fn args(&self) -> Args {
+        match self {
+            IncludeError::Cycle(paths) => vec![("cycle", paths.join(" -> "))],
+            IncludeError::Unreadable(path, reason) => {
+                vec![("path", path.clone()), ("reason", reason.clone())]
+            }
+            IncludeError::NotExpanded(path) => vec![("path", path.clone())],
+        }
+    }
+}
+
+impl MacroError {
+    fn code(&self) -> &'static str {
+        match self {
+            MacroError::Undefined(_) => "E0013",
+            MacroError::NoRule(..) => "E0046",
+            MacroError::NotExpanded(_) => "E0047",
+        }
+    }
+
+    fn args(&self) -> Args {
+        match self {
+            MacroError::Undefined(name) | MacroError::NotExpanded(name) => {
+                vec![("name", name.clone())]
+            }
+            MacroError::NoRule(name, count) => {
+                vec![("name", name.clone()), ("count", count.to_string())]
+            }
+        }
+    }
+}
+
+impl ConstError {
+    fn code(&self) -> &'static str {
+        match self {
+            ConstError::UsesLet(..) => "E0014",
+            ConstError::UsesHost(..) => "E0048",
+            ConstError::HoldsFunction(_) => "E0049",
+            ConstError::OutOfFuel(..) => "E0050",
+        }
+    }
+
+    fn args(&self) -> Args {
+        match self {
+            ConstError::UsesLet(name, var) | ConstError::UsesHost(name, var) => {
+                vec![("name", name.clone()), ("var", var.clone())]
+            }
+            ConstError::HoldsFunction(name) => vec![("name", name.clone())],
+            ConstError::OutOfFuel(name, resource) => vec![
+                ("name", name.clone()),
+                ("resource", resource.name().to_owned()),
+            ],
+        }
+    }
+}
+
+impl FormatError {
+    fn code(&self) -> &'static str {
+        match self {
+            FormatError::NotLiteral => "E0015",
+            FormatError::UnclosedField => "E0051",
+            FormatError::InvalidField(_) => "E0052",
+            FormatError::UnmatchedBrace => "E0053",
+        }
+    }
+}
+
+impl SignatureError {
+    fn code(self) -> &'static str {
+        match self {
+            SignatureError::AnnotationCount(..) => "E0016",
+            SignatureError::MissingType => "E0054",
+            SignatureError::AnyInResult => "E0055",
+            SignatureError::IntersectionInResult => "E0056",
+            SignatureError::NeverInArgument => "E0057",
+            SignatureError::UnionInArgument => "E0058",
+        }
+    }
+}
+
+impl RenameError {
+    fn code(&self) -> &'static str {
+        use RenameError::*;
+        match self {
+            NoVariable => "E0020",
+            DefinedInOtherFile => "E0059",
+            NotInScript => "E0060",
+            InvalidName(..) => "E0061",
+            AlreadyDefined(_) => "E0062",
+            Shadowed(..) => "E0063",
+            Captured(..) => "E0064",
+            Special(_) => "E0065",
+            HasFixity(_) => "E0066",
+            SharedFixity(_) => "E0067",
+            FixityInOtherFile(_) => "E0068",
+        }
+    }
+
+    fn args(&self) -> Args {
+        use RenameError::*;
+        match self {
+            NoVariable | DefinedInOtherFile | NotInScript => vec![],
+            InvalidName(old, new) | Shadowed(old, new) | Captured(old, new) => {
+                vec![("old", old.clone()), ("new", new.clone())]
+            }
+            AlreadyDefined(name) => vec![("name", name.clone())],
+            Special(op) | HasFixity(op) | SharedFixity(op) | FixityInOtherFile(op) => {
+                vec![("op", op.clone())]
+            }
+        }
+    }
+}
+
+impl WarningKind {
+    // Same as ErrorKind::code, for warnings
+    pub fn code(&self) -> &'static str {
+        match self {
+            WarningKind::Deprecated(_, None) => "W0001",
+            WarningKind::Deprecated(_, Some(_)) => "W0002",
+            WarningKind::MissingAnnotation(_) => "W0003",
+        }
+    }
+
+    pub fn args(&self) -> Args {
+        match self {
+            WarningKind::Deprecated(name, None) | WarningKind::MissingAnnotation(name) => {
+                vec![("name", name.clone())]
+            }
+            WarningKind::Deprecated(name, Some(note)) => {
+                vec![("name", name.clone()), ("note", note.clone())]
+            }
+        }
+    }
+}
+
+// Replaces each `{name}` with its argument. Unknown names are left as is.
+fn interpolate(template: &str, args: &[(&str, String)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(i) = rest.find('{') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let arg = rest.find('}').and_then(|j| {
+            let value = &args.iter().find(|(name, _)| *name == &rest[1..j])?.1;
+            Some((value, j))
+        });
+        match arg {
+            Some((value, j)) => {
+                out.push_str(value);
+                rest = &rest[j + 1..];
+            }
+            None => {
+                out.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn english_template(code: &str) -> &'static str {
+    ENGLISH.iter().find(|(c, _)| *c == code).unwrap().1
+}
+
+pub(crate) fn english(kind: &ErrorKind) -> String {
+    interpolate(english_template(kind.code()), &kind.args())
+}
+
+pub(crate) fn english_warning(kind: &WarningKind) -> String {
+    interpolate(english_template(kind.code()), &kind.args())
+}
+
+// Message templates for one language. Codes without a template fall back
+// to the English message.
+#[derive(Debug, Clone, Default)]
+pub struct Catalog {
+    templates: HashMap<String, String>,
+}
+
+impl Catalog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, code: impl Into<String>, template: impl Into<String>) {
+        self.templates.insert(code.into(), template.into());
+    }
+
+    // Reads `CODE = template` lines. Blank lines and lines starting with `#`
+    // are skipped.
+    pub fn parse(text: &str) -> std::result::Result<Self, String> {
+        let mut catalog = Self::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once('=') {
+                Some((code, template)) => catalog.insert(code.trim(), template.trim()),
+                None => return Err(format!("line {}: expected `CODE = template`", i + 1)),
+            }
+        }
+        Ok(catalog)
+    }
+
+    fn template(&self, code: &str) -> &str {
+        match self.templates.get(code) {
+            Some(template) => template,
+            None => english_template(code),
+        }
+    }
+
+    pub fn message(&self, kind: &ErrorKind) -> String {
+        let args = match kind {
+            ErrorKind::Lint(warning) => vec![("warning", self.warning(warning))],
+            _ => kind.args(),
+        };
+        interpolate(self.template(kind.code()), &args)
+    }
+
+    pub fn warning(&self, kind: &WarningKind) -> String {
+        interpolate(self.template(kind.code()), &kind.args())
+    }
+}
+
+impl Error {
+    pub fn localized(&self, catalog: &Catalog) -> String {
+        catalog.message(&self.kind)
+    }
+}
+
+impl Diagnostic {
+    // Same as the Display output, but with the message from `catalog` and
+    // the error code, as in `name:line:col: error[E0002]: msg`.
+    pub fn localized(&self, catalog: &Catalog) -> String {
+        let mut out = format!("{}:", self.file);
+        if let Some((line, col)) = self.location {
+            out.push_str(&format!("{}:{}:", line, col));
+        }
+        out.push_str(&format!(
+            " error[{}]: {}",
+            self.error.kind.code(),
+            self.error.localized(catalog)
+        ));
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_are_unique() {
+        for (i, (code, _)) in ENGLISH.iter().enumerate() {
+            assert!(ENGLISH[..i].iter().all(|(c, _)| c != code), "{}", code);
+        }
+    }
+
+    #[test]
+    fn localizes_denied_warnings() {
+        let mut catalog = Catalog::new();
+        catalog.insert("W0003", "{name} braucht eine Typangabe");
+        let kind = ErrorKind::Lint(WarningKind::MissingAnnotation("f".to_owned()));
+        assert_eq!(catalog.message(&kind), "f braucht eine Typangabe");
+        assert_eq!(english(&kind), "f has no type annotation");
+    }
+}
@@ -0,0 +1,167 @@
+use crate::{
+    ast::{Assoc, Expr, Fixities, Span, Spanned},
+    error::SyntaxError,
+    lexer::{LexError, Token, Triple},
+};
+
+// Operators need a declaration such as `infixl 6 <+>` in the same file or
+// one it includes. The parser reads a chain like `a <+> b <*> c` as a flat
+// list of operands and operators, whose nesting is decided here from the
+// declarations.
+
+// What the operators with a meaning of their own do, unless a script
+// defines them as functions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Builtin {
+    // `a + b` is `a.add b`, and likewise for the other arithmetic operators
+    Method(&'static str),
+    // `f >> g` is `fun x -> g (f x)`
+    Compose,
+    // `f << g` is `fun x -> f (g x)`
+    ComposeBack,
+}
+
+const BUILTINS: &[(&str, Builtin, Assoc, u8)] = &[
+    ("+", Builtin::Method("add"), Assoc::Left, 6),
+    ("-", Builtin::Method("sub"), Assoc::Left, 6),
+    ("*", Builtin::Method("mul"), Assoc::Left, 7),
+    ("/", Builtin::Method("div"), Assoc::Left, 7),
+    (">>", Builtin::Compose, Assoc::Right, 9),
+    ("<<", Builtin::ComposeBack, Assoc::Right, 9),
+];
+
+pub(crate) fn builtin(op: &str) -> Option<Builtin> {
+    BUILTINS
+        .iter()
+        .find(|(o, ..)| *o == op)
+        .map(|&(_, builtin, ..)| builtin)
+}
+
+// `a < b < c` is `a < b` and `b < c`, with `b` evaluated once. Unless a
+// script defines them, comparisons compare strs.
+const COMPARISONS: &[&str] = &["<", "<=", ">", ">="];
+
+pub(crate) fn is_comparison(op: &str) -> bool {
+    COMPARISONS.contains(&op)
+}
+
+pub(crate) fn precedence(digits: &str) -> Option<u8> {
+    match digits.parse() {
+        Ok(n) if n <= 9 => Some(n),
+        _ => None,
+    }
+}
+
+// The operators of a file: the builtin ones, then those declared in the
+// files it includes, then those declared among its tokens, which apply to
+// uses before them
+pub(crate) fn fixities(tokens: &[Result<Triple, LexError>], included: &Fixities) -> Fixities {
+    let mut out: Fixities = BUILTINS
+        .iter()
+        .map(|&(op, _, assoc, prec)| (op.to_owned(), (assoc, prec)))
+        .chain(
+            COMPARISONS
+                .iter()
+                .map(|&op| (op.to_owned(), (Assoc::None, 4))),
+        )
+        .collect();
+    out.extend(included.iter().map(|(op, &fixity)| (op.clone(), fixity)));
+    out.extend(declared(tokens));
+    out
+}
+
+// The declarations among the tokens of a file. Declarations the parser
+// will reject are skipped.
+pub(crate) fn declared(tokens: &[Result<Triple, LexError>]) -> Fixities {
+    let mut out = Fixities::new();
+    for (i, token) in tokens.iter().enumerate() {
+        let assoc = match token {
+            Ok((_, Token::Infixl, _)) => Assoc::Left,
+            Ok((_, Token::Infixr, _)) => Assoc::Right,
+            Ok((_, Token::Infix, _)) => Assoc::None,
+            _ => continue,
+        };
+        let prec = match tokens.get(i + 1) {
+            Some(Ok((_, Token::Number(digits), _))) => match precedence(digits) {
+                Some(prec) => prec,
+                None => continue,
+            },
+            _ => continue,
+        };
+        for token in &tokens[i + 2..] {
+            match token {
+                Ok((_, Token::Operator(op), _)) => {
+                    out.insert(op.clone(), (assoc, prec));
+                }
+                _ => break,
+            }
+        }
+    }
+    out
+}
+
+// Reduces an entry of `resolve`'s pending stack, returning its operands
+// joined by its operators
+fn join(operands: &mut Vec<Spanned<Expr>>, ops: Vec<Spanned<String>>) {
+    let mut rest: Vec<_> = ops
+        .into_iter()
+        .rev()
+        .map(|op| (op, Box::new(operands.pop().unwrap())))
+        .collect();
+    rest.reverse();
+    let first = Box::new(operands.pop().unwrap());
+    let start = first.1;
+    let end = rest.last().unwrap().1 .1.end;
+    let span = Span::in_file(start.file, start.start, end);
+    let expr = if is_comparison(&rest[0].0 .0) {
+        Expr::Compare(first, rest)
+    } else {
+        let (op, rhs) = rest.pop().unwrap();
+        Expr::BinOp(first, op, rhs)
+    };
+    operands.push((expr, span));
+}
+
+// Nests `first op1 e1 op2 e2 ...` by precedence, then associativity.
+// Operators of the same precedence can only be chained if they associate
+// the same way, or if they are all comparisons, which are kept together as
+// one Compare.
+pub(crate) fn resolve(
+    ops: &Fixities,
+    first: Spanned<Expr>,
+    rest: Vec<(Spanned<String>, Box<Spanned<Expr>>)>,
+) -> Result<Box<Spanned<Expr>>, LexError> {
+    let mut operands = vec![first];
+    let mut pending: Vec<(Vec<Spanned<String>>, Assoc, u8)> = vec![];
+    'ops: for ((op, span), rhs) in rest {
+        let &(assoc, prec) = ops.get(&op).ok_or_else(|| LexError {
+            kind: SyntaxError::NoFixity(op.clone()),
+            span,
+        })?;
+        while let Some((top, top_assoc, top_prec)) = pending.last_mut() {
+            let top_op = &top.last().unwrap().0;
+            if *top_prec == prec && is_comparison(top_op) && is_comparison(&op) {
+                top.push((op, span));
+                operands.push(*rhs);
+                continue 'ops;
+            }
+            if *top_prec == prec && (*top_assoc != assoc || assoc == Assoc::None) {
+                return Err(LexError {
+                    kind: SyntaxError::Unchainable(top_op.clone(), op),
+                    span,
+                });
+            }
+            if *top_prec < prec || (*top_prec == prec && assoc == Assoc::Right) {
+                break;
+            }
+            let (top, _, _) = pending.pop().unwrap();
+            join(&mut operands, top);
+        }
+        pending.push((vec![(op, span)], assoc, prec));
+        operands.push(*rhs);
+    }
+    while let Some((ops, _, _)) = pending.pop() {
+        join(&mut operands, ops);
+    }
+    Ok(Box::new(operands.pop().unwrap()))
+}
@@ -0,0 +1,28 @@
+use crate::{ast, span::FileId, ty::TypeckState};
+use std::sync::OnceLock;
+
+// Core combinators and option and list helpers available to every script.
+// Lists are `Cons {head; tail} or `Nil {}, as returned by split. Each
+// definition is closed, so it can be checked again at every use, which
+// makes it polymorphic.
+const SOURCE: &str = include_str!("prelude.zx");
+
+// The prelude's spans are in a file of their own, so that they never refer
+// to a script's source
+pub(crate) const FILE: FileId = FileId(u32::MAX);
+
+pub(crate) fn definitions() -> impl Iterator<Item = &'static ast::VarDefinition> {
+    static PARSED: OnceLock<Vec<ast::TopLevel>> = OnceLock::new();
+    let parsed =
+        PARSED.get_or_init(|| crate::parse_in_file(SOURCE, FILE).expect("invalid prelude"));
+    parsed.iter().map(|item| match item {
+        ast::TopLevel::LetDef(def) if item.is_closed() => def,
+        _ => panic!("the prelude may only contain closed let definitions"),
+    })
+}
+
+pub(crate) fn register(state: &mut TypeckState) {
+    for ((name, _), expr) in definitions() {
+        state.register_builtin(name.clone(), move |core| core.instantiate_closed(expr));
+    }
+}
@@ -0,0 +1,178 @@
+use crate::{eval::Value, lexer};
+
+const INDENT: usize = 4;
+
+// How Value::pretty lays out a value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    // Values nested deeper than this are shown as `...`
+    pub max_depth: Option<usize>,
+    // Records, dicts and sets are cut off after this many entries
+    pub max_items: Option<usize>,
+    // Values that don't fit in a line this long are broken over several,
+    // one entry per line
+    pub width: usize,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout {
+            max_depth: None,
+            max_items: None,
+            width: 80,
+        }
+    }
+}
+
+impl Value<'_> {
+    // Shows the value the way Display does, within the limits of the
+    // layout. Values can't contain themselves, so there are no cycles to
+    // guard against, only values too big to read.
+    pub fn pretty(&self, layout: &Layout) -> String {
+        let mut out = String::new();
+        Printer { layout }.value(self, 0, 0, 0, &mut out);
+        out
+    }
+}
+
+struct Printer<'a> {
+    layout: &'a Layout,
+}
+
+// The delimiters of a value with entries, and the entries shown as a key
+// and value. Keys are empty for sets.
+struct Entries<'v, 'a> {
+    open: &'static str,
+    close: &'static str,
+    entries: Vec<(String, &'v Value<'a>)>,
+    // How many entries were cut off
+    rest: usize,
+}
+
+impl Printer<'_> {
+    fn too_deep(&self, depth: usize) -> bool {
+        self.layout.max_depth.is_some_and(|max| depth > max)
+    }
+
+    fn entries<'v, 'a>(&self, v: &'v Value<'a>) -> Option<Entries<'v, 'a>> {
+        let (open, close, all): (_, _, Vec<_>) = match v {
+            Value::Record(fields) => (
+                "{",
+                "}",
+                fields
+                    .iter()
+                    .map(|(name, v)| (format!("{} = ", name), v))
+                    .collect(),
+            ),
+            Value::Dict(entries) => (
+                "#{",
+                "}",
+                entries
+                    .iter()
+                    .map(|(key, v)| (format!("{}: ", lexer::quote(key)), v))
+                    .collect(),
+            ),
+            Value::Set(elems) => (
+                "#[",
+                "]",
+                elems.values().map(|v| (String::new(), v)).collect(),
+            ),
+            _ => return None,
+        };
+        let shown = self.layout.max_items.unwrap_or(all.len()).min(all.len());
+        let rest = all.len() - shown;
+        let mut entries = all;
+        entries.truncate(shown);
+        Some(Entries {
+            open,
+            close,
+            entries,
+            rest,
+        })
+    }
+
+    // The value on one line
+    fn flat(&self, v: &Value<'_>, depth: usize, out: &mut String) {
+        if self.too_deep(depth) {
+            out.push_str("...");
+            return;
+        }
+        if let Some(e) = self.entries(v) {
+            out.push_str(e.open);
+            for (i, (key, v)) in e.entries.iter().enumerate() {
+                if i > 0 {
+                    out.push_str("; ");
+                }
+                out.push_str(key);
+                self.flat(v, depth + 1, out);
+            }
+            if e.rest > 0 {
+                if !e.entries.is_empty() {
+                    out.push_str("; ");
+                }
+                out.push_str("...");
+            }
+            out.push_str(e.close);
+            return;
+        }
+        match v {
+            Value::Case(tag, payload) => {
+                out.push_str(tag);
+                out.push(' ');
+                let nested = matches!(**payload, Value::Case(..));
+                if nested {
+                    out.push('(');
+                }
+                self.flat(payload, depth + 1, out);
+                if nested {
+                    out.push(')');
+                }
+            }
+            v => out.push_str(&v.to_string()),
+        }
+    }
+
+    // The value starting `column` chars into a line indented by `indent`,
+    // broken over lines where it doesn't fit
+    fn value(&self, v: &Value<'_>, depth: usize, indent: usize, column: usize, out: &mut String) {
+        let mut flat = String::new();
+        self.flat(v, depth, &mut flat);
+        if column + flat.chars().count() <= self.layout.width {
+            out.push_str(&flat);
+            return;
+        }
+        let e = match (v, self.entries(v)) {
+            (_, Some(e)) if !self.too_deep(depth) => e,
+            (Value::Case(tag, payload), _) if !matches!(**payload, Value::Case(..)) => {
+                out.push_str(tag);
+                out.push(' ');
+                let column = column + tag.chars().count() + 1;
+                return self.value(payload, depth + 1, indent, column, out);
+            }
+            _ => return out.push_str(&flat),
+        };
+        let inner = indent + INDENT;
+        out.push_str(e.open);
+        for (i, (key, v)) in e.entries.iter().enumerate() {
+            if i > 0 {
+                out.push(';');
+            }
+            out.push('\n');
+            out.push_str(&" ".repeat(inner));
+            out.push_str(key);
+            let column = inner + key.chars().count();
+            self.value(v, depth + 1, inner, column, out);
+        }
+        if e.rest > 0 {
+            if !e.entries.is_empty() {
+                out.push(';');
+            }
+            out.push('\n');
+            out.push_str(&" ".repeat(inner));
+            out.push_str("...");
+        }
+        out.push('\n');
+        out.push_str(&" ".repeat(indent));
+        out.push_str(e.close);
+    }
+}
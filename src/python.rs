@@ -0,0 +1,86 @@
+// The pyo3 0.22 function macros expand to a conversion clippy flags.
+#![allow(clippy::useless_conversion)]
+
+use crate::{Error, Source, TypeckState};
+use pyo3::{exceptions::PyValueError, prelude::*};
+use std::collections::BTreeMap;
+
+// Exposed to Python as zx.Diagnostic. Offsets are in UTF-8 bytes, lines and
+// columns are one based.
+#[pyclass(name = "Diagnostic", get_all, frozen)]
+struct PyDiagnostic {
+    message: String,
+    start: usize,
+    end: usize,
+    line: usize,
+    col: usize,
+}
+
+#[pymethods]
+impl PyDiagnostic {
+    fn __repr__(&self) -> String {
+        format!("Diagnostic({}:{}: {:?})", self.line, self.col, self.message)
+    }
+}
+
+fn diagnostic(source: &Source, e: &Error) -> PyDiagnostic {
+    let span = e.span.unwrap_or_default();
+    let (line, col) = source.line_col(span.start);
+    PyDiagnostic {
+        message: e.to_string(),
+        start: span.start,
+        end: span.end,
+        line,
+        col,
+    }
+}
+
+// Returns the syntax errors in `source`, if any.
+#[pyfunction]
+fn parse(source: &str) -> Vec<PyDiagnostic> {
+    let source = Source::new("<python>", source);
+    match crate::parse(&source.text) {
+        Ok(_) => vec![],
+        Err(e) => vec![diagnostic(&source, &e)],
+    }
+}
+
+// Returns the syntax or type errors in `source`, if any.
+#[pyfunction]
+fn check(source: &str) -> Vec<PyDiagnostic> {
+    let source = Source::new("<python>", source);
+    let res =
+        crate::parse(&source.text).and_then(|parsed| TypeckState::new().check_script(&parsed));
+    match res {
+        Ok(()) => vec![],
+        Err(e) => vec![diagnostic(&source, &e)],
+    }
+}
+
+// Maps each global defined by `source` to its displayed type, raising
+// ValueError if the script doesn't check.
+#[pyfunction]
+fn types(source: &str) -> PyResult<BTreeMap<String, String>> {
+    let source = Source::new("<python>", source);
+    let to_py = |e: Error| {
+        let d = diagnostic(&source, &e);
+        PyValueError::new_err(format!("{}:{}: {}", d.line, d.col, d.message))
+    };
+    let parsed = crate::parse(&source.text).map_err(to_py)?;
+    let mut state = TypeckState::new();
+    state.check_script(&parsed).map_err(to_py)?;
+    Ok(state
+        .symbols(&parsed)
+        .into_iter()
+        .filter_map(|sym| Some((sym.name, sym.ty?)))
+        .collect())
+}
+
+#[pymodule]
+fn zx(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyDiagnostic>()?;
+    m.add_function(wrap_pyfunction!(parse, m)?)?;
+    m.add_function(wrap_pyfunction!(check, m)?)?;
+    m.add_function(wrap_pyfunction!(types, m)?)?;
+    Ok(())
+}
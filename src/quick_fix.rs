@@ -0,0 +1,240 @@
+use crate::{
+    ast::{self, Expr, Spanned, TopLevel},
+    complete,
+    error::{Error, ErrorKind},
+    lexer,
+    rename::TextEdit,
+    span::Span,
+};
+use std::collections::{HashMap, HashSet};
+
+// An edit that makes an error go away, though the placeholder values it
+// adds may not have the right type
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuickFix {
+    pub title: String,
+    pub edits: Vec<TextEdit>,
+}
+
+// What the placeholders added by fixes are
+const PLACEHOLDER: &str = "{}";
+
+// The expressions of a script and the definitions of its let-bound
+// variables, by the span of their binders
+#[derive(Default)]
+struct Exprs<'a> {
+    // In source order, outer before inner
+    all: Vec<&'a Spanned<Expr>>,
+    defs: HashMap<Span, &'a Spanned<Expr>>,
+}
+
+impl<'a> Exprs<'a> {
+    fn def(&mut self, ((_, span), expr): &'a ast::VarDefinition) {
+        self.defs.insert(*span, expr);
+        self.expr(expr);
+    }
+
+    fn expr(&mut self, expr: &'a Spanned<Expr>) {
+        self.all.push(expr);
+        match &expr.0 {
+            Expr::Let(def, rest, _) => {
+                self.def(def);
+                self.expr(rest);
+            }
+            Expr::LetRec(defs, rest) => {
+                for def in defs {
+                    self.def(def);
+                }
+                self.expr(rest);
+            }
+            e => {
+                for child in ast::children(e) {
+                    self.expr(child);
+                }
+            }
+        }
+    }
+}
+
+struct Finder<'a> {
+    parsed: &'a [TopLevel],
+    exprs: Exprs<'a>,
+    // The binders whose definitions have been looked at
+    seen: HashSet<Span>,
+}
+
+impl<'a> Finder<'a> {
+    fn new(parsed: &'a [TopLevel]) -> Self {
+        let mut exprs = Exprs::default();
+        for item in parsed {
+            match item.unattributed() {
+                TopLevel::Expr(expr) => exprs.expr(expr),
+                TopLevel::Test(_, expr) => exprs.expr(expr),
+                TopLevel::LetDef(def) | TopLevel::Const(def) => exprs.def(def),
+                TopLevel::LetRecDef(defs) => {
+                    for def in defs {
+                        exprs.def(def);
+                    }
+                }
+                TopLevel::Include(_) | TopLevel::Macro(..) | TopLevel::Fixity(..) => {}
+                TopLevel::Attributed(..) => unreachable!(),
+            }
+        }
+        Finder {
+            parsed,
+            exprs,
+            seen: HashSet::new(),
+        }
+    }
+
+    // The definition of the variable used at `span`, if it's let-bound
+    fn resolve(&self, name: &str, span: Span) -> Option<&'a Spanned<Expr>> {
+        let binders = complete::binders_in_scope(self.parsed, span.file, span.start);
+        let &(_, binder, _) = binders.iter().rev().find(|&&(n, _, _)| n == name)?;
+        self.exprs.defs.get(&binder).copied()
+    }
+
+    // The expressions a value flowing from `span` may come from: those
+    // inside it, then those in the definitions of the variables used there
+    fn sources(&mut self, span: Span, out: &mut Vec<&'a Spanned<Expr>>) {
+        let inside: Vec<_> = self
+            .exprs
+            .all
+            .iter()
+            .copied()
+            .filter(|(_, s)| s.file == span.file && span.start <= s.start && s.end <= span.end)
+            .collect();
+        out.extend(&inside);
+        for (expr, s) in inside {
+            if let Expr::Variable(name) = expr {
+                if let Some(def) = self.resolve(name, *s) {
+                    if self.seen.insert(def.1) {
+                        self.sources(def.1, out);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Adds the field to the first record literal the value could come from
+// that doesn't have it
+fn add_field(parsed: &[TopLevel], source: &str, name: &str, span: Span) -> Option<QuickFix> {
+    let mut finder = Finder::new(parsed);
+    let mut sources = vec![];
+    finder.sources(span, &mut sources);
+    let (_, record) = sources.into_iter().find(|(expr, s)| match expr {
+        Expr::Record(fields) if s.file == span.file => {
+            fields.iter().all(|((field, _), _)| field != name)
+        }
+        _ => false,
+    })?;
+    // Just inside the closing brace, before any space
+    let body = source[record.start..record.end - 1].trim_end();
+    let at = record.start + body.len();
+    let new_text = match body {
+        "{" => format!("{} = {}", name, PLACEHOLDER),
+        _ => format!("; {} = {}", name, PLACEHOLDER),
+    };
+    Some(QuickFix {
+        title: format!("Add field {}", name),
+        edits: vec![TextEdit {
+            span: Span::in_file(record.file, at, at),
+            new_text,
+        }],
+    })
+}
+
+type Arm = (ast::CaseMatchPattern, Box<Spanned<Expr>>);
+
+// The arms of a match and where another can be added. A function defined
+// by clauses is a match on its parameter, and it's the function that spans
+// the arms.
+fn match_arms(expr: &Spanned<Expr>) -> Option<(&[Arm], Span)> {
+    match expr {
+        (Expr::Match(scrutinee, _), _) if is_clauses_arg(scrutinee) => None,
+        (Expr::Match(_, cases), span) => Some((cases, *span)),
+        (Expr::FuncDef((arg, _), body), span) if arg == ast::CLAUSES_ARG => match &**body {
+            (Expr::Match(_, cases), _) => Some((cases, *span)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn is_clauses_arg(expr: &Spanned<Expr>) -> bool {
+    matches!(&expr.0, Expr::Variable(name) if name == ast::CLAUSES_ARG)
+}
+
+// Adds an arm for the tag to the match whose scrutinee is at `span`, or
+// else to the first match the value could flow into that doesn't have one
+fn add_case(parsed: &[TopLevel], tag: &str, span: Span) -> Option<QuickFix> {
+    let mut finder = Finder::new(parsed);
+    let scrutinized = finder.exprs.all.iter().copied().find(|expr| match &expr.0 {
+        Expr::Match(scrutinee, _) => scrutinee.1 == span,
+        _ => false,
+    });
+    let mut sources = vec![];
+    finder.sources(span, &mut sources);
+    let (_, end) = scrutinized
+        .into_iter()
+        .chain(sources)
+        .filter_map(match_arms)
+        .filter(|(_, end)| end.file == span.file)
+        .find(|(cases, _)| cases.iter().all(|(((t, _), _), _)| t != tag))?;
+    let at = Span::in_file(end.file, end.end, end.end);
+    Some(QuickFix {
+        title: format!("Add a case for {}", tag),
+        edits: vec![TextEdit {
+            span: at,
+            new_text: format!(" | {} _ -> {}", tag, PLACEHOLDER),
+        }],
+    })
+}
+
+// Defines the variable at the start of the top level definition using it,
+// or of the body of the function for one in a let rec, since those have to
+// be functions
+fn define(parsed: &[TopLevel], name: &str, span: Span) -> Option<QuickFix> {
+    let around = |expr: &Spanned<Expr>| expr.1.file == span.file && expr.1.contains(span.start);
+    let expr = parsed.iter().find_map(|item| match item.unattributed() {
+        TopLevel::Expr(expr) => Some(expr).filter(|e| around(e)),
+        TopLevel::LetDef((_, expr)) | TopLevel::Test(_, expr) => {
+            Some(&**expr).filter(|e| around(e))
+        }
+        TopLevel::LetRecDef(defs) => defs.iter().find_map(|(_, expr)| match &**expr {
+            (Expr::FuncDef((arg, _), body), _) if arg != ast::CLAUSES_ARG && around(body) => {
+                Some(&**body)
+            }
+            _ => None,
+        }),
+        _ => None,
+    })?;
+    let at = Span::in_file(expr.1.file, expr.1.start, expr.1.start);
+    Some(QuickFix {
+        title: format!("Define {}", name),
+        edits: vec![TextEdit {
+            span: at,
+            new_text: format!("let {} = {} in ", name, PLACEHOLDER),
+        }],
+    })
+}
+
+// The fixes for an error found checking `parsed`, which are all in the
+// file of the error, whose text is `source`. Only missing fields,
+// unhandled cases and undefined variables have any.
+pub fn quick_fixes(parsed: &[TopLevel], source: &str, error: &Error) -> Vec<QuickFix> {
+    let span = match error.span {
+        Some(span) => span,
+        None => return vec![],
+    };
+    let fix = match &error.kind {
+        ErrorKind::MissingField(name) => add_field(parsed, source, name, span),
+        ErrorKind::UnhandledCase(tag) => add_case(parsed, tag, span),
+        ErrorKind::UndefinedVariable(name) if name.chars().all(lexer::is_ident_char) => {
+            define(parsed, name, span)
+        }
+        _ => None,
+    };
+    fix.into_iter().collect()
+}
@@ -1,58 +1,275 @@
-use crate::ty::ID;
-use std::{collections::HashSet, hash::Hash};
-
-#[derive(Default, Clone)]
-struct OrderedSet<T> {
-    v: Vec<T>,
-    s: HashSet<T>,
-}
-
-impl<T> OrderedSet<T>
-where
-    T: Eq + Hash + Clone,
-{
-    fn insert(&mut self, value: T) -> bool {
-        if self.s.insert(value.clone()) {
-            self.v.push(value);
-            true
-        } else {
-            false
+// Node IDs are assigned densely from zero in the order nodes are added.
+pub type ID = usize;
+
+// Sets stay as a plain vector until they grow past this many elements, at
+// which point they switch to a bitset indexed by node ID.
+const SMALL_LIMIT: usize = 32;
+
+#[derive(Clone)]
+enum IdSet {
+    Small(Vec<ID>),
+    Dense(Vec<u64>),
+}
+
+impl Default for IdSet {
+    fn default() -> Self {
+        IdSet::Small(vec![])
+    }
+}
+
+impl IdSet {
+    fn insert(&mut self, value: ID) -> bool {
+        match self {
+            IdSet::Small(v) => {
+                if v.contains(&value) {
+                    return false;
+                }
+                if v.len() < SMALL_LIMIT {
+                    v.push(value);
+                    return true;
+                }
+
+                let mut bits = vec![];
+                for &i in v.iter() {
+                    set_bit(&mut bits, i);
+                }
+                set_bit(&mut bits, value);
+                *self = IdSet::Dense(bits);
+                true
+            }
+            IdSet::Dense(bits) => set_bit(bits, value),
+        }
+    }
+
+    fn contains(&self, value: ID) -> bool {
+        match self {
+            IdSet::Small(v) => v.contains(&value),
+            IdSet::Dense(bits) => bits
+                .get(value / 64)
+                .is_some_and(|w| w & (1 << (value % 64)) != 0),
+        }
+    }
+
+    fn remove(&mut self, value: ID) {
+        match self {
+            IdSet::Small(v) => v.retain(|&i| i != value),
+            IdSet::Dense(bits) => {
+                if let Some(w) = bits.get_mut(value / 64) {
+                    *w &= !(1 << (value % 64));
+                }
+            }
+        }
+    }
+
+    fn map(&self, f: impl Fn(ID) -> ID) -> Self {
+        let mut set = IdSet::default();
+        for i in self.iter() {
+            set.insert(f(i));
         }
+        set
     }
 
-    fn iter(&self) -> impl Iterator<Item = &T> {
-        self.v.iter()
+    fn iter(&self) -> impl Iterator<Item = ID> + '_ {
+        let (small, dense) = match self {
+            IdSet::Small(v) => (Some(v.iter().copied()), None),
+            IdSet::Dense(bits) => (None, Some(iter_bits(bits))),
+        };
+        small
+            .into_iter()
+            .flatten()
+            .chain(dense.into_iter().flatten())
     }
 }
 
-#[derive(Default, Clone)]
-pub struct Reachability {
-    upsets: Vec<OrderedSet<ID>>,
-    downsets: Vec<OrderedSet<ID>>,
+fn set_bit(bits: &mut Vec<u64>, i: ID) -> bool {
+    let (word, mask) = (i / 64, 1 << (i % 64));
+    if word >= bits.len() {
+        bits.resize(word + 1, 0);
+    }
+    let is_new = bits[word] & mask == 0;
+    bits[word] |= mask;
+    is_new
+}
+
+fn iter_bits(bits: &[u64]) -> impl Iterator<Item = ID> + '_ {
+    bits.iter().enumerate().flat_map(|(word, &w)| {
+        let mut w = w;
+        std::iter::from_fn(move || {
+            if w == 0 {
+                return None;
+            }
+            let bit = w.trailing_zeros() as usize;
+            w &= w - 1;
+            Some(word * 64 + bit)
+        })
+    })
 }
 
-impl Reachability {
-    pub fn add_node(&mut self) -> ID {
+// A directed graph that is kept transitively closed as edges are added,
+// with a payload of type T on every node. The type checker stores its type
+// heads separately and uses no payload.
+#[derive(Clone)]
+pub struct Reachability<T = ()> {
+    payloads: Vec<T>,
+    upsets: Vec<IdSet>,
+    downsets: Vec<IdSet>,
+}
+
+impl<T> Default for Reachability<T> {
+    fn default() -> Self {
+        Self {
+            payloads: vec![],
+            upsets: vec![],
+            downsets: vec![],
+        }
+    }
+}
+
+impl<T> Reachability<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_node(&mut self, payload: T) -> ID {
         let i = self.upsets.len();
+        self.payloads.push(payload);
         self.upsets.push(Default::default());
         self.downsets.push(Default::default());
         i
     }
 
-    pub fn add_edge(&mut self, lhs: ID, rhs: ID, out: &mut Vec<(ID, ID)>) {
+    pub fn len(&self) -> usize {
+        self.upsets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.upsets.is_empty()
+    }
+
+    pub fn payload(&self, i: ID) -> &T {
+        &self.payloads[i]
+    }
+
+    pub fn payload_mut(&mut self, i: ID) -> &mut T {
+        &mut self.payloads[i]
+    }
+
+    // Whether there is a path from lhs to rhs
+    pub fn reaches(&self, lhs: ID, rhs: ID) -> bool {
+        lhs == rhs || self.downsets[lhs].contains(rhs)
+    }
+
+    pub fn upsets(&self, i: ID) -> impl Iterator<Item = ID> + '_ {
+        self.upsets[i].iter()
+    }
+
+    pub fn downsets(&self, i: ID) -> impl Iterator<Item = ID> + '_ {
+        self.downsets[i].iter()
+    }
+
+    pub fn edge_count(&self) -> usize {
+        self.downsets.iter().map(|s| s.iter().count()).sum()
+    }
+
+    // Drops every node mapped to None and renumbers the rest, along with the
+    // edges between them.
+    pub fn compact(&mut self, remap: &[Option<ID>]) {
+        let keep = |sets: &[IdSet]| -> Vec<IdSet> {
+            sets.iter()
+                .zip(remap)
+                .filter(|(_, new)| new.is_some())
+                .map(|(set, _)| {
+                    let mut kept = IdSet::default();
+                    for i in set.iter().filter_map(|i| remap[i]) {
+                        kept.insert(i);
+                    }
+                    kept
+                })
+                .collect()
+        };
+        self.upsets = keep(&self.upsets);
+        self.downsets = keep(&self.downsets);
+        let payloads = std::mem::take(&mut self.payloads);
+        self.payloads = payloads
+            .into_iter()
+            .zip(remap)
+            .filter(|(_, new)| new.is_some())
+            .map(|(payload, _)| payload)
+            .collect();
+    }
+
+    // Appends all nodes of `other`, shifting their IDs up by the returned
+    // offset.
+    pub fn absorb(&mut self, other: Self) -> ID {
+        let offset = self.upsets.len();
+        let shift = |i| i + offset;
+        self.payloads.extend(other.payloads);
+        self.upsets
+            .extend(other.upsets.iter().map(|s| s.map(shift)));
+        self.downsets
+            .extend(other.downsets.iter().map(|s| s.map(shift)));
+        offset
+    }
+
+    // Removes the nodes from `len` on, along with `edges`, which must include
+    // every edge between an older node and a removed one and every edge
+    // between older nodes added since there were `len` nodes.
+    pub fn undo(&mut self, len: ID, edges: impl IntoIterator<Item = (ID, ID)>) {
+        for (lhs, rhs) in edges {
+            if lhs < len {
+                self.downsets[lhs].remove(rhs);
+            }
+            if rhs < len {
+                self.upsets[rhs].remove(lhs);
+            }
+        }
+        self.payloads.truncate(len);
+        self.upsets.truncate(len);
+        self.downsets.truncate(len);
+    }
+
+    // Inserts an edge without following it through existing ones, for
+    // restoring a graph that is already transitively closed.
+    pub fn insert_closed_edge(&mut self, lhs: ID, rhs: ID) {
+        self.downsets[lhs].insert(rhs);
+        self.upsets[rhs].insert(lhs);
+    }
+
+    // Adds an edge along with every edge it implies, calling `on_edge` for
+    // each one that wasn't present before.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self, on_edge))
+    )]
+    pub fn add_edge(&mut self, lhs: ID, rhs: ID, on_edge: impl FnMut(ID, ID)) {
+        self.add_edge_filtered(lhs, rhs, |_, _, _| true, on_edge)
+    }
+
+    // Like add_edge, but the implied edges `keep` rejects are left out, along
+    // with the edges they would imply in turn.
+    pub fn add_edge_filtered(
+        &mut self,
+        lhs: ID,
+        rhs: ID,
+        mut keep: impl FnMut(&Self, ID, ID) -> bool,
+        mut on_edge: impl FnMut(ID, ID),
+    ) {
         let mut work = vec![(lhs, rhs)];
         while let Some((lhs, rhs)) = work.pop() {
-            if !self.downsets[lhs].insert(rhs) {
+            if self.downsets[lhs].contains(rhs) || !keep(self, lhs, rhs) {
                 continue;
             }
 
-            self.upsets[lhs].insert(lhs);
-            out.push((lhs, rhs));
+            self.downsets[lhs].insert(rhs);
+            self.upsets[rhs].insert(lhs);
+            on_edge(lhs, rhs);
+            #[cfg(feature = "tracing")]
+            tracing::trace!(lhs, rhs, "new edge");
 
-            for lhs2 in self.upsets[lhs].iter().copied() {
+            for lhs2 in self.upsets[lhs].iter() {
                 work.push((lhs2, rhs));
             }
-            for rhs2 in self.downsets[rhs].iter().copied() {
+            for rhs2 in self.downsets[rhs].iter() {
                 work.push((lhs, rhs2));
             }
         }
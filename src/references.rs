@@ -0,0 +1,111 @@
+use crate::{
+    ast::{self, Expr, Spanned, TopLevel},
+    span::{FileId, Span},
+    ty::TypeckState,
+};
+
+// What a find-all-references query was for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Referent {
+    // A variable, by the span of its binder
+    Variable(Span),
+    // Records and cases are structural, so any field or tag of the same
+    // name is the same one. Tags include the leading backtick.
+    Field(String),
+    Tag(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct References {
+    pub referent: Referent,
+    // Sorted by file and then position. A variable's binder isn't a use.
+    pub uses: Vec<Span>,
+}
+
+// The fields and tags of a script, where they're written
+#[derive(Default)]
+struct Labels<'a> {
+    fields: Vec<Spanned<&'a str>>,
+    tags: Vec<Spanned<&'a str>>,
+}
+
+impl<'a> Labels<'a> {
+    fn expr(&mut self, (expr, _): &'a Spanned<Expr>) {
+        match expr {
+            Expr::Case((tag, span), _) => self.tags.push((tag, *span)),
+            Expr::FieldAccess(_, (field, span)) => self.fields.push((field, *span)),
+            Expr::Match(_, cases) => {
+                for (((tag, span), _), _) in cases {
+                    self.tags.push((tag, *span));
+                }
+            }
+            Expr::Record(fields) => {
+                for ((field, span), _) in fields {
+                    self.fields.push((field, *span));
+                }
+            }
+            _ => {}
+        }
+        for child in ast::children(expr) {
+            self.expr(child);
+        }
+    }
+}
+
+// All the spans of `name` among `labels`
+fn uses(labels: &[Spanned<&str>], name: &str) -> Vec<Span> {
+    labels
+        .iter()
+        .filter(|&&(label, _)| label == name)
+        .map(|&(_, span)| span)
+        .collect()
+}
+
+impl TypeckState {
+    // Every use of the variable, field or tag at `offset` in `file`, across
+    // all the files of `parsed`, which must be the script last checked
+    // successfully with its includes expanded.
+    pub fn references(
+        &self,
+        parsed: &[TopLevel],
+        file: FileId,
+        offset: usize,
+    ) -> Option<References> {
+        let mut labels = Labels::default();
+        for item in parsed {
+            match item.unattributed() {
+                TopLevel::Expr(expr) => labels.expr(expr),
+                TopLevel::Test(_, expr) => labels.expr(expr),
+                TopLevel::LetDef((_, expr)) | TopLevel::Const((_, expr)) => labels.expr(expr),
+                TopLevel::LetRecDef(defs) => {
+                    for (_, expr) in defs {
+                        labels.expr(expr);
+                    }
+                }
+                TopLevel::Include(_) | TopLevel::Macro(..) | TopLevel::Fixity(..) => {}
+                TopLevel::Attributed(..) => unreachable!(),
+            }
+        }
+        let at = |&&(_, span): &&Spanned<&str>| span.file == file && span.contains(offset);
+
+        let (referent, mut uses) = if let Some(&(field, _)) = labels.fields.iter().find(at) {
+            (
+                Referent::Field(field.to_owned()),
+                uses(&labels.fields, field),
+            )
+        } else if let Some(&(tag, _)) = labels.tags.iter().find(at) {
+            (Referent::Tag(tag.to_owned()), uses(&labels.tags, tag))
+        } else {
+            let def = self.definition_at(file, offset)?;
+            let uses = self
+                .refs()
+                .iter()
+                .filter(|&&(_, d)| d == def)
+                .map(|&(span, _)| span)
+                .collect();
+            (Referent::Variable(def), uses)
+        };
+        uses.sort_by_key(|span| (span.file, span.start));
+        Some(References { referent, uses })
+    }
+}
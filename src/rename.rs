@@ -0,0 +1,344 @@
+use crate::{
+    ast::{self, Expr, Spanned, TopLevel},
+    complete,
+    error::{Error, ErrorKind, RenameError, Result},
+    lexer::{self, Token},
+    operators,
+    span::{FileId, Span},
+    ty::TypeckState,
+};
+
+// Replaces the text of `span` with `new_text`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextEdit {
+    pub span: Span,
+    pub new_text: String,
+}
+
+// Applies edits that don't overlap to the text they were made for
+pub fn apply_edits(text: &str, edits: &[TextEdit]) -> String {
+    let mut edits: Vec<_> = edits.iter().collect();
+    edits.sort_by_key(|e| e.span.start);
+    let mut out = String::new();
+    let mut pos = 0;
+    for e in edits {
+        out.push_str(&text[pos..e.span.start]);
+        out.push_str(&e.new_text);
+        pos = e.span.end;
+    }
+    out.push_str(&text[pos..]);
+    out
+}
+
+fn refused(e: RenameError, span: Span) -> Error {
+    Error::new(ErrorKind::Rename(e), span)
+}
+
+// The binders and variables of a script. Binders that are in scope in each
+// other's definitions, as in a let rec, are grouped so that renaming one
+// can't make two of the same name.
+#[derive(Default)]
+struct Names<'a> {
+    groups: Vec<Vec<Spanned<&'a str>>>,
+    // Operators included
+    uses: Vec<Spanned<&'a str>>,
+    // The operators named by fixity declarations
+    fixities: Vec<Spanned<&'a str>>,
+}
+
+impl<'a> Names<'a> {
+    fn group(&mut self, names: impl Iterator<Item = &'a Spanned<String>>) {
+        self.groups
+            .push(names.map(|(name, span)| (name.as_str(), *span)).collect());
+    }
+
+    fn top_level(&mut self, item: &'a TopLevel) {
+        match item.unattributed() {
+            TopLevel::Expr(expr) => self.expr(expr),
+            TopLevel::Test(_, expr) => self.expr(expr),
+            TopLevel::LetDef((name, expr)) | TopLevel::Const((name, expr)) => {
+                self.group(std::iter::once(name));
+                self.expr(expr);
+            }
+            TopLevel::LetRecDef(defs) => {
+                self.group(defs.iter().map(|(name, _)| name));
+                for (_, expr) in defs {
+                    self.expr(expr);
+                }
+            }
+            TopLevel::Fixity(_, _, ops) => {
+                self.fixities
+                    .extend(ops.iter().map(|(op, span)| (op.as_str(), *span)));
+            }
+            TopLevel::Include(_) | TopLevel::Macro(..) => {}
+            TopLevel::Attributed(..) => unreachable!(),
+        }
+    }
+
+    fn expr(&mut self, (expr, span): &'a Spanned<Expr>) {
+        match expr {
+            Expr::BinOp(_, (op, op_span), _) => self.uses.push((op, *op_span)),
+            Expr::Compare(_, rest) => {
+                for ((op, op_span), _) in rest {
+                    self.uses.push((op, *op_span));
+                }
+            }
+            Expr::FuncDef(arg, _) => self.group(std::iter::once(arg)),
+            Expr::Let((name, _), ..) => self.group(std::iter::once(name)),
+            Expr::LetRec(defs, _) => self.group(defs.iter().map(|(name, _)| name)),
+            Expr::Match(_, cases) => {
+                for ((_, name), _) in cases {
+                    self.group(std::iter::once(name));
+                }
+            }
+            Expr::Variable(name) => self.uses.push((name, *span)),
+            _ => {}
+        }
+        for child in ast::children(expr) {
+            self.expr(child);
+        }
+    }
+}
+
+impl Names<'_> {
+    // The fixity declarations of an operator, which are renamed along with
+    // it so that its uses parse the same. Operators the parser treats
+    // specially can't be renamed, nor can one whose declarations are shared
+    // with other definitions of the same name.
+    fn operator_fixities(&self, old: &str, new_name: &str, def: Span) -> Result<Vec<Span>> {
+        for op in [old, new_name] {
+            if operators::builtin(op).is_some() || operators::is_comparison(op) {
+                return Err(refused(RenameError::Special(op.to_owned()), def));
+            }
+        }
+        if let Some(&(_, span)) = self.fixities.iter().find(|&&(op, _)| op == new_name) {
+            return Err(refused(RenameError::HasFixity(new_name.to_owned()), span));
+        }
+        let fixities: Vec<_> = self
+            .fixities
+            .iter()
+            .filter(|&&(op, _)| op == old)
+            .map(|&(_, span)| span)
+            .collect();
+        let others = self
+            .groups
+            .iter()
+            .flatten()
+            .find(|&&(name, span)| name == old && span != def);
+        if let (Some(&(_, span)), false) = (others, fixities.is_empty()) {
+            return Err(refused(RenameError::SharedFixity(old.to_owned()), span));
+        }
+        if let Some(&span) = fixities.iter().find(|span| span.file != def.file) {
+            return Err(refused(
+                RenameError::FixityInOtherFile(old.to_owned()),
+                span,
+            ));
+        }
+        Ok(fixities)
+    }
+}
+
+// Whether `name` can stand where `old` does, as a single identifier or
+// operator token of the same kind
+fn is_valid(name: &str, old: &str) -> bool {
+    let tokens: Vec<_> = lexer::Lexer::new(name).collect();
+    match &tokens[..] {
+        [Ok((0, Token::Ident(_), end))] => *end == name.len() && !lexer::is_operator(old),
+        [Ok((0, Token::Operator(_), end))] => *end == name.len() && lexer::is_operator(old),
+        _ => false,
+    }
+}
+
+impl TypeckState {
+    // The edits that rename the variable at `offset` in `file` along with
+    // every use of it. `parsed` must be the script last checked
+    // successfully, with its includes expanded but not its macros, so that
+    // arguments a macro drops are renamed too. The edits are all in
+    // `file`. The rename is refused if it would change what any variable
+    // refers to: a use of the variable that another binder of the new name
+    // would shadow, or a use of the new name that it would capture.
+    pub fn rename(
+        &self,
+        parsed: &[TopLevel],
+        file: FileId,
+        offset: usize,
+        new_name: &str,
+    ) -> Result<Vec<TextEdit>> {
+        let at = Span::in_file(file, offset, offset);
+        let def = self
+            .definition_at(file, offset)
+            .ok_or_else(|| refused(RenameError::NoVariable, at))?;
+        if def.file != file {
+            return Err(refused(RenameError::DefinedInOtherFile, at));
+        }
+        let mut names = Names::default();
+        for item in parsed {
+            names.top_level(item);
+        }
+        let group = names
+            .groups
+            .iter()
+            .find(|group| group.iter().any(|&(_, span)| span == def))
+            .ok_or_else(|| refused(RenameError::NotInScript, at))?;
+        let old = group.iter().find(|&&(_, span)| span == def).unwrap().0;
+        if !is_valid(new_name, old) {
+            let e = RenameError::InvalidName(old.to_owned(), new_name.to_owned());
+            return Err(refused(e, def));
+        }
+        if old == new_name {
+            return Ok(vec![]);
+        }
+        if let Some(&(_, span)) = group.iter().find(|&&(name, _)| name == new_name) {
+            return Err(refused(
+                RenameError::AlreadyDefined(new_name.to_owned()),
+                span,
+            ));
+        }
+
+        // The binder a variable named `name` at `span` would refer to,
+        // between the renamed one and those of the new name
+        let innermost = |span: Span| {
+            complete::binders_in_scope(parsed, span.file, span.start)
+                .into_iter()
+                .rev()
+                .find(|&(name, binder, _)| name == new_name || binder == def)
+                .map(|(_, binder, _)| binder)
+        };
+        // The checker knows the uses in macro bodies, and the scopes of the
+        // script those in arguments, which may not be in the expansion
+        let mut uses: Vec<_> = self
+            .refs()
+            .iter()
+            .filter(|&&(span, d)| d == def && span.file == file)
+            .map(|&(span, _)| span)
+            .chain(
+                names
+                    .uses
+                    .iter()
+                    .filter(|&&(name, span)| name == old && span.file == file)
+                    .filter(|&&(_, span)| {
+                        complete::binders_in_scope(parsed, file, span.start)
+                            .into_iter()
+                            .rev()
+                            .find(|&(name, _, _)| name == old)
+                            .is_some_and(|(_, binder, _)| binder == def)
+                    })
+                    .map(|&(_, span)| span),
+            )
+            .collect();
+        uses.sort_by_key(|span| span.start);
+        uses.dedup();
+        for &span in &uses {
+            if let Some(binder) = innermost(span).filter(|&binder| binder != def) {
+                let e = RenameError::Shadowed(old.to_owned(), new_name.to_owned());
+                return Err(refused(e, binder));
+            }
+        }
+        // Included files can't refer to the definitions of the file
+        // including them
+        let captured = names
+            .uses
+            .iter()
+            .filter(|&&(name, span)| name == new_name && span.file == file);
+        for &(_, span) in captured {
+            if innermost(span) == Some(def) {
+                let e = RenameError::Captured(old.to_owned(), new_name.to_owned());
+                return Err(refused(e, span));
+            }
+        }
+
+        let fixities = match lexer::is_operator(old) {
+            true => names.operator_fixities(old, new_name, def)?,
+            false => vec![],
+        };
+
+        // Operators bound in parentheses keep them
+        let edit = |span: Span| TextEdit {
+            span,
+            new_text: if span.len() == old.len() {
+                new_name.to_owned()
+            } else {
+                format!("({})", new_name)
+            },
+        };
+        let mut edits: Vec<_> = std::iter::once(def)
+            .chain(uses)
+            .chain(fixities)
+            .map(edit)
+            .collect();
+        edits.sort_by_key(|e| e.span.start);
+        Ok(edits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::apply_edits;
+    use crate::{
+        error::{ErrorKind, RenameError},
+        expand_macros, parse_with_includes_using, Source, SourceMap, TypeckState,
+    };
+
+    const LIB: &str = "infixr 3 &&&;\nlet helper = fun b -> if b then false else true";
+
+    // Renames the variable at the first `^` in `main`, which can include
+    // "lib.zx"
+    fn rename(main: &str, new_name: &str) -> Result<String, RenameError> {
+        let offset = main.find('^').unwrap();
+        let text = main.replacen('^', "", 1);
+        let mut map = SourceMap::new();
+        let file = map.add(Source::new("main.zx", text.clone()));
+        let parsed = parse_with_includes_using(&mut map, file, |path| {
+            assert_eq!(path.to_str(), Some("lib.zx"));
+            Ok(Source::new("lib.zx", LIB))
+        })
+        .unwrap();
+        let mut state = TypeckState::new();
+        state
+            .check_script(&expand_macros(parsed.clone()).unwrap())
+            .unwrap();
+        match state.rename(&parsed, file, offset, new_name) {
+            Ok(edits) => Ok(apply_edits(&text, &edits)),
+            Err(e) => match e.kind {
+                ErrorKind::Rename(e) => Err(e),
+                kind => panic!("{:?}", kind),
+            },
+        }
+    }
+
+    #[test]
+    fn renames_across_includes() {
+        let main = "include \"lib.zx\";\nlet ^x = true;\nlet r = helper x";
+        assert_eq!(
+            rename(main, "helper2"),
+            Ok("include \"lib.zx\";\nlet helper2 = true;\nlet r = helper helper2".to_string())
+        );
+        let main = "include \"lib.zx\";\nlet r = ^helper true";
+        assert_eq!(rename(main, "h"), Err(RenameError::DefinedInOtherFile));
+    }
+
+    #[test]
+    fn refuses_to_change_what_variables_refer_to() {
+        let main = "let ^x = true;\nlet f = fun y -> x";
+        let shadowed = RenameError::Shadowed("x".to_string(), "y".to_string());
+        assert_eq!(rename(main, "y"), Err(shadowed));
+
+        let main = "let y = true;\nlet f = fun ^x -> y";
+        let captured = RenameError::Captured("x".to_string(), "y".to_string());
+        assert_eq!(rename(main, "y"), Err(captured));
+    }
+
+    #[test]
+    fn refuses_to_move_fixities() {
+        let main = "include \"lib.zx\";\nlet (^<+>) = both";
+        assert_eq!(
+            rename(main, "&&&"),
+            Err(RenameError::HasFixity("&&&".to_string()))
+        );
+        let main = "include \"lib.zx\";\nlet (^&&&) = both;\nlet r = true &&& false";
+        assert_eq!(
+            rename(main, "<+>"),
+            Err(RenameError::FixityInOtherFile("&&&".to_string()))
+        );
+    }
+}
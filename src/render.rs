@@ -0,0 +1,158 @@
+use crate::{
+    error::{Error, Warning},
+    source::Diagnostic,
+    source_map::SourceMap,
+    span::Span,
+};
+use std::io::IsTerminal;
+
+// Renders diagnostics and warnings for terminals, followed by the line they
+// point at with the span underlined:
+//
+//     a.zx:2:8: error: Undefined variable y
+//       |
+//     2 | f {b = y}
+//       |        ^
+
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+// Whether output to stderr should be colored: only when it is a terminal
+// and NO_COLOR isn't set, see https://no-color.org.
+pub fn stderr_supports_color() -> bool {
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    !no_color && std::io::stderr().is_terminal()
+}
+
+struct Style {
+    color: bool,
+}
+
+impl Style {
+    fn paint(&self, code: &str, text: &str) -> String {
+        if self.color {
+            format!("{}{}{}", code, text, RESET)
+        } else {
+            text.to_owned()
+        }
+    }
+}
+
+impl SourceMap {
+    // The source line is taken from the file the error's span refers to.
+    pub fn render(&self, d: &Diagnostic, color: bool) -> String {
+        let style = Style { color };
+        let mut location = format!("{}:", d.file);
+        if let Some((line, col)) = d.location {
+            location.push_str(&format!("{}:{}:", line, col));
+        }
+        let mut out = format!(
+            "{} {} {}\n",
+            style.paint(BOLD, &location),
+            style.paint(RED, "error:"),
+            style.paint(BOLD, &d.error.to_string())
+        );
+
+        out.push_str(&self.render_snippet(d.error.span, RED, &style));
+        out.push_str(&self.render_trace(&d.error));
+        out
+    }
+
+    // Same as render, for a warning, which is underlined in yellow
+    pub fn render_warning(&self, warning: &Warning, color: bool) -> String {
+        let style = Style { color };
+        let span = warning.span;
+        let location = match self.get(span.file) {
+            Some(file) => {
+                let (line, col) = file.line_col(span.start);
+                format!("{}:{}:{}:", file.source().name, line, col)
+            }
+            None => ":".to_owned(),
+        };
+        let mut out = format!(
+            "{} {} {}\n",
+            style.paint(BOLD, &location),
+            style.paint(YELLOW, "warning:"),
+            style.paint(BOLD, &warning.to_string())
+        );
+        out.push_str(&self.render_snippet(Some(span), YELLOW, &style));
+        out
+    }
+
+    fn render_snippet(&self, span: Option<Span>, underline: &str, style: &Style) -> String {
+        let mut out = String::new();
+        let span = match span {
+            Some(span) => span,
+            None => return out,
+        };
+        let file = match self.get(span.file) {
+            Some(file) => file,
+            None => return out,
+        };
+        let text = file.text();
+        if text.get(span.start..span.end.max(span.start)).is_none() {
+            return out;
+        }
+        let line_no = file.line_index(span.start);
+        let line = file.line_span(line_no);
+
+        // Tabs are kept so that the underline lines up however wide they are
+        let pad: String = text[line.start..span.start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let end = span.end.clamp(span.start, line.end.max(span.start));
+        let width = text[span.start..end].chars().count().max(1);
+
+        let number = (line_no + 1).to_string();
+        let gutter = " ".repeat(number.len());
+        let bar = style.paint(BLUE, "|");
+        out.push_str(&format!("{} {}\n", gutter, bar));
+        out.push_str(&format!(
+            "{} {} {}\n",
+            style.paint(BLUE, &number),
+            bar,
+            &text[line.start..line.end]
+        ));
+        out.push_str(&format!(
+            "{} {} {}{}\n",
+            gutter,
+            bar,
+            pad,
+            style.paint(underline, &"^".repeat(width))
+        ));
+        out
+    }
+
+    // The calls of a runtime error, one per line, innermost first. A
+    // function recursing into the same call is shown once with a count.
+    pub fn render_trace(&self, error: &Error) -> String {
+        let mut out = String::new();
+        let mut frames = error.trace.iter().peekable();
+        while let Some(frame) = frames.next() {
+            let mut repeats = 0;
+            while frames.peek() == Some(&frame) {
+                frames.next();
+                repeats += 1;
+            }
+            out.push_str("  in ");
+            out.push_str(frame.function.as_deref().unwrap_or("a function"));
+            match self.get(frame.span.file) {
+                Some(file) => {
+                    let (line, col) = file.line_col(frame.span.start);
+                    let name = &file.source().name;
+                    out.push_str(&format!(", called at {}:{}:{}", name, line, col));
+                }
+                None => out.push_str(", called from the prelude"),
+            }
+            if repeats > 0 {
+                out.push_str(&format!(" ({} more times)", repeats));
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
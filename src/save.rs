@@ -0,0 +1,443 @@
+use crate::{
+    ast,
+    span::{FileId, Span},
+    ty::{TypeCheckerCore, TypeNode, TypeckState, UTypeHead, Use, VTypeHead, Value, ID},
+};
+use std::io::{self, Read, Write};
+
+// Compact binary format: a magic header followed by LEB128 encoded numbers
+// and length prefixed strings. Version bumps change the magic.
+const CORE_MAGIC: &[u8; 4] = b"ZXC1";
+const STATE_MAGIC: &[u8; 4] = b"ZXS3";
+
+struct Writer<W> {
+    w: W,
+}
+
+impl<W: Write> Writer<W> {
+    fn num(&mut self, mut n: usize) -> io::Result<()> {
+        loop {
+            let byte = (n & 0x7f) as u8;
+            n >>= 7;
+            if n == 0 {
+                return self.w.write_all(&[byte]);
+            }
+            self.w.write_all(&[byte | 0x80])?;
+        }
+    }
+
+    fn str(&mut self, s: &str) -> io::Result<()> {
+        self.num(s.len())?;
+        self.w.write_all(s.as_bytes())
+    }
+
+    fn span(&mut self, span: Span) -> io::Result<()> {
+        self.num(span.file.0 as usize)?;
+        self.num(span.start)?;
+        self.num(span.end)
+    }
+
+    fn spanned_str(&mut self, (s, span): &ast::Spanned<String>) -> io::Result<()> {
+        self.str(s)?;
+        self.span(*span)
+    }
+}
+
+struct Reader<R> {
+    r: R,
+    // Number of nodes, to validate IDs against
+    nodes: usize,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl<R: Read> Reader<R> {
+    fn byte(&mut self) -> io::Result<u8> {
+        let mut buf = [0];
+        self.r.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn num(&mut self) -> io::Result<usize> {
+        let mut n = 0usize;
+        for shift in (0..usize::BITS).step_by(7) {
+            let byte = self.byte()?;
+            n |= ((byte & 0x7f) as usize)
+                .checked_shl(shift)
+                .ok_or_else(|| invalid("number too large"))?;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(invalid("number too large"))
+    }
+
+    fn id(&mut self) -> io::Result<ID> {
+        let i = self.num()?;
+        if i >= self.nodes {
+            return Err(invalid("node ID out of range"));
+        }
+        Ok(i)
+    }
+
+    fn str(&mut self) -> io::Result<String> {
+        let len = self.num()?;
+        let mut buf = vec![];
+        (&mut self.r).take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        String::from_utf8(buf).map_err(|_| invalid("invalid UTF-8 in string"))
+    }
+
+    fn span(&mut self) -> io::Result<Span> {
+        let file = std::convert::TryFrom::try_from(self.num()?)
+            .map_err(|_| invalid("file ID out of range"))?;
+        Ok(Span::in_file(FileId(file), self.num()?, self.num()?))
+    }
+
+    fn spanned_str(&mut self) -> io::Result<ast::Spanned<String>> {
+        Ok((self.str()?, self.span()?))
+    }
+
+    fn magic(&mut self, magic: &[u8; 4]) -> io::Result<()> {
+        let mut buf = [0; 4];
+        self.r.read_exact(&mut buf)?;
+        if &buf != magic {
+            return Err(invalid("not a saved zx type state"));
+        }
+        Ok(())
+    }
+}
+
+fn write_core<W: Write>(core: &TypeCheckerCore, w: &mut Writer<W>) -> io::Result<()> {
+    w.num(core.types.len())?;
+    for node in &core.types {
+        match node {
+            TypeNode::Var => w.num(0)?,
+            TypeNode::Value(VTypeHead::VBool) => w.num(1)?,
+            TypeNode::Value(VTypeHead::VFunc { arg, ret }) => {
+                w.num(2)?;
+                w.num(arg.0)?;
+                w.num(ret.0)?;
+            }
+            TypeNode::Value(VTypeHead::VObj { fields }) => {
+                w.num(3)?;
+                w.num(fields.len())?;
+                for (name, v) in fields {
+                    w.str(name)?;
+                    w.num(v.0)?;
+                }
+            }
+            TypeNode::Value(VTypeHead::VCase { case: (tag, v) }) => {
+                w.num(4)?;
+                w.str(tag)?;
+                w.num(v.0)?;
+            }
+            TypeNode::Use(UTypeHead::UBool) => w.num(5)?,
+            TypeNode::Use(UTypeHead::UFunc { arg, ret }) => {
+                w.num(6)?;
+                w.num(arg.0)?;
+                w.num(ret.0)?;
+            }
+            TypeNode::Use(UTypeHead::UObj { field: (name, u) }) => {
+                w.num(7)?;
+                w.str(name)?;
+                w.num(u.0)?;
+            }
+            TypeNode::Use(UTypeHead::UCase { cases }) => {
+                w.num(8)?;
+                w.num(cases.len())?;
+                for (tag, u) in cases {
+                    w.str(tag)?;
+                    w.num(u.0)?;
+                }
+            }
+            TypeNode::Value(VTypeHead::VStr) => w.num(9)?,
+            TypeNode::Use(UTypeHead::UStr) => w.num(10)?,
+            TypeNode::Value(VTypeHead::VDict { value }) => {
+                w.num(11)?;
+                w.num(value.0)?;
+            }
+            TypeNode::Use(UTypeHead::UDict { value }) => {
+                w.num(12)?;
+                w.num(value.0)?;
+            }
+            TypeNode::Value(VTypeHead::VSet { elem }) => {
+                w.num(13)?;
+                w.num(elem.0)?;
+            }
+            TypeNode::Use(UTypeHead::USet { elem }) => {
+                w.num(14)?;
+                w.num(elem.0)?;
+            }
+            TypeNode::Value(VTypeHead::VCustom(_)) | TypeNode::Use(UTypeHead::UCustom(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "custom type heads can't be saved",
+                ));
+            }
+        }
+    }
+
+    // Only downsets are stored, upsets are the same edges reversed.
+    for i in 0..core.types.len() {
+        let targets: Vec<_> = core.r.downsets(i).collect();
+        w.num(targets.len())?;
+        for j in targets {
+            w.num(j)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_core<R: Read>(r: &mut Reader<R>) -> io::Result<TypeCheckerCore> {
+    r.nodes = r.num()?;
+    let mut core = TypeCheckerCore::new();
+    for _ in 0..r.nodes {
+        let node = match r.num()? {
+            0 => TypeNode::Var,
+            1 => TypeNode::Value(VTypeHead::VBool),
+            2 => TypeNode::Value(VTypeHead::VFunc {
+                arg: Use(r.id()?),
+                ret: Value(r.id()?),
+            }),
+            3 => {
+                let mut fields = std::collections::BTreeMap::new();
+                for _ in 0..r.num()? {
+                    fields.insert(r.str()?, Value(r.id()?));
+                }
+                TypeNode::Value(VTypeHead::VObj { fields })
+            }
+            4 => TypeNode::Value(VTypeHead::VCase {
+                case: (r.str()?, Value(r.id()?)),
+            }),
+            5 => TypeNode::Use(UTypeHead::UBool),
+            6 => TypeNode::Use(UTypeHead::UFunc {
+                arg: Value(r.id()?),
+                ret: Use(r.id()?),
+            }),
+            7 => TypeNode::Use(UTypeHead::UObj {
+                field: (r.str()?, Use(r.id()?)),
+            }),
+            8 => {
+                let mut cases = std::collections::BTreeMap::new();
+                for _ in 0..r.num()? {
+                    cases.insert(r.str()?, Use(r.id()?));
+                }
+                TypeNode::Use(UTypeHead::UCase { cases })
+            }
+            9 => TypeNode::Value(VTypeHead::VStr),
+            10 => TypeNode::Use(UTypeHead::UStr),
+            11 => TypeNode::Value(VTypeHead::VDict {
+                value: Value(r.id()?),
+            }),
+            12 => TypeNode::Use(UTypeHead::UDict {
+                value: Use(r.id()?),
+            }),
+            13 => TypeNode::Value(VTypeHead::VSet {
+                elem: Value(r.id()?),
+            }),
+            14 => TypeNode::Use(UTypeHead::USet { elem: Use(r.id()?) }),
+            _ => return Err(invalid("unknown node kind")),
+        };
+        core.r.add_node(());
+        core.types.push(node);
+    }
+
+    for i in 0..r.nodes {
+        for _ in 0..r.num()? {
+            let j = r.id()?;
+            core.r.insert_closed_edge(i, j);
+        }
+    }
+    core.rebuild_head_index();
+    Ok(core)
+}
+
+impl TypeCheckerCore {
+    // Writes the flow graph so it can be loaded again without re-checking.
+    // Statistics and cancellation tokens are not saved, and graphs with
+    // custom heads are rejected.
+    pub fn save(&self, writer: impl Write) -> io::Result<()> {
+        let mut w = Writer { w: writer };
+        w.w.write_all(CORE_MAGIC)?;
+        write_core(self, &mut w)
+    }
+
+    pub fn load(reader: impl Read) -> io::Result<Self> {
+        let mut r = Reader {
+            r: reader,
+            nodes: 0,
+        };
+        r.magic(CORE_MAGIC)?;
+        read_core(&mut r)
+    }
+}
+
+impl TypeckState {
+    // Saves the core along with the global bindings and their attributes,
+    // e.g. to reload a checked library instantly in later runs.
+    pub fn save(&self, writer: impl Write) -> io::Result<()> {
+        if !self.bindings.changes.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "can't save while a scope is open",
+            ));
+        }
+        let mut w = Writer { w: writer };
+        w.w.write_all(STATE_MAGIC)?;
+        write_core(&self.core, &mut w)?;
+
+        let mut names: Vec<_> = self.bindings.m.iter().collect();
+        names.sort_by(|a, b| a.0.cmp(b.0));
+        w.num(names.len())?;
+        for (name, (v, span)) in names {
+            w.str(name)?;
+            w.num(v.0)?;
+            w.span(*span)?;
+        }
+
+        let mut attributes: Vec<_> = self.bindings.attributes.iter().collect();
+        attributes.sort_by(|a, b| a.0.cmp(b.0));
+        w.num(attributes.len())?;
+        for (name, (def, attrs)) in attributes {
+            w.str(name)?;
+            w.span(*def)?;
+            w.num(attrs.len())?;
+            for attr in attrs {
+                w.spanned_str(&attr.name)?;
+                match &attr.arg {
+                    None => w.num(0)?,
+                    Some(arg) => {
+                        w.num(1)?;
+                        w.spanned_str(arg)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn load(reader: impl Read) -> io::Result<Self> {
+        let mut r = Reader {
+            r: reader,
+            nodes: 0,
+        };
+        r.magic(STATE_MAGIC)?;
+        let mut state = TypeckState::new();
+        state.core = read_core(&mut r)?;
+        for _ in 0..r.num()? {
+            let name = r.str()?;
+            let v = Value(r.id()?);
+            let span = r.span()?;
+            state.bindings.m.insert(name, (v, span));
+        }
+        for _ in 0..r.num()? {
+            let name = r.str()?;
+            let def = r.span()?;
+            let mut attrs = vec![];
+            for _ in 0..r.num()? {
+                let attr_name = r.spanned_str()?;
+                let arg = match r.num()? {
+                    0 => None,
+                    1 => Some(r.spanned_str()?),
+                    _ => return Err(invalid("invalid attribute argument")),
+                };
+                attrs.push(ast::Attribute {
+                    name: attr_name,
+                    arg,
+                });
+            }
+            state.bindings.attributes.insert(name, (def, attrs));
+        }
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CORE_MAGIC, STATE_MAGIC};
+    use crate::{parse, ty::CustomHead, TypeCheckerCore, TypeckState};
+    use std::{io, sync::Arc};
+
+    const SCRIPT: &str = "@deprecated(\"use g\")
+let f = fun x -> {a = x; b = \"s\"};
+let g = fun r -> if r.c then `A r.d else `B r";
+
+    fn checked() -> TypeckState {
+        let mut state = TypeckState::new();
+        state.check_script(&parse(SCRIPT).unwrap()).unwrap();
+        state
+    }
+
+    fn saved(state: &TypeckState) -> Vec<u8> {
+        let mut buf = vec![];
+        state.save(&mut buf).unwrap();
+        buf
+    }
+
+    fn load_err(bytes: &[u8]) -> io::ErrorKind {
+        TypeckState::load(bytes).err().unwrap().kind()
+    }
+
+    #[test]
+    fn round_trips_checked_state() {
+        let state = checked();
+        let loaded = TypeckState::load(&saved(&state)[..]).unwrap();
+        for name in ["f", "g"] {
+            assert_eq!(loaded.display_type_of(name), state.display_type_of(name));
+        }
+        assert_eq!(
+            format!("{:?}", loaded.bindings.attributes.get("f")),
+            format!("{:?}", state.bindings.attributes.get("f"))
+        );
+        assert!(loaded.bindings.attributes.contains_key("f"));
+    }
+
+    #[test]
+    fn rejects_invalid_input() {
+        let bytes = saved(&checked());
+        for len in [2, 4, bytes.len() / 2, bytes.len() - 1] {
+            assert_eq!(load_err(&bytes[..len]), io::ErrorKind::UnexpectedEof);
+        }
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'Y';
+        assert_eq!(load_err(&bad_magic), io::ErrorKind::InvalidData);
+
+        // A function node whose return type is node 5 of 2
+        let func = [STATE_MAGIC as &[u8], &[2, 2, 0, 5]].concat();
+        assert_eq!(load_err(&func), io::ErrorKind::InvalidData);
+        // An edge to node 3 of 1
+        let edge = [CORE_MAGIC as &[u8], &[1, 0, 1, 3]].concat();
+        let err = TypeCheckerCore::load(&edge[..]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // A global bound to node 4 of 1
+        let global = [STATE_MAGIC as &[u8], &[1, 0, 0, 1, 1, b'x', 4]].concat();
+        assert_eq!(load_err(&global), io::ErrorKind::InvalidData);
+    }
+
+    #[derive(Debug)]
+    struct Handle;
+
+    impl CustomHead for Handle {
+        fn name(&self) -> &str {
+            "handle"
+        }
+    }
+
+    #[test]
+    fn rejects_unsaveable_states() {
+        let mut state = checked();
+        state.core.custom(Arc::new(Handle));
+        let err = state.save(&mut vec![]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let mut state = checked();
+        state.bindings.changes.push(("f".to_string(), None));
+        let err = state.save(&mut vec![]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,191 @@
+use crate::{
+    ast::{Expr, Spanned, TopLevel, VarDefinition},
+    span::Span,
+    ty::TypeckState,
+};
+use std::collections::HashMap;
+
+// What an identifier in a script is, for highlighting what the lexer can't
+// tell apart
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum SemanticKind {
+    // A function's argument
+    Parameter,
+    // Bound by a let or let rec in an expression, or by a match arm
+    Local,
+    // Defined at the top level
+    Global,
+    // From the prelude, the native builtins or the host
+    Builtin,
+    Field,
+    Tag,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SemanticToken {
+    pub span: Span,
+    pub kind: SemanticKind,
+}
+
+impl TypeckState {
+    // The identifiers of `parsed` in source order, which must be the script
+    // last checked successfully. Variables are classified by the binder
+    // they refer to, and those that refer to none in the script are
+    // builtins. Operators are only included when the script defines them.
+    pub fn semantic_tokens(&self, parsed: &[TopLevel]) -> Vec<SemanticToken> {
+        let mut c = Collector {
+            binders: HashMap::new(),
+            tokens: vec![],
+            uses: vec![],
+        };
+        for item in parsed {
+            c.top_level(item.unattributed());
+        }
+
+        let refs: HashMap<_, _> = self.refs().iter().copied().collect();
+        let Collector {
+            binders,
+            mut tokens,
+            uses,
+        } = c;
+        for (span, is_operator) in uses {
+            let kind = match refs.get(&span).and_then(|def| binders.get(def)) {
+                Some(&kind) => kind,
+                None if is_operator => continue,
+                None => SemanticKind::Builtin,
+            };
+            tokens.push(SemanticToken { span, kind });
+        }
+        tokens.sort_by_key(|t| (t.span.file, t.span.start));
+        tokens
+    }
+}
+
+struct Collector {
+    // The kind of each binder by its span
+    binders: HashMap<Span, SemanticKind>,
+    tokens: Vec<SemanticToken>,
+    // Variables and operators, which are classified once all the binders
+    // are known
+    uses: Vec<(Span, bool)>,
+}
+
+// Parameters made up by desugaring, such as for holes and clauses, have a
+// `#` no identifier can contain
+fn is_synthetic(name: &str) -> bool {
+    name.contains('#')
+}
+
+impl Collector {
+    fn token(&mut self, span: Span, kind: SemanticKind) {
+        self.tokens.push(SemanticToken { span, kind });
+    }
+
+    // `_` binds nothing, so it isn't a token either
+    fn binder(&mut self, (name, span): &Spanned<String>, kind: SemanticKind) {
+        if !is_synthetic(name) && name != "_" {
+            self.binders.insert(*span, kind);
+            self.token(*span, kind);
+        }
+    }
+
+    fn defs(&mut self, defs: &[VarDefinition], kind: SemanticKind) {
+        for (name, _) in defs {
+            self.binder(name, kind);
+        }
+        for (_, expr) in defs {
+            self.expr(expr);
+        }
+    }
+
+    fn top_level(&mut self, item: &TopLevel) {
+        match item {
+            TopLevel::Expr(expr) => self.expr(expr),
+            TopLevel::Test(_, expr) => self.expr(expr),
+            TopLevel::LetDef(def) | TopLevel::Const(def) => {
+                self.defs(std::slice::from_ref(def), SemanticKind::Global)
+            }
+            TopLevel::LetRecDef(defs) => self.defs(defs, SemanticKind::Global),
+            TopLevel::Include(_) | TopLevel::Macro(..) | TopLevel::Fixity(..) => {}
+            TopLevel::Attributed(_, item) => self.top_level(item),
+        }
+    }
+
+    fn expr(&mut self, (expr, span): &Spanned<Expr>) {
+        match expr {
+            Expr::BinOp(lhs, (_, op_span), rhs) => {
+                self.expr(lhs);
+                self.uses.push((*op_span, true));
+                self.expr(rhs);
+            }
+            Expr::Compare(first, rest) => {
+                self.expr(first);
+                for ((_, op_span), expr) in rest {
+                    self.uses.push((*op_span, true));
+                    self.expr(expr);
+                }
+            }
+            Expr::Call(func, arg) => {
+                self.expr(func);
+                self.expr(arg);
+            }
+            Expr::Case((_, tag_span), expr) => {
+                self.token(*tag_span, SemanticKind::Tag);
+                self.expr(expr);
+            }
+            Expr::FieldAccess(expr, (_, field_span)) => {
+                self.expr(expr);
+                self.token(*field_span, SemanticKind::Field);
+            }
+            Expr::FuncDef(arg, body) => {
+                self.binder(arg, SemanticKind::Parameter);
+                self.expr(body);
+            }
+            Expr::If(cond, then_expr, else_expr, _) => {
+                self.expr(cond);
+                self.expr(then_expr);
+                self.expr(else_expr);
+            }
+            Expr::Let(def, rest, _) => {
+                self.defs(std::slice::from_ref(def), SemanticKind::Local);
+                self.expr(rest);
+            }
+            Expr::LetRec(defs, rest) => {
+                self.defs(defs, SemanticKind::Local);
+                self.expr(rest);
+            }
+            Expr::Literal(_) => {}
+            Expr::MacroCall(_, args) => {
+                for arg in args {
+                    self.expr(arg);
+                }
+            }
+            Expr::Match(expr, cases) => {
+                self.expr(expr);
+                for (((_, tag_span), name), rhs) in cases {
+                    self.token(*tag_span, SemanticKind::Tag);
+                    self.binder(name, SemanticKind::Local);
+                    self.expr(rhs);
+                }
+            }
+            Expr::Record(fields) => {
+                for ((_, field_span), expr) in fields {
+                    self.token(*field_span, SemanticKind::Field);
+                    self.expr(expr);
+                }
+            }
+            Expr::Dict(entries) => {
+                for (_, expr) in entries {
+                    self.expr(expr);
+                }
+            }
+            Expr::Set(items) => {
+                for expr in items {
+                    self.expr(expr);
+                }
+            }
+            Expr::Variable(name) if is_synthetic(name) => {}
+            Expr::Variable(_) => self.uses.push((*span, false)),
+        }
+    }
+}
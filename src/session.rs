@@ -0,0 +1,136 @@
+use crate::{ast, config::CheckConfig, error::Result, ty::TypeckState};
+use std::ops::Deref;
+
+// A checked library that many scripts are checked against, such as user
+// submitted snippets on a server. Each script is checked in isolation and
+// leaves the library unchanged.
+pub struct Session {
+    state: TypeckState,
+}
+
+impl Session {
+    // Checks `library` on top of the prelude.
+    pub fn new(library: &[ast::TopLevel]) -> Result<Self> {
+        Self::with_config(library, CheckConfig::default())
+    }
+
+    // Same as new, but checking the library and every script as `config`
+    // says, e.g. in strict mode
+    pub fn with_config(library: &[ast::TopLevel], config: CheckConfig) -> Result<Self> {
+        let mut state = TypeckState::with_config(config);
+        state.check_script(library)?;
+        Ok(Self { state })
+    }
+
+    pub fn from_state(state: TypeckState) -> Self {
+        Self { state }
+    }
+
+    pub fn library(&self) -> &TypeckState {
+        &self.state
+    }
+
+    // Checks a script, returning the displayed type of every top level item
+    // along with the name it defines, if any.
+    pub fn check(&mut self, parsed: &[ast::TopLevel]) -> Result<Vec<(Option<String>, String)>> {
+        self.state.check_isolated(parsed, |state, types| {
+            types
+                .iter()
+                .map(|(name, v)| (name.clone(), state.core().display(*v)))
+                .collect()
+        })
+    }
+
+    // Same as check, but gives `f` access to the state while the script's
+    // definitions are still in scope, e.g. to query types at offsets.
+    pub fn check_with<T>(
+        &mut self,
+        parsed: &[ast::TopLevel],
+        f: impl FnOnce(&TypeckState) -> T,
+    ) -> Result<T> {
+        self.state.check_isolated(parsed, |state, _| f(state))
+    }
+
+    // An independent copy of the library, for checking on other threads.
+    pub fn fork(&self) -> Self {
+        Self {
+            state: self.state.fork(),
+        }
+    }
+}
+
+// A scope on top of a state's globals, from TypeckState::sandbox. Scripts
+// checked in it see the globals and each other's definitions, but all of
+// it is removed from the state when the sandbox is dropped.
+pub struct Sandbox<'a> {
+    state: &'a mut TypeckState,
+}
+
+impl Sandbox<'_> {
+    // Same as TypeckState::check_script. A failed script only rolls back
+    // its own changes, not those of earlier scripts in the sandbox.
+    pub fn check_script(&mut self, parsed: &[ast::TopLevel]) -> Result<()> {
+        self.state.check_in_scope(parsed).map(|_| ())
+    }
+}
+
+impl Deref for Sandbox<'_> {
+    type Target = TypeckState;
+
+    fn deref(&self) -> &TypeckState {
+        self.state
+    }
+}
+
+impl Drop for Sandbox<'_> {
+    fn drop(&mut self) {
+        self.state.end_scope();
+    }
+}
+
+impl TypeckState {
+    pub fn sandbox(&mut self) -> Sandbox<'_> {
+        self.begin_scope();
+        Sandbox { state: self }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{parse, TypeckState};
+
+    #[test]
+    fn dropped_sandbox_leaves_no_bindings() {
+        let mut state = TypeckState::new();
+        let library = "@deprecated(\"use lib2\")\nlet lib = true";
+        state.check_script(&parse(library).unwrap()).unwrap();
+        let globals = |state: &TypeckState| {
+            let mut names: Vec<_> = state.bindings.m.keys().cloned().collect();
+            names.sort();
+            let mut attributes: Vec<_> = state.bindings.attributes.iter().collect();
+            attributes.sort_by_key(|&(name, _)| name);
+            let attributes = format!("{:?}", attributes);
+            let stats = state.core().stats();
+            (names, attributes, stats.nodes, stats.edges)
+        };
+        let before = globals(&state);
+
+        // Redefining a global drops its attributes while the sandbox lasts
+        let mut sandbox = state.sandbox();
+        let script = "let a = lib;\n@deprecated\nlet lib = fun x -> x";
+        sandbox.check_script(&parse(script).unwrap()).unwrap();
+        let failing = "@deprecated\nlet b = true.x";
+        assert!(sandbox.check_script(&parse(failing).unwrap()).is_err());
+        sandbox
+            .check_script(&parse("let c = lib a").unwrap())
+            .unwrap();
+        assert!(sandbox.type_of("c").is_some());
+        drop(sandbox);
+
+        assert_eq!(globals(&state), before);
+        assert_eq!(state.display_type_of("lib"), Some("bool".to_string()));
+        for name in ["a", "b", "c"] {
+            assert!(state.type_of(name).is_none(), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use crate::{
+    error::{Error, ErrorKind, Result, SignatureError},
+    simplify::TypeExpr,
+    ty::{CustomHead, TypeCheckerCore, TypeckState, Use, Value},
+};
+use std::{collections::HashMap, sync::Arc};
+
+// A host type named in a written out type
+#[derive(Debug)]
+struct Named(String);
+
+impl CustomHead for Named {
+    fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+// A type variable of a signature. The binding can't know anything about
+// it, so it only accepts itself, which makes a binding that assumes more
+// about its arguments than the signature fail to check.
+#[derive(Debug)]
+struct Rigid(String);
+
+impl CustomHead for Rigid {
+    fn name(&self) -> &str {
+        &self.0
+    }
+
+    fn accepts(&self, rhs: &dyn CustomHead) -> bool {
+        std::ptr::addr_eq(self, rhs)
+    }
+}
+
+fn unsupported(e: SignatureError) -> Error {
+    Error::unspanned(ErrorKind::Signature(e))
+}
+
+// What the type variables of a type expression become
+enum Vars {
+    // For checking against a signature
+    Rigid(HashMap<usize, Arc<Rigid>>),
+    // For instantiating a type, with a value and use joined by a flow
+    Fresh(HashMap<usize, (Value, Use)>),
+}
+
+// Builds the types of a type expression, with the same variable for every
+// occurrence of a type variable
+struct Builder<'a> {
+    core: &'a mut TypeCheckerCore,
+    vars: Vars,
+    // The variables bound by enclosing recursive types, which stand for
+    // those types whatever the other variables become
+    recs: HashMap<usize, (Value, Use)>,
+}
+
+impl Builder<'_> {
+    fn var_value(&mut self, var: usize) -> Value {
+        if let Some(&(v, _)) = self.recs.get(&var) {
+            return v;
+        }
+        let Builder { core, vars, .. } = self;
+        match vars {
+            Vars::Rigid(vars) => {
+                let head = vars
+                    .entry(var)
+                    .or_insert_with(|| Arc::new(Rigid(format!("'{}", var))))
+                    .clone();
+                core.custom(head)
+            }
+            Vars::Fresh(vars) => vars.entry(var).or_insert_with(|| core.var()).0,
+        }
+    }
+
+    fn var_use(&mut self, var: usize) -> Use {
+        if let Some(&(_, u)) = self.recs.get(&var) {
+            return u;
+        }
+        let Builder { core, vars, .. } = self;
+        match vars {
+            Vars::Rigid(vars) => {
+                let head = vars
+                    .entry(var)
+                    .or_insert_with(|| Arc::new(Rigid(format!("'{}", var))))
+                    .clone();
+                core.custom_use(head)
+            }
+            Vars::Fresh(vars) => vars.entry(var).or_insert_with(|| core.var()).1,
+        }
+    }
+
+    // What values of the type give, in positive positions
+    fn value(&mut self, ty: &TypeExpr) -> Result<Value> {
+        Ok(match ty {
+            TypeExpr::Bottom => self.core.var().0,
+            TypeExpr::Var(var) => self.var_value(*var),
+            TypeExpr::Bool => self.core.bool(),
+            TypeExpr::Str => self.core.str(),
+            TypeExpr::Func(arg, ret) => {
+                let arg = self.use_(arg)?;
+                let ret = self.value(ret)?;
+                self.core.func(arg, ret)
+            }
+            TypeExpr::Record(fields) => {
+                let fields = fields
+                    .iter()
+                    .map(|(name, ty)| Ok((name.clone(), self.value(ty)?)))
+                    .collect::<Result<_>>()?;
+                self.core.obj(fields)
+            }
+            TypeExpr::Case(cases) => {
+                let cases = cases
+                    .iter()
+                    .map(|(tag, ty)| Ok((tag.clone(), self.value(ty)?)))
+                    .collect::<Result<_>>()?;
+                self.core.case_any(cases)
+            }
+            TypeExpr::Dict(value) => {
+                let value = self.value(value)?;
+                self.core.dict(value)
+            }
+            TypeExpr::Set(elem) => {
+                let elem = self.value(elem)?;
+                self.core.set(elem)
+            }
+            TypeExpr::Custom(name) => self.core.custom(Arc::new(Named(name.clone()))),
+            TypeExpr::Union(types) => {
+                let (v, u) = self.core.var();
+                for ty in types {
+                    let ty = self.value(ty)?;
+                    self.core.flow(ty, u)?;
+                }
+                v
+            }
+            TypeExpr::Top => return Err(unsupported(SignatureError::AnyInResult)),
+            TypeExpr::Rec(var, body) => {
+                let (v, u) = self.core.var();
+                self.recs.insert(*var, (v, u));
+                let body = self.value(body)?;
+                self.core.flow(body, u)?;
+                v
+            }
+            TypeExpr::Intersection(_) => {
+                return Err(unsupported(SignatureError::IntersectionInResult))
+            }
+        })
+    }
+
+    // What uses of the type accept, in negative positions
+    fn use_(&mut self, ty: &TypeExpr) -> Result<Use> {
+        Ok(match ty {
+            TypeExpr::Top => self.core.var().1,
+            TypeExpr::Var(var) => self.var_use(*var),
+            TypeExpr::Bool => self.core.bool_use(),
+            TypeExpr::Str => self.core.str_use(),
+            TypeExpr::Func(arg, ret) => {
+                let arg = self.value(arg)?;
+                let ret = self.use_(ret)?;
+                self.core.func_use(arg, ret)
+            }
+            TypeExpr::Record(fields) => {
+                let fields = fields
+                    .iter()
+                    .map(|(name, ty)| Ok((name.clone(), self.use_(ty)?)))
+                    .collect::<Result<_>>()?;
+                self.core.obj_use_all(fields)
+            }
+            TypeExpr::Case(cases) => {
+                let cases = cases
+                    .iter()
+                    .map(|(tag, ty)| Ok((tag.clone(), self.use_(ty)?)))
+                    .collect::<Result<_>>()?;
+                self.core.case_use(cases)
+            }
+            TypeExpr::Dict(value) => {
+                let value = self.use_(value)?;
+                self.core.dict_use(value)
+            }
+            TypeExpr::Set(elem) => {
+                let elem = self.use_(elem)?;
+                self.core.set_use(elem)
+            }
+            TypeExpr::Custom(name) => self.core.custom_use(Arc::new(Named(name.clone()))),
+            TypeExpr::Intersection(types) => {
+                let (v, u) = self.core.var();
+                for ty in types {
+                    let ty = self.use_(ty)?;
+                    self.core.flow(v, ty)?;
+                }
+                u
+            }
+            TypeExpr::Bottom => return Err(unsupported(SignatureError::NeverInArgument)),
+            TypeExpr::Rec(var, body) => {
+                let (v, u) = self.core.var();
+                self.recs.insert(*var, (v, u));
+                let body = self.use_(body)?;
+                self.core.flow(v, body)?;
+                u
+            }
+            TypeExpr::Union(_) => return Err(unsupported(SignatureError::UnionInArgument)),
+        })
+    }
+}
+
+impl TypeckState {
+    // Checks that a global defined by a previously checked script can be
+    // used wherever something of the signature's type is expected: it
+    // accepts at least what the signature does and gives at most what it
+    // promises. Variables such as `'a` stand for types the binding knows
+    // nothing about. Nothing in the state changes, the check is done on a
+    // copy.
+    pub fn check_signature(&self, name: &str, signature: &TypeExpr) -> Result<()> {
+        let &(v, def_span) = self
+            .bindings
+            .m
+            .get(name)
+            .ok_or_else(|| Error::unspanned(ErrorKind::UndefinedVariable(name.to_owned())))?;
+        self.core
+            .check_against(v, signature)
+            .map_err(|e| e.or_span(def_span))
+    }
+}
+
+impl TypeCheckerCore {
+    // The check of check_signature for any value, on a copy of the core
+    pub(crate) fn check_against(&self, v: Value, signature: &TypeExpr) -> Result<()> {
+        let mut core = self.clone();
+        let mut builder = Builder {
+            core: &mut core,
+            vars: Vars::Rigid(HashMap::new()),
+            recs: HashMap::new(),
+        };
+        let bound = builder.use_(signature)?;
+        core.flow(v, bound)
+    }
+}
+
+impl TypeCheckerCore {
+    // The nodes for a value of the given type, such as one parsed from
+    // "(bool -> 'a) -> 'a", with fresh variables for the type variables.
+    pub fn instantiate(&mut self, ty: &TypeExpr) -> Result<Value> {
+        Builder {
+            core: self,
+            vars: Vars::Fresh(HashMap::new()),
+            recs: HashMap::new(),
+        }
+        .value(ty)
+    }
+
+    // The nodes for a use accepting values of the given type
+    pub fn instantiate_use(&mut self, ty: &TypeExpr) -> Result<Use> {
+        Builder {
+            core: self,
+            vars: Vars::Fresh(HashMap::new()),
+            recs: HashMap::new(),
+        }
+        .use_(ty)
+    }
+}
+
+impl TypeckState {
+    // Registers a builtin whose type is written out, as in
+    // `register_builtin_type("apply", "('a -> 'b) -> 'a -> 'b")`. The type
+    // is instantiated again at every use, so its type variables make the
+    // builtin polymorphic.
+    pub fn register_builtin_type(&mut self, name: impl Into<String>, ty: &str) -> Result<()> {
+        let ty: TypeExpr = ty.parse()?;
+        TypeCheckerCore::new().instantiate(&ty)?;
+        self.register_builtin(name, move |core| {
+            core.instantiate(&ty)
+                .expect("instantiating a type that instantiated before")
+        });
+        Ok(())
+    }
+}
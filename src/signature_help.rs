@@ -0,0 +1,115 @@
+use crate::{
+    ast::{self, Expr, Spanned, TopLevel},
+    lexer,
+    simplify::TypeExpr,
+    span::{FileId, Span},
+    ty::TypeckState,
+};
+
+// The curried parameters of a function being called, for showing while its
+// arguments are typed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignatureHelp {
+    // The function expression
+    pub span: Span,
+    // The displayed parameter types
+    pub params: Vec<String>,
+    // The displayed type of what's left once every parameter is given
+    pub ret: String,
+    // The parameter the argument at the offset is for, which may be past
+    // the last one if the call gives more arguments than the type shows
+    pub active: usize,
+}
+
+// A function and the arguments it's called with, as in `f a b`
+struct Call<'a> {
+    span: Span,
+    func: &'a Spanned<Expr>,
+    args: Vec<&'a Spanned<Expr>>,
+}
+
+fn calls<'a>(expr: &'a Spanned<Expr>, out: &mut Vec<Call<'a>>) {
+    if let Expr::Call(..) = expr.0 {
+        let mut args = vec![];
+        let mut func = expr;
+        while let (Expr::Call(f, arg), _) = func {
+            args.push(&**arg);
+            func = f;
+        }
+        args.reverse();
+        calls(func, out);
+        for arg in &args {
+            calls(arg, out);
+        }
+        out.push(Call {
+            span: expr.1,
+            func,
+            args,
+        });
+        return;
+    }
+    for child in ast::children(&expr.0) {
+        calls(child, out);
+    }
+}
+
+impl TypeckState {
+    // The signature of the innermost call whose arguments `offset` in
+    // `source`, the first file of the script, is among. `parsed` must be the script last checked
+    // successfully, and `source` may be a later version that adds an
+    // argument being typed after the call's last one.
+    pub fn signature_at(
+        &self,
+        parsed: &[TopLevel],
+        source: &str,
+        offset: usize,
+    ) -> Option<SignatureHelp> {
+        let before = &source[..offset];
+        let typed = before
+            .trim_end_matches(lexer::is_ident_char)
+            .trim_end_matches([' ', '\t'])
+            .len();
+
+        let mut found = vec![];
+        for item in parsed {
+            match item.unattributed() {
+                TopLevel::Expr(expr) => calls(expr, &mut found),
+                TopLevel::Test(_, expr) => calls(expr, &mut found),
+                TopLevel::LetDef((_, expr)) | TopLevel::Const((_, expr)) => calls(expr, &mut found),
+                TopLevel::LetRecDef(defs) => {
+                    for (_, expr) in defs {
+                        calls(expr, &mut found);
+                    }
+                }
+                TopLevel::Include(_) | TopLevel::Macro(..) | TopLevel::Fixity(..) => {}
+                TopLevel::Attributed(..) => unreachable!(),
+            }
+        }
+        let call = found
+            .into_iter()
+            .filter(|c| c.span.file == FileId::default() && c.func.1.end < offset)
+            .filter(|c| c.span.contains(offset) || c.span.end == typed)
+            .min_by_key(|c| c.span.len())?;
+
+        let mut ty = self.core.simplify(self.type_of_span(call.func.1)?);
+        let mut params = vec![];
+        while let TypeExpr::Func(arg, ret) = ty {
+            params.push(arg.to_string());
+            ty = *ret;
+        }
+        if params.is_empty() {
+            return None;
+        }
+        let active = call
+            .args
+            .iter()
+            .position(|arg| offset <= arg.1.end)
+            .unwrap_or(call.args.len());
+        Some(SignatureHelp {
+            span: call.func.1,
+            params,
+            ret: ty.to_string(),
+            active,
+        })
+    }
+}
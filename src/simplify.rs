@@ -0,0 +1,547 @@
+use crate::ty::{TypeCheckerCore, TypeNode, UTypeHead, VTypeHead, Value, ID};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TypeExpr {
+    // Accepts any value, in negative positions
+    Top,
+    // Has no values, in positive positions
+    Bottom,
+    Var(usize),
+    Bool,
+    Str,
+    Func(Box<TypeExpr>, Box<TypeExpr>),
+    Record(Vec<(String, TypeExpr)>),
+    Case(Vec<(String, TypeExpr)>),
+    // `dict T`, a dict with values of type T
+    Dict(Box<TypeExpr>),
+    // `set T`, a set of values of type T
+    Set(Box<TypeExpr>),
+    // A host defined type, by name
+    Custom(String),
+    Union(Vec<TypeExpr>),
+    Intersection(Vec<TypeExpr>),
+    // `μ'a. T`, a recursive type, where the variable stands for the whole
+    // type wherever it appears in T
+    Rec(usize, Box<TypeExpr>),
+}
+
+impl TypeExpr {
+    // Functions bind loosest, then unions, intersections and multiple cases.
+    fn precedence(&self) -> u8 {
+        match self {
+            TypeExpr::Func(..) | TypeExpr::Rec(..) => 0,
+            TypeExpr::Union(_) | TypeExpr::Intersection(_) => 1,
+            TypeExpr::Case(cases) if cases.len() > 1 => 1,
+            _ => 2,
+        }
+    }
+
+    fn fmt_prec(&self, f: &mut fmt::Formatter<'_>, min: u8) -> fmt::Result {
+        if self.precedence() < min {
+            write!(f, "(")?;
+            self.fmt_prec(f, 0)?;
+            return write!(f, ")");
+        }
+
+        let join = |f: &mut fmt::Formatter<'_>, parts: &[TypeExpr], sep| {
+            for (i, part) in parts.iter().enumerate() {
+                if i > 0 {
+                    write!(f, "{}", sep)?;
+                }
+                part.fmt_prec(f, 2)?;
+            }
+            Ok(())
+        };
+
+        match self {
+            TypeExpr::Top => write!(f, "any"),
+            TypeExpr::Bottom => write!(f, "never"),
+            TypeExpr::Var(n) => write_var(f, *n),
+            TypeExpr::Bool => write!(f, "bool"),
+            TypeExpr::Str => write!(f, "str"),
+            TypeExpr::Func(arg, ret) => {
+                arg.fmt_prec(f, 2)?;
+                write!(f, " -> ")?;
+                ret.fmt_prec(f, 0)
+            }
+            TypeExpr::Record(fields) => {
+                write!(f, "{{")?;
+                for (i, (name, t)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", name, t)?;
+                }
+                write!(f, "}}")
+            }
+            TypeExpr::Case(cases) => {
+                for (i, (tag, t)) in cases.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " | ")?;
+                    }
+                    write!(f, "{} ", tag)?;
+                    t.fmt_prec(f, 2)?;
+                }
+                Ok(())
+            }
+            TypeExpr::Dict(value) => {
+                write!(f, "dict ")?;
+                value.fmt_prec(f, 2)
+            }
+            TypeExpr::Set(elem) => {
+                write!(f, "set ")?;
+                elem.fmt_prec(f, 2)
+            }
+            TypeExpr::Custom(name) => write!(f, "{}", name),
+            TypeExpr::Union(parts) => join(f, parts, " | "),
+            TypeExpr::Intersection(parts) => join(f, parts, " & "),
+            TypeExpr::Rec(n, body) => {
+                write!(f, "μ")?;
+                write_var(f, *n)?;
+                write!(f, ". ")?;
+                body.fmt_prec(f, 0)
+            }
+        }
+    }
+}
+
+fn write_var(f: &mut fmt::Formatter<'_>, n: usize) -> fmt::Result {
+    write!(f, "'{}", (b'a' + (n % 26) as u8) as char)?;
+    if n >= 26 {
+        write!(f, "{}", n / 26)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for TypeExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_prec(f, 0)
+    }
+}
+
+// The type heads of a set of nodes merged together, before deciding which
+// type variables are worth keeping.
+enum Raw {
+    // Refers back to the enclosing node of this position, whose type is
+    // still being built
+    Back(usize),
+    Node {
+        position: usize,
+        heads: Vec<RawHead>,
+    },
+}
+
+enum RawHead {
+    Bool,
+    Str,
+    Func(Raw, Raw),
+    Record(Vec<(String, Raw)>),
+    Case(Vec<(String, Raw)>),
+    Dict(Raw),
+    Set(Raw),
+    Custom(String),
+}
+
+struct Position {
+    positive: bool,
+    vars: BTreeSet<ID>,
+    has_bool: bool,
+    has_str: bool,
+    // Whether the type refers back to itself, so it needs a μ binder
+    recursive: bool,
+}
+
+// What type variables in the displayed type stand for
+#[derive(PartialEq, Eq, Hash)]
+enum Name {
+    Var(ID),
+    // The type of a recursive position
+    Rec(usize),
+}
+
+struct Builder<'a> {
+    core: &'a TypeCheckerCore,
+    // The positions being built, by the nodes they were built from
+    stack: Vec<((bool, Vec<ID>), usize)>,
+    positions: Vec<Position>,
+}
+
+impl<'a> Builder<'a> {
+    fn build(&mut self, positive: bool, roots: BTreeSet<ID>) -> Raw {
+        let key = (positive, roots.iter().copied().collect());
+        if let Some(&(_, position)) = self.stack.iter().find(|(k, _)| *k == key) {
+            self.positions[position].recursive = true;
+            return Raw::Back(position);
+        }
+
+        // Positive positions are described by everything flowing into them
+        // and negative ones by everything they flow into.
+        let core = self.core;
+        let mut closure = roots.clone();
+        for &i in &roots {
+            if positive {
+                closure.extend(core.r.upsets(i));
+            } else {
+                closure.extend(core.r.downsets(i));
+            }
+        }
+
+        let position = self.positions.len();
+        self.positions.push(Position {
+            positive,
+            vars: closure
+                .iter()
+                .copied()
+                .filter(|&i| matches!(core.types[i], TypeNode::Var))
+                .collect(),
+            has_bool: false,
+            has_str: false,
+            recursive: false,
+        });
+
+        self.stack.push((key, position));
+        let heads = if positive {
+            self.value_heads(&closure)
+        } else {
+            self.use_heads(&closure)
+        };
+        self.stack.pop();
+
+        self.positions[position].has_bool = heads.iter().any(|h| matches!(h, RawHead::Bool));
+        self.positions[position].has_str = heads.iter().any(|h| matches!(h, RawHead::Str));
+        Raw::Node { position, heads }
+    }
+
+    fn value_heads(&mut self, closure: &BTreeSet<ID>) -> Vec<RawHead> {
+        let core = self.core;
+        let mut is_bool = false;
+        let mut is_str = false;
+        let mut func: Option<(BTreeSet<ID>, BTreeSet<ID>)> = None;
+        let mut records = vec![];
+        let mut cases: BTreeMap<&str, BTreeSet<ID>> = BTreeMap::new();
+        let mut dict: Option<BTreeSet<ID>> = None;
+        let mut set: Option<BTreeSet<ID>> = None;
+        let mut customs = BTreeSet::new();
+        for &i in closure {
+            if let TypeNode::Value(head) = &core.types[i] {
+                match head {
+                    VTypeHead::VBool => is_bool = true,
+                    VTypeHead::VStr => is_str = true,
+                    VTypeHead::VFunc { arg, ret } => {
+                        let (args, rets) = func.get_or_insert_with(Default::default);
+                        args.insert(arg.0);
+                        rets.insert(ret.0);
+                    }
+                    VTypeHead::VObj { fields } => records.push(fields),
+                    VTypeHead::VCase { case: (tag, v) } => {
+                        cases.entry(tag).or_default().insert(v.0);
+                    }
+                    VTypeHead::VDict { value } => {
+                        dict.get_or_insert_with(Default::default).insert(value.0);
+                    }
+                    VTypeHead::VSet { elem } => {
+                        set.get_or_insert_with(Default::default).insert(elem.0);
+                    }
+                    VTypeHead::VCustom(head) => {
+                        customs.insert(head.0.name());
+                    }
+                }
+            }
+        }
+
+        let mut heads = vec![];
+        if is_bool {
+            heads.push(RawHead::Bool);
+        }
+        if is_str {
+            heads.push(RawHead::Str);
+        }
+        if let Some((args, rets)) = func {
+            heads.push(RawHead::Func(
+                self.build(false, args),
+                self.build(true, rets),
+            ));
+        }
+        if let Some((first, rest)) = records.split_first() {
+            // A union of records only has the fields common to all of them
+            let names: Vec<_> = first
+                .keys()
+                .filter(|name| rest.iter().all(|r| r.contains_key(*name)))
+                .collect();
+
+            let fields = names
+                .into_iter()
+                .map(|name| {
+                    let ids = records.iter().map(|r| r[name].0).collect();
+                    (name.clone(), self.build(true, ids))
+                })
+                .collect();
+            heads.push(RawHead::Record(fields));
+        }
+        if !cases.is_empty() {
+            let cases = cases
+                .into_iter()
+                .map(|(tag, ids)| (tag.to_owned(), self.build(true, ids)))
+                .collect();
+            heads.push(RawHead::Case(cases));
+        }
+        if let Some(values) = dict {
+            heads.push(RawHead::Dict(self.build(true, values)));
+        }
+        if let Some(elems) = set {
+            heads.push(RawHead::Set(self.build(true, elems)));
+        }
+        heads.extend(
+            customs
+                .into_iter()
+                .map(|name| RawHead::Custom(name.to_owned())),
+        );
+        heads
+    }
+
+    fn use_heads(&mut self, closure: &BTreeSet<ID>) -> Vec<RawHead> {
+        let core = self.core;
+        let mut is_bool = false;
+        let mut is_str = false;
+        let mut func: Option<(BTreeSet<ID>, BTreeSet<ID>)> = None;
+        let mut fields: BTreeMap<&str, BTreeSet<ID>> = BTreeMap::new();
+        let mut matches = vec![];
+        let mut dict: Option<BTreeSet<ID>> = None;
+        let mut set: Option<BTreeSet<ID>> = None;
+        let mut customs = BTreeSet::new();
+        for &i in closure {
+            if let TypeNode::Use(head) = &core.types[i] {
+                match head {
+                    UTypeHead::UBool => is_bool = true,
+                    UTypeHead::UStr => is_str = true,
+                    UTypeHead::UFunc { arg, ret } => {
+                        let (args, rets) = func.get_or_insert_with(Default::default);
+                        args.insert(arg.0);
+                        rets.insert(ret.0);
+                    }
+                    UTypeHead::UObj { field: (name, u) } => {
+                        fields.entry(name).or_default().insert(u.0);
+                    }
+                    UTypeHead::UCase { cases } => matches.push(cases),
+                    UTypeHead::UDict { value } => {
+                        dict.get_or_insert_with(Default::default).insert(value.0);
+                    }
+                    UTypeHead::USet { elem } => {
+                        set.get_or_insert_with(Default::default).insert(elem.0);
+                    }
+                    UTypeHead::UCustom(head) => {
+                        customs.insert(head.0.name());
+                    }
+                }
+            }
+        }
+
+        let mut heads = vec![];
+        if is_bool {
+            heads.push(RawHead::Bool);
+        }
+        if is_str {
+            heads.push(RawHead::Str);
+        }
+        if let Some((args, rets)) = func {
+            heads.push(RawHead::Func(
+                self.build(true, args),
+                self.build(false, rets),
+            ));
+        }
+        if !fields.is_empty() {
+            let fields = fields
+                .into_iter()
+                .map(|(name, ids)| (name.to_owned(), self.build(false, ids)))
+                .collect();
+            heads.push(RawHead::Record(fields));
+        }
+        if let Some((first, rest)) = matches.split_first() {
+            // Only cases handled by every match are accepted
+            let tags: Vec<_> = first
+                .keys()
+                .filter(|tag| rest.iter().all(|m| m.contains_key(*tag)))
+                .collect();
+
+            let cases = tags
+                .into_iter()
+                .map(|tag| {
+                    let ids = matches.iter().map(|m| m[tag].0).collect();
+                    (tag.clone(), self.build(false, ids))
+                })
+                .collect();
+            heads.push(RawHead::Case(cases));
+        }
+        if let Some(values) = dict {
+            heads.push(RawHead::Dict(self.build(false, values)));
+        }
+        if let Some(elems) = set {
+            heads.push(RawHead::Set(self.build(false, elems)));
+        }
+        heads.extend(
+            customs
+                .into_iter()
+                .map(|name| RawHead::Custom(name.to_owned())),
+        );
+        heads
+    }
+
+    // Removes type variables that don't tell the reader anything: ones that
+    // only appear in one polarity, ones that always appear alongside bool, or
+    // always alongside str, and ones that always appear alongside some other
+    // variable.
+    fn remove_redundant_vars(&mut self) {
+        let mut occurrences: BTreeMap<ID, (Vec<usize>, Vec<usize>)> = BTreeMap::new();
+        for (p, pos) in self.positions.iter().enumerate() {
+            for &var in &pos.vars {
+                let (neg, posv) = occurrences.entry(var).or_default();
+                if pos.positive {
+                    posv.push(p);
+                } else {
+                    neg.push(p);
+                }
+            }
+        }
+
+        for (var, (neg, pos)) in occurrences {
+            let all: Vec<_> = neg.iter().chain(&pos).copied().collect();
+            let positions = &self.positions;
+            let redundant = neg.is_empty()
+                || pos.is_empty()
+                || all.iter().all(|&p| positions[p].has_bool)
+                || all.iter().all(|&p| positions[p].has_str)
+                || positions[all[0]].vars.iter().any(|&other| {
+                    other != var && all.iter().all(|&p| positions[p].vars.contains(&other))
+                });
+
+            if redundant {
+                for p in all {
+                    self.positions[p].vars.remove(&var);
+                }
+            }
+        }
+    }
+
+    fn convert(&self, raw: &Raw, names: &mut HashMap<Name, usize>) -> TypeExpr {
+        let (p, heads) = match raw {
+            Raw::Back(p) => return TypeExpr::Var(names[&Name::Rec(*p)]),
+            Raw::Node { position, heads } => (*position, heads),
+        };
+        let position = &self.positions[p];
+        // Named before the parts, so binders come first alphabetically
+        let rec = if position.recursive {
+            let n = names.len();
+            names.insert(Name::Rec(p), n);
+            Some(n)
+        } else {
+            None
+        };
+
+        let mut parts = vec![];
+        for head in heads {
+            parts.push(match head {
+                RawHead::Bool => TypeExpr::Bool,
+                RawHead::Str => TypeExpr::Str,
+                RawHead::Func(arg, ret) => TypeExpr::Func(
+                    Box::new(self.convert(arg, names)),
+                    Box::new(self.convert(ret, names)),
+                ),
+                RawHead::Record(fields) => TypeExpr::Record(
+                    fields
+                        .iter()
+                        .map(|(name, t)| (name.clone(), self.convert(t, names)))
+                        .collect(),
+                ),
+                RawHead::Case(cases) => TypeExpr::Case(
+                    cases
+                        .iter()
+                        .map(|(tag, t)| (tag.clone(), self.convert(t, names)))
+                        .collect(),
+                ),
+                RawHead::Dict(value) => TypeExpr::Dict(Box::new(self.convert(value, names))),
+                RawHead::Set(elem) => TypeExpr::Set(Box::new(self.convert(elem, names))),
+                RawHead::Custom(name) => TypeExpr::Custom(name.clone()),
+            });
+        }
+        for &var in &position.vars {
+            let n = names.len();
+            parts.push(TypeExpr::Var(*names.entry(Name::Var(var)).or_insert(n)));
+        }
+
+        let ty = match (parts.len(), position.positive) {
+            (0, true) => TypeExpr::Bottom,
+            (0, false) => TypeExpr::Top,
+            (1, _) => parts.pop().unwrap(),
+            (_, true) => TypeExpr::Union(parts),
+            (_, false) => TypeExpr::Intersection(parts),
+        };
+        match rec {
+            Some(n) => TypeExpr::Rec(n, Box::new(ty)),
+            None => ty,
+        }
+    }
+}
+
+impl TypeCheckerCore {
+    // Reconstructs the type of `v` from the flow graph, merging heads of the
+    // same kind and dropping uninteresting intermediate type variables.
+    pub fn simplify(&self, v: Value) -> TypeExpr {
+        let mut builder = Builder {
+            core: self,
+            stack: vec![],
+            positions: vec![],
+        };
+        let raw = builder.build(true, std::iter::once(v.0).collect());
+        builder.remove_redundant_vars();
+        builder.convert(&raw, &mut HashMap::new())
+    }
+
+    // Renders the simplified type of `v` in user facing syntax, e.g.
+    // `{x: bool, y: 'a} -> 'a`. Recursive types get a binder, as in
+    // `μ'a. `Cons {head: str, tail: 'a} | `Nil {}`.
+    pub fn display(&self, v: Value) -> String {
+        self.simplify(v).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{parse, ty::TypeckState};
+
+    // The displayed type of every top level item of `script`
+    fn types(script: &str) -> Vec<String> {
+        let mut state = TypeckState::new();
+        let types = state
+            .check_script_with_types(&parse(script).unwrap())
+            .unwrap();
+        types
+            .iter()
+            .map(|(_, v)| state.core().display(*v))
+            .collect()
+    }
+
+    #[test]
+    fn displays_recursive_types() {
+        let types = types(
+            "let rec list = fun s -> `Cons {head = s; tail = list s};
+let rec tree = fun s -> `Node {left = tree s; label = s; right = tree s}",
+        );
+        assert_eq!(
+            types,
+            [
+                "'a -> `Cons (μ'b. {head: 'a, tail: `Cons 'b})",
+                "'a -> `Node (μ'b. {label: 'a, left: `Node 'b, right: `Node 'b})",
+            ]
+        );
+    }
+
+    #[test]
+    fn displays_finite_types_without_binders() {
+        let types = types("let pair = fun s -> `Node {left = `Leaf s; right = `Leaf s}");
+        assert_eq!(types, ["'a -> `Node {left: `Leaf 'a, right: `Leaf 'a}"]);
+    }
+}
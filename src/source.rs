@@ -0,0 +1,113 @@
+use crate::{
+    error::{Error, ErrorKind},
+    span::Span,
+};
+use std::{fmt, io::Read, path::Path};
+
+// A script along with the name it is reported under, such as its path or
+// `<stdin>`.
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub name: String,
+    pub text: String,
+}
+
+// An error located in a named source, printed as `name:line:col: error: msg`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub file: String,
+    // One based line and column, if the error has a span
+    pub location: Option<(usize, usize)>,
+    pub error: Error,
+}
+
+impl Source {
+    // A leading byte order mark is dropped so that spans line up with what
+    // editors show.
+    pub fn new(name: impl Into<String>, text: impl Into<String>) -> Self {
+        let mut text = text.into();
+        if text.starts_with('\u{feff}') {
+            text.drain(..'\u{feff}'.len_utf8());
+        }
+        Self {
+            name: name.into(),
+            text,
+        }
+    }
+
+    pub fn from_bytes(
+        name: impl Into<String>,
+        bytes: Vec<u8>,
+    ) -> std::result::Result<Self, Diagnostic> {
+        match String::from_utf8(bytes) {
+            Ok(text) => Ok(Self::new(name, text)),
+            Err(e) => {
+                let valid = e.utf8_error().valid_up_to();
+                let bytes = e.into_bytes();
+                let prefix = Self::new(name, String::from_utf8_lossy(&bytes[..valid]));
+                let end = prefix.text.len();
+                let error = Error::new(ErrorKind::InvalidUtf8, Span::new(end, end));
+                Err(prefix.diagnostic(error))
+            }
+        }
+    }
+
+    pub fn read_file(path: impl AsRef<Path>) -> std::result::Result<Self, Diagnostic> {
+        let path = path.as_ref();
+        let name = path.display().to_string();
+        match std::fs::read(path) {
+            Ok(bytes) => Self::from_bytes(name, bytes),
+            Err(e) => Err(Diagnostic::io(&name, e)),
+        }
+    }
+
+    pub fn read_stdin() -> std::result::Result<Self, Diagnostic> {
+        let name = "<stdin>";
+        let mut bytes = vec![];
+        match std::io::stdin().read_to_end(&mut bytes) {
+            Ok(_) => Self::from_bytes(name, bytes),
+            Err(e) => Err(Diagnostic::io(name, e)),
+        }
+    }
+
+    // One based line and column of a byte offset, counting columns in chars.
+    // SourceMap does the same without rescanning the text each time.
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        let before = &self.text[..offset.min(self.text.len())];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        (
+            before.matches('\n').count() + 1,
+            before[line_start..].chars().count() + 1,
+        )
+    }
+
+    pub fn diagnostic(&self, error: Error) -> Diagnostic {
+        Diagnostic {
+            file: self.name.clone(),
+            location: error.span.map(|span| self.line_col(span.start)),
+            error,
+        }
+    }
+}
+
+impl Diagnostic {
+    fn io(file: &str, e: std::io::Error) -> Self {
+        Self {
+            file: file.to_owned(),
+            location: None,
+            error: Error::unspanned(ErrorKind::Io((&e).into())),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:", self.file)?;
+        if let Some((line, col)) = self.location {
+            write!(f, "{}:{}:", line, col)?;
+        }
+        write!(f, " error: {}", self.error)
+    }
+}
+
+impl std::error::Error for Diagnostic {}
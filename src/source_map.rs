@@ -0,0 +1,175 @@
+use crate::{
+    error::Error,
+    source::{Diagnostic, Source},
+    span::{FileId, Span},
+};
+
+// A source along with the offsets its lines start at, so that positions
+// can be converted without scanning the text for newlines every time.
+// Lines end at `\n`, and a `\r` before it is not counted as part of the
+// line.
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+    source: Source,
+    line_starts: Vec<usize>,
+}
+
+// The files of a program, which spans refer to by FileId
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+pub(crate) fn line_starts(text: &str) -> Vec<usize> {
+    let newlines = text.match_indices('\n').map(|(i, _)| i + 1);
+    std::iter::once(0).chain(newlines).collect()
+}
+
+impl SourceFile {
+    pub fn new(source: Source) -> Self {
+        let line_starts = line_starts(&source.text);
+        Self {
+            source,
+            line_starts,
+        }
+    }
+
+    pub fn source(&self) -> &Source {
+        &self.source
+    }
+
+    pub fn text(&self) -> &str {
+        &self.source.text
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.line_starts.len()
+    }
+
+    // Offsets past the end of the text are treated as the end, and offsets
+    // inside a char as the start of that char.
+    fn clamp(&self, offset: usize) -> usize {
+        let text = self.text();
+        let mut offset = offset.min(text.len());
+        while !text.is_char_boundary(offset) {
+            offset -= 1;
+        }
+        offset
+    }
+
+    // Zero based index of the line containing `offset`
+    pub fn line_index(&self, offset: usize) -> usize {
+        self.line_starts.partition_point(|&start| start <= offset) - 1
+    }
+
+    // The span of a zero based line, without its line ending
+    pub fn line_span(&self, line: usize) -> Span {
+        let start = self.line_starts[line];
+        let text = self.text();
+        let mut end = self
+            .line_starts
+            .get(line + 1)
+            .map_or(text.len(), |&i| i - 1);
+        if text[start..end].ends_with('\r') {
+            end -= 1;
+        }
+        Span::new(start, end)
+    }
+
+    // One based line and column of a byte offset, counting columns in chars
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        let offset = self.clamp(offset);
+        let line = self.line_index(offset);
+        let start = self.line_starts[line];
+        (line + 1, self.text()[start..offset].chars().count() + 1)
+    }
+
+    // The byte offset of a one based line and column, as given by line_col.
+    // Columns past the end of a line give the end of the line and lines
+    // past the end the end of the text.
+    pub fn offset(&self, line: usize, column: usize) -> usize {
+        let text = self.text();
+        let start = match line.checked_sub(1).and_then(|i| self.line_starts.get(i)) {
+            Some(&start) => start,
+            None => return text.len(),
+        };
+        let end = self.line_span(self.line_index(start)).end;
+        text[start..end]
+            .char_indices()
+            .nth(column.saturating_sub(1))
+            .map_or(end, |(i, _)| start + i)
+    }
+
+    // Zero based line and column in UTF-16 code units, as used by LSP
+    pub fn utf16_position(&self, offset: usize) -> (usize, usize) {
+        let offset = self.clamp(offset);
+        let line = self.line_index(offset);
+        let start = self.line_starts[line];
+        (line, self.text()[start..offset].encode_utf16().count())
+    }
+
+    // The byte offset of a UTF-16 position. Columns past the end of a line
+    // give the end of the line and lines past the end the end of the text.
+    pub fn utf16_offset(&self, line: usize, column: usize) -> usize {
+        let text = self.text();
+        let start = match self.line_starts.get(line) {
+            Some(&start) => start,
+            None => return text.len(),
+        };
+        let mut units = 0;
+        for (i, c) in text[start..].char_indices() {
+            if units >= column || c == '\n' {
+                return start + i;
+            }
+            units += c.len_utf16();
+        }
+        text.len()
+    }
+
+    // Positions in the diagnostic assume the error's span is in this file.
+    pub fn diagnostic(&self, error: Error) -> Diagnostic {
+        Diagnostic {
+            file: self.source.name.clone(),
+            location: error.span.map(|span| self.line_col(span.start)),
+            error,
+        }
+    }
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Adds a file, which can then be parsed with parse_in_file using the
+    // returned ID.
+    pub fn add(&mut self, source: Source) -> FileId {
+        self.files.push(SourceFile::new(source));
+        FileId(self.files.len() as u32 - 1)
+    }
+
+    pub fn get(&self, file: FileId) -> Option<&SourceFile> {
+        self.files.get(file.0 as usize)
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = (FileId, &SourceFile)> {
+        self.files
+            .iter()
+            .enumerate()
+            .map(|(i, file)| (FileId(i as u32), file))
+    }
+
+    // Locates the error in the file its span refers to. Errors without a
+    // span are reported against the default file.
+    pub fn diagnostic(&self, error: Error) -> Diagnostic {
+        let id = error.span.map_or(FileId::default(), |span| span.file);
+        match self.get(id) {
+            Some(file) => file.diagnostic(error),
+            None => Diagnostic {
+                file: String::new(),
+                location: None,
+                error,
+            },
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use crate::{ast, span::Span, ty::TypeckState};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SymbolKind {
+    Function,
+    Value,
+    // A let rec of several definitions, which are its children
+    Group,
+    // An include, named by its path
+    Module,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub name_span: Span,
+    // From the name to the end of the definition
+    pub span: Span,
+    pub ty: Option<String>,
+    // Only filled in by outline
+    pub children: Vec<Symbol>,
+}
+
+fn definition(((name, name_span), expr): &ast::VarDefinition) -> Symbol {
+    Symbol {
+        name: name.clone(),
+        kind: match expr.0 {
+            ast::Expr::FuncDef(..) => SymbolKind::Function,
+            _ => SymbolKind::Value,
+        },
+        name_span: *name_span,
+        span: Span::in_file(name_span.file, name_span.start, expr.1.end),
+        ty: None,
+        children: vec![],
+    }
+}
+
+// The top level definitions of a script in source order, without types.
+pub fn symbols(parsed: &[ast::TopLevel]) -> Vec<Symbol> {
+    let mut symbols = vec![];
+    for item in parsed {
+        let defs = match item.unattributed() {
+            ast::TopLevel::Expr(_)
+            | ast::TopLevel::Include(_)
+            | ast::TopLevel::Macro(..)
+            | ast::TopLevel::Fixity(..)
+            | ast::TopLevel::Test(..) => continue,
+            ast::TopLevel::LetDef(def) | ast::TopLevel::Const(def) => std::slice::from_ref(def),
+            ast::TopLevel::LetRecDef(defs) => &defs[..],
+            ast::TopLevel::Attributed(..) => unreachable!(),
+        };
+        symbols.extend(defs.iter().map(definition));
+    }
+    symbols
+}
+
+// A definition along with the functions defined inside it
+fn outlined(def: &ast::VarDefinition) -> Symbol {
+    let mut symbol = definition(def);
+    nested(&def.1, &mut symbol.children);
+    symbol
+}
+
+// The definitions of a let rec, grouped if there are several
+fn rec_group(defs: &[ast::VarDefinition], out: &mut Vec<Symbol>) {
+    let mut children: Vec<_> = defs.iter().map(outlined).collect();
+    if children.len() == 1 {
+        out.append(&mut children);
+        return;
+    }
+    let names: Vec<_> = children.iter().map(|s| s.name.as_str()).collect();
+    let (first, last) = (&children[0], &children[children.len() - 1]);
+    let span = Span::in_file(first.span.file, first.span.start, last.span.end);
+    out.push(Symbol {
+        name: names.join(", "),
+        kind: SymbolKind::Group,
+        name_span: first.name_span,
+        span,
+        ty: None,
+        children,
+    });
+}
+
+// The functions defined by lets in an expression. Other local variables
+// are left out, as they would crowd the outline.
+fn nested((expr, _): &ast::Spanned<ast::Expr>, out: &mut Vec<Symbol>) {
+    match expr {
+        ast::Expr::Let(def, rest, _) => {
+            match def.1 .0 {
+                ast::Expr::FuncDef(..) => out.push(outlined(def)),
+                _ => nested(&def.1, out),
+            }
+            nested(rest, out);
+        }
+        ast::Expr::LetRec(defs, rest) => {
+            rec_group(defs, out);
+            nested(rest, out);
+        }
+        expr => {
+            for child in ast::children(expr) {
+                nested(child, out);
+            }
+        }
+    }
+}
+
+// The definitions of a script as a tree in source order, without types:
+// the top level definitions and includes, with let recs of several
+// definitions grouped and the functions defined inside each definition as
+// its children.
+pub fn outline(parsed: &[ast::TopLevel]) -> Vec<Symbol> {
+    let mut symbols = vec![];
+    for item in parsed {
+        match item.unattributed() {
+            ast::TopLevel::Expr(_)
+            | ast::TopLevel::Macro(..)
+            | ast::TopLevel::Fixity(..)
+            | ast::TopLevel::Test(..) => {}
+            ast::TopLevel::Include((path, span)) => symbols.push(Symbol {
+                name: path.clone(),
+                kind: SymbolKind::Module,
+                name_span: *span,
+                span: *span,
+                ty: None,
+                children: vec![],
+            }),
+            ast::TopLevel::LetDef(def) | ast::TopLevel::Const(def) => symbols.push(outlined(def)),
+            ast::TopLevel::LetRecDef(defs) => rec_group(defs, &mut symbols),
+            ast::TopLevel::Attributed(..) => unreachable!(),
+        }
+    }
+    symbols
+}
+
+impl TypeckState {
+    // Same as `symbols`, with the displayed type of each definition filled in
+    // if `parsed` is the script that was last checked successfully.
+    pub fn symbols(&self, parsed: &[ast::TopLevel]) -> Vec<Symbol> {
+        let mut symbols = symbols(parsed);
+        self.fill_types(&mut symbols);
+        symbols
+    }
+
+    // Same as `outline`, with types filled in as for `symbols`
+    pub fn outline(&self, parsed: &[ast::TopLevel]) -> Vec<Symbol> {
+        let mut symbols = outline(parsed);
+        self.fill_types(&mut symbols);
+        symbols
+    }
+
+    fn fill_types(&self, symbols: &mut [Symbol]) {
+        for sym in symbols {
+            if let SymbolKind::Function | SymbolKind::Value = sym.kind {
+                sym.ty = self
+                    .type_of_span(sym.name_span)
+                    .map(|v| self.core().display(v));
+            }
+            self.fill_types(&mut sym.children);
+        }
+    }
+}
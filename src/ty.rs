@@ -1,18 +1,68 @@
-use crate::{ast, reachability};
-use anyhow::{Context, Result};
-use std::collections::{HashMap, HashSet};
+use crate::{
+    ast, builtins,
+    config::{CheckConfig, Level, Lint},
+    error::{
+        Error, ErrorKind, FormatError, IncludeError, MacroError, Result, SignatureError, Warning,
+        WarningKind,
+    },
+    operators, prelude, reachability, simplify,
+    span::{FileId, Span},
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-pub type ID = usize;
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+use std::time::Instant;
 
-#[derive(Debug, Copy, Clone)]
-pub struct Value(ID);
+// The clock panics on wasm32-unknown-unknown, so checks there aren't timed.
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+#[derive(Clone, Copy)]
+struct Instant;
 
-#[derive(Debug, Copy, Clone)]
-pub struct Use(ID);
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+impl Instant {
+    fn now() -> Self {
+        Instant
+    }
 
-struct Bindings {
-    m: HashMap<String, Value>,
-    changes: Vec<(String, Option<Value>)>,
+    fn elapsed(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+pub use crate::reachability::ID;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Value(pub(crate) ID);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Use(pub(crate) ID);
+
+// Builds the type of a builtin. It is called again at every use, so types
+// built from fresh variables are polymorphic.
+pub type TypeBuilder = Arc<dyn Fn(&mut TypeCheckerCore) -> Value + Send + Sync>;
+
+type GlobalAttributes = HashMap<String, (Span, Vec<ast::Attribute>)>;
+
+// Each name maps to its type and the span of the binder that defined it.
+// Builtins are only looked up once no binding of the same name is in scope.
+#[derive(Clone)]
+pub(crate) struct Bindings {
+    pub(crate) m: HashMap<String, (Value, Span)>,
+    pub(crate) changes: Vec<(String, Option<(Value, Span)>)>,
+    builtins: HashMap<String, TypeBuilder>,
+    // The attributes of globals, by name, with the span of the definition
+    // they were given on so that later definitions don't inherit them
+    pub(crate) attributes: GlobalAttributes,
+    // Those from before the open scope, which are restored when it ends
+    outer_attributes: Option<GlobalAttributes>,
 }
 
 impl Bindings {
@@ -20,15 +70,38 @@ impl Bindings {
         Self {
             m: HashMap::new(),
             changes: vec![],
+            builtins: HashMap::new(),
+            attributes: HashMap::new(),
+            outer_attributes: None,
         }
     }
 
     fn get(&self, k: &str) -> Option<Value> {
-        self.m.get(k).copied()
+        self.m.get(k).map(|&(v, _)| v)
+    }
+
+    pub(crate) fn builtins(&self) -> impl Iterator<Item = (&str, &TypeBuilder)> {
+        self.builtins
+            .iter()
+            .map(|(name, build)| (name.as_str(), build))
+    }
+
+    fn def_span(&self, k: &str) -> Option<Span> {
+        self.m.get(k).map(|&(_, span)| span)
+    }
+
+    // The attributes of a global, unless it has been shadowed or redefined
+    // since they were given
+    fn attributes(&self, k: &str) -> &[ast::Attribute] {
+        match self.attributes.get(k) {
+            Some((def, attrs)) if self.def_span(k) == Some(*def) => attrs,
+            _ => &[],
+        }
     }
 
-    fn insert(&mut self, k: String, v: Value) {
-        self.m.insert(k.clone(), v);
+    fn insert(&mut self, k: String, v: Value, def: Span) {
+        let old = self.m.insert(k.clone(), (v, def));
+        self.changes.push((k, old));
     }
 
     fn in_child_scope<T>(&mut self, cb: impl FnOnce(&mut Self) -> T) -> T {
@@ -49,20 +122,147 @@ impl Bindings {
     }
 }
 
+// A type defined by the host, such as a file handle. Values flow into uses
+// of custom heads that accept them, and into nothing else.
+pub trait CustomHead: fmt::Debug + Send + Sync {
+    // Shown in types and the DOT graph
+    fn name(&self) -> &str;
+
+    fn accepts(&self, rhs: &dyn CustomHead) -> bool {
+        self.name() == rhs.name()
+    }
+}
+
+// Compared by identity, so that only the same instance is hash-consed.
 #[derive(Debug, Clone)]
-enum VTypeHead {
+pub(crate) struct Custom(pub(crate) Arc<dyn CustomHead>);
+
+impl PartialEq for Custom {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::addr_eq(Arc::as_ptr(&self.0), Arc::as_ptr(&other.0))
+    }
+}
+
+impl Eq for Custom {}
+
+impl std::hash::Hash for Custom {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        (Arc::as_ptr(&self.0) as *const () as usize).hash(state);
+    }
+}
+
+// Maps are ordered so that heads can derive Hash and everything that walks
+// their entries, including error reporting, is deterministic.
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum VTypeHead {
     VBool,
+    VStr,
     VFunc { arg: Use, ret: Value },
-    VObj { fields: HashMap<String, Value> },
+    VObj { fields: BTreeMap<String, Value> },
     VCase { case: (String, Value) },
+    VDict { value: Value },
+    VSet { elem: Value },
+    VCustom(Custom),
 }
 
-#[derive(Debug, Clone)]
-enum UTypeHead {
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum UTypeHead {
     UBool,
+    UStr,
     UFunc { arg: Value, ret: Use },
     UObj { field: (String, Use) },
-    UCase { cases: HashMap<String, Use> },
+    UCase { cases: BTreeMap<String, Use> },
+    UDict { value: Use },
+    USet { elem: Use },
+    UCustom(Custom),
+}
+
+impl VTypeHead {
+    // Pushes the nodes this head refers to, along with whether each one is
+    // used as a value (true) or a use (false).
+    fn children(&self, out: &mut Vec<(ID, bool)>) {
+        use VTypeHead::*;
+        match self {
+            VBool | VStr => {}
+            VFunc { arg, ret } => out.extend(&[(arg.0, false), (ret.0, true)]),
+            VObj { fields } => out.extend(fields.values().map(|v| (v.0, true))),
+            VCase { case: (_, v) } | VDict { value: v } | VSet { elem: v } => out.push((v.0, true)),
+            VCustom(_) => {}
+        }
+    }
+
+    fn map_ids(&self, f: impl Fn(ID) -> ID) -> Self {
+        use VTypeHead::*;
+        match self {
+            VBool => VBool,
+            VStr => VStr,
+            VFunc { arg, ret } => VFunc {
+                arg: Use(f(arg.0)),
+                ret: Value(f(ret.0)),
+            },
+            VObj { fields } => VObj {
+                fields: fields
+                    .iter()
+                    .map(|(k, v)| (k.clone(), Value(f(v.0))))
+                    .collect(),
+            },
+            VCase { case: (tag, v) } => VCase {
+                case: (tag.clone(), Value(f(v.0))),
+            },
+            VDict { value } => VDict {
+                value: Value(f(value.0)),
+            },
+            VSet { elem } => VSet {
+                elem: Value(f(elem.0)),
+            },
+            VCustom(head) => VCustom(head.clone()),
+        }
+    }
+}
+
+impl UTypeHead {
+    fn children(&self, out: &mut Vec<(ID, bool)>) {
+        use UTypeHead::*;
+        match self {
+            UBool | UStr => {}
+            UFunc { arg, ret } => out.extend(&[(arg.0, true), (ret.0, false)]),
+            UObj { field: (_, u) } | UDict { value: u } | USet { elem: u } => {
+                out.push((u.0, false))
+            }
+            UCase { cases } => out.extend(cases.values().map(|u| (u.0, false))),
+            UCustom(_) => {}
+        }
+    }
+
+    fn map_ids(&self, f: impl Fn(ID) -> ID) -> Self {
+        use UTypeHead::*;
+        match self {
+            UBool => UBool,
+            UStr => UStr,
+            UFunc { arg, ret } => UFunc {
+                arg: Value(f(arg.0)),
+                ret: Use(f(ret.0)),
+            },
+            UObj { field: (name, u) } => UObj {
+                field: (name.clone(), Use(f(u.0))),
+            },
+            UCase { cases } => UCase {
+                cases: cases
+                    .iter()
+                    .map(|(k, u)| (k.clone(), Use(f(u.0))))
+                    .collect(),
+            },
+            UDict { value } => UDict {
+                value: Use(f(value.0)),
+            },
+            USet { elem } => USet {
+                elem: Use(f(elem.0)),
+            },
+            UCustom(head) => UCustom(head.clone()),
+        }
+    }
 }
 
 fn check_heads(lhs: &VTypeHead, rhs: &UTypeHead, out: &mut Vec<(Value, Use)>) -> Result<()> {
@@ -70,7 +270,7 @@ fn check_heads(lhs: &VTypeHead, rhs: &UTypeHead, out: &mut Vec<(Value, Use)>) ->
     use VTypeHead::*;
 
     match (lhs, rhs) {
-        (VBool, UBool) => Ok(()),
+        (VBool, UBool) | (VStr, UStr) => Ok(()),
         (
             &VFunc {
                 arg: arg1,
@@ -90,30 +290,191 @@ fn check_heads(lhs: &VTypeHead, rhs: &UTypeHead, out: &mut Vec<(Value, Use)>) ->
                 out.push((*lhs, *rhs));
                 Ok(())
             }
-            None => bail!("Missing field: {}", name),
+            None => Err(Error::unspanned(ErrorKind::MissingField(name.clone()))),
         },
         (VCase { case: (name, lhs) }, UCase { cases }) => match cases.get(name) {
             Some(rhs) => {
                 out.push((*lhs, *rhs));
                 Ok(())
             }
-            None => bail!("Unhandled case: {}", name),
+            None => Err(Error::unspanned(ErrorKind::UnhandledCase(name.clone()))),
         },
-        _ => bail!("Unexpected types"),
+        (&VDict { value: lhs }, &UDict { value: rhs })
+        | (&VSet { elem: lhs }, &USet { elem: rhs }) => {
+            out.push((lhs, rhs));
+            Ok(())
+        }
+        (VCustom(lhs), UCustom(rhs)) if lhs.0.accepts(&*rhs.0) => Ok(()),
+        _ => Err(Error::unspanned(ErrorKind::UnexpectedTypes)),
     }
 }
 
-#[derive(Debug, Clone)]
-enum TypeNode {
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum TypeNode {
     Var,
     Value(VTypeHead),
     Use(UTypeHead),
 }
 
+// Running totals kept across checks, including ones that were rolled back.
+#[derive(Debug, Default, Copy, Clone)]
+struct Counters {
+    edges_inserted: usize,
+    head_checks: usize,
+    worklist_pushes: usize,
+    peak_worklist: usize,
+    widened: usize,
+    check_time: Duration,
+}
+
+impl Counters {
+    fn since(&self, before: &Counters) -> CheckCounters {
+        CheckCounters {
+            edges_inserted: self.edges_inserted - before.edges_inserted,
+            head_checks: self.head_checks - before.head_checks,
+            worklist_pushes: self.worklist_pushes - before.worklist_pushes,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Stats {
+    pub nodes: usize,
+    pub edges: usize,
+    pub edges_inserted: usize,
+    pub head_checks: usize,
+    pub worklist_pushes: usize,
+    // Largest number of pending edges and head pairs during a single flow
+    pub peak_worklist: usize,
+    // Heads merged into a summary by widening, see set_widening
+    pub widened: usize,
+    // Total time spent in the check_script family of methods
+    pub check_time: Duration,
+}
+
+// The work done by the last check of a script, whether it succeeded or not.
+// Edges include those added by closing the graph transitively, pushes count
+// both edges and pairs of heads waiting to be checked.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct CheckCounters {
+    pub edges_inserted: usize,
+    pub head_checks: usize,
+    pub worklist_pushes: usize,
+}
+
+// Shared flag that aborts a check in progress with a Cancelled error, e.g.
+// when an editor has newer text to check.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
 #[derive(Clone)]
 pub struct TypeCheckerCore {
-    r: reachability::Reachability,
-    types: Vec<TypeNode>,
+    pub(crate) r: reachability::Reachability,
+    pub(crate) types: Vec<TypeNode>,
+    // Value heads only ever have outgoing edges and use heads only incoming
+    // ones, so structurally identical heads can safely share a single node.
+    val_heads: HashMap<VTypeHead, Value>,
+    use_heads: HashMap<UTypeHead, Use>,
+    counters: Counters,
+    cancel: Option<CancelToken>,
+    // While set, the node count at the start of an isolated check and the
+    // edges it added that touch older nodes
+    journal: Option<(ID, Vec<(ID, ID)>)>,
+    widen_limit: Option<usize>,
+    widening: Widening,
+}
+
+// The shape of a value head with the nodes it refers to left out, or None
+// if it refers to none, as widening has nothing to merge for those. Heads
+// of the same shape can be merged into one.
+fn shape(head: &VTypeHead) -> Option<VTypeHead> {
+    let mut children = vec![];
+    head.children(&mut children);
+    match head {
+        _ if children.is_empty() => None,
+        // Records are merged whatever their fields
+        VTypeHead::VObj { .. } => Some(VTypeHead::VObj {
+            fields: BTreeMap::new(),
+        }),
+        _ => Some(head.map_ids(|_| 0)),
+    }
+}
+
+// Bookkeeping for widening. Once a variable has as many value heads flowing
+// into it as the limit allows, any further head gets merged into a summary
+// head of the same shape instead, which is the only one of that shape to
+// flow into the variable from then on. Every merged head flows into the
+// summary's children, so uses of the variable check the summary against
+// everything they would have checked the merged heads against, only less
+// precisely. The same goes for everything below the variable, so a merged
+// head isn't flowed on from it by any other way either. The children of
+// summaries are never widened themselves, as for recursive types that
+// would go on making summaries forever.
+#[derive(Clone, Default)]
+struct Widening {
+    // The value heads with children flowing into each variable, counted
+    // the first time they are needed
+    counts: HashMap<ID, usize>,
+    summaries: HashMap<(ID, VTypeHead), Value>,
+    summary_children: HashSet<ID>,
+    // The variables each head was merged into a summary for
+    merged: HashMap<ID, Vec<ID>>,
+}
+
+impl Widening {
+    // Whether to add the implied edge from `lhs` to `rhs`, or merge `lhs`
+    // into a summary for `rhs`, which is left to the caller
+    fn keep(
+        &mut self,
+        limit: usize,
+        types: &[TypeNode],
+        r: &reachability::Reachability,
+        lhs: ID,
+        rhs: ID,
+        merged: &mut Vec<(ID, ID)>,
+    ) -> bool {
+        let shape_of = |i: ID| match &types[i] {
+            TypeNode::Value(head) => shape(head),
+            _ => None,
+        };
+        let shape = match shape_of(lhs) {
+            Some(shape) => shape,
+            None => return true,
+        };
+        if let Some(vars) = self.merged.get(&lhs) {
+            if vars.iter().any(|&var| r.reaches(var, rhs)) {
+                return false;
+            }
+        }
+        if !matches!(types[rhs], TypeNode::Var) || self.summary_children.contains(&rhs) {
+            return true;
+        }
+        let count = self
+            .counts
+            .entry(rhs)
+            .or_insert_with(|| r.upsets(rhs).filter(|&i| shape_of(i).is_some()).count());
+        if *count >= limit && self.summaries.get(&(rhs, shape)) != Some(&Value(lhs)) {
+            self.merged.entry(lhs).or_default().push(rhs);
+            merged.push((lhs, rhs));
+            return false;
+        }
+        *count += 1;
+        true
+    }
 }
 
 impl TypeCheckerCore {
@@ -121,70 +482,489 @@ impl TypeCheckerCore {
         Self {
             r: Default::default(),
             types: vec![],
+            val_heads: HashMap::new(),
+            use_heads: HashMap::new(),
+            counters: Counters::default(),
+            cancel: None,
+            journal: None,
+            widen_limit: None,
+            widening: Widening::default(),
+        }
+    }
+
+    fn check_cancelled(&self) -> Result<()> {
+        match &self.cancel {
+            Some(token) if token.is_cancelled() => Err(Error::unspanned(ErrorKind::Cancelled)),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            nodes: self.types.len(),
+            edges: self.r.edge_count(),
+            edges_inserted: self.counters.edges_inserted,
+            head_checks: self.counters.head_checks,
+            worklist_pushes: self.counters.worklist_pushes,
+            peak_worklist: self.counters.peak_worklist,
+            widened: self.counters.widened,
+            check_time: self.counters.check_time,
+        }
+    }
+
+    // Starts recording what is added to the graph so it can be undone.
+    fn begin_isolated(&mut self) {
+        assert!(self.journal.is_none());
+        self.journal = Some((self.types.len(), vec![]));
+    }
+
+    // Removes everything added since begin_isolated.
+    fn end_isolated(&mut self) {
+        let (mark, edges) = self.journal.take().unwrap();
+        self.r.undo(mark, edges);
+        self.types.truncate(mark);
+        self.val_heads.retain(|_, v| v.0 < mark);
+        self.use_heads.retain(|_, u| u.0 < mark);
+        self.widening.counts.clear();
+        self.widening
+            .summaries
+            .retain(|&(var, _), s| var < mark && s.0 < mark);
+        self.widening.summary_children.retain(|&i| i < mark);
+        self.widening.merged.retain(|&head, _| head < mark);
+        for vars in self.widening.merged.values_mut() {
+            vars.retain(|&var| var < mark);
+        }
+    }
+
+    // Approximates the types of very large scripts, e.g. generated ones,
+    // to bound the work of checking uses: with a limit, a variable accepts
+    // at most that many value heads with parts, and the rest are merged by
+    // shape, so that e.g. the functions flowing into it beyond the limit
+    // give one function whose argument is what all of them accept. Uses
+    // below it are then checked against the summaries, not every head.
+    // Scripts that check without widening may fail with it, but not the
+    // other way around.
+    pub fn set_widening(&mut self, limit: Option<usize>) {
+        self.widen_limit = limit;
+    }
+
+    fn add_edge(&mut self, lhs: ID, rhs: ID, out: &mut Vec<(ID, ID)>, merged: &mut Vec<(ID, ID)>) {
+        let journal = &mut self.journal;
+        let types = &self.types;
+        let widening = &mut self.widening;
+        let limit = self.widen_limit;
+        self.r.add_edge_filtered(
+            lhs,
+            rhs,
+            |r, l, rr| match limit {
+                Some(limit) => widening.keep(limit, types, r, l, rr, merged),
+                None => true,
+            },
+            |l, r| {
+                out.push((l, r));
+                if let Some((mark, edges)) = journal {
+                    if l.min(r) < *mark {
+                        edges.push((l, r));
+                    }
+                }
+            },
+        );
+    }
+
+    // Flows the value head `lhs` into the summary of its shape for `var`,
+    // creating the summary if it is the first head merged
+    fn merge_into_summary(&mut self, lhs: ID, var: ID, pending: &mut Vec<(Value, Use)>) {
+        let head = match &self.types[lhs] {
+            TypeNode::Value(head) => head.clone(),
+            _ => unreachable!("merging a node that isn't a value head"),
+        };
+        let key = (var, shape(&head).unwrap());
+        let summary = match self.widening.summaries.get(&key) {
+            // Record summaries only have the fields of every record merged
+            // into them, so a record without some of them needs a narrower
+            // summary, which the old one flows into
+            Some(&summary) => match (&head, &self.types[summary.0]) {
+                (
+                    VTypeHead::VObj { fields },
+                    TypeNode::Value(old @ VTypeHead::VObj { fields: old_fields }),
+                ) if !old_fields.keys().all(|name| fields.contains_key(name)) => {
+                    let old = old.clone();
+                    let narrower = VTypeHead::VObj {
+                        fields: old_fields
+                            .iter()
+                            .filter(|(name, _)| fields.contains_key(*name))
+                            .map(|(name, v)| (name.clone(), *v))
+                            .collect(),
+                    };
+                    let narrower = self.new_summary(&narrower, key, pending);
+                    self.push_merge(&old, narrower, pending);
+                    narrower
+                }
+                _ => summary,
+            },
+            None => self.new_summary(&head, key, pending),
+        };
+        self.push_merge(&head, summary, pending);
+        self.counters.widened += 1;
+    }
+
+    // A summary of the head's shape for the variable of `key`, with fresh
+    // variables for children
+    fn new_summary(
+        &mut self,
+        head: &VTypeHead,
+        key: (ID, VTypeHead),
+        pending: &mut Vec<(Value, Use)>,
+    ) -> Value {
+        let mut children = vec![];
+        head.children(&mut children);
+        let fresh: HashMap<ID, ID> = children
+            .into_iter()
+            .map(|(i, _)| (i, self.var().0 .0))
+            .collect();
+        self.widening.summary_children.extend(fresh.values());
+        let summary = self.new_val(head.map_ids(|i| fresh[&i]));
+        pending.push((summary, Use(key.0)));
+        self.widening.summaries.insert(key, summary);
+        summary
+    }
+
+    // Queues the flows of a head's children into those of a summary of its
+    // shape, which make the head flow into the summary
+    fn push_merge(&self, head: &VTypeHead, summary: Value, pending: &mut Vec<(Value, Use)>) {
+        let summary_head = match &self.types[summary.0] {
+            TypeNode::Value(head) => head,
+            _ => unreachable!("a summary that isn't a value head"),
+        };
+        if let (
+            VTypeHead::VObj { fields },
+            VTypeHead::VObj {
+                fields: summary_fields,
+            },
+        ) = (head, summary_head)
+        {
+            for (name, v) in summary_fields {
+                pending.push((fields[name], Use(v.0)));
+            }
+            return;
+        }
+        let (mut children, mut summary_children) = (vec![], vec![]);
+        head.children(&mut children);
+        summary_head.children(&mut summary_children);
+        for ((child, is_value), (summary_child, _)) in children.into_iter().zip(summary_children) {
+            pending.push(if is_value {
+                (Value(child), Use(summary_child))
+            } else {
+                (Value(summary_child), Use(child))
+            });
         }
     }
 
     fn new_val(&mut self, val_type: VTypeHead) -> Value {
-        let i = self.r.add_node();
+        if let Some(&v) = self.val_heads.get(&val_type) {
+            return v;
+        }
+
+        let i = self.r.add_node(());
         assert!(i == self.types.len());
-        self.types.push(TypeNode::Value(val_type));
+        self.types.push(TypeNode::Value(val_type.clone()));
+        self.val_heads.insert(val_type, Value(i));
         Value(i)
     }
 
     fn new_use(&mut self, constraint: UTypeHead) -> Use {
-        let i = self.r.add_node();
+        if let Some(&u) = self.use_heads.get(&constraint) {
+            return u;
+        }
+
+        let i = self.r.add_node(());
         assert!(i == self.types.len());
-        self.types.push(TypeNode::Use(constraint));
+        self.types.push(TypeNode::Use(constraint.clone()));
+        self.use_heads.insert(constraint, Use(i));
         Use(i)
     }
 
     pub fn var(&mut self) -> (Value, Use) {
-        let i = self.r.add_node();
+        let i = self.r.add_node(());
         assert!(i == self.types.len());
         self.types.push(TypeNode::Var);
         (Value(i), Use(i))
     }
 
-    fn bool(&mut self) -> Value {
+    pub fn bool(&mut self) -> Value {
         self.new_val(VTypeHead::VBool)
     }
-    fn bool_use(&mut self) -> Use {
+    pub fn bool_use(&mut self) -> Use {
         self.new_use(UTypeHead::UBool)
     }
 
-    fn func(&mut self, arg: Use, ret: Value) -> Value {
+    pub fn str(&mut self) -> Value {
+        self.new_val(VTypeHead::VStr)
+    }
+    pub fn str_use(&mut self) -> Use {
+        self.new_use(UTypeHead::UStr)
+    }
+
+    pub fn func(&mut self, arg: Use, ret: Value) -> Value {
         self.new_val(VTypeHead::VFunc { arg, ret })
     }
-    fn func_use(&mut self, arg: Value, ret: Use) -> Use {
+    pub fn func_use(&mut self, arg: Value, ret: Use) -> Use {
         self.new_use(UTypeHead::UFunc { arg, ret })
     }
 
-    fn obj(&mut self, fields: Vec<(String, Value)>) -> Value {
+    pub fn obj(&mut self, fields: Vec<(String, Value)>) -> Value {
         let fields = fields.into_iter().collect();
         self.new_val(VTypeHead::VObj { fields })
     }
-    fn obj_use(&mut self, field: (String, Use)) -> Use {
+    pub fn obj_use(&mut self, field: (String, Use)) -> Use {
         self.new_use(UTypeHead::UObj { field })
     }
 
-    fn case(&mut self, case: (String, Value)) -> Value {
+    pub fn case(&mut self, case: (String, Value)) -> Value {
         self.new_val(VTypeHead::VCase { case })
     }
-    fn case_use(&mut self, cases: Vec<(String, Use)>) -> Use {
+    pub fn case_use(&mut self, cases: Vec<(String, Use)>) -> Use {
         let cases = cases.into_iter().collect();
         self.new_use(UTypeHead::UCase { cases })
     }
 
-    fn flow(&mut self, lhs: Value, rhs: Use) -> Result<()> {
+    // Dicts and sets can't be changed in place, so unlike mutable
+    // collections they are covariant
+    pub fn dict(&mut self, value: Value) -> Value {
+        self.new_val(VTypeHead::VDict { value })
+    }
+    pub fn dict_use(&mut self, value: Use) -> Use {
+        self.new_use(UTypeHead::UDict { value })
+    }
+
+    pub fn set(&mut self, elem: Value) -> Value {
+        self.new_val(VTypeHead::VSet { elem })
+    }
+    pub fn set_use(&mut self, elem: Use) -> Use {
+        self.new_use(UTypeHead::USet { elem })
+    }
+
+    pub fn custom(&mut self, head: Arc<dyn CustomHead>) -> Value {
+        self.new_val(VTypeHead::VCustom(Custom(head)))
+    }
+    pub fn custom_use(&mut self, head: Arc<dyn CustomHead>) -> Use {
+        self.new_use(UTypeHead::UCustom(Custom(head)))
+    }
+
+    // A use requiring all of `fields`, made of a variable flowing into one
+    // field access per field. Fresh variables have no heads to check, so
+    // the edges are added directly.
+    pub fn obj_use_all(&mut self, fields: Vec<(String, Use)>) -> Use {
+        let (v, u) = self.var();
+        for field in fields {
+            let field_use = self.obj_use(field);
+            self.add_fresh_edge(v.0, field_use.0);
+        }
+        u
+    }
+
+    // A value that may be any of `cases`, the counterpart of case_use.
+    pub fn case_any(&mut self, cases: Vec<(String, Value)>) -> Value {
+        let (v, u) = self.var();
+        for case in cases {
+            let case_val = self.case(case);
+            self.add_fresh_edge(case_val.0, u.0);
+        }
+        v
+    }
+
+    // Adds an edge into or out of a fresh variable, along with those of any
+    // summary widening merges a head into, which also only involve fresh
+    // variables
+    fn add_fresh_edge(&mut self, lhs: ID, rhs: ID) {
+        let (mut pairs, mut merged, mut pending) = (vec![], vec![], vec![]);
+        self.add_edge(lhs, rhs, &mut pairs, &mut merged);
+        for (lhs, var) in merged {
+            self.merge_into_summary(lhs, var, &mut pending);
+        }
+        for (lhs, rhs) in pending {
+            self.add_fresh_edge(lhs.0, rhs.0);
+        }
+    }
+
+    // Checks an expression without free variables on its own, giving a fresh
+    // copy of its type. Cancellation is the only way an expression that
+    // checked before can fail, so polling is paused until it is done.
+    pub(crate) fn instantiate_closed(&mut self, expr: &ast::Spanned<ast::Expr>) -> Value {
+        let cancel = self.cancel.take();
+        let res = check_expr(self, &mut Bindings::new(), &mut TypeIndex::default(), expr);
+        self.cancel = cancel;
+        res.expect("closed expression failed to check")
+    }
+
+    // Flows between heads that are known to fit together, e.g. in the types
+    // of builtins, which only cancellation could fail, so polling is paused
+    // as in instantiate_closed.
+    pub(crate) fn flow_fitting(&mut self, lhs: Value, rhs: Use) {
+        let cancel = self.cancel.take();
+        let res = self.flow(lhs, rhs);
+        self.cancel = cancel;
+        res.expect("heads that fit together failed to flow")
+    }
+
+    // An empty core that checks as this one does, for items checked on
+    // their own thread and absorbed into this one afterwards
+    fn shard(&self) -> Self {
+        let mut core = Self::new();
+        core.cancel = self.cancel.clone();
+        core.widen_limit = self.widen_limit;
+        core
+    }
+
+    // Moves all nodes of `other` into this core, returning the offset that
+    // was added to their IDs.
+    fn absorb(&mut self, other: Self) -> ID {
+        let offset = self.types.len();
+        let shift = |i| i + offset;
+        assert!(self.r.absorb(other.r) == offset);
+        for node in other.types {
+            self.types.push(match node {
+                TypeNode::Var => TypeNode::Var,
+                TypeNode::Value(head) => TypeNode::Value(head.map_ids(shift)),
+                TypeNode::Use(head) => TypeNode::Use(head.map_ids(shift)),
+            });
+        }
+        for (head, v) in other.val_heads {
+            self.val_heads
+                .entry(head.map_ids(shift))
+                .or_insert(Value(shift(v.0)));
+        }
+        for (head, u) in other.use_heads {
+            self.use_heads
+                .entry(head.map_ids(shift))
+                .or_insert(Use(shift(u.0)));
+        }
+        self.counters.edges_inserted += other.counters.edges_inserted;
+        self.counters.head_checks += other.counters.head_checks;
+        self.counters.worklist_pushes += other.counters.worklist_pushes;
+        self.counters.widened += other.counters.widened;
+        let widening = other.widening;
+        for ((var, shape), v) in widening.summaries {
+            self.widening
+                .summaries
+                .insert((shift(var), shape), Value(shift(v.0)));
+        }
+        let children = widening.summary_children.into_iter().map(shift);
+        self.widening.summary_children.extend(children);
+        for (head, vars) in widening.merged {
+            let vars = vars.into_iter().map(shift).collect();
+            self.widening.merged.insert(shift(head), vars);
+        }
+        self.counters.peak_worklist = self
+            .counters
+            .peak_worklist
+            .max(other.counters.peak_worklist);
+        offset
+    }
+
+    // Removes every node that can no longer affect the types of `roots`,
+    // renumbering the remaining nodes and updating `roots` in place.
+    pub fn compact(&mut self, roots: &mut [Value]) {
+        let n = self.types.len();
+        let mut live = vec![false; n];
+        // Values only care about what flows into them and uses about what
+        // they flow into, so nodes are visited once per polarity.
+        let mut visited = [vec![false; n], vec![false; n]];
+        let mut work: Vec<_> = roots.iter().map(|v| (v.0, true)).collect();
+        while let Some((i, positive)) = work.pop() {
+            if std::mem::replace(&mut visited[positive as usize][i], true) {
+                continue;
+            }
+
+            let related: Vec<_> = if positive {
+                self.r.upsets(i).collect()
+            } else {
+                self.r.downsets(i).collect()
+            };
+            for j in std::iter::once(i).chain(related) {
+                live[j] = true;
+                match (&self.types[j], positive) {
+                    (TypeNode::Value(head), true) => head.children(&mut work),
+                    (TypeNode::Use(head), false) => head.children(&mut work),
+                    _ => {}
+                }
+            }
+        }
+
+        let mut remap = vec![None; n];
+        let mut types = vec![];
+        for (i, node) in self.types.iter().enumerate() {
+            if live[i] {
+                remap[i] = Some(types.len());
+                types.push(node);
+            }
+        }
+
+        let map = |i: ID| remap[i].unwrap();
+        self.types = types
+            .into_iter()
+            .map(|node| match node {
+                TypeNode::Var => TypeNode::Var,
+                TypeNode::Value(head) => TypeNode::Value(head.map_ids(map)),
+                TypeNode::Use(head) => TypeNode::Use(head.map_ids(map)),
+            })
+            .collect();
+        self.r.compact(&remap);
+        assert!(self.r.len() == self.types.len());
+
+        self.rebuild_head_index();
+
+        for root in roots {
+            *root = Value(map(root.0));
+        }
+    }
+
+    // Recomputes the hash-consing tables after the node list was replaced.
+    pub(crate) fn rebuild_head_index(&mut self) {
+        self.val_heads.clear();
+        self.use_heads.clear();
+        // Summaries become ordinary heads
+        self.widening = Widening::default();
+        for (i, node) in self.types.iter().enumerate() {
+            match node {
+                TypeNode::Var => {}
+                TypeNode::Value(head) => {
+                    self.val_heads.entry(head.clone()).or_insert(Value(i));
+                }
+                TypeNode::Use(head) => {
+                    self.use_heads.entry(head.clone()).or_insert(Use(i));
+                }
+            }
+        }
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self)))]
+    pub(crate) fn flow(&mut self, lhs: Value, rhs: Use) -> Result<()> {
         let mut pending_edges = vec![(lhs, rhs)];
         let mut type_pairs_to_check = vec![];
+        let mut merged = vec![];
+        self.counters.worklist_pushes += 1;
         while let Some((lhs, rhs)) = pending_edges.pop() {
-            self.r.add_edge(lhs.0, rhs.0, &mut type_pairs_to_check);
+            self.check_cancelled()?;
+            self.add_edge(lhs.0, rhs.0, &mut type_pairs_to_check, &mut merged);
+            for (lhs, var) in merged.drain(..) {
+                self.merge_into_summary(lhs, var, &mut pending_edges);
+            }
+            let worklist = pending_edges.len() + type_pairs_to_check.len();
+            self.counters.peak_worklist = self.counters.peak_worklist.max(worklist);
+            // Every new edge is queued to have its heads checked
+            self.counters.edges_inserted += type_pairs_to_check.len();
+            self.counters.worklist_pushes += type_pairs_to_check.len();
 
             while let Some((lhs, rhs)) = type_pairs_to_check.pop() {
                 if let TypeNode::Value(lhs_head) = &self.types[lhs] {
                     if let TypeNode::Use(rhs_head) = &self.types[rhs] {
+                        #[cfg(feature = "tracing")]
+                        tracing::trace!(?lhs_head, ?rhs_head, "check heads");
+                        self.counters.head_checks += 1;
+                        let n = pending_edges.len();
                         check_heads(lhs_head, rhs_head, &mut pending_edges)?;
+                        self.counters.worklist_pushes += pending_edges.len() - n;
                     }
                 }
             }
@@ -192,189 +972,1207 @@ impl TypeCheckerCore {
         assert!(pending_edges.is_empty() && type_pairs_to_check.is_empty());
         Ok(())
     }
+
+    fn flow_at(&mut self, lhs: Value, rhs: Use, span: Span) -> Result<()> {
+        self.flow(lhs, rhs).map_err(|e| e.or_span(span))
+    }
+
+    // Whether `lhs` can flow into `rhs`, giving the error flowing it would
+    // cause, without changing the graph, e.g. to tell if an argument would
+    // fit a function. The flow is undone again like an isolated check. Inside
+    // one, everything undone is newer than what the outer journal records.
+    pub fn can_flow(&mut self, lhs: Value, rhs: Use) -> Result<()> {
+        let outer = self.journal.take();
+        self.begin_isolated();
+        let result = self.flow(lhs, rhs);
+        self.end_isolated();
+        self.journal = outer;
+        result
+    }
 }
 
-pub struct TypeckState {
+impl Default for TypeCheckerCore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The type of every expression and binder in the last checked script, and
+// the binder each variable refers to, so that tools can look things up by
+// position.
+#[derive(Default)]
+struct TypeIndex {
+    types: Vec<(Span, Value)>,
+    defs: Vec<Span>,
+    refs: Vec<(Span, Span)>,
+    warnings: Vec<Warning>,
+}
+
+impl TypeIndex {
+    fn binder(&mut self, span: Span, v: Value) {
+        self.types.push((span, v));
+        self.defs.push(span);
+    }
+
+    fn clear(&mut self) {
+        self.types.clear();
+        self.defs.clear();
+        self.refs.clear();
+        self.warnings.clear();
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisplayedType {
+    pub span: Span,
+    pub ty: String,
+}
+
+pub struct Snapshot {
     core: TypeCheckerCore,
-    bindings: Bindings,
+    bindings: HashMap<String, (Value, Span)>,
+    attributes: GlobalAttributes,
+}
+
+// Observer notified after each top level item of a script is checked.
+pub trait Progress {
+    // `elapsed` is measured from the start of the current check_script call,
+    // and `name` is the first name the item defines, if any.
+    fn item_checked(&mut self, index: usize, total: usize, name: Option<&str>, elapsed: Duration);
+}
+
+impl<F: FnMut(usize, usize, Option<&str>, Duration)> Progress for F {
+    fn item_checked(&mut self, index: usize, total: usize, name: Option<&str>, elapsed: Duration) {
+        self(index, total, name, elapsed)
+    }
+}
+
+pub struct TypeckState {
+    pub(crate) core: TypeCheckerCore,
+    pub(crate) bindings: Bindings,
+    index: TypeIndex,
+    progress: Option<Box<dyn Progress + Send>>,
+    last_check: CheckCounters,
+    config: CheckConfig,
 }
 
 impl TypeckState {
+    // A state with the prelude's definitions and the native builtins in
+    // scope.
     pub fn new() -> Self {
-        Self {
+        let mut state = Self::without_prelude();
+        prelude::register(&mut state);
+        state
+    }
+
+    // A state with only the native builtins in scope
+    pub fn without_prelude() -> Self {
+        let mut state = Self {
             core: TypeCheckerCore::new(),
             bindings: Bindings::new(),
+            index: TypeIndex::default(),
+            progress: None,
+            last_check: CheckCounters::default(),
+            config: CheckConfig::default(),
+        };
+        builtins::register(&mut state);
+        state
+    }
+
+    // Same as new, but checking scripts as `config` says
+    pub fn with_config(config: CheckConfig) -> Self {
+        let mut state = Self::new();
+        for &cap in &config.feature_gates {
+            cap.register(&mut state);
+        }
+        state.core.set_widening(config.limits.widening);
+        state.config = config;
+        state
+    }
+
+    pub fn config(&self) -> &CheckConfig {
+        &self.config
+    }
+
+    // Makes `name` available to all later scripts, with the type returned by
+    // `build`, unless a script defines a global of the same name. Builtins
+    // are not included when the state is saved.
+    pub fn register_builtin(
+        &mut self,
+        name: impl Into<String>,
+        build: impl Fn(&mut TypeCheckerCore) -> Value + Send + Sync + 'static,
+    ) {
+        self.bindings.builtins.insert(name.into(), Arc::new(build));
+    }
+
+    // Makes the builtin of `cap` available to scripts checked from now on.
+    pub fn enable(&mut self, cap: builtins::Capability) {
+        cap.register(self);
+        self.config.feature_gates.insert(cap);
+    }
+
+    // A separate copy of the globals and builtins, without the progress
+    // callback, e.g. to check scripts against them on another thread.
+    pub fn fork(&self) -> Self {
+        assert!(self.bindings.changes.is_empty());
+        Self {
+            core: self.core.clone(),
+            bindings: self.bindings.clone(),
+            index: TypeIndex::default(),
+            progress: None,
+            last_check: CheckCounters::default(),
+            config: self.config.clone(),
         }
     }
 
+    // Checks a script against the current globals and passes the state and
+    // the types of its top level items to `f`, then removes everything the
+    // script added. Unlike a snapshot, this doesn't copy the type state.
+    pub fn check_isolated<T>(
+        &mut self,
+        parsed: &[ast::TopLevel],
+        f: impl FnOnce(&Self, &[(Option<String>, Value)]) -> T,
+    ) -> Result<T> {
+        let start = Instant::now();
+        self.begin_scope();
+        let before = self.core.counters;
+        let res = self.check_items(parsed, start);
+        self.core.counters.check_time += start.elapsed();
+        self.last_check = self.core.counters.since(&before);
+        let res = res.map(|types| f(self, &types));
+        self.end_scope();
+        res
+    }
+
+    pub fn set_progress(&mut self, progress: Option<Box<dyn Progress + Send>>) {
+        self.progress = progress;
+    }
+
+    fn report_progress(
+        &mut self,
+        first: usize,
+        items: &[ast::TopLevel],
+        total: usize,
+        start: Instant,
+    ) {
+        if let Some(progress) = &mut self.progress {
+            for (i, item) in items.iter().enumerate() {
+                let name = match item.unattributed() {
+                    ast::TopLevel::Expr(_)
+                    | ast::TopLevel::Include(_)
+                    | ast::TopLevel::Macro(..)
+                    | ast::TopLevel::Fixity(..)
+                    | ast::TopLevel::Test(..) => None,
+                    ast::TopLevel::LetDef(((name, _), _))
+                    | ast::TopLevel::Const(((name, _), _)) => Some(name.as_str()),
+                    ast::TopLevel::LetRecDef(defs) => {
+                        defs.first().map(|((name, _), _)| name.as_str())
+                    }
+                    ast::TopLevel::Attributed(..) => unreachable!(),
+                };
+                progress.item_checked(first + i, total, name, start.elapsed());
+            }
+        }
+    }
+
+    // Checks a script and adds its definitions to the globals. A definition
+    // with the name of an existing global replaces it for later scripts,
+    // while definitions that used the old one keep the type they had. The
+    // old type's nodes stay around until the next compact.
     pub fn check_script(&mut self, parsed: &[ast::TopLevel]) -> Result<()> {
+        self.check_script_with_types(parsed).map(|_| ())
+    }
+
+    // Same as check_script, but also returns the inferred type of every top
+    // level item, along with the name it defines, if any. Recursive groups
+    // produce one entry per definition.
+    pub fn check_script_with_types(
+        &mut self,
+        parsed: &[ast::TopLevel],
+    ) -> Result<Vec<(Option<String>, Value)>> {
+        let types = self.check_in_scope(parsed)?;
+        // Now that script type-checked successfully, make the global definitions permanent
+        // by removing them from the changes rollback list
+        self.bindings.changes.clear();
+        Ok(types)
+    }
+
+    // Starts a scope whose definitions and type nodes are all removed again
+    // by end_scope, see Sandbox.
+    pub(crate) fn begin_scope(&mut self) {
+        assert!(self.bindings.changes.is_empty());
+        self.core.begin_isolated();
+        self.bindings.outer_attributes = Some(self.bindings.attributes.clone());
+        self.index.clear();
+    }
+
+    pub(crate) fn end_scope(&mut self) {
+        self.bindings.unwind(0);
+        if let Some(attributes) = self.bindings.outer_attributes.take() {
+            self.bindings.attributes = attributes;
+        }
+        self.index.clear();
+        self.core.end_isolated();
+    }
+
+    // Checks a script on top of the bindings in scope, leaving its own
+    // definitions in the rollback list. On error, only the changes made by
+    // this script are rolled back.
+    pub(crate) fn check_in_scope(
+        &mut self,
+        parsed: &[ast::TopLevel],
+    ) -> Result<Vec<(Option<String>, Value)>> {
+        let start = Instant::now();
         // Create temporary copy of the entire type state so we can roll
         // back all the changes if the script contains an error.
-        let mut temp = self.core.clone();
+        let temp = (self.core.clone(), self.bindings.attributes.clone());
+        let mark = self.bindings.changes.len();
+        let before = self.core.counters;
+
+        let res = self.check_items(parsed, start);
+        match res {
+            // Roll back changes to the type state and bindings
+            Err(_) => self.rollback(temp, start, mark),
+            Ok(_) => self.core.counters.check_time += start.elapsed(),
+        }
+        self.last_check = self.core.counters.since(&before);
+        res
+    }
+
+    // Checks the items of a script, reporting progress, and applies the lint
+    // levels. Whatever they added is left for the caller to remove on error.
+    fn check_items(
+        &mut self,
+        parsed: &[ast::TopLevel],
+        start: Instant,
+    ) -> Result<Vec<(Option<String>, Value)>> {
+        self.index.clear();
+        let mut types = Vec::with_capacity(parsed.len());
+        for (i, item) in parsed.iter().enumerate() {
+            check_toplevel(
+                &mut self.core,
+                &mut self.bindings,
+                &mut self.index,
+                item,
+                &mut types,
+            )?;
+            self.report_progress(i, std::slice::from_ref(item), parsed.len(), start);
+        }
+        self.apply_lint_levels(parsed)?;
+        Ok(types)
+    }
 
-        for item in parsed {
-            if let Err(e) = check_toplevel(&mut self.core, &mut self.bindings, item) {
-                // Roll back changes to the type state and bindings
-                std::mem::swap(&mut self.core, &mut temp);
-                self.bindings.unwind(0);
+    // Drops the warnings of allowed lints and fails on those of denied ones
+    fn apply_lint_levels(&mut self, parsed: &[ast::TopLevel]) -> Result<()> {
+        let config = &self.config;
+        let level = |w: &Warning| config.level(Lint::of(&w.kind));
+        if config.level(Lint::MissingAnnotation) != Level::Allow {
+            self.index.warnings.extend(missing_annotations(parsed));
+        }
+        self.index.warnings.retain(|w| level(w) != Level::Allow);
+        match self.index.warnings.iter().find(|w| level(w) == Level::Deny) {
+            Some(w) => Err(Error::new(ErrorKind::Lint(w.kind.clone()), w.span)),
+            None => Ok(()),
+        }
+    }
+
+    // Makes later checks poll `token` and stop with a Cancelled error once
+    // it is cancelled. The state is rolled back as for any other error.
+    pub fn set_cancel_token(&mut self, token: Option<CancelToken>) {
+        self.core.cancel = token;
+    }
+
+    // See TypeCheckerCore::set_widening
+    pub fn set_widening(&mut self, limit: Option<usize>) {
+        self.core.set_widening(limit);
+        self.config.limits.widening = limit;
+    }
+
+    // What the solver did during the last check_script call, unlike stats
+    // which are totals over the lifetime of the core.
+    pub fn last_check_counters(&self) -> CheckCounters {
+        self.last_check
+    }
+
+    // The warnings found in the last successfully checked script, in the
+    // order they were found.
+    pub fn warnings(&self) -> &[Warning] {
+        &self.index.warnings
+    }
+
+    pub fn core(&self) -> &TypeCheckerCore {
+        &self.core
+    }
+
+    // The inferred type of a global defined by a previously checked script.
+    pub fn type_of(&self, name: &str) -> Option<Value> {
+        self.bindings.get(name)
+    }
+
+    // The doc comments of a global, with one line per `///` line, if it has
+    // any.
+    pub fn docs(&self, name: &str) -> Option<String> {
+        let lines: Vec<_> = self
+            .bindings
+            .attributes(name)
+            .iter()
+            .filter(|attr| attr.name.0 == "doc")
+            .filter_map(|attr| attr.arg.as_ref().map(|(line, _)| line.as_str()))
+            .collect();
+        if lines.is_empty() {
+            None
+        } else {
+            Some(lines.join("\n"))
+        }
+    }
+
+    pub fn display_type_of(&self, name: &str) -> Option<String> {
+        self.type_of(name).map(|v| self.core.display(v))
+    }
+
+    // Removes a global so later scripts can't refer to it, returning its
+    // type. As with redefinition, definitions that used it are unaffected
+    // and compact reclaims its nodes. A builtin of the same name becomes
+    // visible again.
+    pub fn remove(&mut self, name: &str) -> Option<Value> {
+        assert!(self.bindings.changes.is_empty());
+        self.bindings.m.remove(name).map(|(v, _)| v)
+    }
+
+    // Every global defined by previously checked scripts, sorted by name.
+    // Builtins are not included.
+    pub fn globals(&self) -> impl Iterator<Item = (&str, Value)> {
+        let mut globals: Vec<_> = self
+            .bindings
+            .m
+            .iter()
+            .map(|(name, &(v, _))| (name.as_str(), v))
+            .collect();
+        globals.sort_unstable_by_key(|&(name, _)| name);
+        globals.into_iter()
+    }
+
+    // The type of the innermost expression or binder containing `offset` in
+    // `file`, one of the files of the last successfully checked script.
+    pub fn type_at(&self, file: FileId, offset: usize) -> Option<DisplayedType> {
+        let (span, v) = self
+            .index
+            .types
+            .iter()
+            .filter(|(span, _)| span.file == file && span.contains(offset))
+            .min_by_key(|(span, _)| span.len())?;
+        Some(DisplayedType {
+            span: *span,
+            ty: self.core.display(*v),
+        })
+    }
+
+    // The span of the binder that the variable or binder at `offset` in
+    // `file` refers to, in the last successfully checked script. The binder
+    // may be in another file.
+    pub fn definition_at(&self, file: FileId, offset: usize) -> Option<Span> {
+        let at = |span: &Span| span.file == file && span.contains(offset);
+        let refs = &self.index.refs;
+        if let Some(&(_, def)) = refs.iter().find(|(span, _)| at(span)) {
+            return Some(def);
+        }
+        self.index
+            .defs
+            .iter()
+            .copied()
+            .filter(|def| at(def))
+            .min_by_key(|def| def.len())
+    }
+
+    // Every use of the binder that the variable or binder at `offset` in
+    // `file` refers to, sorted by file and then position.
+    pub fn references_at(&self, file: FileId, offset: usize) -> Vec<Span> {
+        let def = match self.definition_at(file, offset) {
+            Some(def) => def,
+            None => return vec![],
+        };
+        let mut spans: Vec<_> = self
+            .index
+            .refs
+            .iter()
+            .filter(|&&(_, d)| d == def)
+            .map(|&(span, _)| span)
+            .collect();
+        spans.sort_by_key(|span| (span.file, span.start));
+        spans
+    }
+
+    // Each variable of the last checked script that refers to a binder in
+    // it, with the binder's span
+    pub(crate) fn refs(&self) -> &[(Span, Span)] {
+        &self.index.refs
+    }
+
+    // The type of the binder or expression with exactly this span
+    pub(crate) fn type_of_span(&self, span: Span) -> Option<Value> {
+        self.index
+            .types
+            .iter()
+            .find(|&&(s, _)| s == span)
+            .map(|&(_, v)| v)
+    }
+
+    // The type of the innermost expression ending at `end` in `file`
+    pub(crate) fn type_ending_at(&self, file: FileId, end: usize) -> Option<Value> {
+        self.index
+            .types
+            .iter()
+            .filter(|(span, _)| span.file == file && span.end == end)
+            .min_by_key(|(span, _)| span.len())
+            .map(|&(_, v)| v)
+    }
+
+    // Saves the type state and global bindings so they can be restored
+    // later, for example to re-check a script from the first item that
+    // changed.
+    pub fn snapshot(&self) -> Snapshot {
+        assert!(self.bindings.changes.is_empty());
+        Snapshot {
+            core: self.core.clone(),
+            bindings: self.bindings.m.clone(),
+            attributes: self.bindings.attributes.clone(),
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        let counters = self.core.counters;
+        let cancel = self.core.cancel.take();
+        let widen_limit = self.core.widen_limit;
+        self.core = snapshot.core.clone();
+        self.core.counters = counters;
+        self.core.cancel = cancel;
+        self.core.widen_limit = widen_limit;
+        self.bindings.m = snapshot.bindings.clone();
+        self.bindings.attributes = snapshot.attributes.clone();
+        self.bindings.changes.clear();
+        self.index.clear();
+    }
+
+    // Frees the type nodes that aren't reachable from any global binding,
+    // such as those left over from top level expressions. Must not be called
+    // while a script is being checked.
+    pub fn compact(&mut self) {
+        assert!(self.bindings.changes.is_empty());
+        let mut roots: Vec<_> = self.bindings.m.values().map(|&(v, _)| v).collect();
+        let n = roots.len();
+        roots.extend(self.index.types.iter().map(|(_, v)| *v));
+        self.core.compact(&mut roots);
+
+        for ((v, _), root) in self.bindings.m.values_mut().zip(&roots[..n]) {
+            *v = *root;
+        }
+        for ((_, v), root) in self.index.types.iter_mut().zip(&roots[n..]) {
+            *v = *root;
+        }
+    }
+
+    // Same as check_script, but runs of consecutive top level items that don't
+    // reference any other definitions are checked on multiple threads, each
+    // thread using its own core which is merged back afterwards.
+    pub fn check_script_parallel(&mut self, parsed: &[ast::TopLevel]) -> Result<()> {
+        let start = Instant::now();
+        let temp = (self.core.clone(), self.bindings.attributes.clone());
+        let before = self.core.counters;
+
+        self.index.clear();
+        let mut rest = parsed;
+        while !rest.is_empty() {
+            let first = parsed.len() - rest.len();
+            let n = rest.iter().take_while(|item| item.is_closed()).count();
+            let (batch, tail) = rest.split_at(n.max(1));
+            rest = tail;
+
+            let res = if n > 1 {
+                self.check_closed_batch(batch)
+            } else {
+                check_toplevel(
+                    &mut self.core,
+                    &mut self.bindings,
+                    &mut self.index,
+                    &batch[0],
+                    &mut vec![],
+                )
+            };
+            if let Err(e) = res {
+                self.rollback(temp, start, 0);
+                self.last_check = self.core.counters.since(&before);
                 return Err(e);
             }
+            self.report_progress(first, batch, parsed.len(), start);
+        }
+        if let Err(e) = self.apply_lint_levels(parsed) {
+            self.rollback(temp, start, 0);
+            self.last_check = self.core.counters.since(&before);
+            return Err(e);
         }
 
-        // Now that script type-checked successfully, make the global definitions permanent
-        // by removing them from the changes rollback list
         self.bindings.changes.clear();
+        self.core.counters.check_time += start.elapsed();
+        self.last_check = self.core.counters.since(&before);
         Ok(())
     }
+
+    // Restores the core and attributes saved before a failed check and the
+    // bindings from before `mark`, keeping the counters so the work done by
+    // the failed check still shows up in stats.
+    fn rollback(
+        &mut self,
+        (core, attributes): (TypeCheckerCore, GlobalAttributes),
+        start: Instant,
+        mark: usize,
+    ) {
+        let mut counters = self.core.counters;
+        counters.check_time += start.elapsed();
+        self.core = core;
+        self.core.counters = counters;
+        self.bindings.attributes = attributes;
+        self.bindings.unwind(mark);
+        self.index.clear();
+    }
+
+    fn check_closed_batch(&mut self, items: &[ast::TopLevel]) -> Result<()> {
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let chunk_size = items.len().div_ceil(threads);
+
+        let core = &self.core;
+        let shards: Vec<_> = std::thread::scope(|s| {
+            let handles: Vec<_> = items
+                .chunks(chunk_size)
+                .map(|chunk| s.spawn(move || check_shard(chunk, core.shard())))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        // Shards are merged in order so that the first error reported and
+        // the final bindings are the same as for a sequential check.
+        for shard in shards {
+            let (core, defs, attributes, index) = shard?;
+            let offset = self.core.absorb(core);
+            for (name, (v, def)) in defs {
+                self.bindings.attributes.remove(&name);
+                self.bindings.insert(name, Value(v.0 + offset), def);
+            }
+            self.bindings.attributes.extend(attributes);
+            let shifted = index
+                .types
+                .into_iter()
+                .map(|(span, v)| (span, Value(v.0 + offset)));
+            self.index.types.extend(shifted);
+            self.index.defs.extend(index.defs);
+            self.index.refs.extend(index.refs);
+            self.index.warnings.extend(index.warnings);
+        }
+        Ok(())
+    }
+}
+
+impl Default for TypeckState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+type Shard = (
+    TypeCheckerCore,
+    Vec<(String, (Value, Span))>,
+    GlobalAttributes,
+    TypeIndex,
+);
+
+fn check_shard(items: &[ast::TopLevel], mut core: TypeCheckerCore) -> Result<Shard> {
+    let mut bindings = Bindings::new();
+    let mut index = TypeIndex::default();
+    for item in items {
+        check_toplevel(&mut core, &mut bindings, &mut index, item, &mut vec![])?;
+    }
+
+    let defs = bindings
+        .changes
+        .iter()
+        .map(|(name, _)| (name.clone(), bindings.m[name]))
+        .collect();
+    Ok((core, defs, bindings.attributes, index))
 }
 
 fn check_toplevel(
     engine: &mut TypeCheckerCore,
     bindings: &mut Bindings,
+    index: &mut TypeIndex,
     def: &ast::TopLevel,
+    out: &mut Vec<(Option<String>, Value)>,
 ) -> Result<()> {
     use ast::TopLevel::*;
     match def {
         Expr(expr) => {
-            check_expr(engine, bindings, expr)?;
+            let t = check_expr(engine, bindings, index, expr)?;
+            out.push((None, t));
         }
-        LetDef((name, var_expr)) => {
-            let var_type = check_expr(engine, bindings, var_expr)?;
-            bindings.insert(name.clone(), var_type);
+        LetDef(((name, name_span), var_expr)) | Const(((name, name_span), var_expr)) => {
+            let var_type = check_expr(engine, bindings, index, var_expr)?;
+            index.binder(*name_span, var_type);
+            bindings.attributes.remove(name);
+            bindings.insert(name.clone(), var_type, *name_span);
+            out.push((Some(name.clone()), var_type));
         }
         LetRecDef(defs) => {
             let mut temp_bounds = Vec::with_capacity(defs.len());
-            for (name, _) in defs {
+            for ((name, name_span), _) in defs {
                 let (temp_type, temp_bound) = engine.var();
-                bindings.insert(name.clone(), temp_type);
+                index.binder(*name_span, temp_type);
+                bindings.attributes.remove(name);
+                bindings.insert(name.clone(), temp_type, *name_span);
                 temp_bounds.push(temp_bound);
+                out.push((Some(name.clone()), temp_type));
             }
 
             for ((_, expr), bound) in defs.iter().zip(temp_bounds) {
-                let var_type = check_expr(engine, bindings, expr)?;
-                engine.flow(var_type, bound)?;
+                let var_type = check_expr(engine, bindings, index, expr)?;
+                engine.flow_at(var_type, bound, expr.1)?;
+            }
+        }
+        Include((path, span)) => {
+            let e = IncludeError::NotExpanded(path.clone());
+            return Err(Error::new(ErrorKind::Include(e), *span));
+        }
+        // Only calls need expanding, the rules themselves have no type
+        Macro(..) => {}
+        // Already applied by the parser
+        Fixity(..) => {}
+        Test(_, expr) => {
+            let t = check_expr(engine, bindings, index, expr)?;
+            let bound = engine.bool_use();
+            engine.flow_at(t, bound, expr.1)?;
+            out.push((None, t));
+        }
+        Attributed(attrs, item) => {
+            let first = out.len();
+            check_toplevel(engine, bindings, index, item, out)?;
+            check_annotations(engine, bindings, attrs, &out[first..])?;
+            let attrs: Vec<_> = attrs.iter().map(|(attr, _)| attr.clone()).collect();
+            for name in out[first..].iter().filter_map(|(name, _)| name.as_ref()) {
+                let def = bindings.def_span(name).unwrap();
+                bindings
+                    .attributes
+                    .insert(name.clone(), (def, attrs.clone()));
             }
         }
     };
     Ok(())
 }
 
+// Checks the types of an item's `@type` attributes against the types of
+// what it defines, as for check_signature, one attribute per definition in
+// a recursive group
+fn check_annotations(
+    engine: &TypeCheckerCore,
+    bindings: &Bindings,
+    attrs: &[ast::Spanned<ast::Attribute>],
+    defined: &[(Option<String>, Value)],
+) -> Result<()> {
+    let annotations: Vec<_> = attrs
+        .iter()
+        .filter(|(attr, _)| attr.name.0 == "type")
+        .collect();
+    match annotations.first() {
+        None => return Ok(()),
+        Some((_, span)) if annotations.len() != defined.len() => {
+            let e = SignatureError::AnnotationCount(annotations.len(), defined.len());
+            return Err(Error::new(ErrorKind::Signature(e), *span));
+        }
+        Some(_) => {}
+    }
+    for ((attr, span), (name, v)) in annotations.into_iter().zip(defined) {
+        let (arg, arg_span) = attr
+            .arg
+            .as_ref()
+            .ok_or_else(|| Error::new(ErrorKind::Signature(SignatureError::MissingType), *span))?;
+        // Spans in parse errors are offsets into the argument
+        let ty: simplify::TypeExpr = arg.parse().map_err(|e: Error| Error {
+            span: Some(*arg_span),
+            ..e
+        })?;
+        let def = name.as_ref().and_then(|name| bindings.def_span(name));
+        engine
+            .check_against(*v, &ty)
+            .map_err(|e| e.or_span(def.unwrap_or(*span)))?;
+    }
+    Ok(())
+}
+
+// A warning for every definition in the script without a `@type` attribute
+fn missing_annotations(parsed: &[ast::TopLevel]) -> Vec<Warning> {
+    let mut out = vec![];
+    for item in parsed {
+        if item
+            .attributes()
+            .iter()
+            .any(|(attr, _)| attr.name.0 == "type")
+        {
+            continue;
+        }
+        let defs = match item.unattributed() {
+            ast::TopLevel::LetDef(def) | ast::TopLevel::Const(def) => std::slice::from_ref(def),
+            ast::TopLevel::LetRecDef(defs) => defs,
+            _ => continue,
+        };
+        for ((name, span), _) in defs {
+            let kind = WarningKind::MissingAnnotation(name.clone());
+            out.push(Warning { kind, span: *span });
+        }
+    }
+    out
+}
+
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "debug", skip_all, fields(span = ?expr.1))
+)]
 fn check_expr(
     engine: &mut TypeCheckerCore,
     bindings: &mut Bindings,
-    expr: &ast::Expr,
+    index: &mut TypeIndex,
+    expr: &ast::Spanned<ast::Expr>,
+) -> Result<Value> {
+    engine.check_cancelled()?;
+    let t = check_expr_kind(engine, bindings, index, expr)?;
+    index.types.push((expr.1, t));
+    Ok(t)
+}
+
+// The template of `format "..."`, unless format has been rebound. Each
+// placeholder is checked as a field access on the argument that must give a
+// str, so format has no type of its own.
+fn format_template<'a>(
+    bindings: &Bindings,
+    expr: &'a ast::Spanned<ast::Expr>,
+) -> Option<(&'a str, Span)> {
+    let (func, template) = match &expr.0 {
+        ast::Expr::Call(func, template) => (func, template),
+        _ => return None,
+    };
+    match (&func.0, &template.0) {
+        (ast::Expr::Variable(name), ast::Expr::Literal(ast::Literal::Str(s)))
+            if name == "format"
+                && bindings.def_span(name).is_none()
+                && !bindings.builtins.contains_key(name) =>
+        {
+            Some((s, template.1))
+        }
+        _ => None,
+    }
+}
+
+fn check_expr_kind(
+    engine: &mut TypeCheckerCore,
+    bindings: &mut Bindings,
+    index: &mut TypeIndex,
+    expr: &ast::Spanned<ast::Expr>,
 ) -> Result<Value> {
     use ast::Expr::*;
+    let (expr, span) = expr;
+    let span = *span;
     match expr {
         Literal(val) => {
             use ast::Literal::*;
             match val {
                 Bool(_) => Ok(engine.bool()),
+                Str(_) => Ok(engine.str()),
             }
         }
-        Variable(name) => bindings
-            .get(&name)
-            .with_context(|| format!("Undefined variable {}", name)),
+        Variable(name) => {
+            if let Some(def) = bindings.def_span(name) {
+                index.refs.push((span, def));
+                let attrs = bindings.attributes(name);
+                if let Some(attr) = attrs.iter().find(|attr| attr.name.0 == "deprecated") {
+                    let note = attr.arg.as_ref().map(|(note, _)| note.clone());
+                    let kind = WarningKind::Deprecated(name.clone(), note);
+                    index.warnings.push(Warning { kind, span });
+                }
+                return Ok(bindings.get(name).unwrap());
+            }
+            match bindings.builtins.get(name) {
+                Some(build) => Ok(build(engine)),
+                None if name == "format" => {
+                    Err(Error::new(ErrorKind::Format(FormatError::NotLiteral), span))
+                }
+                None => Err(Error::new(ErrorKind::UndefinedVariable(name.clone()), span)),
+            }
+        }
+        MacroCall((name, _), _) => {
+            let e = MacroError::NotExpanded(name.clone());
+            Err(Error::new(ErrorKind::Macro(e), span))
+        }
         Record(fields) => {
             let mut field_names = HashSet::with_capacity(fields.len());
             let mut field_type_pairs = Vec::with_capacity(fields.len());
-            for (name, expr) in fields {
-                if !field_names.insert(&*name) {
-                    bail!("Repeated field name: {}", name);
+            for ((name, name_span), expr) in fields {
+                if !field_names.insert(name) {
+                    return Err(Error::new(
+                        ErrorKind::RepeatedField(name.clone()),
+                        *name_span,
+                    ));
                 }
 
-                let t = check_expr(engine, bindings, expr)?;
+                let t = check_expr(engine, bindings, index, expr)?;
                 field_type_pairs.push((name.clone(), t));
             }
 
             Ok(engine.obj(field_type_pairs))
         }
-        Case(tag, val_expr) => {
-            let val_type = check_expr(engine, bindings, val_expr)?;
+        Dict(entries) => {
+            let (value, value_bound) = engine.var();
+            for (_, expr) in entries {
+                let t = check_expr(engine, bindings, index, expr)?;
+                engine.flow_at(t, value_bound, expr.1)?;
+            }
+            Ok(engine.dict(value))
+        }
+        Set(items) => {
+            let (elem, elem_bound) = engine.var();
+            for expr in items {
+                let t = check_expr(engine, bindings, index, expr)?;
+                engine.flow_at(t, elem_bound, expr.1)?;
+            }
+            Ok(engine.set(elem))
+        }
+        Case((tag, _), val_expr) => {
+            let val_type = check_expr(engine, bindings, index, val_expr)?;
             Ok(engine.case((tag.clone(), val_type)))
         }
-        If(cond_expr, then_expr, else_expr) => {
-            let cond_type = check_expr(engine, bindings, cond_expr)?;
+        If(cond_expr, then_expr, else_expr, _) => {
+            let cond_type = check_expr(engine, bindings, index, cond_expr)?;
             let bound = engine.bool_use();
-            engine.flow(cond_type, bound)?;
+            engine.flow_at(cond_type, bound, cond_expr.1)?;
 
-            let then_type = check_expr(engine, bindings, then_expr)?;
-            let else_type = check_expr(engine, bindings, else_expr)?;
+            let then_type = check_expr(engine, bindings, index, then_expr)?;
+            let else_type = check_expr(engine, bindings, index, else_expr)?;
 
             let (merged, merged_bound) = engine.var();
-            engine.flow(then_type, merged_bound)?;
-            engine.flow(else_type, merged_bound)?;
+            engine.flow_at(then_type, merged_bound, then_expr.1)?;
+            engine.flow_at(else_type, merged_bound, else_expr.1)?;
             Ok(merged)
         }
-        FieldAccess(lhs_expr, name) => {
-            let lhs_type = check_expr(engine, bindings, lhs_expr)?;
+        FieldAccess(lhs_expr, (name, _)) => {
+            let lhs_type = check_expr(engine, bindings, index, lhs_expr)?;
             let (field_type, field_bound) = engine.var();
             let bound = engine.obj_use((name.clone(), field_bound));
-            engine.flow(lhs_type, bound)?;
+            engine.flow_at(lhs_type, bound, span)?;
             Ok(field_type)
         }
         Match(match_expr, cases) => {
-            let match_type = check_expr(engine, bindings, match_expr)?;
+            let match_type = check_expr(engine, bindings, index, match_expr)?;
             let (result_type, result_bound) = engine.var();
 
             let mut case_names = HashSet::with_capacity(cases.len());
             let mut case_type_pairs = Vec::with_capacity(cases.len());
-            for ((tag, name), rhs_expr) in cases {
-                if !case_names.insert(&*name) {
-                    bail!("Repeated match case {}", name);
+            for (((tag, tag_span), (name, name_span)), rhs_expr) in cases {
+                if !case_names.insert(tag) {
+                    return Err(Error::new(ErrorKind::RepeatedCase(tag.clone()), *tag_span));
                 }
                 let (wrapped_type, wrapped_bound) = engine.var();
+                index.binder(*name_span, wrapped_type);
                 case_type_pairs.push((tag.clone(), wrapped_bound));
 
                 let rhs_type = bindings.in_child_scope(|bindings| {
-                    bindings.insert(name.clone(), wrapped_type);
-                    check_expr(engine, bindings, rhs_expr)
+                    bindings.insert(name.clone(), wrapped_type, *name_span);
+                    check_expr(engine, bindings, index, rhs_expr)
                 })?;
-                engine.flow(rhs_type, result_bound)?;
+                engine.flow_at(rhs_type, result_bound, rhs_expr.1)?;
             }
 
             let bound = engine.case_use(case_type_pairs);
-            engine.flow(match_type, bound)?;
+            engine.flow_at(match_type, bound, match_expr.1)?;
             Ok(result_type)
         }
-        FuncDef(arg_name, body_expr) => {
+        FuncDef((arg_name, arg_span), body_expr) => {
             let (arg_type, arg_bound) = engine.var();
+            index.binder(*arg_span, arg_type);
             let body_type = bindings.in_child_scope(|bindings| {
-                bindings.insert(arg_name.clone(), arg_type);
-                check_expr(engine, bindings, body_expr)
+                bindings.insert(arg_name.clone(), arg_type, *arg_span);
+                check_expr(engine, bindings, index, body_expr)
             })?;
             Ok(engine.func(arg_bound, body_type))
         }
+        BinOp(lhs_expr, (op, op_span), rhs_expr)
+            if bindings.def_span(op).is_none() && !bindings.builtins.contains_key(op) =>
+        {
+            let builtin = match operators::builtin(op) {
+                Some(builtin) => builtin,
+                None => {
+                    let kind = ErrorKind::UndefinedVariable(op.clone());
+                    return Err(Error::new(kind, *op_span));
+                }
+            };
+            let lhs_type = check_expr(engine, bindings, index, lhs_expr)?;
+            let rhs_type = check_expr(engine, bindings, index, rhs_expr)?;
+            let (first, second) = match builtin {
+                operators::Builtin::Method(method) => {
+                    let (ret_type, ret_bound) = engine.var();
+                    let func_bound = engine.func_use(rhs_type, ret_bound);
+                    let bound = engine.obj_use((method.to_owned(), func_bound));
+                    engine.flow_at(lhs_type, bound, span)?;
+                    return Ok(ret_type);
+                }
+                operators::Builtin::Compose => ((lhs_type, lhs_expr), (rhs_type, rhs_expr)),
+                operators::Builtin::ComposeBack => ((rhs_type, rhs_expr), (lhs_type, lhs_expr)),
+            };
+            // The type of `fun x -> second (first x)`
+            let (arg, arg_bound) = engine.var();
+            let (mid, mid_bound) = engine.var();
+            let (ret, ret_bound) = engine.var();
+            let bound = engine.func_use(arg, mid_bound);
+            engine.flow_at(first.0, bound, first.1 .1)?;
+            let bound = engine.func_use(mid, ret_bound);
+            engine.flow_at(second.0, bound, second.1 .1)?;
+            Ok(engine.func(arg_bound, ret))
+        }
+        BinOp(lhs_expr, (op, op_span), rhs_expr) => {
+            let op_expr = (Variable(op.clone()), *op_span);
+            let mut func_type = check_expr(engine, bindings, index, &op_expr)?;
+            for arg_expr in [lhs_expr, rhs_expr] {
+                let arg_type = check_expr(engine, bindings, index, arg_expr)?;
+                let (ret_type, ret_bound) = engine.var();
+                let bound = engine.func_use(arg_type, ret_bound);
+                engine.flow_at(func_type, bound, span)?;
+                func_type = ret_type;
+            }
+            Ok(func_type)
+        }
+        Compare(first_expr, rest) => {
+            let mut lhs_type = check_expr(engine, bindings, index, first_expr)?;
+            for ((op, op_span), rhs_expr) in rest {
+                let rhs_type = check_expr(engine, bindings, index, rhs_expr)?;
+                if bindings.def_span(op).is_none() && !bindings.builtins.contains_key(op) {
+                    let bound = engine.str_use();
+                    engine.flow_at(lhs_type, bound, span)?;
+                    let bound = engine.str_use();
+                    engine.flow_at(rhs_type, bound, rhs_expr.1)?;
+                } else {
+                    let op_expr = (Variable(op.clone()), *op_span);
+                    let mut func_type = check_expr(engine, bindings, index, &op_expr)?;
+                    for arg_type in [lhs_type, rhs_type] {
+                        let (ret_type, ret_bound) = engine.var();
+                        let bound = engine.func_use(arg_type, ret_bound);
+                        engine.flow_at(func_type, bound, span)?;
+                        func_type = ret_type;
+                    }
+                    let bound = engine.bool_use();
+                    engine.flow_at(func_type, bound, span)?;
+                }
+                lhs_type = rhs_type;
+            }
+            Ok(engine.bool())
+        }
         Call(func_expr, arg_expr) => {
-            let func_type = check_expr(engine, bindings, func_expr)?;
-            let arg_type = check_expr(engine, bindings, arg_expr)?;
+            if let Some((template, template_span)) = format_template(bindings, func_expr) {
+                let pieces = builtins::parse_format(template)
+                    .map_err(|e| Error::new(ErrorKind::Format(e), template_span))?;
+                let arg_type = check_expr(engine, bindings, index, arg_expr)?;
+                let mut fields = vec![];
+                for piece in pieces {
+                    if let builtins::FormatPiece::Field(name) = piece {
+                        let bound = engine.str_use();
+                        fields.push((name, bound));
+                    }
+                }
+                let bound = engine.obj_use_all(fields);
+                engine.flow_at(arg_type, bound, arg_expr.1)?;
+                return Ok(engine.str());
+            }
+
+            let func_type = check_expr(engine, bindings, index, func_expr)?;
+            let arg_type = check_expr(engine, bindings, index, arg_expr)?;
 
             let (ret_type, ret_bound) = engine.var();
             let bound = engine.func_use(arg_type, ret_bound);
-            engine.flow(func_type, bound)?;
+            engine.flow_at(func_type, bound, span)?;
             Ok(ret_type)
         }
-        Let((name, var_expr), rest_expr) => {
-            let var_type = check_expr(engine, bindings, var_expr)?;
+        Let(((name, name_span), var_expr), rest_expr, _) => {
+            let var_type = check_expr(engine, bindings, index, var_expr)?;
+            index.binder(*name_span, var_type);
             bindings.in_child_scope(|bindings| {
-                bindings.insert(name.clone(), var_type);
-                check_expr(engine, bindings, rest_expr)
+                bindings.insert(name.clone(), var_type, *name_span);
+                check_expr(engine, bindings, index, rest_expr)
             })
         }
         LetRec(defs, rest_expr) => bindings.in_child_scope(|bindings| {
             let mut temp_bounds = Vec::with_capacity(defs.len());
-            for (name, _) in defs {
+            for ((name, name_span), _) in defs {
                 let (temp_type, temp_bound) = engine.var();
-                bindings.insert(name.clone(), temp_type);
+                index.binder(*name_span, temp_type);
+                bindings.insert(name.clone(), temp_type, *name_span);
                 temp_bounds.push(temp_bound);
             }
 
             for ((_, expr), bound) in defs.iter().zip(temp_bounds) {
-                let var_type = check_expr(engine, bindings, expr)?;
-                engine.flow(var_type, bound)?;
+                let var_type = check_expr(engine, bindings, index, expr)?;
+                engine.flow_at(var_type, bound, expr.1)?;
             }
 
-            check_expr(engine, bindings, rest_expr)
+            check_expr(engine, bindings, index, rest_expr)
         }),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{shape, TypeCheckerCore, TypeNode, TypeckState};
+    use crate::parse;
+
+    // Two closed items, so that the parallel check runs them on threads
+    const WIDENED: &str = "let r = fun b -> if b then {a = true} else if b then {a = true; c = true} else {a = false; d = {x = true}};
+let s = fun b -> if b then `A {x = true} else if b then `A {x = false} else `A {x = true}";
+
+    fn check(parallel: bool, script: &str) -> (Result<(), String>, Vec<Option<String>>, usize) {
+        let mut state = TypeckState::new();
+        state.set_widening(Some(1));
+        let parsed = parse(script).unwrap();
+        let res = match parallel {
+            true => state.check_script_parallel(&parsed),
+            false => state.check_script(&parsed),
+        };
+        let types = ["r", "s"].iter().map(|name| state.display_type_of(name));
+        let widened = state.core().stats().widened;
+        (res.map_err(|e| e.to_string()), types.collect(), widened)
+    }
+
+    #[test]
+    fn parallel_check_matches_sequential() {
+        let failing =
+            "let r = fun b -> b; let s = fun b -> (if b then {a = true} else {c = true}).a";
+        assert_eq!(check(true, WIDENED), check(false, WIDENED));
+        assert!(check(true, WIDENED).2 > 0);
+
+        // A failed check leaves partial state behind, so only the error and bindings are compared
+        let (res, types, _) = check(true, failing);
+        let (expected_res, expected_types, _) = check(false, failing);
+        assert_eq!((&res, types), (&expected_res, expected_types));
+        assert_eq!(res, Err("Missing field: a".to_string()));
+    }
+
+    #[test]
+    fn compact_keeps_globals_and_drops_dead_nodes() {
+        let script = "let f = fun x -> {a = x; b = \"s\"};
+(fun r -> if r.c then `A r.d else `B {e = r}) {c = true; d = false};
+let g = fun r -> (f r).a;
+{x = f; y = g; z = `C true}";
+        let mut state = TypeckState::new();
+        state.check_script(&parse(script).unwrap()).unwrap();
+        let before = state.core().stats().nodes;
+        let types = ["f", "g"].map(|name| state.display_type_of(name));
+
+        state.compact();
+        assert!(state.core().stats().nodes < before);
+        assert_eq!(["f", "g"].map(|name| state.display_type_of(name)), types);
+        // The globals can still be used after compacting
+        let uses = parse("let h = not (g true); let i = (f false).b").unwrap();
+        state.check_script(&uses).unwrap();
+    }
+
+    #[test]
+    fn can_flow_leaves_the_graph_unchanged() {
+        let mut core = TypeCheckerCore::new();
+        let (v, u) = core.var();
+        let b = core.bool();
+        let r = core.obj(vec![("a".to_string(), b)]);
+        core.flow(r, u).unwrap();
+        let bool_use = core.bool_use();
+        let fits = core.obj_use(("a".to_string(), bool_use));
+        let str_use = core.str_use();
+        let wrong = core.obj_use(("a".to_string(), str_use));
+        let missing = core.obj_use(("c".to_string(), str_use));
+        let counts = |core: &TypeCheckerCore| (core.stats().nodes, core.stats().edges);
+        let before = counts(&core);
+
+        assert!(core.can_flow(v, fits).is_ok());
+        assert_eq!(counts(&core), before);
+        for rhs in [wrong, missing] {
+            assert!(core.can_flow(v, rhs).is_err());
+            assert_eq!(counts(&core), before);
+        }
+        // Nested in an isolated check, which still undoes its own changes
+        core.begin_isolated();
+        let (w, _) = core.var();
+        core.flow(r, fits).unwrap();
+        assert!(core.can_flow(w, missing).is_ok());
+        assert!(core.can_flow(v, missing).is_err());
+        core.end_isolated();
+        assert_eq!(counts(&core), before);
+
+        // Whereas flowing for real adds edges
+        core.flow(v, fits).unwrap();
+        assert!(counts(&core).1 > before.1);
+    }
+
+    // Each function flowing into `sink` gives the result of `f true` a
+    // record head, which flows into every x
+    fn many_heads(n: usize) -> String {
+        let mut items = vec!["let sink = fun f -> f true".to_string()];
+        for i in 0..n {
+            items.push(format!(
+                "let x{} = sink (fun b -> {{a = b; f{} = b}})",
+                i, i
+            ));
+        }
+        items.join(";\n")
+    }
+
+    fn checked_with(limit: Option<usize>, script: &str) -> TypeckState {
+        let mut state = TypeckState::new();
+        state.set_widening(limit);
+        state.check_script(&parse(script).unwrap()).unwrap();
+        state
+    }
+
+    // The most value heads with parts flowing into a variable that isn't
+    // part of a summary
+    fn most_heads(core: &TypeCheckerCore) -> usize {
+        let has_parts = |j: usize| match &core.types[j] {
+            TypeNode::Value(head) => shape(head).is_some(),
+            _ => false,
+        };
+        (0..core.types.len())
+            .filter(|&i| matches!(core.types[i], TypeNode::Var))
+            .filter(|i| !core.widening.summary_children.contains(i))
+            .map(|i| core.r.upsets(i).filter(|&j| has_parts(j)).count())
+            .max()
+            .unwrap()
+    }
+
+    #[test]
+    fn widening_bounds_heads_per_variable() {
+        for n in [40, 80] {
+            let script = many_heads(n);
+            let exact = checked_with(None, &script);
+            let widened = checked_with(Some(4), &script);
+            assert!(most_heads(&exact.core) >= n);
+            // Beyond the limit, only the record summary and the narrower one
+            // replacing it once records without some field are merged
+            assert!(most_heads(&widened.core) <= 4 + 2);
+            assert!(widened.core().stats().edges < exact.core().stats().edges);
+        }
+    }
+
+    #[test]
+    fn widening_off_or_unreached_changes_nothing() {
+        let stats = |state: &TypeckState| {
+            let s = state.core().stats();
+            (s.nodes, s.edges, s.widened, state.display_type_of("x3"))
+        };
+        let script = many_heads(40);
+        let mut default = TypeckState::new();
+        default.check_script(&parse(&script).unwrap()).unwrap();
+        assert_eq!(stats(&checked_with(None, &script)), stats(&default));
+
+        let script = many_heads(4);
+        let widened = checked_with(Some(8), &script);
+        assert_eq!(stats(&widened), stats(&checked_with(None, &script)));
+        assert_eq!(widened.core().stats().widened, 0);
+    }
+}
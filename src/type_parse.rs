@@ -0,0 +1,222 @@
+use crate::{
+    error::{Error, ErrorKind, Result, SyntaxError},
+    simplify::TypeExpr,
+    span::Span,
+};
+use std::str::FromStr;
+
+// Reads types written the way they are displayed, such as
+// "(bool -> 'a) -> 'a" or "{name: str, tags: set str}". Spans in errors
+// are offsets into the string.
+impl FromStr for TypeExpr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut p = Parser { src: s, pos: 0 };
+        let ty = p.func()?;
+        p.skip_space();
+        if p.pos < s.len() {
+            return Err(p.error(SyntaxError::ExpectedEnd));
+        }
+        Ok(ty)
+    }
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+impl Parser<'_> {
+    fn error(&self, kind: SyntaxError) -> Error {
+        let end = self.src[self.pos..]
+            .chars()
+            .next()
+            .map_or(self.pos, |c| self.pos + c.len_utf8());
+        Error::new(ErrorKind::Syntax(kind), Span::new(self.pos, end))
+    }
+
+    fn skip_space(&mut self) {
+        let rest = &self.src[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_space();
+        if self.src[self.pos..].starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Result<()> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(self.error(SyntaxError::ExpectedToken(token.to_string())))
+        }
+    }
+
+    fn name(&mut self) -> &str {
+        let start = self.pos;
+        let len = self.src[start..]
+            .find(|c| !is_name_char(c))
+            .unwrap_or(self.src.len() - start);
+        self.pos += len;
+        &self.src[start..self.pos]
+    }
+
+    // Functions bind loosest and associate to the right
+    fn func(&mut self) -> Result<TypeExpr> {
+        let arg = self.union()?;
+        if self.eat("->") {
+            let ret = self.func()?;
+            return Ok(TypeExpr::Func(Box::new(arg), Box::new(ret)));
+        }
+        Ok(arg)
+    }
+
+    // Cases in a union are gathered into one Case, as they are displayed
+    fn union(&mut self) -> Result<TypeExpr> {
+        let mut parts = vec![self.intersection()?];
+        while self.eat("|") {
+            parts.push(self.intersection()?);
+        }
+        if parts.len() == 1 {
+            return Ok(parts.pop().unwrap());
+        }
+        let mut cases = vec![];
+        // Where the cases go among the other parts
+        let mut at = None;
+        let mut others = vec![];
+        for part in parts {
+            match part {
+                TypeExpr::Case(c) => {
+                    at.get_or_insert(others.len());
+                    cases.extend(c);
+                }
+                part => others.push(part),
+            }
+        }
+        match at {
+            Some(_) if others.is_empty() => return Ok(TypeExpr::Case(cases)),
+            Some(i) => others.insert(i, TypeExpr::Case(cases)),
+            None => {}
+        }
+        Ok(TypeExpr::Union(others))
+    }
+
+    fn intersection(&mut self) -> Result<TypeExpr> {
+        let mut parts = vec![self.applied()?];
+        while self.eat("&") {
+            parts.push(self.applied()?);
+        }
+        if parts.len() == 1 {
+            return Ok(parts.pop().unwrap());
+        }
+        Ok(TypeExpr::Intersection(parts))
+    }
+
+    // A case, dict or set applied to its payload type
+    fn applied(&mut self) -> Result<TypeExpr> {
+        self.skip_space();
+        if self.eat("`") {
+            let tag = format!("`{}", self.name());
+            if tag.len() == 1 {
+                return Err(self.error(SyntaxError::ExpectedTag));
+            }
+            let payload = self.applied()?;
+            return Ok(TypeExpr::Case(vec![(tag, payload)]));
+        }
+        let start = self.pos;
+        match self.name() {
+            "dict" => return Ok(TypeExpr::Dict(Box::new(self.applied()?))),
+            "set" => return Ok(TypeExpr::Set(Box::new(self.applied()?))),
+            _ => self.pos = start,
+        }
+        self.atom()
+    }
+
+    fn atom(&mut self) -> Result<TypeExpr> {
+        self.skip_space();
+        if self.eat("(") {
+            let ty = self.func()?;
+            self.expect(")")?;
+            return Ok(ty);
+        }
+        if self.eat("{") {
+            let mut fields = vec![];
+            if !self.eat("}") {
+                loop {
+                    self.skip_space();
+                    let name = self.name().to_owned();
+                    if name.is_empty() {
+                        return Err(self.error(SyntaxError::ExpectedField));
+                    }
+                    self.expect(":")?;
+                    fields.push((name, self.func()?));
+                    if self.eat("}") {
+                        break;
+                    }
+                    self.expect(",")?;
+                }
+            }
+            return Ok(TypeExpr::Record(fields));
+        }
+        // Extends as far as it can, like a function
+        if self.eat("μ") {
+            self.expect("'")?;
+            let var = self.var()?;
+            self.expect(".")?;
+            return Ok(TypeExpr::Rec(var, Box::new(self.func()?)));
+        }
+        if self.eat("'") {
+            return Ok(TypeExpr::Var(self.var()?));
+        }
+        let start = self.pos;
+        Ok(match self.name() {
+            "any" => TypeExpr::Top,
+            "never" => TypeExpr::Bottom,
+            "bool" => TypeExpr::Bool,
+            "str" => TypeExpr::Str,
+            "" => return Err(self.error(SyntaxError::ExpectedType)),
+            name if name.starts_with(char::is_alphabetic) => TypeExpr::Custom(name.to_owned()),
+            _ => {
+                self.pos = start;
+                return Err(self.error(SyntaxError::ExpectedType));
+            }
+        })
+    }
+
+    // 'a to 'z, then 'a1 and so on, as type variables are displayed. The
+    // quote has been read.
+    fn var(&mut self) -> Result<usize> {
+        let start = self.pos;
+        let name = self.name();
+        let mut chars = name.chars();
+        let letter = match chars.next() {
+            Some(c @ 'a'..='z') => c as usize - 'a' as usize,
+            _ => {
+                self.pos = start;
+                return Err(self.error(SyntaxError::ExpectedTypeVar));
+            }
+        };
+        let round = match chars.as_str() {
+            "" => 0,
+            digits => match digits.parse::<usize>() {
+                Ok(n) => n,
+                Err(_) => {
+                    self.pos = start;
+                    return Err(self.error(SyntaxError::ExpectedTypeVar));
+                }
+            },
+        };
+        Ok(round * 26 + letter)
+    }
+}
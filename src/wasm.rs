@@ -0,0 +1,40 @@
+use crate::{Source, TypeckState};
+use serde_json::json;
+use wasm_bindgen::prelude::*;
+
+// Checks a script and returns a plain object for the playground:
+// `{ok, diagnostics: [{message, start, end, line, col}], types: [{name, type}]}`.
+// Offsets are in UTF-8 bytes, lines and columns are one based.
+#[wasm_bindgen]
+pub fn check(source: &str) -> JsValue {
+    let source = Source::new("<playground>", source);
+    let mut diagnostics = vec![];
+    let mut types = vec![];
+
+    let res = crate::parse(&source.text).and_then(|parsed| {
+        let mut state = TypeckState::new();
+        state.check_script(&parsed)?;
+        for sym in state.symbols(&parsed) {
+            types.push(json!({"name": sym.name, "type": sym.ty}));
+        }
+        Ok(())
+    });
+    if let Err(e) = res {
+        let span = e.span.unwrap_or_default();
+        let (line, col) = source.line_col(span.start);
+        diagnostics.push(json!({
+            "message": e.to_string(),
+            "start": span.start,
+            "end": span.end,
+            "line": line,
+            "col": col,
+        }));
+    }
+
+    let result = json!({
+        "ok": diagnostics.is_empty(),
+        "diagnostics": diagnostics,
+        "types": types,
+    });
+    js_sys::JSON::parse(&result.to_string()).unwrap()
+}
@@ -0,0 +1,16 @@
+// Runs the examples against their expected files, as `zx test-examples
+// examples` does
+
+#[test]
+fn examples_match_expected() {
+    let results = zx::golden::run_dir("examples", false).unwrap();
+    assert!(!results.is_empty());
+    for result in results {
+        assert_eq!(
+            result.outcome,
+            zx::golden::Outcome::Passed,
+            "{}",
+            result.path.display()
+        );
+    }
+}
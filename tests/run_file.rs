@@ -0,0 +1,25 @@
+// run_file checks scripts as `zx check` does, with their includes and
+// macros
+
+use std::fs;
+
+#[test]
+fn run_file_expands_includes_and_macros() {
+    let dir = std::env::temp_dir().join(format!("zx-run-file-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("ops.zx"), "infixr 3 &&&;\nlet (&&&) = both").unwrap();
+    fs::write(dir.join("bad.zx"), "let y = true.x").unwrap();
+    fs::write(
+        dir.join("main.zx"),
+        "include \"ops.zx\";\nmacro pick c a b = if c then a else b;\nlet x = pick! true (true &&& false) false",
+    )
+    .unwrap();
+    fs::write(dir.join("broken.zx"), "include \"bad.zx\";\nlet z = true").unwrap();
+
+    let res = zx::run_file(dir.join("main.zx"));
+    let err = zx::run_file(dir.join("broken.zx")).unwrap_err();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(res.is_ok(), "{}", res.unwrap_err());
+    assert!(err.file.ends_with("bad.zx"), "{}", err);
+}
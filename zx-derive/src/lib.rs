@@ -0,0 +1,88 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Fields, Type};
+
+// Derives `zx::ZxType`. Structs with named fields become records, newtypes
+// the type they wrap and unit structs the empty record. Enum variants
+// become cases tagged with the variant name, whose payload follows the same
+// rules as a struct.
+#[proc_macro_derive(ZxType)]
+pub fn derive_zx_type(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let mut input = parse_macro_input!(input as DeriveInput);
+    let (value, use_) = match &input.data {
+        Data::Struct(data) => match fields(&data.fields) {
+            Ok(payload) => payload,
+            Err(e) => return e.into_compile_error().into(),
+        },
+        Data::Enum(data) => {
+            let mut values = vec![];
+            let mut uses = vec![];
+            for variant in &data.variants {
+                let (value, use_) = match fields(&variant.fields) {
+                    Ok(payload) => payload,
+                    Err(e) => return e.into_compile_error().into(),
+                };
+                let tag = format!("`{}", variant.ident);
+                values.push(quote! { (#tag.to_owned(), #value) });
+                uses.push(quote! { (#tag.to_owned(), #use_) });
+            }
+            (
+                quote! {{ let cases = vec![#(#values),*]; core.case_any(cases) }},
+                quote! {{ let cases = vec![#(#uses),*]; core.case_use(cases) }},
+            )
+        }
+        Data::Union(_) => {
+            let e = syn::Error::new_spanned(&input.ident, "ZxType can't be derived for unions");
+            return e.into_compile_error().into();
+        }
+    };
+
+    for param in input.generics.type_params_mut() {
+        param.bounds.push(parse_quote!(::zx::ZxType));
+    }
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let expanded = quote! {
+        impl #impl_generics ::zx::ZxType for #name #ty_generics #where_clause {
+            fn zx_value(core: &mut ::zx::TypeCheckerCore) -> ::zx::Value {
+                #value
+            }
+            fn zx_use(core: &mut ::zx::TypeCheckerCore) -> ::zx::Use {
+                #use_
+            }
+        }
+    };
+    expanded.into()
+}
+
+// The expressions building the value and use types of a struct or variant
+fn fields(fields: &Fields) -> syn::Result<(TokenStream, TokenStream)> {
+    let with = |ty: &Type| {
+        (
+            quote! { <#ty as ::zx::ZxType>::zx_value(core) },
+            quote! { <#ty as ::zx::ZxType>::zx_use(core) },
+        )
+    };
+    match fields {
+        Fields::Named(named) => {
+            let mut values = vec![];
+            let mut uses = vec![];
+            for field in &named.named {
+                let name = field.ident.as_ref().unwrap().to_string();
+                let (value, use_) = with(&field.ty);
+                values.push(quote! { (#name.to_owned(), #value) });
+                uses.push(quote! { (#name.to_owned(), #use_) });
+            }
+            Ok((
+                quote! {{ let fields = vec![#(#values),*]; core.obj(fields) }},
+                quote! {{ let fields = vec![#(#uses),*]; core.obj_use_all(fields) }},
+            ))
+        }
+        Fields::Unnamed(unnamed) if unnamed.unnamed.len() == 1 => Ok(with(&unnamed.unnamed[0].ty)),
+        Fields::Unnamed(unnamed) => Err(syn::Error::new_spanned(
+            unnamed,
+            "zx has no tuples, use named fields instead",
+        )),
+        Fields::Unit => Ok(with(&parse_quote!(()))),
+    }
+}